@@ -1,8 +1,44 @@
+#include <stddef.h>
 #include <stdint.h>
 
+typedef struct {
+    uint64_t ahash;
+    uint64_t dhash;
+    uint64_t phash;
+} PIHashes;
+
+typedef struct {
+    uint64_t id;
+    double score;
+} PIHashMatch;
+
 void *ext_init(const char *);
+/* Uses the cache in one of these modes. The path has to be a nul
+   terminated string. Returns NULL for any other mode or a NULL path. */
+#define PIHASH_CACHE_DISABLED 0
+#define PIHASH_CACHE_READ_ONLY 1
+#define PIHASH_CACHE_WRITE_ONLY 2
+#define PIHASH_CACHE_READ_WRITE 3
+void *ext_init_with_cache_mode(const char *, uint32_t);
 void ext_free();
 uint64_t ext_get_ahash(void *, const char *);
 uint64_t ext_get_dhash(void *, const char *);
 uint64_t ext_get_phash(void *, const char *);
-void *ext_get_phashes(void *, const char *);
\ No newline at end of file
+void *ext_get_phashes(void *, const char *);
+void ext_free_pihashes(void *);
+
+/* Clients of a daemon started with `pihash daemon <socket>`. Failures are
+   reported as NULL or -1. Hashes are at medium precision.
+
+   Every pointer may be NULL, which fails the call (disconnecting NULL does
+   nothing). Otherwise the client has to come from ext_daemon_connect and
+   not be disconnected yet, strings have to be nul terminated, the data of
+   ext_daemon_get_pihashes_from_memory has to hold the given number of
+   bytes and the paths and results of ext_daemon_get_pihashes_batch and
+   the matches of ext_daemon_query have to hold the given count. */
+void *ext_daemon_connect(const char *);
+void ext_daemon_disconnect(void *);
+PIHashes *ext_daemon_get_pihashes(void *, const char *);
+PIHashes *ext_daemon_get_pihashes_from_memory(void *, const uint8_t *, size_t);
+int64_t ext_daemon_get_pihashes_batch(void *, const char **, size_t, PIHashes *);
+int64_t ext_daemon_query(void *, const char *, PIHashMatch *, size_t);
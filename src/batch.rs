@@ -0,0 +1,328 @@
+// Copyright 2016 Drew Short <drew@sothr.com>.
+//
+// Licensed under the MIT license<LICENSE-MIT or http://opensource.org/licenses/MIT>.
+// This file may not be copied, modified, or distributed except according to those terms.
+
+extern crate image;
+
+use std::any::Any;
+use std::fmt;
+use std::io::{Error, ErrorKind};
+use std::iter::Fuse;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::PathBuf;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+use cache::Cache;
+use hash::{self, PerceptualHashes, Precision};
+
+// Structs/Enums //
+
+/**
+ * Something to be hashed by a batch
+ */
+pub enum BatchInput {
+    Path(PathBuf),
+    // An encoded image that is already in memory, with a name to report it by
+    Bytes { name: String, data: Vec<u8> },
+}
+
+/**
+ * How a batch is hashed
+ *
+ * At most max_in_flight inputs are read from the input iterator ahead of the
+ * results that have been consumed, which bounds the memory a batch uses.
+ */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct BatchConfig {
+    pub precision: Precision,
+    pub threads: usize,
+    pub max_in_flight: usize,
+}
+
+/**
+ * The outcome of hashing a single input
+ */
+#[derive(Debug)]
+pub struct BatchResult {
+    // Position of the input in the batch
+    pub index: usize,
+    // The path or name of the input
+    pub source: String,
+    pub hashes: Result<PerceptualHashes, Error>,
+}
+
+struct Job {
+    index: usize,
+    input: BatchInput,
+}
+
+/**
+ * Results of a running batch, in the order they complete
+ *
+ * Dropping this before it is exhausted stops the batch once the inputs
+ * already handed to the workers are done.
+ */
+pub struct BatchResults<I: Iterator<Item = BatchInput>> {
+    inputs: Fuse<I>,
+    next_index: usize,
+    in_flight: usize,
+    max_in_flight: usize,
+    jobs: Option<Sender<Job>>,
+    results: Receiver<BatchResult>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl BatchInput {
+    /**
+     * The path or name the input is reported by
+     */
+    pub fn source(&self) -> String {
+        match *self {
+            BatchInput::Path(ref path) => path.to_string_lossy().into_owned(),
+            BatchInput::Bytes { ref name, .. } => name.clone(),
+        }
+    }
+}
+
+impl From<PathBuf> for BatchInput {
+    fn from(path: PathBuf) -> BatchInput {
+        BatchInput::Path(path)
+    }
+}
+
+impl fmt::Debug for BatchInput {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            BatchInput::Path(ref path) => write!(f, "Path({:?})", path),
+            BatchInput::Bytes { ref name, ref data } => {
+                write!(f, "Bytes {{ name: {:?}, length: {} }}", name, data.len())
+            }
+        }
+    }
+}
+
+impl Default for BatchConfig {
+    fn default() -> BatchConfig {
+        BatchConfig::new(Precision::Medium)
+    }
+}
+
+impl BatchConfig {
+    /**
+     * Use a thread per available core, with two inputs queued for each
+     */
+    pub fn new(precision: Precision) -> BatchConfig {
+        let threads = thread::available_parallelism()
+            .map(|threads| threads.get())
+            .unwrap_or(1);
+        BatchConfig {
+            precision,
+            threads,
+            max_in_flight: threads * 2,
+        }
+    }
+}
+
+impl<I: Iterator<Item = BatchInput>> Iterator for BatchResults<I> {
+    type Item = BatchResult;
+
+    fn next(&mut self) -> Option<BatchResult> {
+        // Keep the workers busy without reading further ahead than allowed
+        while self.in_flight < self.max_in_flight && self.jobs.is_some() {
+            match self.inputs.next() {
+                Some(input) => {
+                    let job = Job {
+                        index: self.next_index,
+                        input,
+                    };
+                    self.next_index += 1;
+                    if let Some(ref jobs) = self.jobs {
+                        if jobs.send(job).is_err() {
+                            break;
+                        }
+                        self.in_flight += 1;
+                    }
+                }
+                // Closing the queue lets the workers exit once it's drained
+                None => self.jobs = None,
+            }
+        }
+
+        if self.in_flight == 0 {
+            return None;
+        }
+        match self.results.recv() {
+            Ok(result) => {
+                self.in_flight -= 1;
+                Some(result)
+            }
+            Err(_) => None,
+        }
+    }
+}
+
+impl<I: Iterator<Item = BatchInput>> Drop for BatchResults<I> {
+    fn drop(&mut self) {
+        self.jobs = None;
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+// Functions //
+
+/**
+ * Hash a batch of inputs across a pool of threads
+ *
+ * Inputs are read lazily as results are consumed. A failure to hash one input
+ * is reported in its result and doesn't stop the rest of the batch. Byte
+ * buffers are never cached.
+ */
+pub fn hash_batch<I: IntoIterator<Item = BatchInput>>(
+    cache: &Option<Cache>,
+    inputs: I,
+    config: &BatchConfig,
+) -> BatchResults<I::IntoIter> {
+    let threads = config.threads.max(1);
+    let (job_sender, job_receiver) = channel::<Job>();
+    let (result_sender, result_receiver) = channel();
+    let job_receiver = Arc::new(Mutex::new(job_receiver));
+
+    let workers = (0..threads)
+        .map(|_| {
+            let jobs = job_receiver.clone();
+            let results = result_sender.clone();
+            let cache = cache.clone();
+            let precision = config.precision;
+            thread::spawn(move || loop {
+                // Only hold the lock while waiting for the next job
+                let job = match jobs.lock() {
+                    Ok(jobs) => jobs.recv(),
+                    Err(_) => return,
+                };
+                let job = match job {
+                    Ok(job) => job,
+                    Err(_) => return,
+                };
+                let result = BatchResult {
+                    index: job.index,
+                    source: job.input.source(),
+                    hashes: hash_input(job.input, &precision, &cache),
+                };
+                if results.send(result).is_err() {
+                    return;
+                }
+            })
+        })
+        .collect();
+
+    BatchResults {
+        inputs: inputs.into_iter().fuse(),
+        next_index: 0,
+        in_flight: 0,
+        max_in_flight: config.max_in_flight.max(1),
+        jobs: Some(job_sender),
+        results: result_receiver,
+        workers,
+    }
+}
+
+/**
+ * Hash a single input, turning a panic into an error
+ */
+pub(crate) fn hash_input(
+    input: BatchInput,
+    precision: &Precision,
+    cache: &Option<Cache>,
+) -> Result<PerceptualHashes, Error> {
+    // A panic while hashing one image shouldn't take the whole batch down
+    let hashed = panic::catch_unwind(AssertUnwindSafe(|| match input {
+        BatchInput::Path(ref path) => hash::try_get_perceptual_hashes(path, precision, cache),
+        BatchInput::Bytes { ref name, ref data } => match image::load_from_memory(data) {
+            Ok(image) => Ok(hash::get_perceptual_hashes_from_image(name, &image, precision)),
+            Err(e) => Err(Error::new(ErrorKind::InvalidData, e)),
+        },
+    }));
+    match hashed {
+        Ok(result) => result,
+        Err(cause) => Err(Error::other(format!(
+            "Hashing panicked: {}",
+            get_panic_message(&cause)
+        ))),
+    }
+}
+
+fn get_panic_message(cause: &Box<dyn Any + Send>) -> &str {
+    if let Some(message) = cause.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = cause.downcast_ref::<String>() {
+        message
+    } else {
+        "unknown cause"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+    use std::io::Read;
+    use std::path::{Path, PathBuf};
+
+    use hash::{self, Precision};
+
+    use super::{hash_batch, BatchConfig, BatchInput};
+
+    #[test]
+    fn test_batch_matches_single_hashing() {
+        let paths = [
+            "test_images/sample_02_large.jpg",
+            "test_images/sample_03_large.jpg",
+            "test_images/sample_04_large.jpg",
+        ];
+        let mut bytes = Vec::new();
+        File::open("test_images/sample_01_medium.jpg")
+            .unwrap()
+            .read_to_end(&mut bytes)
+            .unwrap();
+
+        let mut inputs: Vec<BatchInput> = paths
+            .iter()
+            .map(|path| BatchInput::from(PathBuf::from(path)))
+            .collect();
+        inputs.push(BatchInput::Bytes {
+            name: String::from("sample_01_medium"),
+            data: bytes,
+        });
+        inputs.push(BatchInput::from(PathBuf::from("test_images/missing.jpg")));
+        inputs.push(BatchInput::Bytes {
+            name: String::from("garbage"),
+            data: vec![1, 2, 3],
+        });
+
+        let config = BatchConfig {
+            precision: Precision::Medium,
+            threads: 3,
+            max_in_flight: 2,
+        };
+        let mut results: Vec<_> = hash_batch(&None, inputs, &config).collect();
+        results.sort_by_key(|result| result.index);
+        assert_eq!(results.len(), 6);
+
+        for (result, path) in results.iter().zip(paths.iter()) {
+            let expected = hash::get_perceptual_hashes(Path::new(path), &Precision::Medium, &None);
+            let hashes = result.hashes.as_ref().unwrap();
+            assert_eq!(result.source, *path);
+            assert_eq!(
+                (hashes.ahash, hashes.dhash, hashes.phash),
+                (expected.ahash, expected.dhash, expected.phash)
+            );
+        }
+        assert_eq!(results[3].hashes.as_ref().unwrap().orig_path, "sample_01_medium");
+        assert!(results[4].hashes.is_err());
+        assert!(results[5].hashes.is_err());
+    }
+}
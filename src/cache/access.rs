@@ -0,0 +1,206 @@
+// Copyright 2016 Drew Short <drew@sothr.com>.
+//
+// Licensed under the MIT license<LICENSE-MIT or http://opensource.org/licenses/MIT>.
+// This file may not be copied, modified, or distributed except according to those terms.
+
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Error, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::{get_temp_path, CacheKey};
+
+// Constants //
+
+// Uses recorded between checks of whether the log needs compacting
+const COMPACT_CHECK_INTERVAL: usize = 256;
+// The log is compacted once it's grown to this many times its size when it
+// was last compacted, as long as it's at least COMPACT_MIN_BYTES
+const COMPACT_GROWTH: u64 = 2;
+const COMPACT_MIN_BYTES: u64 = 1024 * 1024;
+// Starts a compacted log, followed by the size of the lines after it
+const COMPACTED_HEADER: &str = "#compacted";
+
+// Structs/Enums //
+
+/**
+ * When a cached value was last used and how many times it has been used
+ */
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct CacheAccess {
+    /// Seconds since the Unix epoch, or 0 if it isn't known
+    pub last_used: u64,
+    pub uses: u64,
+}
+
+/**
+ * Records the uses of cached values in a file beside them
+ *
+ * Each line is `<last used> <uses> <key>`. Uses are appended as they
+ * happen, so several processes can share the log, and the lines of a key
+ * are merged when the log is compacted. A compacted log starts with its
+ * size, so that any process can tell when it has grown enough to compact
+ * again. Recording is best effort: uses recorded by another process while
+ * the log is compacted can be lost.
+ */
+#[derive(Clone, Debug)]
+pub struct AccessLog {
+    path: PathBuf,
+    // Off for caches that reading mustn't change
+    recording: Arc<AtomicBool>,
+    recorded: Arc<AtomicUsize>,
+}
+
+impl CacheAccess {
+    fn merge(&mut self, other: &CacheAccess) {
+        self.last_used = self.last_used.max(other.last_used);
+        self.uses += other.uses;
+    }
+}
+
+impl AccessLog {
+    pub fn new(path: &Path) -> AccessLog {
+        AccessLog {
+            path: path.to_path_buf(),
+            recording: Arc::new(AtomicBool::new(true)),
+            recorded: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /**
+     * Record uses from now on, or ignore them
+     */
+    pub fn set_recording(&self, recording: bool) {
+        self.recording.store(recording, Ordering::SeqCst);
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /**
+     * Record a use of a value now, returning if the log has grown enough
+     * that it should be compacted
+     */
+    pub fn record(&self, key: &CacheKey) -> Result<bool, Error> {
+        if !self.recording.load(Ordering::SeqCst) {
+            return Ok(false);
+        }
+        let line = format!("{} 1 {}\n", get_now(), key);
+        // A single write of a short line isn't interleaved with others
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?
+            .write_all(line.as_bytes())?;
+        let recorded = self.recorded.fetch_add(1, Ordering::SeqCst) + 1;
+        if !recorded.is_multiple_of(COMPACT_CHECK_INTERVAL) {
+            return Ok(false);
+        }
+        self.needs_compacting()
+    }
+
+    fn needs_compacting(&self) -> Result<bool, Error> {
+        let size = self.size()?;
+        if size < COMPACT_MIN_BYTES {
+            return Ok(false);
+        }
+        // Logs from before the header are compacted straight away
+        let mut header = String::new();
+        BufReader::new(File::open(&self.path)?).read_line(&mut header)?;
+        let compacted_size = header
+            .strip_prefix(COMPACTED_HEADER)
+            .and_then(|size| size.trim().parse::<u64>().ok())
+            .unwrap_or(0);
+        Ok(size > compacted_size.saturating_mul(COMPACT_GROWTH))
+    }
+
+    /**
+     * The bytes the log takes up
+     */
+    pub fn size(&self) -> Result<u64, Error> {
+        match fs::metadata(&self.path) {
+            Ok(metadata) => Ok(metadata.len()),
+            Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(0),
+            Err(e) => Err(e),
+        }
+    }
+
+    /**
+     * The uses of every value in the log. Lines that can't be read are
+     * skipped.
+     */
+    pub fn read(&self) -> Result<HashMap<CacheKey, CacheAccess>, Error> {
+        let file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(HashMap::new()),
+            Err(e) => return Err(e),
+        };
+        let mut accesses: HashMap<CacheKey, CacheAccess> = HashMap::new();
+        for line in BufReader::new(file).lines() {
+            let line = line?;
+            let mut fields = line.splitn(3, ' ');
+            let access = (fields.next(), fields.next(), fields.next());
+            if let (Some(last_used), Some(uses), Some(key)) = access {
+                if let (Ok(last_used), Ok(uses), Ok(key)) =
+                    (last_used.parse(), uses.parse(), key.parse())
+                {
+                    accesses
+                        .entry(key)
+                        .or_default()
+                        .merge(&CacheAccess { last_used, uses });
+                }
+            }
+        }
+        Ok(accesses)
+    }
+
+    /**
+     * Rewrite the log with a line per value, dropping the values that
+     * aren't kept
+     */
+    pub fn compact<F: Fn(&CacheKey) -> bool>(&self, keep: F) -> Result<(), Error> {
+        let mut lines = String::new();
+        for (key, access) in self.read()? {
+            if keep(&key) {
+                lines.push_str(&format!("{} {} {}\n", access.last_used, access.uses, key));
+            }
+        }
+        let header = format!("{} {}\n", COMPACTED_HEADER, lines.len());
+        let temp_path = get_temp_path(&self.path);
+        let written = File::create(&temp_path).and_then(|mut file| {
+            file.write_all(header.as_bytes())?;
+            file.write_all(lines.as_bytes())
+        });
+        if let Err(e) = written.and_then(|_| fs::rename(&temp_path, &self.path)) {
+            let _ = fs::remove_file(&temp_path);
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    pub fn clear(&self) -> Result<(), Error> {
+        match fs::remove_file(&self.path) {
+            Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(()),
+            result => result,
+        }
+    }
+}
+
+// Functions //
+
+/**
+ * Seconds since the Unix epoch
+ */
+pub fn get_now() -> u64 {
+    to_unix_time(SystemTime::now())
+}
+
+pub fn to_unix_time(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
@@ -0,0 +1,236 @@
+// Copyright 2016 Drew Short <drew@sothr.com>.
+//
+// Licensed under the MIT license<LICENSE-MIT or http://opensource.org/licenses/MIT>.
+// This file may not be copied, modified, or distributed except according to those terms.
+
+use std::io::{BufReader, BufWriter, Error, ErrorKind, Read, Write};
+
+use super::flate2::read::GzDecoder;
+use super::flate2::write::GzEncoder;
+use super::flate2::Compression;
+use super::{
+    decode_digest, decode_hash, decode_matrix, decode_png, encode_hash, encode_matrix, encode_png,
+    CacheBackend, CacheEntryStats, CacheKey,
+};
+
+// Constants //
+
+const ARCHIVE_MAGIC: &[u8; 8] = b"PIHCARCH";
+const ARCHIVE_VERSION: u32 = 1;
+
+// Functions //
+
+/**
+ * Write the values of a backend to an archive
+ *
+ * An archive is gzip compressed. It starts with `PIHCARCH` and the format
+ * version as a little endian u32, followed by a record for each value: the
+ * length of the key as a little endian u32, the key as text, the length of
+ * the value as a little endian u32 and the value, encoded as the backends
+ * encode it. A record with an empty key ends the archive. The digests of
+ * files remembered by their metadata only apply on the machine they were
+ * made on, so they're left out, as are values that can't be read.
+ */
+pub fn write_archive<W: Write>(
+    backend: &dyn CacheBackend,
+    writer: W,
+) -> Result<CacheEntryStats, Error> {
+    let mut encoder = GzEncoder::new(BufWriter::new(writer), Compression::default());
+    encoder.write_all(ARCHIVE_MAGIC)?;
+    encoder.write_all(&ARCHIVE_VERSION.to_le_bytes())?;
+    let mut written: CacheEntryStats = Default::default();
+    for entry in backend.entries()? {
+        if let CacheKey::Digest(_) = entry.key {
+            continue;
+        }
+        let value = match read_value(backend, &entry.key) {
+            Ok(Some(value)) => value,
+            // Removed since the entries were listed
+            Ok(None) => continue,
+            Err(ref e) if e.kind() == ErrorKind::InvalidData => continue,
+            Err(e) => return Err(e),
+        };
+        let key = format!("{}", entry.key);
+        write_field(&mut encoder, key.as_bytes())?;
+        write_field(&mut encoder, &value)?;
+        written.entries += 1;
+        written.bytes += value.len() as u64;
+    }
+    encoder.write_all(&0u32.to_le_bytes())?;
+    encoder.finish()?.flush()?;
+    Ok(written)
+}
+
+/**
+ * Put the values in an archive into a backend. Values are put as they're
+ * read, so those before any problem with the archive are kept.
+ */
+pub fn read_archive<R: Read>(
+    backend: &dyn CacheBackend,
+    reader: R,
+) -> Result<CacheEntryStats, Error> {
+    let mut decoder = GzDecoder::new(BufReader::new(reader));
+    let mut header = [0u8; 12];
+    decoder
+        .read_exact(&mut header)
+        .map_err(|_| invalid_data("Not a pihash cache archive"))?;
+    if &header[..8] != ARCHIVE_MAGIC {
+        return Err(invalid_data("Not a pihash cache archive"));
+    }
+    let version = read_u32(&header[8..]);
+    if version != ARCHIVE_VERSION {
+        return Err(invalid_data(&format!(
+            "Unsupported cache archive version {}",
+            version
+        )));
+    }
+
+    let mut read: CacheEntryStats = Default::default();
+    loop {
+        let key = read_field(&mut decoder)?;
+        if key.is_empty() {
+            break;
+        }
+        let key: CacheKey = String::from_utf8(key)
+            .ok()
+            .and_then(|key| key.parse().ok())
+            .ok_or_else(|| invalid_data("A cache archive has an unknown key"))?;
+        let value = read_field(&mut decoder)?;
+        write_value(backend, &key, &value)?;
+        read.entries += 1;
+        read.bytes += value.len() as u64;
+    }
+    // Reading to the end checks the archive against its checksum
+    let mut rest = Vec::new();
+    decoder.read_to_end(&mut rest)?;
+    if !rest.is_empty() {
+        return Err(invalid_data("A cache archive continues past its end"));
+    }
+    Ok(read)
+}
+
+/**
+ * A value of a backend as the backends encode it
+ */
+fn read_value(backend: &dyn CacheBackend, key: &CacheKey) -> Result<Option<Vec<u8>>, Error> {
+    Ok(match *key {
+        CacheKey::Image { ref digest, size } => match backend.get_image(digest, size)? {
+            Some(image) => Some(encode_png(&image)?),
+            None => None,
+        },
+        CacheKey::Matrix { ref digest, size } => match backend.get_matrix(digest, size)? {
+            Some(matrix) => Some(encode_matrix(&matrix)?),
+            None => None,
+        },
+        CacheKey::Thumbnail { ref digest, size } => match backend.get_thumbnail(digest, size)? {
+            Some(thumbnail) => Some(encode_png(&thumbnail)?),
+            None => None,
+        },
+        CacheKey::Hash(ref key) => backend
+            .get_hash(key)?
+            .map(|hash| encode_hash(hash).to_vec()),
+        CacheKey::Digest(ref key) => backend.get_digest(key)?.map(|digest| digest.into_bytes()),
+    })
+}
+
+/**
+ * Decode a value and put it in a backend
+ */
+fn write_value(backend: &dyn CacheBackend, key: &CacheKey, value: &[u8]) -> Result<(), Error> {
+    match *key {
+        CacheKey::Image { ref digest, size } => {
+            backend.put_image(digest, size, &decode_png(value)?)
+        }
+        CacheKey::Matrix { ref digest, size } => {
+            backend.put_matrix(digest, size, &decode_matrix(value)?)
+        }
+        CacheKey::Thumbnail { ref digest, size } => {
+            backend.put_thumbnail(digest, size, &decode_png(value)?)
+        }
+        CacheKey::Hash(ref key) => backend.put_hash(key, decode_hash(value)?),
+        CacheKey::Digest(ref key) => backend.put_digest(key, &decode_digest(value)?),
+    }
+}
+
+fn write_field<W: Write>(writer: &mut W, field: &[u8]) -> Result<(), Error> {
+    writer.write_all(&(field.len() as u32).to_le_bytes())?;
+    writer.write_all(field)
+}
+
+/**
+ * Read a field written by write_field, without trusting its length until
+ * that much has been read
+ */
+fn read_field<R: Read>(reader: &mut R) -> Result<Vec<u8>, Error> {
+    let mut length = [0u8; 4];
+    reader
+        .read_exact(&mut length)
+        .map_err(|_| invalid_data("A cache archive is cut short"))?;
+    let length = read_u32(&length) as u64;
+    let mut field = Vec::new();
+    reader.take(length).read_to_end(&mut field)?;
+    if field.len() as u64 != length {
+        return Err(invalid_data("A cache archive is cut short"));
+    }
+    Ok(field)
+}
+
+fn invalid_data(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    let mut value = [0u8; 4];
+    value.copy_from_slice(&bytes[..4]);
+    u32::from_le_bytes(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Write};
+
+    use super::super::flate2::write::GzEncoder;
+    use super::super::flate2::Compression;
+    use super::{read_archive, write_archive};
+    use cache::{CacheBackend, DigestAlgorithm, HashKey, MemoryBackend, MetadataKey};
+    use hash::{HashType, Precision};
+
+    #[test]
+    fn test_round_trip() {
+        let digest = "4beb6f2d852b75a313863916a1803ebad13a3196";
+        let source = MemoryBackend::new(16);
+        let hash_key = HashKey::new(digest, HashType::AHash, Precision::Medium);
+        let metadata_key = MetadataKey {
+            fingerprint: String::from("0123456789abcdef0123456789abcdef"),
+            algorithm: DigestAlgorithm::Sha1,
+        };
+        source.put_hash(&hash_key, 17).unwrap();
+        source
+            .put_matrix(digest, 2, &[vec![0.1, 0.2], vec![0.3, 0.4]])
+            .unwrap();
+        source.put_digest(&metadata_key, digest).unwrap();
+
+        let mut archive = Vec::new();
+        let written = write_archive(&source, &mut archive).unwrap();
+        // Digests by file metadata are left behind
+        assert_eq!(written.entries, 2);
+
+        let target = MemoryBackend::new(16);
+        let read = read_archive(&target, Cursor::new(&archive)).unwrap();
+        assert_eq!(read, written);
+        assert_eq!(target.get_hash(&hash_key).unwrap(), Some(17));
+        assert_eq!(
+            target.get_matrix(digest, 2).unwrap(),
+            Some(vec![vec![0.1, 0.2], vec![0.3, 0.4]])
+        );
+        assert_eq!(target.get_digest(&metadata_key).unwrap(), None);
+
+        // Anything cut short or changed is refused
+        let cut = &archive[..archive.len() - 4];
+        assert!(read_archive(&MemoryBackend::new(16), Cursor::new(cut)).is_err());
+        let mut not_an_archive = GzEncoder::new(Vec::new(), Compression::default());
+        not_an_archive.write_all(b"something else").unwrap();
+        let not_an_archive = not_an_archive.finish().unwrap();
+        assert!(read_archive(&target, Cursor::new(&not_an_archive)).is_err());
+    }
+}
@@ -0,0 +1,285 @@
+// Copyright 2016 Drew Short <drew@sothr.com>.
+//
+// Licensed under the MIT license<LICENSE-MIT or http://opensource.org/licenses/MIT>.
+// This file may not be copied, modified, or distributed except according to those terms.
+
+use std::fmt;
+use std::fs::{File, Metadata};
+use std::io::{Error, Read};
+use std::path::Path;
+use std::str::FromStr;
+
+use super::sha1::Sha1;
+
+// Constants //
+
+// Files are read this much at a time while they're digested
+const READ_BUFFER_SIZE: usize = 64 * 1024;
+
+const XXH_PRIME64_1: u64 = 11_400_714_785_074_694_791;
+const XXH_PRIME64_2: u64 = 14_029_467_366_897_019_727;
+const XXH_PRIME64_3: u64 = 1_609_587_929_392_839_161;
+const XXH_PRIME64_4: u64 = 9_650_029_242_287_828_579;
+const XXH_PRIME64_5: u64 = 2_870_177_450_012_600_261;
+
+// Structs/Enums //
+
+/**
+ * How the contents of a file are digested to identify it in the cache
+ *
+ * SHA-1 is the default. XXH64 is many times faster but only 64 bits, so
+ * two different files are far more likely to be mistaken for each other
+ * in very large collections.
+ */
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub enum DigestAlgorithm {
+    #[default]
+    Sha1,
+    XxHash64,
+}
+
+/**
+ * XXH64, fed a piece at a time
+ */
+pub struct XxHash64 {
+    seed: u64,
+    total_length: u64,
+    accumulators: [u64; 4],
+    buffer: [u8; 32],
+    buffered: usize,
+}
+
+impl FromStr for DigestAlgorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<DigestAlgorithm, String> {
+        match s.to_lowercase().as_str() {
+            "sha1" => Ok(DigestAlgorithm::Sha1),
+            "xxh64" => Ok(DigestAlgorithm::XxHash64),
+            _ => Err(format!("Unknown digest '{}'", s)),
+        }
+    }
+}
+
+impl fmt::Display for DigestAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DigestAlgorithm::Sha1 => write!(f, "sha1"),
+            DigestAlgorithm::XxHash64 => write!(f, "xxh64"),
+        }
+    }
+}
+
+impl XxHash64 {
+    pub fn with_seed(seed: u64) -> XxHash64 {
+        XxHash64 {
+            seed,
+            total_length: 0,
+            accumulators: [
+                seed.wrapping_add(XXH_PRIME64_1).wrapping_add(XXH_PRIME64_2),
+                seed.wrapping_add(XXH_PRIME64_2),
+                seed,
+                seed.wrapping_sub(XXH_PRIME64_1),
+            ],
+            buffer: [0u8; 32],
+            buffered: 0,
+        }
+    }
+
+    pub fn update(&mut self, mut bytes: &[u8]) {
+        self.total_length += bytes.len() as u64;
+        if self.buffered > 0 {
+            let needed = (32 - self.buffered).min(bytes.len());
+            self.buffer[self.buffered..self.buffered + needed].copy_from_slice(&bytes[..needed]);
+            self.buffered += needed;
+            bytes = &bytes[needed..];
+            if self.buffered < 32 {
+                return;
+            }
+            let buffer = self.buffer;
+            self.consume_stripe(&buffer);
+            self.buffered = 0;
+        }
+        while bytes.len() >= 32 {
+            self.consume_stripe(&bytes[..32]);
+            bytes = &bytes[32..];
+        }
+        self.buffer[..bytes.len()].copy_from_slice(bytes);
+        self.buffered = bytes.len();
+    }
+
+    pub fn digest(&self) -> u64 {
+        let mut hash = if self.total_length >= 32 {
+            let [first, second, third, fourth] = self.accumulators;
+            let mut hash = first
+                .rotate_left(1)
+                .wrapping_add(second.rotate_left(7))
+                .wrapping_add(third.rotate_left(12))
+                .wrapping_add(fourth.rotate_left(18));
+            for &accumulator in &self.accumulators {
+                hash = (hash ^ xxh_round(0, accumulator))
+                    .wrapping_mul(XXH_PRIME64_1)
+                    .wrapping_add(XXH_PRIME64_4);
+            }
+            hash
+        } else {
+            self.seed.wrapping_add(XXH_PRIME64_5)
+        };
+        hash = hash.wrapping_add(self.total_length);
+
+        let mut remaining = &self.buffer[..self.buffered];
+        while remaining.len() >= 8 {
+            hash ^= xxh_round(0, read_u64(remaining));
+            hash = hash
+                .rotate_left(27)
+                .wrapping_mul(XXH_PRIME64_1)
+                .wrapping_add(XXH_PRIME64_4);
+            remaining = &remaining[8..];
+        }
+        if remaining.len() >= 4 {
+            hash ^= (read_u32(remaining) as u64).wrapping_mul(XXH_PRIME64_1);
+            hash = hash
+                .rotate_left(23)
+                .wrapping_mul(XXH_PRIME64_2)
+                .wrapping_add(XXH_PRIME64_3);
+            remaining = &remaining[4..];
+        }
+        for &byte in remaining {
+            hash ^= (byte as u64).wrapping_mul(XXH_PRIME64_5);
+            hash = hash.rotate_left(11).wrapping_mul(XXH_PRIME64_1);
+        }
+
+        hash ^= hash >> 33;
+        hash = hash.wrapping_mul(XXH_PRIME64_2);
+        hash ^= hash >> 29;
+        hash = hash.wrapping_mul(XXH_PRIME64_3);
+        hash ^ (hash >> 32)
+    }
+
+    fn consume_stripe(&mut self, stripe: &[u8]) {
+        for (lane, accumulator) in self.accumulators.iter_mut().enumerate() {
+            *accumulator = xxh_round(*accumulator, read_u64(&stripe[lane * 8..]));
+        }
+    }
+}
+
+// Functions //
+
+/**
+ * Digest the contents of a file as hex, reading it a piece at a time
+ */
+pub fn digest_file(path: &Path, algorithm: DigestAlgorithm) -> Result<String, Error> {
+    let mut file = File::open(path)?;
+    let mut buffer = vec![0u8; READ_BUFFER_SIZE];
+    match algorithm {
+        DigestAlgorithm::Sha1 => {
+            let mut sha1 = Sha1::new();
+            read_pieces(&mut file, &mut buffer, |piece| sha1.update(piece))?;
+            Ok(format!("{}", sha1.digest()))
+        }
+        DigestAlgorithm::XxHash64 => {
+            let mut xxhash = XxHash64::with_seed(0);
+            read_pieces(&mut file, &mut buffer, |piece| xxhash.update(piece))?;
+            Ok(format!("{:016x}", xxhash.digest()))
+        }
+    }
+}
+
+/**
+ * Identify a version of a file by its device, inode, size and modification
+ * time, which change whenever it's replaced or written to. Only available
+ * on Unix.
+ */
+#[cfg(unix)]
+pub fn get_metadata_fingerprint(metadata: &Metadata) -> Option<String> {
+    use std::os::unix::fs::MetadataExt;
+
+    let mut bytes = Vec::with_capacity(40);
+    bytes.extend_from_slice(&metadata.dev().to_le_bytes());
+    bytes.extend_from_slice(&metadata.ino().to_le_bytes());
+    bytes.extend_from_slice(&metadata.size().to_le_bytes());
+    bytes.extend_from_slice(&metadata.mtime().to_le_bytes());
+    bytes.extend_from_slice(&metadata.mtime_nsec().to_le_bytes());
+    // Two differently seeded hashes, so that files don't collide in practice
+    let mut first = XxHash64::with_seed(0);
+    let mut second = XxHash64::with_seed(XXH_PRIME64_5);
+    first.update(&bytes);
+    second.update(&bytes);
+    Some(format!("{:016x}{:016x}", first.digest(), second.digest()))
+}
+
+#[cfg(not(unix))]
+pub fn get_metadata_fingerprint(_: &Metadata) -> Option<String> {
+    None
+}
+
+fn read_pieces<F: FnMut(&[u8])>(
+    file: &mut File,
+    buffer: &mut [u8],
+    mut consume: F,
+) -> Result<(), Error> {
+    loop {
+        match file.read(buffer)? {
+            0 => return Ok(()),
+            length => consume(&buffer[..length]),
+        }
+    }
+}
+
+fn xxh_round(accumulator: u64, input: u64) -> u64 {
+    accumulator
+        .wrapping_add(input.wrapping_mul(XXH_PRIME64_2))
+        .rotate_left(31)
+        .wrapping_mul(XXH_PRIME64_1)
+}
+
+fn read_u64(bytes: &[u8]) -> u64 {
+    let mut value = [0u8; 8];
+    value.copy_from_slice(&bytes[..8]);
+    u64::from_le_bytes(value)
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    let mut value = [0u8; 4];
+    value.copy_from_slice(&bytes[..4]);
+    u32::from_le_bytes(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::{digest_file, DigestAlgorithm, XxHash64};
+
+    #[test]
+    fn test_xxhash64() {
+        let digest = |bytes: &[u8]| {
+            let mut xxhash = XxHash64::with_seed(0);
+            xxhash.update(bytes);
+            xxhash.digest()
+        };
+        assert_eq!(digest(b""), 0xef46_db37_51d8_e999);
+        assert_eq!(digest(b"abc"), 0x44bc_2cf5_ad77_0999);
+
+        // Feeding a piece at a time gives the same digest
+        let bytes: Vec<u8> = (0..1000u32).map(|i| (i * 7) as u8).collect();
+        let mut pieces = XxHash64::with_seed(0);
+        for piece in bytes.chunks(13) {
+            pieces.update(piece);
+        }
+        assert_eq!(pieces.digest(), digest(&bytes));
+    }
+
+    #[test]
+    fn test_digest_file() {
+        let path = Path::new("test_images/sample_03_small.jpg");
+        let sha1 = digest_file(path, DigestAlgorithm::Sha1).unwrap();
+        let xxhash = digest_file(path, DigestAlgorithm::XxHash64).unwrap();
+        assert_eq!(sha1.len(), 40);
+        assert_eq!(xxhash.len(), 16);
+        assert_eq!(
+            xxhash,
+            digest_file(path, DigestAlgorithm::XxHash64).unwrap()
+        );
+    }
+}
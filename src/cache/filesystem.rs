@@ -0,0 +1,780 @@
+// Copyright 2016 Drew Short <drew@sothr.com>.
+//
+// Licensed under the MIT license<LICENSE-MIT or http://opensource.org/licenses/MIT>.
+// This file may not be copied, modified, or distributed except according to those terms.
+
+use std::fs::{self, create_dir_all, read_dir, remove_dir_all, File, Metadata};
+use std::io::{Error, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+
+use super::super::rustc_serialize::json;
+use super::access::{to_unix_time, AccessLog};
+use super::image::DynamicImage;
+use super::lock::FileLock;
+use super::{
+    decode_counters, decode_digest, decode_hash, decode_matrix, decode_png, encode_counters,
+    encode_hash, encode_matrix, encode_png, get_name, get_temp_path, CacheBackend, CacheCounters,
+    CacheEntry, CacheKey, CacheStatus, HashKey, MetadataKey,
+};
+use hash::PREPROCESSING;
+
+// Constants //
+
+const CACHED_IMAGE_EXT: &str = "png";
+const CACHED_MATRIX_EXT: &str = "dft";
+const CACHED_HASH_EXT: &str = "hash";
+const CACHED_DIGEST_EXT: &str = "digest";
+// Caching version information. Values are kept in a directory named by the
+// version, apart from version 1 which kept them at the top of the cache.
+const CACHE_VERSION: u32 = 2;
+const CACHE_METADATA_FILE: &str = "cache.meta";
+// Held while the cache as a whole is changed
+const CACHE_LOCK_FILE: &str = "cache.lock";
+const ACCESS_LOG_FILE: &str = "access.log";
+const COUNTERS_FILE: &str = "counters";
+// The directories each kind of value is kept under
+const VALUE_DIRS: [&str; 5] = ["image", "matrix", "thumbnail", "hash", "digest"];
+
+// Structs/Enums //
+
+/**
+ * Marks a directory as a cache. Nothing is ever deleted from a directory
+ * without one.
+ */
+#[derive(RustcDecodable, RustcEncodable)]
+struct CacheMetadata {
+    cache_version: u32,
+}
+
+/**
+ * Keeps every value in its own file under a directory
+ *
+ * Images are PNG files and matrices a checksummed binary format, under
+ * `image/<size>x<size>/` and `matrix/<size>x<size>/`, and hashes are 8
+ * little endian bytes under
+ * `hash/<precision>/<hash type>/v<version>/<preprocessing>/`. The digests
+ * of files are text under `digest/<algorithm>/`, named by the fingerprint
+ * of the file. Files are spread over directories named by the first 10
+ * characters of the digest. Reads are recorded in an access log, and the
+ * modification time of a file counts as a use, and hits and misses are
+ * added up in a counters file.
+ * Every file is written somewhere else first and moved into place, so
+ * readers never see one partially written, even on NFS. Setting up,
+ * migrating and clearing the cache are done holding a lock on
+ * `cache.lock`, so processes sharing a cache don't do them at once.
+ *
+ * All of this is under a directory named by the version of the cache, like
+ * `v2/`, next to the `cache.meta` file marking the directory as a cache.
+ * Values of older versions are moved into the current one by init.
+ */
+#[derive(Clone, Debug)]
+pub struct FileSystemBackend {
+    cache_dir: PathBuf,
+    values_dir: PathBuf,
+    accesses: AccessLog,
+}
+
+impl Default for CacheMetadata {
+    fn default() -> CacheMetadata {
+        CacheMetadata {
+            cache_version: CACHE_VERSION,
+        }
+    }
+}
+
+impl FileSystemBackend {
+    pub fn new(cache_dir: &Path) -> FileSystemBackend {
+        let values_dir = cache_dir.join(get_namespace(CACHE_VERSION));
+        FileSystemBackend {
+            cache_dir: cache_dir.to_path_buf(),
+            accesses: AccessLog::new(&values_dir.join(ACCESS_LOG_FILE)),
+            values_dir,
+        }
+    }
+
+    pub fn cache_dir(&self) -> &Path {
+        &self.cache_dir
+    }
+
+    /**
+     * The file a value is kept in
+     */
+    pub fn get_path(&self, key: &CacheKey) -> PathBuf {
+        get_path_in(&self.values_dir, key)
+    }
+
+    /**
+     * The key of a value file, if the path is one
+     */
+    fn get_key(&self, path: &Path) -> Option<CacheKey> {
+        get_key_in(&self.values_dir, path)
+    }
+
+    /**
+     * The marker of the cache, None if there isn't one and an error if it
+     * can't be read
+     */
+    fn read_metadata(&self) -> Result<Option<CacheMetadata>, Error> {
+        let mut metadata = String::new();
+        match File::open(self.cache_dir.join(CACHE_METADATA_FILE)) {
+            Ok(mut file) => file.read_to_string(&mut metadata)?,
+            Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        match json::decode(&metadata) {
+            Ok(metadata) => Ok(Some(metadata)),
+            Err(e) => Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "{} has an unreadable {}: {}",
+                    self.cache_dir.display(),
+                    CACHE_METADATA_FILE,
+                    e
+                ),
+            )),
+        }
+    }
+
+    fn not_a_cache(&self) -> Error {
+        Error::new(
+            ErrorKind::InvalidInput,
+            format!("{} is not a pihash cache", self.cache_dir.display()),
+        )
+    }
+
+    /**
+     * Refuse a directory without a marker that has anything in it the cache
+     * didn't make. Names alone aren't enough, every value has to be laid out
+     * the way the cache keeps them.
+     */
+    fn check_unmarked(&self) -> Result<(), Error> {
+        let listing = match read_dir(&self.cache_dir) {
+            Ok(listing) => listing,
+            Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        for entry in listing {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let file_type = entry.file_type()?;
+            let made_by_cache = if file_type.is_file() {
+                // Left by another process that's setting the cache up
+                name == CACHE_LOCK_FILE || name.starts_with(CACHE_METADATA_FILE)
+            } else if !file_type.is_dir() {
+                false
+            } else if parse_namespace(&name).is_some() {
+                is_namespace_layout(&entry.path())?
+            } else {
+                VALUE_DIRS.contains(&name.as_str())
+                    && is_value_layout(&self.cache_dir, &entry.path())?
+            };
+            if !made_by_cache {
+                return Err(self.not_a_cache());
+            }
+        }
+        Ok(())
+    }
+
+    /**
+     * Move the values of older versions of the cache into this one
+     */
+    fn migrate(&self) -> Result<CacheStatus, Error> {
+        let mut status = CacheStatus::Ready;
+        let mut namespaces = Vec::new();
+        if VALUE_DIRS
+            .iter()
+            .any(|name| self.cache_dir.join(name).is_dir())
+        {
+            namespaces.push((1, self.cache_dir.clone()));
+        }
+        for entry in read_dir(&self.cache_dir)? {
+            let entry = entry?;
+            match parse_namespace(&entry.file_name().to_string_lossy()) {
+                Some(version) if version < CACHE_VERSION => {
+                    namespaces.push((version, entry.path()))
+                }
+                // Newer versions are left to the versions that use them
+                _ => (),
+            }
+        }
+
+        for (version, root) in namespaces {
+            let (migrated, discarded) = self.migrate_namespace(version, &root)?;
+            status = match status {
+                CacheStatus::Migrated {
+                    from,
+                    migrated: total_migrated,
+                    discarded: total_discarded,
+                } => CacheStatus::Migrated {
+                    from: from.min(version),
+                    migrated: total_migrated + migrated,
+                    discarded: total_discarded + discarded,
+                },
+                _ => CacheStatus::Migrated {
+                    from: version,
+                    migrated,
+                    discarded,
+                },
+            };
+        }
+        Ok(status)
+    }
+
+    /**
+     * Move the values an older version kept under a directory into this
+     * version, removing those that can't be used. Anything that isn't a
+     * value is left where it is.
+     */
+    fn migrate_namespace(&self, version: u32, root: &Path) -> Result<(u64, u64), Error> {
+        let mut migrated = 0;
+        let mut discarded = 0;
+        for (path, _) in get_value_files(root)? {
+            let key = match get_key_in(root, &path) {
+                Some(key) => key,
+                None => continue,
+            };
+            let target = self.get_path(&key);
+            if is_compatible(version, &key) && !target.exists() {
+                if let Some(parent) = target.parent() {
+                    create_dir_all(parent)?;
+                }
+                fs::rename(&path, &target)?;
+                migrated += 1;
+            } else {
+                fs::remove_file(&path)?;
+                discarded += 1;
+            }
+        }
+
+        // Keys are the same in every version, so the uses still apply
+        let access_log = root.join(ACCESS_LOG_FILE);
+        let target = self.values_dir.join(ACCESS_LOG_FILE);
+        if access_log.is_file() && !target.exists() {
+            fs::rename(&access_log, &target)?;
+        }
+        for name in VALUE_DIRS.iter() {
+            remove_empty_dirs(&root.join(name));
+        }
+        if root != self.cache_dir {
+            let _ = fs::remove_dir(root);
+        }
+        Ok((migrated, discarded))
+    }
+
+    /**
+     * The contents of a value's file, or None if it doesn't exist
+     */
+    fn read(&self, key: &CacheKey) -> Result<Option<Vec<u8>>, Error> {
+        match File::open(self.get_path(key)) {
+            Ok(mut file) => {
+                let mut contents = Vec::new();
+                file.read_to_end(&mut contents)?;
+                if let Ok(true) = self.accesses.record(key) {
+                    let _ = self.compact();
+                }
+                Ok(Some(contents))
+            }
+            // It just hasn't been cached
+            Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /**
+     * Write a value's file
+     */
+    fn write(&self, key: &CacheKey, contents: &[u8]) -> Result<(), Error> {
+        let path = self.get_path(key);
+        if let Some(parent) = path.parent() {
+            create_dir_all(parent)?;
+        }
+        write_atomically(&path, contents)
+    }
+}
+
+impl CacheBackend for FileSystemBackend {
+    fn location(&self) -> String {
+        self.cache_dir.to_string_lossy().into_owned()
+    }
+
+    /**
+     * Create the required directories for the cache and mark it, or move
+     * the values of older versions into the current one. A directory that
+     * has anything else in it and isn't marked is refused, as it probably
+     * isn't meant to be a cache.
+     */
+    fn init(&self) -> Result<CacheStatus, Error> {
+        // Checked before anything is made in the directory, and again once
+        // no other process can be setting it up
+        if self.read_metadata()?.is_none() {
+            self.check_unmarked()?;
+        }
+        create_dir_all(&self.cache_dir)?;
+        let _lock = FileLock::lock(&self.cache_dir.join(CACHE_LOCK_FILE))?;
+        let metadata = self.read_metadata()?;
+        let status = match metadata {
+            Some(_) => self.migrate()?,
+            None => {
+                self.check_unmarked()?;
+                CacheStatus::Created
+            }
+        };
+        create_dir_all(&self.values_dir)?;
+
+        // A newer version sharing the cache keeps it marked as its own
+        let current_metadata = CacheMetadata {
+            cache_version: metadata.map_or(CACHE_VERSION, |metadata| {
+                metadata.cache_version.max(CACHE_VERSION)
+            }),
+        };
+        let encoded_cache_metadata = json::encode(&current_metadata).unwrap();
+        write_atomically(
+            &self.cache_dir.join(CACHE_METADATA_FILE),
+            encoded_cache_metadata.as_bytes(),
+        )?;
+        Ok(status)
+    }
+
+    fn is_initialized(&self) -> bool {
+        match self.read_metadata() {
+            Ok(metadata) => metadata.is_some(),
+            Err(_) => false,
+        }
+    }
+
+    fn get_image(&self, digest: &str, size: u32) -> Result<Option<DynamicImage>, Error> {
+        let key = CacheKey::Image {
+            digest: String::from(digest),
+            size,
+        };
+        match self.read(&key)? {
+            Some(png) => decode_png(&png).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn put_image(&self, digest: &str, size: u32, image: &DynamicImage) -> Result<(), Error> {
+        let key = CacheKey::Image {
+            digest: String::from(digest),
+            size,
+        };
+        self.write(&key, &encode_png(image)?)
+    }
+
+    fn get_matrix(&self, digest: &str, size: u32) -> Result<Option<Vec<Vec<f64>>>, Error> {
+        let key = CacheKey::Matrix {
+            digest: String::from(digest),
+            size,
+        };
+        match self.read(&key)? {
+            Some(matrix) => decode_matrix(&matrix[..]).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn put_matrix(&self, digest: &str, size: u32, matrix: &[Vec<f64>]) -> Result<(), Error> {
+        let key = CacheKey::Matrix {
+            digest: String::from(digest),
+            size,
+        };
+        self.write(&key, &encode_matrix(matrix)?)
+    }
+
+    fn get_thumbnail(&self, digest: &str, size: u32) -> Result<Option<DynamicImage>, Error> {
+        let key = CacheKey::Thumbnail {
+            digest: String::from(digest),
+            size,
+        };
+        match self.read(&key)? {
+            Some(png) => decode_png(&png).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn put_thumbnail(&self, digest: &str, size: u32, image: &DynamicImage) -> Result<(), Error> {
+        let key = CacheKey::Thumbnail {
+            digest: String::from(digest),
+            size,
+        };
+        self.write(&key, &encode_png(image)?)
+    }
+
+    fn get_hash(&self, key: &HashKey) -> Result<Option<u64>, Error> {
+        match self.read(&CacheKey::Hash(key.clone()))? {
+            Some(hash) => decode_hash(&hash).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn put_hash(&self, key: &HashKey, hash: u64) -> Result<(), Error> {
+        self.write(&CacheKey::Hash(key.clone()), &encode_hash(hash))
+    }
+
+    fn get_digest(&self, key: &MetadataKey) -> Result<Option<String>, Error> {
+        match self.read(&CacheKey::Digest(key.clone()))? {
+            Some(digest) => decode_digest(&digest).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn put_digest(&self, key: &MetadataKey, digest: &str) -> Result<(), Error> {
+        self.write(&CacheKey::Digest(key.clone()), digest.as_bytes())
+    }
+
+    fn delete(&self, key: &CacheKey) -> Result<bool, Error> {
+        match fs::remove_file(self.get_path(key)) {
+            Ok(_) => Ok(true),
+            Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /**
+     * Every value file, recognised by its path. Anything else in the
+     * directory, such as temporary files, is skipped.
+     */
+    fn entries(&self) -> Result<Vec<CacheEntry>, Error> {
+        let accesses = self.accesses.read()?;
+        let mut entries = Vec::new();
+        for (path, metadata) in get_value_files(&self.values_dir)? {
+            if let Some(key) = self.get_key(&path) {
+                let mut access = accesses.get(&key).cloned().unwrap_or_default();
+                let modified = to_unix_time(metadata.modified()?);
+                access.last_used = access.last_used.max(modified);
+                entries.push(CacheEntry {
+                    key,
+                    bytes: metadata.len(),
+                    access,
+                });
+            }
+        }
+        Ok(entries)
+    }
+
+    fn entry_bytes(&self, key: &CacheKey) -> Result<Option<u64>, Error> {
+        match fs::metadata(self.get_path(key)) {
+            Ok(metadata) => Ok(Some(metadata.len())),
+            Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn overhead_bytes(&self) -> Result<u64, Error> {
+        self.accesses.size()
+    }
+
+    /**
+     * The size of every file the cache made, including its metadata and
+     * anything left behind by older versions
+     */
+    fn disk_usage(&self) -> Result<u64, Error> {
+        let mut bytes = 0;
+        let mut paths = Vec::new();
+        match read_dir(&self.cache_dir) {
+            Ok(listing) => {
+                for entry in listing {
+                    let entry = entry?;
+                    if is_cache_file(&entry.file_name().to_string_lossy()) {
+                        paths.push(entry.path());
+                    }
+                }
+            }
+            Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e),
+        }
+        while let Some(path) = paths.pop() {
+            let metadata = match fs::symlink_metadata(&path) {
+                Ok(metadata) => metadata,
+                // Removed by another process in the meantime
+                Err(ref e) if e.kind() == ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+            bytes += get_disk_size(&metadata);
+            if metadata.is_dir() {
+                for entry in read_dir(&path)? {
+                    paths.push(entry?.path());
+                }
+            }
+        }
+        Ok(bytes)
+    }
+
+    fn counters(&self) -> Result<CacheCounters, Error> {
+        match fs::read_to_string(self.values_dir.join(COUNTERS_FILE)) {
+            Ok(text) => Ok(decode_counters(&text)),
+            Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(Default::default()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /**
+     * Add to the counters file, holding the lock on the cache so that no
+     * other process's counts are lost
+     */
+    fn add_counters(&self, counters: &CacheCounters) -> Result<(), Error> {
+        create_dir_all(&self.values_dir)?;
+        let _lock = FileLock::lock(&self.cache_dir.join(CACHE_LOCK_FILE))?;
+        let mut saved = self.counters()?;
+        saved.add(counters);
+        write_atomically(
+            &self.values_dir.join(COUNTERS_FILE),
+            encode_counters(&saved).as_bytes(),
+        )
+    }
+
+    fn record_accesses(&self, record: bool) {
+        self.accesses.set_recording(record);
+    }
+
+    /**
+     * Drop the uses of values that have been deleted from the access log
+     */
+    fn compact(&self) -> Result<(), Error> {
+        self.accesses.compact(|key| self.get_path(key).is_file())
+    }
+
+    /**
+     * Remove everything the cache made, of every version, apart from the
+     * lock file. A directory that isn't marked as a cache is refused.
+     */
+    fn clear(&self) -> Result<(), Error> {
+        if self.read_metadata()?.is_none() {
+            return if self.cache_dir.exists() {
+                Err(self.not_a_cache())
+            } else {
+                Ok(())
+            };
+        }
+        let lock = FileLock::lock(&self.cache_dir.join(CACHE_LOCK_FILE))?;
+        for entry in read_dir(&self.cache_dir)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if name == CACHE_METADATA_FILE || name == CACHE_LOCK_FILE || !is_cache_file(&name) {
+                continue;
+            }
+            if entry.file_type()?.is_dir() {
+                remove_dir_all(entry.path())?;
+            } else {
+                fs::remove_file(entry.path())?;
+            }
+        }
+        // The marker goes last, so a cache that's partly cleared is still one
+        fs::remove_file(self.cache_dir.join(CACHE_METADATA_FILE))?;
+        drop(lock);
+        // The lock file stays, as another process may be waiting on it and
+        // would otherwise hold a lock no one else can see
+        Ok(())
+    }
+}
+
+// Functions //
+
+/**
+ * The directory the values of a version of the cache are kept in
+ */
+fn get_namespace(version: u32) -> String {
+    format!("v{}", version)
+}
+
+fn parse_namespace(name: &str) -> Option<u32> {
+    name.strip_prefix('v')?.parse().ok()
+}
+
+/**
+ * If a file or directory at the top of a cache is one the cache made
+ */
+fn is_cache_file(name: &str) -> bool {
+    // The marker is written through a temporary file beside it
+    name.starts_with(CACHE_METADATA_FILE)
+        || name == CACHE_LOCK_FILE
+        || name == ACCESS_LOG_FILE
+        || VALUE_DIRS.contains(&name)
+        || parse_namespace(name).is_some()
+}
+
+/**
+ * If everything in the directory of a version is something the cache made
+ */
+fn is_namespace_layout(root: &Path) -> Result<bool, Error> {
+    for entry in read_dir(root)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let file_type = entry.file_type()?;
+        let made_by_cache = if file_type.is_file() {
+            name.starts_with(ACCESS_LOG_FILE) || name.starts_with(COUNTERS_FILE)
+        } else {
+            file_type.is_dir()
+                && VALUE_DIRS.contains(&name.as_str())
+                && is_value_layout(root, &entry.path())?
+        };
+        if !made_by_cache {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/**
+ * If every file under a directory of values is a value, kept where the
+ * version under root keeps it
+ */
+fn is_value_layout(root: &Path, directory: &Path) -> Result<bool, Error> {
+    let mut directories = vec![directory.to_path_buf()];
+    while let Some(directory) = directories.pop() {
+        for entry in read_dir(&directory)? {
+            let entry = entry?;
+            let file_type = entry.file_type()?;
+            if file_type.is_dir() {
+                directories.push(entry.path());
+            } else if !file_type.is_file() || get_key_in(root, &entry.path()).is_none() {
+                return Ok(false);
+            }
+        }
+    }
+    Ok(true)
+}
+
+/**
+ * If a value kept by an older version of the cache is still good
+ */
+fn is_compatible(version: u32, key: &CacheKey) -> bool {
+    match *key {
+        // Hashes of other versions of their algorithm are never used again
+        CacheKey::Hash(ref key) => {
+            key.version == key.hash_type.get_version() && key.preprocessing == PREPROCESSING
+        }
+        // Every version so far has kept the other values the same way
+        _ => version >= 1,
+    }
+}
+
+/**
+ * The file a value is kept in under the directory of a version
+ */
+fn get_path_in(root: &Path, key: &CacheKey) -> PathBuf {
+    let (directory, extension) = match *key {
+        CacheKey::Image { size, .. } => (
+            root.join("image").join(format!("{}x{}", size, size)),
+            CACHED_IMAGE_EXT,
+        ),
+        CacheKey::Matrix { size, .. } => (
+            root.join("matrix").join(format!("{}x{}", size, size)),
+            CACHED_MATRIX_EXT,
+        ),
+        CacheKey::Thumbnail { size, .. } => (
+            root.join("thumbnail").join(format!("{}x{}", size, size)),
+            CACHED_IMAGE_EXT,
+        ),
+        CacheKey::Hash(ref key) => (
+            root.join("hash")
+                .join(get_name(&key.precision))
+                .join(get_name(&key.hash_type))
+                .join(format!("v{}", key.version))
+                .join(&key.preprocessing),
+            CACHED_HASH_EXT,
+        ),
+        CacheKey::Digest(ref key) => (
+            root.join("digest").join(format!("{}", key.algorithm)),
+            CACHED_DIGEST_EXT,
+        ),
+    };
+    let digest = key.digest();
+    directory
+        .join(digest.get(..10).unwrap_or(digest))
+        .join(format!("{}.{}", digest, extension))
+}
+
+/**
+ * The key of a value file under the directory of a version, if the path is
+ * one
+ */
+fn get_key_in(root: &Path, path: &Path) -> Option<CacheKey> {
+    let relative = path.strip_prefix(root).ok()?;
+    let mut parts: Vec<String> = relative
+        .iter()
+        .map(|part| part.to_string_lossy().into_owned())
+        .collect();
+    // Drop the directory the digest is spread over and the extension
+    let file_name = parts.pop()?;
+    parts.pop()?;
+    parts.push(String::from(file_name.split('.').next()?));
+    let key: CacheKey = parts.join("/").parse().ok()?;
+    if get_path_in(root, &key) == path {
+        Some(key)
+    } else {
+        None
+    }
+}
+
+/**
+ * Write a file somewhere else and move it into place, so that readers in
+ * this or any other process never see it partially written
+ */
+fn write_atomically(path: &Path, contents: &[u8]) -> Result<(), Error> {
+    let temp_path = get_temp_path(path);
+    let written = File::create(&temp_path).and_then(|mut file| {
+        file.write_all(contents)?;
+        file.flush()
+    });
+    if let Err(e) = written.and_then(|_| fs::rename(&temp_path, path)) {
+        let _ = fs::remove_file(&temp_path);
+        return Err(e);
+    }
+    Ok(())
+}
+
+/**
+ * The space a file or directory takes up on disk
+ */
+#[cfg(unix)]
+fn get_disk_size(metadata: &Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+    // Blocks are always counted in units of 512 bytes
+    metadata.blocks() * 512
+}
+
+#[cfg(not(unix))]
+fn get_disk_size(metadata: &Metadata) -> u64 {
+    metadata.len()
+}
+
+/**
+ * Every file under the value directories of a version
+ */
+fn get_value_files(root: &Path) -> Result<Vec<(PathBuf, Metadata)>, Error> {
+    let mut files = Vec::new();
+    let mut directories: Vec<PathBuf> = VALUE_DIRS.iter().map(|name| root.join(name)).collect();
+    while let Some(directory) = directories.pop() {
+        let listing = match read_dir(&directory) {
+            Ok(listing) => listing,
+            // Nothing of this kind has been cached yet
+            Err(ref e) if e.kind() == ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        for entry in listing {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            if metadata.is_dir() {
+                directories.push(entry.path());
+            } else {
+                files.push((entry.path(), metadata));
+            }
+        }
+    }
+    Ok(files)
+}
+
+/**
+ * Remove a directory and those under it that are empty, leaving any with
+ * files in them
+ */
+fn remove_empty_dirs(directory: &Path) {
+    if let Ok(listing) = read_dir(directory) {
+        for entry in listing.filter_map(|entry| entry.ok()) {
+            if entry.file_type().map(|kind| kind.is_dir()).unwrap_or(false) {
+                remove_empty_dirs(&entry.path());
+            }
+        }
+    }
+    let _ = fs::remove_dir(directory);
+}
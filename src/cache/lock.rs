@@ -0,0 +1,91 @@
+// Copyright 2016 Drew Short <drew@sothr.com>.
+//
+// Licensed under the MIT license<LICENSE-MIT or http://opensource.org/licenses/MIT>.
+// This file may not be copied, modified, or distributed except according to those terms.
+
+#[cfg(unix)]
+extern crate libc;
+
+use std::fs::{File, OpenOptions};
+use std::io::Error;
+use std::path::Path;
+
+// Structs/Enums //
+
+/**
+ * An exclusive advisory lock on a file, held until it's dropped
+ *
+ * Locks are taken with flock, which Linux maps to POSIX locks on NFS so that
+ * they're seen by other machines sharing the cache. They only keep out
+ * other processes that take the same lock. No lock is taken on platforms
+ * other than Unix.
+ */
+#[derive(Debug)]
+pub struct FileLock {
+    _file: File,
+}
+
+impl FileLock {
+    /**
+     * Wait for the lock on a file, creating the file if it doesn't exist
+     */
+    pub fn lock(path: &Path) -> Result<FileLock, Error> {
+        let file = open_lock_file(path)?;
+        lock_file(&file, true)?;
+        Ok(FileLock { _file: file })
+    }
+
+    /**
+     * Take the lock on a file if no one else holds it
+     */
+    pub fn try_lock(path: &Path) -> Result<Option<FileLock>, Error> {
+        let file = open_lock_file(path)?;
+        if lock_file(&file, false)? {
+            Ok(Some(FileLock { _file: file }))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+// Functions //
+
+fn open_lock_file(path: &Path) -> Result<File, Error> {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+}
+
+/**
+ * Lock a file, returning false if it's held by someone else and not waiting
+ */
+#[cfg(unix)]
+fn lock_file(file: &File, wait: bool) -> Result<bool, Error> {
+    use std::io::ErrorKind;
+    use std::os::unix::io::AsRawFd;
+
+    let operation = if wait {
+        libc::LOCK_EX
+    } else {
+        libc::LOCK_EX | libc::LOCK_NB
+    };
+    loop {
+        if unsafe { libc::flock(file.as_raw_fd(), operation) } == 0 {
+            return Ok(true);
+        }
+        let e = Error::last_os_error();
+        match e.kind() {
+            ErrorKind::Interrupted => continue,
+            ErrorKind::WouldBlock => return Ok(false),
+            _ => return Err(e),
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn lock_file(_file: &File, _wait: bool) -> Result<bool, Error> {
+    Ok(true)
+}
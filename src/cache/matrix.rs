@@ -0,0 +1,234 @@
+// Copyright 2016 Drew Short <drew@sothr.com>.
+//
+// Licensed under the MIT license<LICENSE-MIT or http://opensource.org/licenses/MIT>.
+// This file may not be copied, modified, or distributed except according to those terms.
+
+use std::io::{Error, ErrorKind, Read};
+use std::str::FromStr;
+
+use super::digest::XxHash64;
+use super::flate2::read::ZlibDecoder;
+
+// Constants //
+
+const MATRIX_MAGIC: &[u8; 4] = b"PIHM";
+const MATRIX_VERSION: u16 = 1;
+// Magic, version, element size, a reserved byte, rows and columns
+const HEADER_LENGTH: usize = 16;
+const CHECKSUM_LENGTH: usize = 8;
+const CHECKSUM_SEED: u64 = 0;
+
+// Functions //
+
+/**
+ * Encode a matrix in the binary format
+ *
+ * The header is `PIHM`, the format version as a little endian u16, the
+ * size in bytes of each element, a reserved zero byte, and the number of
+ * rows and columns as little endian u32s. The elements follow row by row
+ * as little endian f32s if every one of them is exactly an f32, or f64s
+ * otherwise, and then an XXH64 checksum of everything before it.
+ */
+pub fn encode_matrix(matrix: &[Vec<f64>]) -> Result<Vec<u8>, Error> {
+    let rows = matrix.len();
+    let columns = matrix.first().map_or(0, |row| row.len());
+    if matrix.iter().any(|row| row.len() != columns) {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "Only matrices with rows of the same length can be cached",
+        ));
+    }
+    if rows > 0 && columns == 0 {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "Only matrices with values in their rows can be cached",
+        ));
+    }
+    let exact_f32 = matrix
+        .iter()
+        .flat_map(|row| row.iter())
+        .all(|&value| f64::from(value as f32).to_bits() == value.to_bits());
+    let element_size = if exact_f32 { 4 } else { 8 };
+
+    let mut bytes = Vec::with_capacity(
+        HEADER_LENGTH + rows * columns * element_size as usize + CHECKSUM_LENGTH,
+    );
+    bytes.extend_from_slice(MATRIX_MAGIC);
+    bytes.extend_from_slice(&MATRIX_VERSION.to_le_bytes());
+    bytes.push(element_size);
+    bytes.push(0);
+    bytes.extend_from_slice(&(rows as u32).to_le_bytes());
+    bytes.extend_from_slice(&(columns as u32).to_le_bytes());
+    for &value in matrix.iter().flat_map(|row| row.iter()) {
+        if exact_f32 {
+            bytes.extend_from_slice(&(value as f32).to_le_bytes());
+        } else {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+    }
+    let checksum = get_checksum(&bytes);
+    bytes.extend_from_slice(&checksum.to_le_bytes());
+    Ok(bytes)
+}
+
+/**
+ * Decode a matrix in the binary format, or the zlib compressed comma
+ * separated rows of text that were cached before it
+ */
+pub fn decode_matrix(bytes: &[u8]) -> Result<Vec<Vec<f64>>, Error> {
+    if !bytes.starts_with(MATRIX_MAGIC) {
+        return decode_text_matrix(bytes);
+    }
+    if bytes.len() < HEADER_LENGTH + CHECKSUM_LENGTH {
+        return Err(invalid_data("A cached matrix is cut short"));
+    }
+    let (contents, checksum) = bytes.split_at(bytes.len() - CHECKSUM_LENGTH);
+    if get_checksum(contents) != read_u64(checksum) {
+        return Err(invalid_data("A cached matrix doesn't match its checksum"));
+    }
+    let version = u16::from_le_bytes([contents[4], contents[5]]);
+    if version != MATRIX_VERSION {
+        return Err(invalid_data(&format!(
+            "Unsupported cached matrix version {}",
+            version
+        )));
+    }
+    let element_size = contents[6] as usize;
+    let rows = read_u32(&contents[8..12]) as usize;
+    let columns = read_u32(&contents[12..16]) as usize;
+    let elements = &contents[HEADER_LENGTH..];
+    // Checked before anything is allocated for the matrix. Rows without any
+    // columns would take no space here but any amount of memory to decode.
+    if (element_size != 4 && element_size != 8)
+        || (rows > 0 && columns == 0)
+        || rows
+            .checked_mul(columns)
+            .and_then(|count| count.checked_mul(element_size))
+            != Some(elements.len())
+    {
+        return Err(invalid_data("A cached matrix doesn't match its dimensions"));
+    }
+
+    let values = elements.chunks(element_size).map(|element| {
+        if element_size == 4 {
+            f64::from(f32::from_bits(read_u32(element)))
+        } else {
+            f64::from_bits(read_u64(element))
+        }
+    });
+    let mut matrix = Vec::with_capacity(rows);
+    let mut row = Vec::with_capacity(columns);
+    for value in values {
+        row.push(value);
+        if row.len() == columns {
+            matrix.push(row);
+            row = Vec::with_capacity(columns);
+        }
+    }
+    Ok(matrix)
+}
+
+/**
+ * Read a zlib compressed matrix of comma separated rows
+ */
+fn decode_text_matrix(bytes: &[u8]) -> Result<Vec<Vec<f64>>, Error> {
+    let mut decoder = ZlibDecoder::new(bytes);
+    let mut matrix_data_str = String::new();
+    decoder
+        .read_to_string(&mut matrix_data_str)
+        .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+    // convert the matrix
+    matrix_data_str
+        .trim()
+        .split('\n')
+        .map(|line| {
+            line.split(',')
+                .map(|f| f64::from_str(f).map_err(|e| Error::new(ErrorKind::InvalidData, e)))
+                .collect()
+        })
+        .collect()
+}
+
+fn get_checksum(bytes: &[u8]) -> u64 {
+    let mut hasher = XxHash64::with_seed(CHECKSUM_SEED);
+    hasher.update(bytes);
+    hasher.digest()
+}
+
+fn invalid_data(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    let mut value = [0u8; 4];
+    value.copy_from_slice(&bytes[..4]);
+    u32::from_le_bytes(value)
+}
+
+fn read_u64(bytes: &[u8]) -> u64 {
+    let mut value = [0u8; 8];
+    value.copy_from_slice(&bytes[..8]);
+    u64::from_le_bytes(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::super::flate2::write::ZlibEncoder;
+    use super::super::flate2::Compression;
+    use super::{decode_matrix, encode_matrix, get_checksum};
+
+    #[test]
+    fn test_round_trip() {
+        // Exactly f32s are kept as f32s
+        let small = vec![vec![0.5, -2.0, 1024.0], vec![0.0, 3.25, -0.125]];
+        let encoded = encode_matrix(&small).unwrap();
+        assert_eq!(encoded.len(), 16 + 6 * 4 + 8);
+        assert_eq!(decode_matrix(&encoded).unwrap(), small);
+
+        // Anything else keeps every bit
+        let exact = vec![vec![0.1, 1.0 / 3.0], vec![f64::MAX, -1e-300]];
+        let encoded = encode_matrix(&exact).unwrap();
+        assert_eq!(encoded.len(), 16 + 4 * 8 + 8);
+        assert_eq!(decode_matrix(&encoded).unwrap(), exact);
+
+        assert!(encode_matrix(&[vec![1.0], vec![1.0, 2.0]]).is_err());
+        assert_eq!(
+            decode_matrix(&encode_matrix(&[]).unwrap()).unwrap().len(),
+            0
+        );
+    }
+
+    #[test]
+    fn test_corrupted() {
+        let encoded = encode_matrix(&[vec![0.1, 0.2], vec![0.3, 0.4]]).unwrap();
+        let mut flipped = encoded.clone();
+        flipped[20] ^= 1;
+        assert!(decode_matrix(&flipped).is_err());
+        for length in 0..encoded.len() {
+            assert!(decode_matrix(&encoded[..length]).is_err());
+        }
+
+        // Billions of empty rows, with a valid checksum
+        assert!(encode_matrix(&[Vec::new()]).is_err());
+        let mut empty_rows = encode_matrix(&[]).unwrap();
+        empty_rows.truncate(16);
+        empty_rows[8..12].copy_from_slice(&u32::MAX.to_le_bytes());
+        let checksum = get_checksum(&empty_rows);
+        empty_rows.extend_from_slice(&checksum.to_le_bytes());
+        assert!(decode_matrix(&empty_rows).is_err());
+    }
+
+    #[test]
+    fn test_legacy_format() {
+        let mut compressor = ZlibEncoder::new(Vec::new(), Compression::default());
+        compressor.write_all(b"1.5,2\n0.25,-3\n").unwrap();
+        let legacy = compressor.finish().unwrap();
+        assert_eq!(
+            decode_matrix(&legacy).unwrap(),
+            vec![vec![1.5, 2.0], vec![0.25, -3.0]]
+        );
+        assert!(decode_matrix(&legacy[..legacy.len() / 2]).is_err());
+    }
+}
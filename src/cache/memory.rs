@@ -0,0 +1,283 @@
+// Copyright 2016 Drew Short <drew@sothr.com>.
+//
+// Licensed under the MIT license<LICENSE-MIT or http://opensource.org/licenses/MIT>.
+// This file may not be copied, modified, or distributed except according to those terms.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{Error, ErrorKind};
+use std::sync::{Mutex, MutexGuard};
+
+use super::access::{get_now, CacheAccess};
+use super::image::DynamicImage;
+use super::{CacheBackend, CacheEntry, CacheKey, HashKey, MetadataKey};
+
+// Structs/Enums //
+
+#[derive(Clone)]
+enum CachedValue {
+    Image(DynamicImage),
+    Matrix(Vec<Vec<f64>>),
+    Hash(u64),
+    Digest(String),
+}
+
+/**
+ * A value with the tick it was last used on, which orders the values by
+ * use more finely than the time
+ */
+struct MemoryEntry {
+    tick: u64,
+    access: CacheAccess,
+    value: CachedValue,
+}
+
+struct MemoryEntries {
+    tick: u64,
+    values: HashMap<CacheKey, MemoryEntry>,
+}
+
+/**
+ * Keeps up to a number of values in memory, dropping the least recently
+ * used ones to make room. Nothing is kept between runs, so it suits long
+ * running processes, or sits in front of another backend.
+ */
+pub struct MemoryBackend {
+    capacity: usize,
+    entries: Mutex<MemoryEntries>,
+}
+
+impl CachedValue {
+    /**
+     * Roughly the memory the value uses
+     */
+    fn get_bytes(&self) -> u64 {
+        match *self {
+            CachedValue::Image(ref image) => image.raw_pixels().len() as u64,
+            CachedValue::Matrix(ref matrix) => matrix.iter().map(|row| row.len() as u64 * 8).sum(),
+            CachedValue::Hash(_) => 8,
+            CachedValue::Digest(ref digest) => digest.len() as u64,
+        }
+    }
+}
+
+impl MemoryBackend {
+    pub fn new(capacity: usize) -> MemoryBackend {
+        MemoryBackend {
+            capacity,
+            entries: Mutex::new(MemoryEntries {
+                tick: 0,
+                values: HashMap::new(),
+            }),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn len(&self) -> usize {
+        self.lock().values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /**
+     * Values are only ever replaced whole, so they're still usable after a
+     * panic while the lock was held
+     */
+    fn lock(&self) -> MutexGuard<'_, MemoryEntries> {
+        self.entries.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn get(&self, key: &CacheKey) -> Option<CachedValue> {
+        let mut entries = self.lock();
+        entries.tick += 1;
+        let tick = entries.tick;
+        entries.values.get_mut(key).map(|entry| {
+            entry.tick = tick;
+            entry.access.last_used = get_now();
+            entry.access.uses += 1;
+            entry.value.clone()
+        })
+    }
+
+    fn put(&self, key: CacheKey, value: CachedValue) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut entries = self.lock();
+        entries.tick += 1;
+        if !entries.values.contains_key(&key) && entries.values.len() >= self.capacity {
+            let oldest = entries
+                .values
+                .iter()
+                .min_by_key(|(_, entry)| entry.tick)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                entries.values.remove(&oldest);
+            }
+        }
+        let entry = MemoryEntry {
+            tick: entries.tick,
+            access: CacheAccess {
+                last_used: get_now(),
+                uses: 1,
+            },
+            value,
+        };
+        entries.values.insert(key, entry);
+    }
+}
+
+impl fmt::Debug for MemoryBackend {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "MemoryBackend {{ capacity: {}, entries: {} }}",
+            self.capacity,
+            self.len()
+        )
+    }
+}
+
+impl CacheBackend for MemoryBackend {
+    fn location(&self) -> String {
+        String::from("memory")
+    }
+
+    fn get_image(&self, digest: &str, size: u32) -> Result<Option<DynamicImage>, Error> {
+        let key = CacheKey::Image {
+            digest: String::from(digest),
+            size,
+        };
+        match self.get(&key) {
+            Some(CachedValue::Image(image)) => Ok(Some(image)),
+            Some(_) => Err(get_kind_error(&key)),
+            None => Ok(None),
+        }
+    }
+
+    fn put_image(&self, digest: &str, size: u32, image: &DynamicImage) -> Result<(), Error> {
+        let key = CacheKey::Image {
+            digest: String::from(digest),
+            size,
+        };
+        self.put(key, CachedValue::Image(image.clone()));
+        Ok(())
+    }
+
+    fn get_matrix(&self, digest: &str, size: u32) -> Result<Option<Vec<Vec<f64>>>, Error> {
+        let key = CacheKey::Matrix {
+            digest: String::from(digest),
+            size,
+        };
+        match self.get(&key) {
+            Some(CachedValue::Matrix(matrix)) => Ok(Some(matrix)),
+            Some(_) => Err(get_kind_error(&key)),
+            None => Ok(None),
+        }
+    }
+
+    fn put_matrix(&self, digest: &str, size: u32, matrix: &[Vec<f64>]) -> Result<(), Error> {
+        let key = CacheKey::Matrix {
+            digest: String::from(digest),
+            size,
+        };
+        self.put(key, CachedValue::Matrix(matrix.to_vec()));
+        Ok(())
+    }
+
+    fn get_thumbnail(&self, digest: &str, size: u32) -> Result<Option<DynamicImage>, Error> {
+        let key = CacheKey::Thumbnail {
+            digest: String::from(digest),
+            size,
+        };
+        match self.get(&key) {
+            Some(CachedValue::Image(image)) => Ok(Some(image)),
+            Some(_) => Err(get_kind_error(&key)),
+            None => Ok(None),
+        }
+    }
+
+    fn put_thumbnail(&self, digest: &str, size: u32, image: &DynamicImage) -> Result<(), Error> {
+        let key = CacheKey::Thumbnail {
+            digest: String::from(digest),
+            size,
+        };
+        self.put(key, CachedValue::Image(image.clone()));
+        Ok(())
+    }
+
+    fn get_hash(&self, key: &HashKey) -> Result<Option<u64>, Error> {
+        let key = CacheKey::Hash(key.clone());
+        match self.get(&key) {
+            Some(CachedValue::Hash(hash)) => Ok(Some(hash)),
+            Some(_) => Err(get_kind_error(&key)),
+            None => Ok(None),
+        }
+    }
+
+    fn put_hash(&self, key: &HashKey, hash: u64) -> Result<(), Error> {
+        self.put(CacheKey::Hash(key.clone()), CachedValue::Hash(hash));
+        Ok(())
+    }
+
+    fn get_digest(&self, key: &MetadataKey) -> Result<Option<String>, Error> {
+        let key = CacheKey::Digest(key.clone());
+        match self.get(&key) {
+            Some(CachedValue::Digest(digest)) => Ok(Some(digest)),
+            Some(_) => Err(get_kind_error(&key)),
+            None => Ok(None),
+        }
+    }
+
+    fn put_digest(&self, key: &MetadataKey, digest: &str) -> Result<(), Error> {
+        self.put(
+            CacheKey::Digest(key.clone()),
+            CachedValue::Digest(String::from(digest)),
+        );
+        Ok(())
+    }
+
+    fn delete(&self, key: &CacheKey) -> Result<bool, Error> {
+        Ok(self.lock().values.remove(key).is_some())
+    }
+
+    fn entries(&self) -> Result<Vec<CacheEntry>, Error> {
+        Ok(self
+            .lock()
+            .values
+            .iter()
+            .map(|(key, entry)| CacheEntry {
+                key: key.clone(),
+                bytes: entry.value.get_bytes(),
+                access: entry.access,
+            })
+            .collect())
+    }
+
+    fn entry_bytes(&self, key: &CacheKey) -> Result<Option<u64>, Error> {
+        Ok(self
+            .lock()
+            .values
+            .get(key)
+            .map(|entry| entry.value.get_bytes()))
+    }
+
+    fn clear(&self) -> Result<(), Error> {
+        self.lock().values.clear();
+        Ok(())
+    }
+}
+
+// Functions //
+
+fn get_kind_error(key: &CacheKey) -> Error {
+    Error::new(
+        ErrorKind::InvalidData,
+        format!("{} holds the wrong kind of value", key),
+    )
+}
@@ -0,0 +1,2071 @@
+// Copyright 2016 Drew Short <drew@sothr.com>.
+//
+// Licensed under the MIT license<LICENSE-MIT or http://opensource.org/licenses/MIT>.
+// This file may not be copied, modified, or distributed except according to those terms.
+
+extern crate flate2;
+extern crate image;
+#[cfg(unix)]
+extern crate libc;
+extern crate sha1;
+
+use std::fmt;
+use std::collections::HashSet;
+use std::fs;
+use std::io::{Error, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use self::image::{DynamicImage, ImageOutputFormat};
+use self::matrix::{decode_matrix, encode_matrix};
+use hash::{HashType, Precision, PREPROCESSING};
+
+pub use self::access::CacheAccess;
+pub use self::digest::DigestAlgorithm;
+pub use self::filesystem::FileSystemBackend;
+pub use self::memory::MemoryBackend;
+pub use self::store::StoreBackend;
+pub(crate) use self::lock::FileLock;
+
+mod access;
+mod archive;
+mod digest;
+mod filesystem;
+mod lock;
+mod matrix;
+mod memory;
+mod store;
+
+// Constants //
+
+pub const DEFAULT_CACHE_DIR: &str = "./.hash_cache";
+
+// Distinguishes the temporary files of concurrent writers in this process
+static TEMP_FILE_COUNTER: AtomicUsize = AtomicUsize::new(0);
+// How many values are put in a cache with limits between checks of them
+const LIMIT_CHECK_INTERVAL: usize = 256;
+
+// Structs/Enums //
+
+/**
+ * Identifies a cached value. Written as a path like key, such as
+ * `image/8x8/<digest>`, `matrix/32x32/<digest>` or
+ * `hash/medium/phash/v1/lanczos3-grayscale/<digest>`, where the digest is
+ * that of the source file in hex. Thumbnails shown in reports are kept
+ * apart from the images prepared for hashing, as `thumbnail/128x128/<digest>`.
+ * The digests of files are kept as `digest/sha1/<fingerprint>`, by a
+ * fingerprint of their metadata.
+ */
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum CacheKey {
+    Image { digest: String, size: u32 },
+    Matrix { digest: String, size: u32 },
+    Thumbnail { digest: String, size: u32 },
+    Hash(HashKey),
+    Digest(MetadataKey),
+}
+
+/**
+ * Identifies a finished hash of a file. Hashes made by another version of
+ * the algorithm, or from images prepared another way, are kept apart so
+ * that they're never mistaken for current ones.
+ */
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct HashKey {
+    pub digest: String,
+    pub hash_type: HashType,
+    pub precision: Precision,
+    pub version: u32,
+    pub preprocessing: String,
+}
+
+/**
+ * Identifies the digest of a version of a file, by a fingerprint of its
+ * device, inode, size and modification time
+ */
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct MetadataKey {
+    pub fingerprint: String,
+    pub algorithm: DigestAlgorithm,
+}
+
+/**
+ * A value in a cache, the space it uses and how it has been used
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct CacheEntry {
+    pub key: CacheKey,
+    pub bytes: u64,
+    pub access: CacheAccess,
+}
+
+/**
+ * Which values are evicted first when a cache is over its limits
+ */
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum EvictionPolicy {
+    #[default]
+    LeastRecentlyUsed,
+    LeastFrequentlyUsed,
+}
+
+/**
+ * How large a cache may grow. Values are evicted once it's over either
+ * limit.
+ */
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct CacheLimits {
+    pub max_bytes: Option<u64>,
+    pub max_entries: Option<u64>,
+    pub policy: EvictionPolicy,
+}
+
+/**
+ * What the backend held when the limits were last checked, and what has
+ * been put in it since
+ */
+#[derive(Copy, Clone, Debug, Default)]
+struct TrackedUsage {
+    bytes: u64,
+    entries: u64,
+    checked_entries: u64,
+    puts: u64,
+}
+
+/**
+ * The number of entries of one kind in the cache and the bytes they use
+ */
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct CacheEntryStats {
+    pub entries: u64,
+    pub bytes: u64,
+}
+
+/**
+ * How many times looking a kind of value up found it in the cache, and how
+ * many times it didn't
+ */
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct CacheCounts {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct CacheCounters {
+    pub images: CacheCounts,
+    pub matrices: CacheCounts,
+    pub thumbnails: CacheCounts,
+    pub hashes: CacheCounts,
+    pub digests: CacheCounts,
+}
+
+/**
+ * The entries in the cache, the lookups of every process that has used it
+ * and the space it takes on disk, including anything kept alongside the
+ * values
+ */
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct CacheStats {
+    pub images: CacheEntryStats,
+    pub matrices: CacheEntryStats,
+    pub thumbnails: CacheEntryStats,
+    pub hashes: CacheEntryStats,
+    pub digests: CacheEntryStats,
+    pub counters: CacheCounters,
+    pub disk_bytes: u64,
+}
+
+/**
+ * The entries of the cache that were checked, and the ones that couldn't be
+ * decoded or didn't match their checksum
+ */
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CacheVerification {
+    pub checked: u64,
+    pub corrupted: Vec<CacheKey>,
+}
+
+/**
+ * What init found where a cache is kept. A new cache was Created, an
+ * existing one was Ready to use, or the values of an older version were
+ * Migrated, the oldest being `from`, with those that are no longer any use
+ * discarded.
+ */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum CacheStatus {
+    Created,
+    Ready,
+    Migrated {
+        from: u32,
+        migrated: u64,
+        discarded: u64,
+    },
+}
+
+/**
+ * How a cache is used while hashing. A ReadOnly cache is never changed, so
+ * it can be shared from a read-only mount. A WriteOnly cache makes every
+ * value again and replaces what was cached, refreshing it. Cleaning,
+ * pruning, evicting from or importing into a cache that can't be written
+ * fails with PermissionDenied.
+ */
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum CacheMode {
+    Disabled,
+    ReadOnly,
+    WriteOnly,
+    #[default]
+    ReadWrite,
+}
+
+/**
+ * Somewhere cached values are kept
+ *
+ * Getting a value that isn't there is Ok(None). A value that is there but
+ * can't be decoded is an error, so that it can be reported and replaced.
+ * Backends are shared between threads and must handle concurrent calls.
+ */
+pub trait CacheBackend: Send + Sync {
+    /**
+     * Where the values are kept, for messages
+     */
+    fn location(&self) -> String;
+
+    /**
+     * Prepare the backend to be used, creating anything it needs and
+     * bringing values of older versions up to date
+     */
+    fn init(&self) -> Result<CacheStatus, Error> {
+        Ok(CacheStatus::Ready)
+    }
+
+    /**
+     * If init has set the backend up
+     */
+    fn is_initialized(&self) -> bool {
+        true
+    }
+
+    fn get_image(&self, digest: &str, size: u32) -> Result<Option<DynamicImage>, Error>;
+
+    fn put_image(&self, digest: &str, size: u32, image: &DynamicImage) -> Result<(), Error>;
+
+    fn get_matrix(&self, digest: &str, size: u32) -> Result<Option<Vec<Vec<f64>>>, Error>;
+
+    fn put_matrix(&self, digest: &str, size: u32, matrix: &[Vec<f64>]) -> Result<(), Error>;
+
+    fn get_thumbnail(&self, digest: &str, size: u32) -> Result<Option<DynamicImage>, Error>;
+
+    fn put_thumbnail(&self, digest: &str, size: u32, image: &DynamicImage) -> Result<(), Error>;
+
+    fn get_hash(&self, key: &HashKey) -> Result<Option<u64>, Error>;
+
+    fn put_hash(&self, key: &HashKey, hash: u64) -> Result<(), Error>;
+
+    fn get_digest(&self, key: &MetadataKey) -> Result<Option<String>, Error>;
+
+    fn put_digest(&self, key: &MetadataKey, digest: &str) -> Result<(), Error>;
+
+    /**
+     * Remove a value, returning if it was there
+     */
+    fn delete(&self, key: &CacheKey) -> Result<bool, Error>;
+
+    /**
+     * Every value in the backend
+     */
+    fn entries(&self) -> Result<Vec<CacheEntry>, Error>;
+
+    /**
+     * The bytes a value takes up, None if it isn't there or that isn't
+     * known without looking at every value
+     */
+    fn entry_bytes(&self, _key: &CacheKey) -> Result<Option<u64>, Error> {
+        Ok(None)
+    }
+
+    /**
+     * The bytes taken up by what the backend records about its values, such
+     * as when they were used, which count towards the limits too
+     */
+    fn overhead_bytes(&self) -> Result<u64, Error> {
+        Ok(0)
+    }
+
+    /**
+     * Reclaim the space left behind by values that have been replaced or
+     * deleted
+     */
+    fn compact(&self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /**
+     * Remove every value
+     */
+    fn clear(&self) -> Result<(), Error>;
+
+    /**
+     * The bytes the backend takes up where it's kept
+     */
+    fn disk_usage(&self) -> Result<u64, Error> {
+        Ok(self.entries()?.iter().map(|entry| entry.bytes).sum())
+    }
+
+    /**
+     * The hits and misses saved by every process that has used the backend
+     */
+    fn counters(&self) -> Result<CacheCounters, Error> {
+        Ok(Default::default())
+    }
+
+    /**
+     * Add to the saved hits and misses
+     */
+    fn add_counters(&self, _counters: &CacheCounters) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /**
+     * Record the uses of values as they're read, or read them without
+     * changing anything
+     */
+    fn record_accesses(&self, _record: bool) {}
+}
+
+/**
+ * Keeps prepared images and matrices so that they don't need to be made
+ * again, keyed by the contents of the file they came from
+ *
+ * Values are kept in a backend, optionally with a memory backend in front
+ * of it for long running processes. Files are identified by a digest of
+ * their contents, which can be remembered by their metadata so that
+ * unchanged files aren't read again. With limits, what's put in the cache
+ * is tracked and values are evicted to keep it within them once it could
+ * be over them. Hits and misses are counted in memory, and added to those
+ * saved in the backend by save_counters and every so many values put in the
+ * cache. The mode decides whether values are looked up and put in the cache
+ * while hashing. Looking at the cache, such as with stats or verify, works
+ * in any mode, but changing it, such as with clean, import or prune, is
+ * refused unless the mode writes to the cache.
+ */
+#[derive(Clone)]
+pub struct Cache {
+    mode: CacheMode,
+    backend: Arc<dyn CacheBackend>,
+    memory: Option<Arc<MemoryBackend>>,
+    digest_algorithm: DigestAlgorithm,
+    trust_metadata: bool,
+    limits: Option<CacheLimits>,
+    puts: Arc<AtomicUsize>,
+    // Unknown until the limits are first checked
+    usage: Arc<Mutex<Option<TrackedUsage>>>,
+    // Hits and misses not yet saved in the backend
+    counters: Arc<Mutex<CacheCounters>>,
+}
+
+impl fmt::Display for CacheStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CacheStatus::Created => write!(f, "created"),
+            CacheStatus::Ready => write!(f, "ready"),
+            CacheStatus::Migrated {
+                from,
+                migrated,
+                discarded,
+            } => write!(
+                f,
+                "migrated {} entries from version {}, discarding {}",
+                migrated, from, discarded
+            ),
+        }
+    }
+}
+
+impl fmt::Display for CacheKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CacheKey::Image {
+                ref digest,
+                size,
+            } => write!(f, "image/{}x{}/{}", size, size, digest),
+            CacheKey::Matrix {
+                ref digest,
+                size,
+            } => write!(f, "matrix/{}x{}/{}", size, size, digest),
+            CacheKey::Thumbnail {
+                ref digest,
+                size,
+            } => write!(f, "thumbnail/{}x{}/{}", size, size, digest),
+            CacheKey::Hash(ref key) => write!(
+                f,
+                "hash/{}/{}/v{}/{}/{}",
+                get_name(&key.precision),
+                get_name(&key.hash_type),
+                key.version,
+                key.preprocessing,
+                key.digest
+            ),
+            CacheKey::Digest(ref key) => write!(f, "digest/{}/{}", key.algorithm, key.fingerprint),
+        }
+    }
+}
+
+impl FromStr for CacheKey {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<CacheKey, String> {
+        let parts: Vec<&str> = s.split('/').collect();
+        let key = match parts.as_slice() {
+            ["image", size, digest] => parse_size(size).map(|size| CacheKey::Image {
+                digest: String::from(*digest),
+                size,
+            }),
+            ["matrix", size, digest] => parse_size(size).map(|size| CacheKey::Matrix {
+                digest: String::from(*digest),
+                size,
+            }),
+            ["thumbnail", size, digest] => parse_size(size).map(|size| CacheKey::Thumbnail {
+                digest: String::from(*digest),
+                size,
+            }),
+            ["hash", precision, hash_type, version, preprocessing, digest] => {
+                let version = version
+                    .get(1..)
+                    .filter(|_| version.starts_with('v'))
+                    .and_then(|version| version.parse().ok());
+                match (precision.parse(), hash_type.parse(), version) {
+                    (Ok(precision), Ok(hash_type), Some(version))
+                        if is_preprocessing(preprocessing) =>
+                    {
+                        Some(CacheKey::Hash(HashKey {
+                            digest: String::from(*digest),
+                            hash_type,
+                            precision,
+                            version,
+                            preprocessing: String::from(*preprocessing),
+                        }))
+                    }
+                    _ => None,
+                }
+            }
+            ["digest", algorithm, fingerprint] => algorithm.parse().ok().map(|algorithm| {
+                CacheKey::Digest(MetadataKey {
+                    fingerprint: String::from(*fingerprint),
+                    algorithm,
+                })
+            }),
+            _ => None,
+        };
+        match key {
+            Some(ref key) if is_digest(key.digest()) => Ok(key.clone()),
+            _ => Err(format!("'{}' isn't a cache key", s)),
+        }
+    }
+}
+
+impl CacheKey {
+    /**
+     * The digest of the file the value belongs to, or the fingerprint of
+     * the file for a digest
+     */
+    pub fn digest(&self) -> &str {
+        match *self {
+            CacheKey::Image { ref digest, .. }
+            | CacheKey::Matrix { ref digest, .. }
+            | CacheKey::Thumbnail { ref digest, .. } => digest,
+            CacheKey::Hash(ref key) => &key.digest,
+            CacheKey::Digest(ref key) => &key.fingerprint,
+        }
+    }
+}
+
+impl FromStr for CacheMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<CacheMode, String> {
+        match s.to_lowercase().as_str() {
+            "disabled" => Ok(CacheMode::Disabled),
+            "read-only" => Ok(CacheMode::ReadOnly),
+            "write-only" => Ok(CacheMode::WriteOnly),
+            "read-write" => Ok(CacheMode::ReadWrite),
+            _ => Err(format!("Unknown cache mode '{}'", s)),
+        }
+    }
+}
+
+impl fmt::Display for CacheMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CacheMode::Disabled => write!(f, "disabled"),
+            CacheMode::ReadOnly => write!(f, "read-only"),
+            CacheMode::WriteOnly => write!(f, "write-only"),
+            CacheMode::ReadWrite => write!(f, "read-write"),
+        }
+    }
+}
+
+impl CacheMode {
+    pub fn can_read(self) -> bool {
+        self == CacheMode::ReadOnly || self == CacheMode::ReadWrite
+    }
+
+    pub fn can_write(self) -> bool {
+        self == CacheMode::WriteOnly || self == CacheMode::ReadWrite
+    }
+}
+
+impl FromStr for EvictionPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<EvictionPolicy, String> {
+        match s.to_lowercase().as_str() {
+            "lru" => Ok(EvictionPolicy::LeastRecentlyUsed),
+            "lfu" => Ok(EvictionPolicy::LeastFrequentlyUsed),
+            _ => Err(format!("Unknown eviction policy '{}'", s)),
+        }
+    }
+}
+
+impl fmt::Display for EvictionPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            EvictionPolicy::LeastRecentlyUsed => write!(f, "lru"),
+            EvictionPolicy::LeastFrequentlyUsed => write!(f, "lfu"),
+        }
+    }
+}
+
+impl HashKey {
+    /**
+     * The key of a hash made by the current version of its algorithm
+     */
+    pub fn new(digest: &str, hash_type: HashType, precision: Precision) -> HashKey {
+        HashKey {
+            digest: String::from(digest),
+            hash_type,
+            precision,
+            version: hash_type.get_version(),
+            preprocessing: String::from(PREPROCESSING),
+        }
+    }
+}
+
+impl fmt::Debug for Cache {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Cache {{ location: {:?}, mode: {}, memory: {:?}, digest_algorithm: {}, \
+             trust_metadata: {}, limits: {:?} }}",
+            self.backend.location(),
+            self.mode,
+            self.memory,
+            self.digest_algorithm,
+            self.trust_metadata,
+            self.limits
+        )
+    }
+}
+
+impl CacheCounts {
+    fn add(&mut self, other: &CacheCounts) {
+        self.hits += other.hits;
+        self.misses += other.misses;
+    }
+}
+
+impl CacheCounters {
+    pub fn add(&mut self, other: &CacheCounters) {
+        self.images.add(&other.images);
+        self.matrices.add(&other.matrices);
+        self.thumbnails.add(&other.thumbnails);
+        self.hashes.add(&other.hashes);
+        self.digests.add(&other.digests);
+    }
+
+    fn kinds(&mut self) -> [(&'static str, &mut CacheCounts); 5] {
+        [
+            ("images", &mut self.images),
+            ("matrices", &mut self.matrices),
+            ("thumbnails", &mut self.thumbnails),
+            ("hashes", &mut self.hashes),
+            ("digests", &mut self.digests),
+        ]
+    }
+}
+
+impl CacheLimits {
+    fn is_exceeded(&self, bytes: u64, entries: u64) -> bool {
+        self.max_bytes.is_some_and(|max_bytes| bytes > max_bytes)
+            || self.max_entries.is_some_and(|max_entries| entries > max_entries)
+    }
+}
+
+impl Default for Cache {
+    fn default() -> Cache {
+        Cache::from_directory(DEFAULT_CACHE_DIR)
+    }
+}
+
+impl Cache {
+    pub fn new(backend: Arc<dyn CacheBackend>) -> Cache {
+        Cache {
+            mode: Default::default(),
+            backend,
+            memory: None,
+            digest_algorithm: Default::default(),
+            trust_metadata: false,
+            limits: None,
+            puts: Arc::new(AtomicUsize::new(0)),
+            usage: Arc::new(Mutex::new(None)),
+            counters: Arc::new(Mutex::new(Default::default())),
+        }
+    }
+
+    /**
+     * A cache of files under a directory, one per value
+     */
+    pub fn from_directory(cache_dir: &str) -> Cache {
+        Cache::new(Arc::new(FileSystemBackend::new(Path::new(cache_dir))))
+    }
+
+    /**
+     * Keep up to `capacity` values in memory in front of the backend
+     */
+    pub fn keep_in_memory(&mut self, capacity: usize) {
+        self.memory = Some(Arc::new(MemoryBackend::new(capacity)));
+    }
+
+    /**
+     * Identify files by another digest. Values cached under one digest
+     * aren't found by another.
+     */
+    pub fn use_digest(&mut self, algorithm: DigestAlgorithm) {
+        self.digest_algorithm = algorithm;
+    }
+
+    pub fn digest_algorithm(&self) -> DigestAlgorithm {
+        self.digest_algorithm
+    }
+
+    /**
+     * Remember the digests of files by their device, inode, size and
+     * modification time, and reuse them instead of reading files that
+     * haven't changed. Only available on Unix. A file rewritten in place
+     * with the same size within the resolution of its modification time
+     * keeps its old digest.
+     */
+    pub fn trust_file_metadata(&mut self, trust: bool) {
+        self.trust_metadata = trust;
+    }
+
+    pub fn set_mode(&mut self, mode: CacheMode) {
+        self.mode = mode;
+        self.backend.record_accesses(mode.can_write());
+    }
+
+    pub fn mode(&self) -> CacheMode {
+        self.mode
+    }
+
+    /**
+     * Keep the cache within limits, or let it grow without bound
+     */
+    pub fn set_limits(&mut self, limits: Option<CacheLimits>) {
+        self.limits = limits;
+    }
+
+    pub fn limits(&self) -> Option<CacheLimits> {
+        self.limits
+    }
+
+    pub fn backend(&self) -> &dyn CacheBackend {
+        &*self.backend
+    }
+
+    pub fn location(&self) -> String {
+        self.backend.location()
+    }
+
+    /**
+     * Set up the backend, reporting what was done. A read-only cache isn't
+     * set up or migrated, so it must already have been.
+     */
+    pub fn init(&self) -> Result<CacheStatus, Error> {
+        match self.mode {
+            CacheMode::Disabled => Ok(CacheStatus::Ready),
+            CacheMode::ReadOnly if self.backend.is_initialized() => Ok(CacheStatus::Ready),
+            CacheMode::ReadOnly => Err(Error::new(
+                ErrorKind::NotFound,
+                format!(
+                    "{} is not a pihash cache, and can't be made one while read-only",
+                    self.location()
+                ),
+            )),
+            CacheMode::WriteOnly | CacheMode::ReadWrite => self.backend.init(),
+        }
+    }
+
+    /**
+     * If the backend has been set up by init
+     */
+    pub fn is_initialized(&self) -> bool {
+        self.backend.is_initialized()
+    }
+
+    /**
+     * Count the entries of each kind in the cache and the space they use,
+     * along with every hit and miss, saved or not
+     */
+    pub fn stats(&self) -> Result<CacheStats, Error> {
+        let mut stats = CacheStats {
+            counters: self.backend.counters()?,
+            disk_bytes: self.backend.disk_usage()?,
+            ..Default::default()
+        };
+        stats.counters.add(&self.lock_counters());
+        for entry in self.backend.entries()? {
+            let kind_stats = match entry.key {
+                CacheKey::Image { .. } => &mut stats.images,
+                CacheKey::Matrix { .. } => &mut stats.matrices,
+                CacheKey::Thumbnail { .. } => &mut stats.thumbnails,
+                CacheKey::Hash(_) => &mut stats.hashes,
+                CacheKey::Digest(_) => &mut stats.digests,
+            };
+            kind_stats.entries += 1;
+            kind_stats.bytes += entry.bytes;
+        }
+        Ok(stats)
+    }
+
+    /**
+     * Check that every entry in the cache can be decoded and matches its
+     * checksum, if it has one
+     */
+    pub fn verify(&self) -> Result<CacheVerification, Error> {
+        let mut verification: CacheVerification = Default::default();
+        for entry in self.backend.entries()? {
+            verification.checked += 1;
+            let readable = match entry.key {
+                CacheKey::Image {
+                    ref digest,
+                    size,
+                } => self.backend.get_image(digest, size).map(|_| ()),
+                CacheKey::Matrix {
+                    ref digest,
+                    size,
+                } => self.backend.get_matrix(digest, size).map(|_| ()),
+                CacheKey::Thumbnail {
+                    ref digest,
+                    size,
+                } => self.backend.get_thumbnail(digest, size).map(|_| ()),
+                CacheKey::Hash(ref key) => self.backend.get_hash(key).map(|_| ()),
+                CacheKey::Digest(ref key) => self.backend.get_digest(key).map(|_| ()),
+            };
+            if readable.is_err() {
+                verification.corrupted.push(entry.key);
+            }
+        }
+        Ok(verification)
+    }
+
+    /**
+     * Evict values until the cache is within its limits, returning what
+     * was removed. What the backend records about the values, like the
+     * access log, counts towards the bytes.
+     */
+    pub fn enforce_limits(&self) -> Result<CacheEntryStats, Error> {
+        self.check_writable("evict values from")?;
+        let limits = match self.limits {
+            Some(limits) => limits,
+            None => return Ok(Default::default()),
+        };
+        let mut entries = self.backend.entries()?;
+        let mut bytes: u64 =
+            entries.iter().map(|entry| entry.bytes).sum::<u64>() + self.backend.overhead_bytes()?;
+        let mut count = entries.len() as u64;
+        if !limits.is_exceeded(bytes, count) {
+            self.set_usage(bytes, count);
+            return Ok(Default::default());
+        }
+
+        match limits.policy {
+            EvictionPolicy::LeastRecentlyUsed => {
+                entries.sort_by_key(|entry| (entry.access.last_used, entry.access.uses))
+            }
+            EvictionPolicy::LeastFrequentlyUsed => {
+                entries.sort_by_key(|entry| (entry.access.uses, entry.access.last_used))
+            }
+        }
+        let mut evicted = Vec::new();
+        for entry in entries {
+            if !limits.is_exceeded(bytes, count) {
+                break;
+            }
+            bytes -= entry.bytes;
+            count -= 1;
+            evicted.push(entry);
+        }
+        self.set_usage(bytes, count);
+        self.remove_entries(evicted)
+    }
+
+    fn set_usage(&self, bytes: u64, entries: u64) {
+        *self.lock_usage() = Some(TrackedUsage {
+            bytes,
+            entries,
+            checked_entries: entries,
+            puts: 0,
+        });
+    }
+
+    /**
+     * Remove every value that doesn't belong to one of the files, returning
+     * what was removed. Values cached under another digest algorithm are
+     * removed too, as they can't be found any more.
+     */
+    pub fn prune<P: AsRef<Path>>(&self, files: &[P]) -> Result<CacheEntryStats, Error> {
+        self.check_writable("prune")?;
+        // Files that can't be read aren't referencing anything
+        let referenced: HashSet<String> = files
+            .iter()
+            .filter_map(|file| self.get_file_hash(file.as_ref()).ok())
+            .collect();
+        let mut unreferenced = Vec::new();
+        for entry in self.backend.entries()? {
+            let is_referenced = match entry.key {
+                CacheKey::Digest(ref key) => match self.backend.get_digest(key) {
+                    Ok(Some(digest)) => referenced.contains(&digest),
+                    _ => false,
+                },
+                ref key => referenced.contains(key.digest()),
+            };
+            if !is_referenced {
+                unreferenced.push(entry);
+            }
+        }
+        self.remove_entries(unreferenced)
+    }
+
+    fn remove_entries(&self, entries: Vec<CacheEntry>) -> Result<CacheEntryStats, Error> {
+        self.check_writable("remove values from")?;
+        let mut removed: CacheEntryStats = Default::default();
+        for entry in entries {
+            if let Some(ref memory) = self.memory {
+                memory.delete(&entry.key)?;
+            }
+            if self.backend.delete(&entry.key)? {
+                removed.entries += 1;
+                removed.bytes += entry.bytes;
+            }
+        }
+        if removed.entries > 0 {
+            self.backend.compact()?;
+        }
+        Ok(removed)
+    }
+
+    /**
+     * Fail a change to the cache that its mode doesn't allow
+     */
+    fn check_writable(&self, action: &str) -> Result<(), Error> {
+        if self.mode.can_write() {
+            return Ok(());
+        }
+        Err(Error::new(
+            ErrorKind::PermissionDenied,
+            format!("Can't {} a {} cache", action, self.mode),
+        ))
+    }
+
+    /**
+     * Save the counters every so many values put in the cache, and check
+     * the limits once the cache could be over them
+     */
+    fn note_put(&self, key: &CacheKey) {
+        let puts = self.puts.fetch_add(1, Ordering::SeqCst) + 1;
+        let interval = puts.is_multiple_of(LIMIT_CHECK_INTERVAL);
+        if interval {
+            if let Err(e) = self.save_counters() {
+                eprintln!("Unable to save cache counters. {}", e);
+            }
+        }
+        if let Some(limits) = self.limits {
+            if self.track_put(key, &limits, interval) {
+                if let Err(e) = self.enforce_limits() {
+                    eprintln!("Unable to keep the cache within its limits. {}", e);
+                }
+            }
+        }
+    }
+
+    /**
+     * Add a value put in the cache to the usage tracked since the limits
+     * were last checked, returning if they should be checked again. Only
+     * checking finds the values other processes have put, so that's also
+     * done once as many values have been put as there were then.
+     */
+    fn track_put(&self, key: &CacheKey, limits: &CacheLimits, interval: bool) -> bool {
+        // Values that are replaced are counted again, which is only ever
+        // too cautious
+        let bytes = self.backend.entry_bytes(key).ok().flatten().unwrap_or(0);
+        match *self.lock_usage() {
+            // Nothing is known before the first check
+            None => interval,
+            Some(ref mut usage) => {
+                usage.bytes += bytes;
+                usage.entries += 1;
+                usage.puts += 1;
+                limits.is_exceeded(usage.bytes, usage.entries)
+                    || usage.puts >= usage.checked_entries.max(LIMIT_CHECK_INTERVAL as u64)
+            }
+        }
+    }
+
+    fn lock_usage(&self) -> MutexGuard<'_, Option<TrackedUsage>> {
+        self.usage.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /**
+     * Counters are always left whole, so they're still good after a panic
+     * while the lock was held
+     */
+    fn lock_counters(&self) -> MutexGuard<'_, CacheCounters> {
+        self.counters.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /**
+     * Count a lookup of a kind of value
+     */
+    fn count<F: FnOnce(&mut CacheCounters) -> &mut CacheCounts>(&self, kind: F, hit: bool) {
+        let mut counters = self.lock_counters();
+        let counts = kind(&mut counters);
+        if hit {
+            counts.hits += 1;
+        } else {
+            counts.misses += 1;
+        }
+    }
+
+    /**
+     * Add the hits and misses counted since they were last saved to those
+     * saved in the backend. A read-only cache keeps them in memory.
+     */
+    pub fn save_counters(&self) -> Result<(), Error> {
+        if !self.mode.can_write() {
+            return Ok(());
+        }
+        let counters = {
+            let mut counters = self.lock_counters();
+            let unsaved = *counters;
+            *counters = Default::default();
+            unsaved
+        };
+        if counters == Default::default() {
+            return Ok(());
+        }
+        self.backend.add_counters(&counters)
+    }
+
+    /**
+     * Write every value in the cache to a single archive, apart from the
+     * digests remembered by file metadata, which only apply to the files on
+     * this machine. Returns the values written.
+     */
+    pub fn export<W: Write>(&self, writer: W) -> Result<CacheEntryStats, Error> {
+        archive::write_archive(&*self.backend, writer)
+    }
+
+    /**
+     * Add the values in an archive written by export to the cache,
+     * replacing any that are already there. Returns the values added.
+     */
+    pub fn import<R: Read>(&self, reader: R) -> Result<CacheEntryStats, Error> {
+        self.check_writable("import into")?;
+        let imported = archive::read_archive(&*self.backend, reader)?;
+        if let Some(ref memory) = self.memory {
+            memory.clear()?;
+        }
+        if self.limits.is_some() {
+            self.enforce_limits()?;
+        }
+        Ok(imported)
+    }
+
+    /**
+     * A value that can't be read is a miss. One that is there but can't be
+     * decoded, such as one cut short by a writer that was interrupted, is
+     * removed so that it's replaced once it has been made again, unless the
+     * cache is read-only.
+     */
+    fn read_failed(&self, key: CacheKey, e: Error) {
+        if e.kind() != ErrorKind::InvalidData {
+            eprintln!("Unable to read cached {}: {}", key, e);
+            return;
+        }
+        if !self.mode.can_write() {
+            return;
+        }
+        if let Some(ref memory) = self.memory {
+            let _ = memory.delete(&key);
+        }
+        if let Err(e) = self.backend.delete(&key) {
+            eprintln!("Unable to remove corrupted {} from cache: {}", key, e);
+        }
+    }
+
+    /**
+     * Remove every value from the cache
+     */
+    pub fn clean(&self) -> Result<(), Error> {
+        self.check_writable("clean")?;
+        if let Some(ref memory) = self.memory {
+            memory.clear()?;
+        }
+        self.backend.clear()
+    }
+
+    /**
+     * Get the hash of the desired file and return it as a hex string
+     */
+    pub fn get_file_hash(&self, path: &Path) -> Result<String, Error> {
+        // The metadata is read first, so a change while the file is being
+        // digested leaves the digest under a key that won't match again
+        let metadata_key = if self.trust_metadata {
+            digest::get_metadata_fingerprint(&fs::metadata(path)?).map(|fingerprint| {
+                MetadataKey {
+                    fingerprint,
+                    algorithm: self.digest_algorithm,
+                }
+            })
+        } else {
+            None
+        };
+        if let Some(ref key) = metadata_key {
+            if let Some(digest) = self.get_digest_from_cache(key) {
+                return Ok(digest);
+            }
+        }
+        let digest = digest::digest_file(path, self.digest_algorithm)?;
+        if let Some(ref key) = metadata_key.filter(|_| self.mode.can_write()) {
+            if let Some(ref memory) = self.memory {
+                let _ = memory.put_digest(key, &digest);
+            }
+            if let Err(e) = self.backend.put_digest(key, &digest) {
+                eprintln!("Unable to store digest in cache. {}", e);
+            }
+        }
+        Ok(digest)
+    }
+
+    fn get_digest_from_cache(&self, key: &MetadataKey) -> Option<String> {
+        if !self.mode.can_read() {
+            return None;
+        }
+        if let Some(ref memory) = self.memory {
+            if let Ok(Some(digest)) = memory.get_digest(key) {
+                self.count(|counters| &mut counters.digests, true);
+                return Some(digest);
+            }
+        }
+        let digest = self.backend.get_digest(key);
+        self.count(
+            |counters| &mut counters.digests,
+            digest.as_ref().is_ok_and(|digest| digest.is_some()),
+        );
+        match digest {
+            Ok(Some(digest)) => {
+                if let Some(ref memory) = self.memory {
+                    let _ = memory.put_digest(key, &digest);
+                }
+                Some(digest)
+            }
+            Ok(None) => None,
+            Err(e) => {
+                self.read_failed(CacheKey::Digest(key.clone()), e);
+                None
+            }
+        }
+    }
+
+    /**
+     * Put an image buffer in the cache, returning false if the cache isn't
+     * written to
+     */
+    pub fn put_image_in_cache(
+        &self,
+        path: &Path,
+        size: u32,
+        image: &DynamicImage,
+    ) -> Result<bool, Error> {
+        if !self.mode.can_write() {
+            return Ok(false);
+        }
+        let sha1 = self.get_file_hash(path)?;
+        self.put_image_in_cache_by_digest(&sha1, size, image)
+    }
+
+    /**
+     * Put an image buffer in the cache under the already known digest of
+     * the file it came from
+     */
+    pub fn put_image_in_cache_by_digest(
+        &self,
+        sha1: &str,
+        size: u32,
+        image: &DynamicImage,
+    ) -> Result<bool, Error> {
+        if !self.mode.can_write() {
+            return Ok(false);
+        }
+        if let Some(ref memory) = self.memory {
+            memory.put_image(sha1, size, image)?;
+        }
+        self.backend.put_image(sha1, size, image)?;
+        self.note_put(&CacheKey::Image {
+            digest: String::from(sha1),
+            size,
+        });
+        Ok(true)
+    }
+
+    /**
+     * Get an image buffer out of the cache
+     */
+    pub fn get_image_from_cache(&self, path: &Path, size: u32) -> Option<DynamicImage> {
+        if !self.mode.can_read() {
+            return None;
+        }
+        match self.get_file_hash(path) {
+            Ok(sha1) => self.get_image_from_cache_by_digest(&sha1, size),
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                None
+            }
+        }
+    }
+
+    /**
+     * Get an image buffer out of the cache by the digest of the file it came
+     * from
+     */
+    pub fn get_image_from_cache_by_digest(&self, sha1: &str, size: u32) -> Option<DynamicImage> {
+        if !self.mode.can_read() {
+            return None;
+        }
+        if let Some(ref memory) = self.memory {
+            if let Ok(Some(image)) = memory.get_image(sha1, size) {
+                self.count(|counters| &mut counters.images, true);
+                return Some(image);
+            }
+        }
+        let image = self.backend.get_image(sha1, size);
+        self.count(
+            |counters| &mut counters.images,
+            image.as_ref().is_ok_and(|image| image.is_some()),
+        );
+        match image {
+            Ok(Some(image)) => {
+                if let Some(ref memory) = self.memory {
+                    let _ = memory.put_image(sha1, size, &image);
+                }
+                Some(image)
+            }
+            Ok(None) => None,
+            Err(e) => {
+                self.read_failed(
+                    CacheKey::Image {
+                        digest: String::from(sha1),
+                        size,
+                    },
+                    e,
+                );
+                None
+            }
+        }
+    }
+
+    /**
+     * Put a matrix in the cache, returning false if the cache isn't written
+     * to
+     */
+    pub fn put_matrix_in_cache(
+        &self,
+        path: &Path,
+        size: u32,
+        file_contents: &[Vec<f64>],
+    ) -> Result<bool, Error> {
+        if !self.mode.can_write() {
+            return Ok(false);
+        }
+        let sha1 = self.get_file_hash(path)?;
+        self.put_matrix_in_cache_by_digest(&sha1, size, file_contents)
+    }
+
+    /**
+     * Put a matrix in the cache under the already known digest of the file
+     * it came from
+     */
+    pub fn put_matrix_in_cache_by_digest(
+        &self,
+        sha1: &str,
+        size: u32,
+        file_contents: &[Vec<f64>],
+    ) -> Result<bool, Error> {
+        if !self.mode.can_write() {
+            return Ok(false);
+        }
+        if let Some(ref memory) = self.memory {
+            memory.put_matrix(sha1, size, file_contents)?;
+        }
+        self.backend.put_matrix(sha1, size, file_contents)?;
+        self.note_put(&CacheKey::Matrix {
+            digest: String::from(sha1),
+            size,
+        });
+        Ok(true)
+    }
+
+    /**
+     * Get a matrix out of the cache
+     */
+    pub fn get_matrix_from_cache(&self, path: &Path, size: u32) -> Option<Vec<Vec<f64>>> {
+        if !self.mode.can_read() {
+            return None;
+        }
+        match self.get_file_hash(path) {
+            Ok(sha1) => self.get_matrix_from_cache_by_digest(&sha1, size),
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                None
+            }
+        }
+    }
+
+    /**
+     * Get a matrix out of the cache by the digest of the file it came from
+     */
+    pub fn get_matrix_from_cache_by_digest(&self, sha1: &str, size: u32) -> Option<Vec<Vec<f64>>> {
+        if !self.mode.can_read() {
+            return None;
+        }
+        if let Some(ref memory) = self.memory {
+            if let Ok(Some(matrix)) = memory.get_matrix(sha1, size) {
+                self.count(|counters| &mut counters.matrices, true);
+                return Some(matrix);
+            }
+        }
+        let matrix = self.backend.get_matrix(sha1, size);
+        self.count(
+            |counters| &mut counters.matrices,
+            matrix.as_ref().is_ok_and(|matrix| matrix.is_some()),
+        );
+        match matrix {
+            Ok(Some(matrix)) => {
+                if let Some(ref memory) = self.memory {
+                    let _ = memory.put_matrix(sha1, size, &matrix);
+                }
+                Some(matrix)
+            }
+            Ok(None) => None,
+            Err(e) => {
+                self.read_failed(
+                    CacheKey::Matrix {
+                        digest: String::from(sha1),
+                        size,
+                    },
+                    e,
+                );
+                None
+            }
+        }
+    }
+
+    /**
+     * Put a thumbnail of an image in the cache, returning false if the cache
+     * isn't written to
+     */
+    pub fn put_thumbnail_in_cache(
+        &self,
+        path: &Path,
+        size: u32,
+        thumbnail: &DynamicImage,
+    ) -> Result<bool, Error> {
+        if !self.mode.can_write() {
+            return Ok(false);
+        }
+        let sha1 = self.get_file_hash(path)?;
+        if let Some(ref memory) = self.memory {
+            memory.put_thumbnail(&sha1, size, thumbnail)?;
+        }
+        self.backend.put_thumbnail(&sha1, size, thumbnail)?;
+        self.note_put(&CacheKey::Thumbnail { digest: sha1, size });
+        Ok(true)
+    }
+
+    /**
+     * Get a thumbnail of an image out of the cache
+     */
+    pub fn get_thumbnail_from_cache(&self, path: &Path, size: u32) -> Option<DynamicImage> {
+        if !self.mode.can_read() {
+            return None;
+        }
+        let sha1 = match self.get_file_hash(path) {
+            Ok(sha1) => sha1,
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                return None;
+            }
+        };
+        if let Some(ref memory) = self.memory {
+            if let Ok(Some(thumbnail)) = memory.get_thumbnail(&sha1, size) {
+                self.count(|counters| &mut counters.thumbnails, true);
+                return Some(thumbnail);
+            }
+        }
+        let thumbnail = self.backend.get_thumbnail(&sha1, size);
+        self.count(
+            |counters| &mut counters.thumbnails,
+            thumbnail.as_ref().is_ok_and(|thumbnail| thumbnail.is_some()),
+        );
+        match thumbnail {
+            Ok(Some(thumbnail)) => {
+                if let Some(ref memory) = self.memory {
+                    let _ = memory.put_thumbnail(&sha1, size, &thumbnail);
+                }
+                Some(thumbnail)
+            }
+            Ok(None) => None,
+            Err(e) => {
+                self.read_failed(CacheKey::Thumbnail { digest: sha1, size }, e);
+                None
+            }
+        }
+    }
+
+    /**
+     * Put a finished hash in the cache, returning false if the cache isn't
+     * written to
+     */
+    pub fn put_hash_in_cache(&self, key: &HashKey, hash: u64) -> Result<bool, Error> {
+        if !self.mode.can_write() {
+            return Ok(false);
+        }
+        if let Some(ref memory) = self.memory {
+            memory.put_hash(key, hash)?;
+        }
+        self.backend.put_hash(key, hash)?;
+        self.note_put(&CacheKey::Hash(key.clone()));
+        Ok(true)
+    }
+
+    /**
+     * Get a finished hash out of the cache
+     */
+    pub fn get_hash_from_cache(&self, key: &HashKey) -> Option<u64> {
+        if !self.mode.can_read() {
+            return None;
+        }
+        if let Some(ref memory) = self.memory {
+            if let Ok(Some(hash)) = memory.get_hash(key) {
+                self.count(|counters| &mut counters.hashes, true);
+                return Some(hash);
+            }
+        }
+        let hash = self.backend.get_hash(key);
+        self.count(
+            |counters| &mut counters.hashes,
+            hash.as_ref().is_ok_and(|hash| hash.is_some()),
+        );
+        match hash {
+            Ok(Some(hash)) => {
+                if let Some(ref memory) = self.memory {
+                    let _ = memory.put_hash(key, hash);
+                }
+                Some(hash)
+            }
+            Ok(None) => None,
+            Err(e) => {
+                self.read_failed(CacheKey::Hash(key.clone()), e);
+                None
+            }
+        }
+    }
+}
+
+// Functions //
+
+/**
+ * Write counters as a `<kind> <hits> <misses>` line for each kind
+ */
+fn encode_counters(counters: &CacheCounters) -> String {
+    let mut counters = *counters;
+    counters
+        .kinds()
+        .iter()
+        .map(|(kind, counts)| format!("{} {} {}\n", kind, counts.hits, counts.misses))
+        .collect()
+}
+
+/**
+ * Read the counters written by encode_counters, skipping lines that can't
+ * be read
+ */
+fn decode_counters(text: &str) -> CacheCounters {
+    let mut counters: CacheCounters = Default::default();
+    for line in text.lines() {
+        let fields: Vec<&str> = line.split(' ').collect();
+        if let [name, hits, misses] = fields[..] {
+            if let (Ok(hits), Ok(misses)) = (hits.parse(), misses.parse()) {
+                for (kind, counts) in counters.kinds().iter_mut() {
+                    if *kind == name {
+                        counts.add(&CacheCounts { hits, misses });
+                    }
+                }
+            }
+        }
+    }
+    counters
+}
+
+fn encode_png(image: &DynamicImage) -> Result<Vec<u8>, Error> {
+    let mut png = Vec::new();
+    image
+        .write_to(&mut png, ImageOutputFormat::PNG)
+        .map_err(Error::other)?;
+    Ok(png)
+}
+
+fn decode_png(png: &[u8]) -> Result<DynamicImage, Error> {
+    image::load_from_memory_with_format(png, image::ImageFormat::PNG)
+        .map_err(|e| Error::new(ErrorKind::InvalidData, e))
+}
+
+fn encode_hash(hash: u64) -> [u8; 8] {
+    hash.to_le_bytes()
+}
+
+fn decode_hash(bytes: &[u8]) -> Result<u64, Error> {
+    if bytes.len() != 8 {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("A cached hash is {} bytes instead of 8", bytes.len()),
+        ));
+    }
+    let mut hash = [0u8; 8];
+    hash.copy_from_slice(bytes);
+    Ok(u64::from_le_bytes(hash))
+}
+
+fn decode_digest(bytes: &[u8]) -> Result<String, Error> {
+    match String::from_utf8(bytes.to_vec()) {
+        Ok(ref digest) if is_digest(digest) => Ok(digest.clone()),
+        _ => Err(Error::new(
+            ErrorKind::InvalidData,
+            "A cached digest isn't hex",
+        )),
+    }
+}
+
+fn parse_size(size: &str) -> Option<u32> {
+    let mut sides = size.split('x');
+    match (sides.next(), sides.next(), sides.next()) {
+        (Some(width), Some(height), None) if width == height => width.parse().ok(),
+        _ => None,
+    }
+}
+
+/**
+ * Preprocessing descriptions are used in file names too
+ */
+fn is_preprocessing(preprocessing: &str) -> bool {
+    !preprocessing.is_empty()
+        && preprocessing
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/**
+ * Digests are used in file names, so only hex is accepted
+ */
+fn is_digest(digest: &str) -> bool {
+    digest.len() >= 10 && digest.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+fn get_name<T: fmt::Display>(value: &T) -> String {
+    format!("{}", value).to_lowercase()
+}
+
+/**
+ * A path next to the target that no other writer will be using. Process ids
+ * are only unique to a machine, so the host is named too for caches shared
+ * between machines.
+ */
+fn get_temp_path(path: &Path) -> PathBuf {
+    let count = TEMP_FILE_COUNTER.fetch_add(1, Ordering::SeqCst);
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(format!(".{}.{}.{}.tmp", get_hostname(), process::id(), count));
+    PathBuf::from(temp_path)
+}
+
+/**
+ * The name of this machine, limited to characters that are safe in a file name
+ */
+#[cfg(unix)]
+fn get_hostname() -> String {
+    let mut buffer = [0u8; 256];
+    let name = buffer.as_mut_ptr() as *mut libc::c_char;
+    if unsafe { libc::gethostname(name, buffer.len()) } != 0 {
+        return String::from("localhost");
+    }
+    let length = buffer.iter().position(|&b| b == 0).unwrap_or(buffer.len());
+    let hostname: String = buffer[..length]
+        .iter()
+        .map(|&b| b as char)
+        .filter(|c| c.is_ascii_alphanumeric() || *c == '-' || *c == '_')
+        .collect();
+    if hostname.is_empty() {
+        String::from("localhost")
+    } else {
+        hostname
+    }
+}
+
+#[cfg(not(unix))]
+fn get_hostname() -> String {
+    ::std::env::var("COMPUTERNAME").unwrap_or_else(|_| String::from("localhost"))
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate image;
+
+    use std::env::temp_dir;
+    use std::fs::{copy, create_dir_all, metadata, remove_dir_all, rename, File};
+    use std::io::{Error, ErrorKind, Write};
+    use std::path::Path;
+    use std::sync::Arc;
+    use std::thread;
+
+    use cache::{
+        Cache, CacheBackend, CacheCounts, CacheKey, CacheLimits, CacheMode, CacheStatus,
+        DigestAlgorithm, EvictionPolicy, FileSystemBackend, HashKey, MemoryBackend, MetadataKey,
+        StoreBackend,
+    };
+    use hash::{HashType, Precision};
+
+    /**
+     * Exercise every operation of a backend
+     */
+    fn check_backend(backend: &dyn CacheBackend) {
+        let digest = "4beb6f2d852b75a313863916a1803ebad13a3196";
+        let image = image::open("test_images/sample_03_small.jpg")
+            .unwrap()
+            .thumbnail(8, 8)
+            .grayscale();
+        let matrix = vec![vec![1.5, 2.0], vec![0.25, -3.0]];
+        let hash_key = HashKey::new(digest, HashType::PHash, Precision::High);
+        let metadata_key = MetadataKey {
+            fingerprint: String::from("0123456789abcdef0123456789abcdef"),
+            algorithm: DigestAlgorithm::Sha1,
+        };
+        backend.init().unwrap();
+        backend.clear().unwrap();
+        backend.init().unwrap();
+        assert!(backend.get_image(digest, 8).unwrap().is_none());
+        assert!(backend.get_hash(&hash_key).unwrap().is_none());
+
+        backend.put_image(digest, 8, &image).unwrap();
+        backend.put_matrix(digest, 2, &matrix).unwrap();
+        backend.put_hash(&hash_key, u64::MAX - 1).unwrap();
+        backend.put_digest(&metadata_key, digest).unwrap();
+        assert_eq!(
+            backend.get_image(digest, 8).unwrap().unwrap().raw_pixels(),
+            image.raw_pixels()
+        );
+        assert_eq!(backend.get_matrix(digest, 2).unwrap(), Some(matrix));
+        assert_eq!(backend.get_hash(&hash_key).unwrap(), Some(u64::MAX - 1));
+        assert_eq!(
+            backend.get_digest(&metadata_key).unwrap(),
+            Some(String::from(digest))
+        );
+
+        let mut keys: Vec<String> = backend
+            .entries()
+            .unwrap()
+            .iter()
+            .map(|entry| format!("{}", entry.key))
+            .collect();
+        keys.sort();
+        assert_eq!(
+            keys,
+            vec![
+                format!("digest/sha1/{}", metadata_key.fingerprint),
+                format!("hash/high/phash/v1/lanczos3-grayscale/{}", digest),
+                format!("image/8x8/{}", digest),
+                format!("matrix/2x2/{}", digest),
+            ]
+        );
+        for key in &keys {
+            assert_eq!(format!("{}", key.parse::<CacheKey>().unwrap()), *key);
+        }
+
+        let image_key = CacheKey::Image {
+            digest: String::from(digest),
+            size: 8,
+        };
+        assert!(backend.delete(&image_key).unwrap());
+        assert!(!backend.delete(&image_key).unwrap());
+        assert!(backend.get_image(digest, 8).unwrap().is_none());
+        assert_eq!(backend.entries().unwrap().len(), 3);
+        backend.clear().unwrap();
+    }
+
+    #[test]
+    fn test_get_file_hash() {
+        let target = "test_images/sample_01_large.jpg";
+        let target_path = Path::new(target);
+        let cache: Cache = Default::default();
+        let hash = cache.get_file_hash(&target_path);
+        match hash {
+            Ok(v) => {
+                println!("Hash: {}", v);
+                assert_eq!(v, String::from("4beb6f2d852b75a313863916a1803ebad13a3196"));
+            }
+            Err(e) => {
+                println!("Error: {:?}", e);
+                assert!(false);
+            }
+        }
+    }
+
+    #[test]
+    fn test_backends() {
+        check_backend(&MemoryBackend::new(16));
+        let store_path = temp_dir().join("pihash_test_cache.store");
+        check_backend(&StoreBackend::open(&store_path).unwrap());
+        ::std::fs::remove_file(&store_path).unwrap();
+    }
+
+    #[test]
+    fn test_stats_and_verify() {
+        let cache_dir = temp_dir().join("pihash_test_cache_stats");
+        let _ = remove_dir_all(&cache_dir);
+        let cache = Cache::from_directory(&cache_dir.to_string_lossy());
+        cache.init().unwrap();
+        let stats = cache.stats().unwrap();
+        assert_eq!(stats.images, Default::default());
+        assert_eq!(stats.counters, Default::default());
+        assert!(stats.disk_bytes > 0);
+
+        let source = Path::new("test_images/sample_03_small.jpg");
+        let image = image::open(source).unwrap().thumbnail(8, 8);
+        cache.put_image_in_cache(source, 8, &image).unwrap();
+        cache
+            .put_matrix_in_cache(source, 2, &[vec![1.5, 2.0], vec![0.25, -3.0]])
+            .unwrap();
+        assert_eq!(
+            cache.get_matrix_from_cache(source, 2),
+            Some(vec![vec![1.5, 2.0], vec![0.25, -3.0]])
+        );
+
+        let stats = cache.stats().unwrap();
+        assert_eq!(stats.images.entries, 1);
+        assert_eq!(stats.matrices.entries, 1);
+        assert!(stats.images.bytes > 0);
+        assert!(stats.disk_bytes > stats.images.bytes + stats.matrices.bytes);
+        assert_eq!(
+            stats.counters.matrices,
+            CacheCounts { hits: 1, misses: 0 }
+        );
+        let verification = cache.verify().unwrap();
+        assert_eq!(verification.checked, 2);
+        assert!(verification.corrupted.is_empty());
+
+        // Overwrite the matrix with something that isn't a matrix
+        let digest = cache.get_file_hash(source).unwrap();
+        let matrix_path = cache_dir
+            .join("v2/matrix/2x2")
+            .join(&digest[..10])
+            .join(format!("{}.dft", digest));
+        File::create(&matrix_path)
+            .unwrap()
+            .write_all(b"garbage")
+            .unwrap();
+        assert_eq!(
+            cache.verify().unwrap().corrupted,
+            vec![CacheKey::Matrix { digest, size: 2 }]
+        );
+        // Reading it is a miss that removes it, to be made again
+        assert_eq!(cache.get_matrix_from_cache(source, 2), None);
+        assert!(!matrix_path.exists());
+        assert!(cache.verify().unwrap().corrupted.is_empty());
+
+        // Counters are kept for the next process once they're saved
+        cache.save_counters().unwrap();
+        let reopened = Cache::from_directory(&cache_dir.to_string_lossy());
+        assert_eq!(
+            reopened.stats().unwrap().counters.matrices,
+            CacheCounts { hits: 1, misses: 1 }
+        );
+
+        remove_dir_all(&cache_dir).unwrap();
+    }
+
+    #[test]
+    fn test_memory_cache() {
+        let store_path = temp_dir().join("pihash_test_cache_memory.store");
+        let backend = Arc::new(StoreBackend::open(&store_path).unwrap());
+        let mut cache = Cache::new(backend.clone());
+        cache.keep_in_memory(1);
+
+        let first = Path::new("test_images/sample_03_small.jpg");
+        let second = Path::new("test_images/sample_04_small.jpg");
+        cache.put_matrix_in_cache(first, 2, &[vec![1.0]]).unwrap();
+        // Served from memory once the backend is empty
+        backend.clear().unwrap();
+        assert_eq!(cache.get_matrix_from_cache(first, 2), Some(vec![vec![1.0]]));
+
+        // Only the most recently used entry is kept in memory
+        cache.put_matrix_in_cache(second, 2, &[vec![2.0]]).unwrap();
+        backend.clear().unwrap();
+        assert_eq!(cache.get_matrix_from_cache(first, 2), None);
+        assert_eq!(cache.get_matrix_from_cache(second, 2), Some(vec![vec![2.0]]));
+        ::std::fs::remove_file(&store_path).unwrap();
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_trust_file_metadata() {
+        let cache_dir = temp_dir().join("pihash_test_cache_metadata");
+        let _ = remove_dir_all(&cache_dir);
+        let mut cache = Cache::from_directory(&cache_dir.to_string_lossy());
+        cache.init().unwrap();
+        cache.trust_file_metadata(true);
+        let source = cache_dir.join("image.jpg");
+        copy("test_images/sample_03_small.jpg", &source).unwrap();
+
+        let digest = cache.get_file_hash(&source).unwrap();
+        assert_eq!(cache.stats().unwrap().digests.entries, 1);
+        // Unchanged files aren't read again, so a planted digest is used
+        let key = cache.backend().entries().unwrap().remove(0).key;
+        if let CacheKey::Digest(ref key) = key {
+            cache.backend().put_digest(key, "0123456789").unwrap();
+        }
+        assert_eq!(cache.get_file_hash(&source).unwrap(), "0123456789");
+
+        // Changing the file changes its metadata
+        File::create(&source).unwrap().write_all(b"changed").unwrap();
+        let changed = cache.get_file_hash(&source).unwrap();
+        assert!(changed != digest && changed != "0123456789");
+
+        cache.use_digest(DigestAlgorithm::XxHash64);
+        assert_eq!(cache.get_file_hash(&source).unwrap().len(), 16);
+        remove_dir_all(&cache_dir).unwrap();
+    }
+
+    #[test]
+    fn test_limits_and_prune() {
+        let cache_dir = temp_dir().join("pihash_test_cache_limits");
+        let _ = remove_dir_all(&cache_dir);
+        let mut cache = Cache::from_directory(&cache_dir.to_string_lossy());
+        cache.init().unwrap();
+        let files = [
+            Path::new("test_images/sample_02_large.jpg"),
+            Path::new("test_images/sample_03_small.jpg"),
+            Path::new("test_images/sample_04_small.jpg"),
+        ];
+        for file in &files {
+            cache.put_matrix_in_cache(file, 2, &[vec![1.0]]).unwrap();
+        }
+        // The first is used most, and the last not at all
+        for _ in 0..2 {
+            cache.get_matrix_from_cache(files[0], 2).unwrap();
+        }
+        cache.get_matrix_from_cache(files[1], 2).unwrap();
+
+        // Without limits nothing is evicted
+        assert_eq!(cache.enforce_limits().unwrap().entries, 0);
+        cache.set_limits(Some(CacheLimits {
+            max_entries: Some(2),
+            policy: EvictionPolicy::LeastFrequentlyUsed,
+            ..Default::default()
+        }));
+        assert_eq!(cache.enforce_limits().unwrap().entries, 1);
+        assert!(cache.get_matrix_from_cache(files[2], 2).is_none());
+        assert!(cache.get_matrix_from_cache(files[1], 2).is_some());
+
+        // Only values of the files given are kept
+        let removed = cache.prune(&files[..1]).unwrap();
+        assert_eq!(removed.entries, 1);
+        let keys: Vec<CacheKey> = cache
+            .backend()
+            .entries()
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.key)
+            .collect();
+        assert_eq!(
+            keys,
+            vec![CacheKey::Matrix {
+                digest: cache.get_file_hash(files[0]).unwrap(),
+                size: 2,
+            }]
+        );
+        remove_dir_all(&cache_dir).unwrap();
+    }
+
+    #[test]
+    fn test_access_log_limits() {
+        let cache_dir = temp_dir().join("pihash_test_cache_access_log");
+        let _ = remove_dir_all(&cache_dir);
+        let mut cache = Cache::from_directory(&cache_dir.to_string_lossy());
+        cache.init().unwrap();
+        let digest = "4beb6f2d852b75a313863916a1803ebad13a3196";
+        cache.put_matrix_in_cache_by_digest(digest, 2, &[vec![1.0]]).unwrap();
+
+        // Uses of a value that's long gone pile up until the log is compacted
+        let access_log = cache_dir.join("v2/access.log");
+        let line = format!("1 1 matrix/2x2/{}\n", "0".repeat(40));
+        File::create(&access_log)
+            .unwrap()
+            .write_all(line.repeat(32 * 1024).as_bytes())
+            .unwrap();
+        for _ in 0..256 {
+            cache.get_matrix_from_cache_by_digest(digest, 2).unwrap();
+        }
+        let log_bytes = metadata(&access_log).unwrap().len();
+        assert!(log_bytes > 0 && log_bytes < 1024);
+
+        // The log counts towards the limits
+        let entries = cache.backend().entries().unwrap();
+        cache.set_limits(Some(CacheLimits {
+            max_bytes: Some(entries.iter().map(|entry| entry.bytes).sum()),
+            ..Default::default()
+        }));
+        assert_eq!(cache.enforce_limits().unwrap().entries, 1);
+        remove_dir_all(&cache_dir).unwrap();
+    }
+
+    #[test]
+    fn test_migrate_and_refuse() {
+        let cache_dir = temp_dir().join("pihash_test_cache_migrate");
+        let _ = remove_dir_all(&cache_dir);
+        let write_legacy = |key: &HashKey, hash: u64| {
+            let name = format!("{}", CacheKey::Hash(key.clone()));
+            let directory = cache_dir
+                .join(Path::new(&name).parent().unwrap())
+                .join(&key.digest[..10]);
+            create_dir_all(&directory).unwrap();
+            File::create(directory.join(format!("{}.hash", key.digest)))
+                .unwrap()
+                .write_all(&hash.to_le_bytes())
+                .unwrap();
+        };
+
+        // A directory with other things in it is never taken over
+        create_dir_all(&cache_dir).unwrap();
+        File::create(cache_dir.join("notes.txt")).unwrap();
+        let cache = Cache::from_directory(&cache_dir.to_string_lossy());
+        assert!(cache.init().is_err());
+        assert!(!cache.is_initialized());
+        assert!(cache.clean().is_err());
+        assert!(cache_dir.join("notes.txt").is_file());
+
+        // Nor is one that only has things named like the cache's in it
+        let unmarked_dir = temp_dir().join("pihash_test_cache_unmarked");
+        let _ = remove_dir_all(&unmarked_dir);
+        create_dir_all(unmarked_dir.join("image")).unwrap();
+        File::create(unmarked_dir.join("image/holiday.jpg")).unwrap();
+        let unmarked = Cache::from_directory(&unmarked_dir.to_string_lossy());
+        assert!(unmarked.init().is_err());
+        assert!(unmarked_dir.join("image/holiday.jpg").is_file());
+        // Unless it's really laid out like the first version
+        let digest = "4beb6f2d852b75a313863916a1803ebad13a3196";
+        let image_dir = unmarked_dir.join("image/8x8").join(&digest[..10]);
+        create_dir_all(&image_dir).unwrap();
+        rename(
+            unmarked_dir.join("image/holiday.jpg"),
+            image_dir.join(format!("{}.png", digest)),
+        )
+        .unwrap();
+        assert_eq!(unmarked.init().unwrap(), CacheStatus::Created);
+        remove_dir_all(&unmarked_dir).unwrap();
+
+        // Values of the first version were kept at the top of the cache
+        File::create(cache_dir.join("cache.meta"))
+            .unwrap()
+            .write_all(b"{\"cache_version\":1}")
+            .unwrap();
+        let digest = "4beb6f2d852b75a313863916a1803ebad13a3196";
+        let current = HashKey::new(digest, HashType::DHash, Precision::Low);
+        let mut outdated = current.clone();
+        outdated.version = 0;
+        write_legacy(&current, 42);
+        write_legacy(&outdated, 7);
+        assert_eq!(
+            cache.init().unwrap(),
+            CacheStatus::Migrated {
+                from: 1,
+                migrated: 1,
+                discarded: 1,
+            }
+        );
+        assert_eq!(cache.get_hash_from_cache(&current), Some(42));
+        assert_eq!(cache.init().unwrap(), CacheStatus::Ready);
+        assert!(!cache_dir.join("hash").exists());
+
+        // Only what the cache made is removed
+        cache.clean().unwrap();
+        assert!(cache_dir.join("notes.txt").is_file());
+        assert!(!cache_dir.join("cache.meta").exists());
+        assert!(!cache_dir.join("v2").exists());
+        assert!(cache_dir.join("cache.lock").is_file());
+        remove_dir_all(&cache_dir).unwrap();
+    }
+
+    #[test]
+    fn test_concurrent_writes() {
+        let cache_dir = temp_dir().join("pihash_test_cache_concurrent");
+        let _ = remove_dir_all(&cache_dir);
+        let source = Path::new("test_images/sample_03_small.jpg");
+        let matrix = vec![vec![0.125; 32]; 32];
+        let workers: Vec<_> = (0..4)
+            .map(|_| {
+                let cache_dir = cache_dir.clone();
+                let matrix = matrix.clone();
+                thread::spawn(move || {
+                    // Each worker sets up the cache as its own process would
+                    let cache = Cache::from_directory(&cache_dir.to_string_lossy());
+                    cache.init().unwrap();
+                    for _ in 0..20 {
+                        cache.put_matrix_in_cache(source, 32, &matrix).unwrap();
+                        // Never partially written by another worker
+                        assert_eq!(
+                            cache.get_matrix_from_cache(source, 32).as_ref(),
+                            Some(&matrix)
+                        );
+                    }
+                })
+            })
+            .collect();
+        for worker in workers {
+            worker.join().unwrap();
+        }
+        let cache = Cache::from_directory(&cache_dir.to_string_lossy());
+        assert_eq!(cache.stats().unwrap().matrices.entries, 1);
+        remove_dir_all(&cache_dir).unwrap();
+    }
+
+    #[test]
+    fn test_export_and_import() {
+        let cache_dir = temp_dir().join("pihash_test_cache_export");
+        let _ = remove_dir_all(&cache_dir);
+        let cache = Cache::from_directory(&cache_dir.to_string_lossy());
+        cache.init().unwrap();
+        let source = Path::new("test_images/sample_03_small.jpg");
+        let image = image::open(source).unwrap().thumbnail(8, 8);
+        let digest = cache.get_file_hash(source).unwrap();
+        let hash_key = HashKey::new(&digest, HashType::DHash, Precision::Medium);
+        cache.put_image_in_cache(source, 8, &image).unwrap();
+        cache.put_matrix_in_cache(source, 2, &[vec![0.1, 0.2]]).unwrap();
+        cache.put_hash_in_cache(&hash_key, 99).unwrap();
+
+        let mut archive = Vec::new();
+        let exported = cache.export(&mut archive).unwrap();
+        assert_eq!(exported.entries, 3);
+
+        // Into another kind of backend
+        let store_path = temp_dir().join("pihash_test_cache_import.store");
+        let _ = ::std::fs::remove_file(&store_path);
+        let imported_cache = Cache::new(Arc::new(StoreBackend::open(&store_path).unwrap()));
+        assert_eq!(imported_cache.import(&archive[..]).unwrap(), exported);
+        assert_eq!(imported_cache.get_hash_from_cache(&hash_key), Some(99));
+        assert_eq!(
+            imported_cache.get_matrix_from_cache(source, 2),
+            Some(vec![vec![0.1, 0.2]])
+        );
+        assert!(imported_cache.get_image_from_cache(source, 8).is_some());
+        assert!(imported_cache.import(&archive[..archive.len() / 2]).is_err());
+
+        imported_cache.clean().unwrap();
+        ::std::fs::remove_file(&store_path).unwrap();
+        remove_dir_all(&cache_dir).unwrap();
+    }
+
+    #[test]
+    fn test_cache_modes() {
+        let cache_dir = temp_dir().join("pihash_test_cache_modes");
+        let _ = remove_dir_all(&cache_dir);
+        let mut cache = Cache::from_directory(&cache_dir.to_string_lossy());
+        let source = Path::new("test_images/sample_03_small.jpg");
+        let digest = cache.get_file_hash(source).unwrap();
+        let hash_key = HashKey::new(&digest, HashType::AHash, Precision::Medium);
+
+        // A read-only cache must already be there
+        cache.set_mode(CacheMode::ReadOnly);
+        assert!(cache.init().is_err());
+        assert!(!cache_dir.exists());
+
+        cache.set_mode(CacheMode::ReadWrite);
+        cache.init().unwrap();
+        assert!(cache.put_hash_in_cache(&hash_key, 1).unwrap());
+        assert_eq!(cache.get_hash_from_cache(&hash_key), Some(1));
+
+        cache.set_mode(CacheMode::ReadOnly);
+        assert_eq!(cache.init().unwrap(), CacheStatus::Ready);
+        assert!(!cache.put_hash_in_cache(&hash_key, 2).unwrap());
+        assert!(!cache.put_matrix_in_cache(source, 2, &[vec![0.5]]).unwrap());
+        assert_eq!(cache.get_hash_from_cache(&hash_key), Some(1));
+        // Reading isn't recorded either
+        let entries = cache.backend().entries().unwrap();
+        assert_eq!(entries[0].access.uses, 1);
+        assert_eq!(cache.stats().unwrap().matrices.entries, 0);
+
+        // A corrupted value is left for something that can write to fix
+        let hash_path =
+            FileSystemBackend::new(&cache_dir).get_path(&CacheKey::Hash(hash_key.clone()));
+        File::create(&hash_path).unwrap().write_all(b"bad").unwrap();
+        assert_eq!(cache.get_hash_from_cache(&hash_key), None);
+        assert!(hash_path.exists());
+
+        cache.set_mode(CacheMode::WriteOnly);
+        assert_eq!(cache.get_hash_from_cache(&hash_key), None);
+        assert!(cache.put_hash_in_cache(&hash_key, 3).unwrap());
+
+        cache.set_mode(CacheMode::Disabled);
+        assert_eq!(cache.get_hash_from_cache(&hash_key), None);
+        assert!(!cache.put_hash_in_cache(&hash_key, 4).unwrap());
+
+        // Nothing that changes the cache is allowed unless it can be written
+        let mut archive = Vec::new();
+        cache.export(&mut archive).unwrap();
+        cache.set_limits(Some(CacheLimits {
+            max_entries: Some(0),
+            ..Default::default()
+        }));
+        for mode in &[CacheMode::Disabled, CacheMode::ReadOnly] {
+            cache.set_mode(*mode);
+            let denied = |result: Result<_, Error>| {
+                result.err().map(|e| e.kind()) == Some(ErrorKind::PermissionDenied)
+            };
+            assert!(denied(cache.clean()));
+            assert!(denied(cache.import(&archive[..]).map(|_| ())));
+            assert!(denied(cache.prune::<&Path>(&[]).map(|_| ())));
+            assert!(denied(cache.enforce_limits().map(|_| ())));
+        }
+        cache.set_limits(None);
+
+        cache.set_mode(CacheMode::ReadWrite);
+        assert_eq!(cache.get_hash_from_cache(&hash_key), Some(3));
+        for mode in &["disabled", "read-only", "write-only", "read-write"] {
+            assert_eq!(&format!("{}", mode.parse::<CacheMode>().unwrap()), mode);
+        }
+        assert!("sometimes".parse::<CacheMode>().is_err());
+        remove_dir_all(&cache_dir).unwrap();
+    }
+}
@@ -0,0 +1,585 @@
+// Copyright 2016 Drew Short <drew@sothr.com>.
+//
+// Licensed under the MIT license<LICENSE-MIT or http://opensource.org/licenses/MIT>.
+// This file may not be copied, modified, or distributed except according to those terms.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{self, create_dir_all, File, OpenOptions};
+use std::io::{BufReader, Error, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+
+use super::access::AccessLog;
+use super::image::DynamicImage;
+use super::lock::FileLock;
+use super::{
+    decode_counters, decode_digest, decode_hash, decode_matrix, decode_png, encode_counters,
+    encode_hash, encode_matrix, encode_png, get_temp_path, CacheBackend, CacheCounters,
+    CacheEntry, CacheKey, HashKey, MetadataKey,
+};
+
+// Constants //
+
+const STORE_MAGIC: &[u8; 8] = b"PIHCSTOR";
+const STORE_VERSION: u32 = 1;
+const HEADER_LENGTH: u64 = 12;
+// flags, key length and value length
+const RECORD_HEADER_LENGTH: u64 = 9;
+const FLAG_VALUE: u8 = 0;
+const FLAG_DELETED: u8 = 1;
+// Compact once this much of the file is replaced or deleted values, as long
+// as that's more than the live values
+const COMPACT_THRESHOLD: u64 = 4 * 1024 * 1024;
+
+// Structs/Enums //
+
+/**
+ * Where a value is in the file
+ */
+#[derive(Copy, Clone, Debug)]
+struct StoredValue {
+    offset: u64,
+    length: u32,
+    // Of the whole record, counted as garbage once the value is replaced
+    record_length: u64,
+}
+
+struct StoreState {
+    file: File,
+    values: HashMap<CacheKey, StoredValue>,
+    end: u64,
+    garbage: u64,
+}
+
+/**
+ * Keeps every value in a single file, for filesystems where many small
+ * files are slow
+ *
+ * The file is a log of records, each a flag byte, the key and value lengths
+ * as little endian u32s, the key as text and then the value, encoded as the
+ * filesystem backend would. Writing a key again or deleting it appends a
+ * new record, and the file is compacted once enough of it is stale. A
+ * record cut short by a crash is dropped when the file is next opened.
+ * Values are recorded in an access log beside the file as they're read
+ * and written, and hits and misses are added up in a `.counters` file.
+ * Only one process can have a store open at a time, which is enforced with
+ * a lock on a `.lock` file beside it.
+ */
+pub struct StoreBackend {
+    path: PathBuf,
+    state: Mutex<StoreState>,
+    accesses: AccessLog,
+    counters_path: PathBuf,
+    _lock: FileLock,
+}
+
+impl StoreState {
+    /**
+     * Read the records of a file, truncating anything after the last whole
+     * one
+     */
+    fn load(file: File) -> Result<StoreState, Error> {
+        let length = file.metadata()?.len();
+        let mut state = StoreState {
+            file,
+            values: HashMap::new(),
+            end: HEADER_LENGTH,
+            garbage: 0,
+        };
+        if length < HEADER_LENGTH {
+            state.file.set_len(0)?;
+            let mut header = STORE_MAGIC.to_vec();
+            header.extend_from_slice(&STORE_VERSION.to_le_bytes());
+            state.file.write_all(&header)?;
+            return Ok(state);
+        }
+
+        let mut reader = BufReader::new(&state.file);
+        let mut header = [0u8; HEADER_LENGTH as usize];
+        reader.read_exact(&mut header)?;
+        if &header[..8] != STORE_MAGIC {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "Not a pihash cache store",
+            ));
+        }
+        let version = read_u32(&header[8..]);
+        if version != STORE_VERSION {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Unsupported cache store version {}", version),
+            ));
+        }
+
+        let mut values = HashMap::new();
+        let mut garbage = 0;
+        let mut offset = HEADER_LENGTH;
+        loop {
+            let mut record_header = [0u8; RECORD_HEADER_LENGTH as usize];
+            if reader.read_exact(&mut record_header).is_err() {
+                break;
+            }
+            let key_length = read_u32(&record_header[1..5]);
+            let value_length = read_u32(&record_header[5..]);
+            let record_length = RECORD_HEADER_LENGTH + key_length as u64 + value_length as u64;
+            if offset + record_length > length {
+                break;
+            }
+            let mut key = vec![0u8; key_length as usize];
+            reader.read_exact(&mut key)?;
+            reader.seek(SeekFrom::Current(value_length as i64))?;
+
+            let key = String::from_utf8(key)
+                .ok()
+                .and_then(|key| key.parse::<CacheKey>().ok());
+            let previous = match key {
+                Some(key) => {
+                    if record_header[0] == FLAG_VALUE {
+                        let value = StoredValue {
+                            offset: offset + RECORD_HEADER_LENGTH + key_length as u64,
+                            length: value_length,
+                            record_length,
+                        };
+                        values.insert(key, value)
+                    } else {
+                        garbage += record_length;
+                        values.remove(&key)
+                    }
+                }
+                None => {
+                    garbage += record_length;
+                    None
+                }
+            };
+            if let Some(previous) = previous {
+                garbage += previous.record_length;
+            }
+            offset += record_length;
+        }
+        drop(reader);
+
+        if offset < length {
+            state.file.set_len(offset)?;
+        }
+        state.values = values;
+        state.end = offset;
+        state.garbage = garbage;
+        Ok(state)
+    }
+
+    fn read(&mut self, key: &CacheKey) -> Result<Option<Vec<u8>>, Error> {
+        let value = match self.values.get(key) {
+            Some(value) => *value,
+            None => return Ok(None),
+        };
+        let mut contents = vec![0u8; value.length as usize];
+        self.file.seek(SeekFrom::Start(value.offset))?;
+        self.file.read_exact(&mut contents)?;
+        Ok(Some(contents))
+    }
+
+    /**
+     * Append a record, with no value when deleting
+     */
+    fn append(&mut self, key: &CacheKey, contents: Option<&[u8]>) -> Result<(), Error> {
+        let key_text = format!("{}", key);
+        let value = contents.unwrap_or(&[]);
+        if value.len() > u32::MAX as usize {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "The value is too large to store",
+            ));
+        }
+        let mut record =
+            Vec::with_capacity(RECORD_HEADER_LENGTH as usize + key_text.len() + value.len());
+        record.push(if contents.is_some() {
+            FLAG_VALUE
+        } else {
+            FLAG_DELETED
+        });
+        record.extend_from_slice(&(key_text.len() as u32).to_le_bytes());
+        record.extend_from_slice(&(value.len() as u32).to_le_bytes());
+        record.extend_from_slice(key_text.as_bytes());
+        record.extend_from_slice(value);
+
+        self.file.seek(SeekFrom::Start(self.end))?;
+        if let Err(e) = self.file.write_all(&record) {
+            // Don't leave part of a record for the next one to follow
+            let _ = self.file.set_len(self.end);
+            return Err(e);
+        }
+        let previous = if contents.is_some() {
+            let value = StoredValue {
+                offset: self.end + RECORD_HEADER_LENGTH + key_text.len() as u64,
+                length: value.len() as u32,
+                record_length: record.len() as u64,
+            };
+            self.values.insert(key.clone(), value)
+        } else {
+            self.garbage += record.len() as u64;
+            self.values.remove(key)
+        };
+        if let Some(previous) = previous {
+            self.garbage += previous.record_length;
+        }
+        self.end += record.len() as u64;
+        Ok(())
+    }
+
+    fn get_live_length(&self) -> u64 {
+        self.end - HEADER_LENGTH - self.garbage
+    }
+}
+
+impl StoreBackend {
+    /**
+     * Open the store at a path, creating it if it doesn't exist. A store
+     * that another process has open is refused.
+     */
+    pub fn open(path: &Path) -> Result<StoreBackend, Error> {
+        if let Some(parent) = path.parent() {
+            create_dir_all(parent)?;
+        }
+        let mut lock_path = path.as_os_str().to_owned();
+        lock_path.push(".lock");
+        let lock = match FileLock::try_lock(Path::new(&lock_path))? {
+            Some(lock) => lock,
+            None => {
+                return Err(Error::new(
+                    ErrorKind::WouldBlock,
+                    format!("{} is in use by another process", path.display()),
+                ))
+            }
+        };
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        let mut access_path = path.as_os_str().to_owned();
+        access_path.push(".access");
+        let mut counters_path = path.as_os_str().to_owned();
+        counters_path.push(".counters");
+        Ok(StoreBackend {
+            path: path.to_path_buf(),
+            state: Mutex::new(StoreState::load(file)?),
+            accesses: AccessLog::new(Path::new(&access_path)),
+            counters_path: PathBuf::from(counters_path),
+            _lock: lock,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn compact_state(&self, state: &mut StoreState) -> Result<(), Error> {
+        let temp_path = get_temp_path(&self.path);
+        let compacted = self.write_compacted(state, &temp_path);
+        let file = match compacted.and_then(|_| fs::rename(&temp_path, &self.path)) {
+            Ok(_) => OpenOptions::new().read(true).write(true).open(&self.path)?,
+            Err(e) => {
+                let _ = fs::remove_file(&temp_path);
+                return Err(e);
+            }
+        };
+        *state = StoreState::load(file)?;
+        Ok(())
+    }
+
+    fn write_compacted(&self, state: &mut StoreState, temp_path: &Path) -> Result<(), Error> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(temp_path)?;
+        let mut compacted = StoreState::load(file)?;
+        let keys: Vec<CacheKey> = state.values.keys().cloned().collect();
+        for key in keys {
+            if let Some(contents) = state.read(&key)? {
+                compacted.append(&key, Some(&contents))?;
+            }
+        }
+        compacted.file.sync_all()
+    }
+
+    fn get(&self, key: &CacheKey) -> Result<Option<Vec<u8>>, Error> {
+        let contents = self.lock().read(key)?;
+        if contents.is_some() {
+            if let Ok(true) = self.accesses.record(key) {
+                let state = self.lock();
+                let _ = self.accesses.compact(|key| state.values.contains_key(key));
+            }
+        }
+        Ok(contents)
+    }
+
+    fn put(&self, key: &CacheKey, contents: &[u8]) -> Result<(), Error> {
+        let mut state = self.lock();
+        state.append(key, Some(contents))?;
+        if state.garbage > COMPACT_THRESHOLD && state.garbage > state.get_live_length() {
+            self.compact_state(&mut state)?;
+        }
+        if let Ok(true) = self.accesses.record(key) {
+            let _ = self.accesses.compact(|key| state.values.contains_key(key));
+        }
+        Ok(())
+    }
+
+    /**
+     * The index is only changed once a record is written, so it still
+     * matches the file after a panic while the lock was held
+     */
+    fn lock(&self) -> MutexGuard<'_, StoreState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl fmt::Debug for StoreBackend {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "StoreBackend {{ path: {:?} }}", self.path)
+    }
+}
+
+impl CacheBackend for StoreBackend {
+    fn location(&self) -> String {
+        self.path.to_string_lossy().into_owned()
+    }
+
+    fn get_image(&self, digest: &str, size: u32) -> Result<Option<DynamicImage>, Error> {
+        let key = CacheKey::Image {
+            digest: String::from(digest),
+            size,
+        };
+        match self.get(&key)? {
+            Some(png) => decode_png(&png).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn put_image(&self, digest: &str, size: u32, image: &DynamicImage) -> Result<(), Error> {
+        let key = CacheKey::Image {
+            digest: String::from(digest),
+            size,
+        };
+        self.put(&key, &encode_png(image)?)
+    }
+
+    fn get_matrix(&self, digest: &str, size: u32) -> Result<Option<Vec<Vec<f64>>>, Error> {
+        let key = CacheKey::Matrix {
+            digest: String::from(digest),
+            size,
+        };
+        match self.get(&key)? {
+            Some(matrix) => decode_matrix(&matrix[..]).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn put_matrix(&self, digest: &str, size: u32, matrix: &[Vec<f64>]) -> Result<(), Error> {
+        let key = CacheKey::Matrix {
+            digest: String::from(digest),
+            size,
+        };
+        self.put(&key, &encode_matrix(matrix)?)
+    }
+
+    fn get_thumbnail(&self, digest: &str, size: u32) -> Result<Option<DynamicImage>, Error> {
+        let key = CacheKey::Thumbnail {
+            digest: String::from(digest),
+            size,
+        };
+        match self.get(&key)? {
+            Some(png) => decode_png(&png).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn put_thumbnail(&self, digest: &str, size: u32, image: &DynamicImage) -> Result<(), Error> {
+        let key = CacheKey::Thumbnail {
+            digest: String::from(digest),
+            size,
+        };
+        self.put(&key, &encode_png(image)?)
+    }
+
+    fn get_hash(&self, key: &HashKey) -> Result<Option<u64>, Error> {
+        match self.get(&CacheKey::Hash(key.clone()))? {
+            Some(hash) => decode_hash(&hash).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn put_hash(&self, key: &HashKey, hash: u64) -> Result<(), Error> {
+        self.put(&CacheKey::Hash(key.clone()), &encode_hash(hash))
+    }
+
+    fn get_digest(&self, key: &MetadataKey) -> Result<Option<String>, Error> {
+        match self.get(&CacheKey::Digest(key.clone()))? {
+            Some(digest) => decode_digest(&digest).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn put_digest(&self, key: &MetadataKey, digest: &str) -> Result<(), Error> {
+        self.put(&CacheKey::Digest(key.clone()), digest.as_bytes())
+    }
+
+    fn delete(&self, key: &CacheKey) -> Result<bool, Error> {
+        let mut state = self.lock();
+        if !state.values.contains_key(key) {
+            return Ok(false);
+        }
+        state.append(key, None)?;
+        Ok(true)
+    }
+
+    fn entries(&self) -> Result<Vec<CacheEntry>, Error> {
+        let accesses = self.accesses.read()?;
+        Ok(self
+            .lock()
+            .values
+            .iter()
+            .map(|(key, value)| CacheEntry {
+                key: key.clone(),
+                bytes: value.length as u64,
+                access: accesses.get(key).cloned().unwrap_or_default(),
+            })
+            .collect())
+    }
+
+    fn entry_bytes(&self, key: &CacheKey) -> Result<Option<u64>, Error> {
+        Ok(self.lock().values.get(key).map(|value| value.length as u64))
+    }
+
+    fn overhead_bytes(&self) -> Result<u64, Error> {
+        self.accesses.size()
+    }
+
+    /**
+     * Rewrite the file and the access log with only the live values
+     */
+    fn compact(&self) -> Result<(), Error> {
+        let mut state = self.lock();
+        self.compact_state(&mut state)?;
+        self.accesses.compact(|key| state.values.contains_key(key))
+    }
+
+    fn clear(&self) -> Result<(), Error> {
+        let mut state = self.lock();
+        state.file.set_len(HEADER_LENGTH)?;
+        state.values.clear();
+        state.end = HEADER_LENGTH;
+        state.garbage = 0;
+        match fs::remove_file(&self.counters_path) {
+            Ok(_) => (),
+            Err(ref e) if e.kind() == ErrorKind::NotFound => (),
+            Err(e) => return Err(e),
+        }
+        self.accesses.clear()
+    }
+
+    /**
+     * The size of the store and the files beside it
+     */
+    fn disk_usage(&self) -> Result<u64, Error> {
+        let mut bytes = 0;
+        let paths = [
+            self.path.as_path(),
+            self.accesses.path(),
+            self.counters_path.as_path(),
+        ];
+        for path in &paths {
+            match fs::metadata(path) {
+                Ok(metadata) => bytes += metadata.len(),
+                Err(ref e) if e.kind() == ErrorKind::NotFound => (),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(bytes)
+    }
+
+    fn counters(&self) -> Result<CacheCounters, Error> {
+        match fs::read_to_string(&self.counters_path) {
+            Ok(text) => Ok(decode_counters(&text)),
+            Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(Default::default()),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn add_counters(&self, counters: &CacheCounters) -> Result<(), Error> {
+        let mut saved = self.counters()?;
+        saved.add(counters);
+        let temp_path = get_temp_path(&self.counters_path);
+        fs::write(&temp_path, encode_counters(&saved))?;
+        fs::rename(&temp_path, &self.counters_path)
+    }
+
+    fn record_accesses(&self, record: bool) {
+        self.accesses.set_recording(record);
+    }
+}
+
+// Functions //
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    let mut value = [0u8; 4];
+    value.copy_from_slice(&bytes[..4]);
+    u32::from_le_bytes(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env::temp_dir;
+    use std::fs::{remove_file, OpenOptions};
+    use std::io::Write;
+
+    use super::StoreBackend;
+    use cache::{CacheBackend, CacheKey, HashKey};
+    use hash::{HashType, Precision};
+
+    #[test]
+    fn test_reopen_and_compact() {
+        let path = temp_dir().join("pihash_test_store_reopen.store");
+        let _ = remove_file(&path);
+        let key = |hash_type| {
+            HashKey::new(
+                "4beb6f2d852b75a313863916a1803ebad13a3196",
+                hash_type,
+                Precision::Low,
+            )
+        };
+        {
+            let store = StoreBackend::open(&path).unwrap();
+            store.put_hash(&key(HashType::AHash), 1).unwrap();
+            store.put_hash(&key(HashType::AHash), 2).unwrap();
+            store.put_hash(&key(HashType::DHash), 3).unwrap();
+            store.delete(&CacheKey::Hash(key(HashType::DHash))).unwrap();
+        }
+        // Part of a record, as if a write was interrupted
+        let written = path.metadata().unwrap().len();
+        OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(&[0, 40, 0])
+            .unwrap();
+
+        let store = StoreBackend::open(&path).unwrap();
+        assert_eq!(path.metadata().unwrap().len(), written);
+        // It can't be opened again while it's open
+        assert!(StoreBackend::open(&path).is_err());
+        assert_eq!(store.get_hash(&key(HashType::AHash)).unwrap(), Some(2));
+        assert_eq!(store.get_hash(&key(HashType::DHash)).unwrap(), None);
+
+        store.compact().unwrap();
+        assert!(path.metadata().unwrap().len() < written);
+        assert_eq!(store.get_hash(&key(HashType::AHash)).unwrap(), Some(2));
+        assert_eq!(store.entries().unwrap().len(), 1);
+        store.clear().unwrap();
+        remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,443 @@
+// Copyright 2016 Drew Short <drew@sothr.com>.
+//
+// Licensed under the MIT license<LICENSE-MIT or http://opensource.org/licenses/MIT>.
+// This file may not be copied, modified, or distributed except according to those terms.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Error, ErrorKind};
+use std::path::Path;
+
+use hash::{HashType, PerceptualHashes, Precision};
+use similarity::{CombinationMode, HashDistances, SimilarityPolicy};
+use PIHash;
+
+// Constants //
+
+// Quorum used when recommending a weighted vote policy
+const RECOMMENDED_VOTE_QUORUM: f64 = 0.5;
+
+// Structs/Enums //
+
+/**
+ * Two images and whether a person has judged them to be duplicates
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct LabelledPair {
+    pub first: String,
+    pub second: String,
+    pub duplicate: bool,
+}
+
+/**
+ * Counts of correct and incorrect duplicate predictions
+ */
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct ConfusionMatrix {
+    pub true_positives: u64,
+    pub false_positives: u64,
+    pub true_negatives: u64,
+    pub false_negatives: u64,
+}
+
+/**
+ * The results of treating every pair at or under a distance as a duplicate
+ */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ThresholdStats {
+    pub threshold: u64,
+    pub results: ConfusionMatrix,
+}
+
+/**
+ * Calibration results for a single hash type
+ *
+ * The histograms are indexed by hamming distance, and there is a threshold
+ * entry for every possible distance. Together the thresholds form the ROC
+ * curve of the algorithm.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct AlgorithmCalibration {
+    pub hash_type: HashType,
+    pub duplicate_histogram: Vec<u64>,
+    pub distinct_histogram: Vec<u64>,
+    pub thresholds: Vec<ThresholdStats>,
+    pub best_threshold: ThresholdStats,
+}
+
+/**
+ * The full calibration report for a set of labelled pairs
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct CalibrationReport {
+    pub precision: Precision,
+    pub pairs: usize,
+    /// Images that couldn't be hashed and why, whose pairs are left out
+    pub skipped: Vec<String>,
+    pub algorithms: Vec<AlgorithmCalibration>,
+    pub recommended_policy: SimilarityPolicy,
+    pub recommended_policy_results: ConfusionMatrix,
+}
+
+impl ConfusionMatrix {
+    pub fn record(&mut self, predicted_duplicate: bool, duplicate: bool) {
+        match (predicted_duplicate, duplicate) {
+            (true, true) => self.true_positives += 1,
+            (true, false) => self.false_positives += 1,
+            (false, false) => self.true_negatives += 1,
+            (false, true) => self.false_negatives += 1,
+        }
+    }
+
+    /**
+     * The fraction of predicted duplicates that are real duplicates
+     */
+    pub fn precision(&self) -> f64 {
+        ratio(
+            self.true_positives,
+            self.true_positives + self.false_positives,
+        )
+    }
+
+    /**
+     * The fraction of real duplicates that were predicted, the true positive rate
+     */
+    pub fn recall(&self) -> f64 {
+        ratio(
+            self.true_positives,
+            self.true_positives + self.false_negatives,
+        )
+    }
+
+    pub fn false_positive_rate(&self) -> f64 {
+        ratio(
+            self.false_positives,
+            self.false_positives + self.true_negatives,
+        )
+    }
+
+    pub fn f1_score(&self) -> f64 {
+        let precision = self.precision();
+        let recall = self.recall();
+        if precision + recall > 0_f64 {
+            2_f64 * precision * recall / (precision + recall)
+        } else {
+            0_f64
+        }
+    }
+}
+
+fn ratio(numerator: u64, denominator: u64) -> f64 {
+    if denominator > 0 {
+        numerator as f64 / denominator as f64
+    } else {
+        0_f64
+    }
+}
+
+// Functions //
+
+/**
+ * Read a list of labelled image pairs
+ *
+ * Each line is in the form `first,second,label` where the label is one of
+ * `duplicate`, `true`, `1` or `not-duplicate`, `false`, `0`. Blank lines and
+ * lines starting with `#` are ignored.
+ */
+pub fn read_labelled_pairs(path: &Path) -> Result<Vec<LabelledPair>, Error> {
+    let file = File::open(path)?;
+    let mut pairs = Vec::new();
+    for (index, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<&str> = line.split(',').map(|field| field.trim()).collect();
+        if fields.len() != 3 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Line {}: expected 'first,second,label'", index + 1),
+            ));
+        }
+        let duplicate = match fields[2].to_lowercase().as_str() {
+            "duplicate" | "true" | "1" => true,
+            "not-duplicate" | "false" | "0" => false,
+            label => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Line {}: unknown label '{}'", index + 1, label),
+                ));
+            }
+        };
+        pairs.push(LabelledPair {
+            first: String::from(fields[0]),
+            second: String::from(fields[1]),
+            duplicate,
+        });
+    }
+    Ok(pairs)
+}
+
+/**
+ * Hash every image in the labelled pairs and calibrate the requested hash
+ * types. Pairs with an image that can't be hashed are skipped, rather than
+ * skewing the distances.
+ */
+pub fn calibrate(
+    lib: &PIHash,
+    pairs: &[LabelledPair],
+    precision: &Precision,
+    hash_types: &[HashType],
+) -> CalibrationReport {
+    // Images are commonly part of several pairs, so only hash them once
+    let mut hashes: HashMap<&str, Option<PerceptualHashes>> = HashMap::new();
+    let mut skipped = Vec::new();
+    for pair in pairs {
+        for path in [&pair.first, &pair.second].iter() {
+            if !hashes.contains_key(path.as_str()) {
+                let image_hashes =
+                    match lib.try_get_perceptual_hashes(Path::new(path.as_str()), precision) {
+                        Ok(image_hashes) => Some(image_hashes),
+                        Err(e) => {
+                            skipped.push(format!("{}: {}", path, e));
+                            None
+                        }
+                    };
+                hashes.insert(path.as_str(), image_hashes);
+            }
+        }
+    }
+
+    let samples: Vec<(HashDistances, bool)> = pairs
+        .iter()
+        .filter_map(|pair| {
+            let first = hashes[pair.first.as_str()].as_ref()?;
+            let second = hashes[pair.second.as_str()].as_ref()?;
+            Some((HashDistances::between(first, second), pair.duplicate))
+        })
+        .collect();
+    CalibrationReport {
+        skipped,
+        ..calibrate_distances(&samples, precision, hash_types)
+    }
+}
+
+/**
+ * Calibrate the requested hash types from already calculated distances
+ */
+pub fn calibrate_distances(
+    samples: &[(HashDistances, bool)],
+    precision: &Precision,
+    hash_types: &[HashType],
+) -> CalibrationReport {
+    let hash_length = precision.get_hash_length() as u64;
+    let algorithms: Vec<AlgorithmCalibration> = hash_types
+        .iter()
+        .map(|hash_type| calibrate_algorithm(samples, hash_type, hash_length))
+        .collect();
+
+    // Hash types that were not calibrated neither vote nor veto
+    let mut base_policy = SimilarityPolicy::for_hash_types(*precision, &[]);
+    for algorithm in &algorithms {
+        base_policy.set_max_distance(&algorithm.hash_type, algorithm.best_threshold.threshold);
+        base_policy.threshold_mut(&algorithm.hash_type).weight =
+            algorithm.best_threshold.results.f1_score();
+    }
+
+    // Pick whichever way of combining the hashes works best on the samples
+    let mut recommended_policy = base_policy;
+    let mut recommended_policy_results = evaluate_policy(samples, &recommended_policy);
+    let mut vote_policy = base_policy;
+    vote_policy.mode = CombinationMode::WeightedVote(RECOMMENDED_VOTE_QUORUM);
+    let vote_results = evaluate_policy(samples, &vote_policy);
+    if vote_results.f1_score() > recommended_policy_results.f1_score() {
+        recommended_policy = vote_policy;
+        recommended_policy_results = vote_results;
+    }
+
+    CalibrationReport {
+        precision: *precision,
+        pairs: samples.len(),
+        skipped: Vec::new(),
+        algorithms,
+        recommended_policy,
+        recommended_policy_results,
+    }
+}
+
+fn calibrate_algorithm(
+    samples: &[(HashDistances, bool)],
+    hash_type: &HashType,
+    hash_length: u64,
+) -> AlgorithmCalibration {
+    let mut duplicate_histogram = vec![0u64; hash_length as usize + 1];
+    let mut distinct_histogram = vec![0u64; hash_length as usize + 1];
+    for &(ref distances, duplicate) in samples {
+        let distance = distances.get(hash_type).min(hash_length) as usize;
+        if duplicate {
+            duplicate_histogram[distance] += 1;
+        } else {
+            distinct_histogram[distance] += 1;
+        }
+    }
+
+    let thresholds: Vec<ThresholdStats> = (0..hash_length + 1)
+        .map(|threshold| {
+            let mut results: ConfusionMatrix = Default::default();
+            for &(ref distances, duplicate) in samples {
+                results.record(distances.get(hash_type) <= threshold, duplicate);
+            }
+            ThresholdStats { threshold, results }
+        })
+        .collect();
+
+    // Prefer the smallest threshold when several share the best score
+    let mut best_threshold = thresholds[0];
+    for stats in &thresholds {
+        if stats.results.f1_score() > best_threshold.results.f1_score() {
+            best_threshold = *stats;
+        }
+    }
+
+    AlgorithmCalibration {
+        hash_type: *hash_type,
+        duplicate_histogram,
+        distinct_histogram,
+        thresholds,
+        best_threshold,
+    }
+}
+
+fn evaluate_policy(samples: &[(HashDistances, bool)], policy: &SimilarityPolicy) -> ConfusionMatrix {
+    let mut results: ConfusionMatrix = Default::default();
+    for &(distances, duplicate) in samples {
+        results.record(policy.evaluate(distances).similar, duplicate);
+    }
+    results
+}
+
+impl fmt::Display for CalibrationReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Calibrated {} pairs at {} precision", self.pairs, self.precision)?;
+        if !self.skipped.is_empty() {
+            writeln!(
+                f,
+                "Skipped the pairs of {} images that couldn't be hashed",
+                self.skipped.len()
+            )?;
+        }
+        for algorithm in &self.algorithms {
+            writeln!(f)?;
+            writeln!(f, "{}:", algorithm.hash_type)?;
+            writeln!(f, "distance\tduplicate\tdistinct")?;
+            for distance in 0..algorithm.duplicate_histogram.len() {
+                let duplicates = algorithm.duplicate_histogram[distance];
+                let distincts = algorithm.distinct_histogram[distance];
+                if duplicates > 0 || distincts > 0 {
+                    writeln!(f, "{}\t{}\t{}", distance, duplicates, distincts)?;
+                }
+            }
+            writeln!(f, "threshold\tprecision\trecall\tfalse_positive_rate\tf1")?;
+            for stats in &algorithm.thresholds {
+                writeln!(
+                    f,
+                    "{}\t{:.4}\t{:.4}\t{:.4}\t{:.4}",
+                    stats.threshold,
+                    stats.results.precision(),
+                    stats.results.recall(),
+                    stats.results.false_positive_rate(),
+                    stats.results.f1_score()
+                )?;
+            }
+            writeln!(
+                f,
+                "Best threshold: {} (f1 {:.4})",
+                algorithm.best_threshold.threshold,
+                algorithm.best_threshold.results.f1_score()
+            )?;
+        }
+        writeln!(f)?;
+        writeln!(f, "Recommended policy: {:?}", self.recommended_policy.mode)?;
+        for algorithm in &self.algorithms {
+            writeln!(
+                f,
+                "{}: max distance {}, weight {:.4}",
+                algorithm.hash_type,
+                self.recommended_policy.max_distance(&algorithm.hash_type),
+                self.recommended_policy.threshold(&algorithm.hash_type).weight
+            )?;
+        }
+        write!(
+            f,
+            "precision {:.4}, recall {:.4}, f1 {:.4}",
+            self.recommended_policy_results.precision(),
+            self.recommended_policy_results.recall(),
+            self.recommended_policy_results.f1_score()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cache::CacheMode;
+    use hash::{HashType, Precision};
+    use similarity::HashDistances;
+    use PIHash;
+
+    use super::{calibrate, calibrate_distances, LabelledPair};
+
+    fn sample(ahash: u64, dhash: u64, phash: u64, duplicate: bool) -> (HashDistances, bool) {
+        (
+            HashDistances {
+                ahash,
+                dhash,
+                phash,
+            },
+            duplicate,
+        )
+    }
+
+    #[test]
+    fn test_calibration_finds_separating_threshold() {
+        let samples = vec![
+            sample(1, 2, 40, true),
+            sample(3, 4, 2, true),
+            sample(12, 20, 3, false),
+            sample(20, 30, 30, false),
+        ];
+        let report = calibrate_distances(
+            &samples,
+            &Precision::Medium,
+            &[HashType::AHash, HashType::DHash],
+        );
+        assert_eq!(report.algorithms.len(), 2);
+        assert_eq!(report.algorithms[0].best_threshold.threshold, 3);
+        assert_eq!(report.algorithms[0].duplicate_histogram[1], 1);
+        assert_eq!(report.algorithms[0].distinct_histogram[12], 1);
+        assert_eq!(report.algorithms[1].best_threshold.threshold, 4);
+        assert_eq!(report.recommended_policy.max_distance(&HashType::AHash), 3);
+        assert_eq!(report.recommended_policy_results.f1_score(), 1_f64);
+    }
+
+    #[test]
+    fn test_calibration_skips_unreadable_images() {
+        let pair = |first: &str, second: &str, duplicate| LabelledPair {
+            first: format!("test_images/{}", first),
+            second: format!("test_images/{}", second),
+            duplicate,
+        };
+        let pairs = vec![
+            pair("sample_02_large.jpg", "sample_02_small.jpg", true),
+            pair("sample_02_large.jpg", "sample_03_small.jpg", false),
+            pair("sample_02_large.jpg", "missing.jpg", true),
+        ];
+        let lib = PIHash::new(None, CacheMode::Disabled);
+        let report = calibrate(&lib, &pairs, &Precision::Medium, &[HashType::DHash]);
+        assert_eq!(report.pairs, 2);
+        assert_eq!(report.skipped.len(), 1);
+        assert!(report.skipped[0].starts_with("test_images/missing.jpg"));
+        assert_eq!(report.recommended_policy_results.f1_score(), 1_f64);
+    }
+}
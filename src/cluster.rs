@@ -0,0 +1,240 @@
+// Copyright 2016 Drew Short <drew@sothr.com>.
+//
+// Licensed under the MIT license<LICENSE-MIT or http://opensource.org/licenses/MIT>.
+// This file may not be copied, modified, or distributed except according to those terms.
+
+use std::collections::HashMap;
+
+use hash::PerceptualHashes;
+use index::mih::PerceptualHashIndex;
+use similarity::{Similarity, SimilarityPolicy};
+
+// Constants //
+
+// Substrings per hash used for the candidate search
+const CLUSTER_INDEX_SUBSTRINGS: u32 = 4;
+
+// Structs/Enums //
+
+/**
+ * How two members of a cluster compare
+ */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct PairDistance {
+    pub first: usize,
+    pub second: usize,
+    pub similarity: Similarity,
+}
+
+/**
+ * A group of images that are connected by near duplicate pairs
+ *
+ * Members are positions in the slice of hashes that was clustered. Two
+ * members don't have to be similar to each other directly, it's enough for
+ * them to be linked through other members.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct DuplicateCluster {
+    pub members: Vec<usize>,
+    pub distances: Vec<PairDistance>,
+    pub representative: usize,
+}
+
+impl DuplicateCluster {
+    /**
+     * Whether the policy found two members similar to each other directly,
+     * rather than only through other members
+     */
+    pub fn is_similar(&self, first: usize, second: usize) -> bool {
+        self.distances.iter().any(|pair| {
+            ((pair.first == first && pair.second == second)
+                || (pair.first == second && pair.second == first))
+                && pair.similarity.similar
+        })
+    }
+}
+
+/**
+ * Disjoint sets with path compression and union by rank
+ */
+struct UnionFind {
+    parents: Vec<usize>,
+    ranks: Vec<u8>,
+}
+
+impl UnionFind {
+    fn new(size: usize) -> UnionFind {
+        UnionFind {
+            parents: (0..size).collect(),
+            ranks: vec![0; size],
+        }
+    }
+
+    fn find(&mut self, item: usize) -> usize {
+        let mut root = item;
+        while self.parents[root] != root {
+            root = self.parents[root];
+        }
+        // Point everything on the way directly at the root
+        let mut current = item;
+        while self.parents[current] != root {
+            let next = self.parents[current];
+            self.parents[current] = root;
+            current = next;
+        }
+        root
+    }
+
+    fn union(&mut self, first: usize, second: usize) {
+        let first_root = self.find(first);
+        let second_root = self.find(second);
+        if first_root == second_root {
+            return;
+        }
+        if self.ranks[first_root] < self.ranks[second_root] {
+            self.parents[first_root] = second_root;
+        } else if self.ranks[first_root] > self.ranks[second_root] {
+            self.parents[second_root] = first_root;
+        } else {
+            self.parents[second_root] = first_root;
+            self.ranks[first_root] += 1;
+        }
+    }
+}
+
+// Functions //
+
+/**
+ * Group a set of hashes into clusters of near duplicates
+ *
+ * Candidate pairs come from a multi-index hash search, so only pairs the
+ * policy could consider similar are ever compared. The pairwise distances and
+ * representative of a cluster are calculated from every pair of its members,
+ * which is quadratic in the size of the cluster but not of the collection.
+ *
+ * # Returns
+ *
+ * Every cluster with at least two members, largest first. The representative
+ * of a cluster is the member that is the most similar to the rest of it.
+ */
+pub fn find_duplicate_clusters(
+    hashes: &[PerceptualHashes],
+    policy: &SimilarityPolicy,
+) -> Vec<DuplicateCluster> {
+    let index = PerceptualHashIndex::build(
+        policy.precision.get_hash_length(),
+        CLUSTER_INDEX_SUBSTRINGS,
+        hashes.iter().cloned().zip(0..hashes.len()),
+    );
+
+    let mut sets = UnionFind::new(hashes.len());
+    for (position, image_hashes) in hashes.iter().enumerate() {
+        for candidate in index.find_similar_positions(image_hashes, policy) {
+            if candidate > position && policy.compare(image_hashes, &hashes[candidate]).similar {
+                sets.union(position, candidate);
+            }
+        }
+    }
+
+    let mut groups: HashMap<usize, Vec<usize>> = HashMap::new();
+    for position in 0..hashes.len() {
+        let root = sets.find(position);
+        groups.entry(root).or_default().push(position);
+    }
+
+    let mut clusters: Vec<DuplicateCluster> = groups
+        .into_values()
+        .filter(|members| members.len() > 1)
+        .map(|members| build_cluster(hashes, policy, members))
+        .collect();
+    clusters.sort_by(|first, second| {
+        second
+            .members
+            .len()
+            .cmp(&first.members.len())
+            .then(first.members[0].cmp(&second.members[0]))
+    });
+    clusters
+}
+
+fn build_cluster(
+    hashes: &[PerceptualHashes],
+    policy: &SimilarityPolicy,
+    members: Vec<usize>,
+) -> DuplicateCluster {
+    let mut distances = Vec::new();
+    let mut total_scores = vec![0_f64; members.len()];
+    for first in 0..members.len() {
+        for second in (first + 1)..members.len() {
+            let similarity = policy.compare(&hashes[members[first]], &hashes[members[second]]);
+            total_scores[first] += similarity.score;
+            total_scores[second] += similarity.score;
+            distances.push(PairDistance {
+                first: members[first],
+                second: members[second],
+                similarity,
+            });
+        }
+    }
+
+    // Members are in ascending order, so ties go to the earliest one
+    let mut representative = 0;
+    for (member, score) in total_scores.iter().enumerate() {
+        if *score > total_scores[representative] {
+            representative = member;
+        }
+    }
+
+    DuplicateCluster {
+        representative: members[representative],
+        members,
+        distances,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hash::PerceptualHashes;
+    use similarity::SimilarityPolicy;
+
+    use super::find_duplicate_clusters;
+
+    fn hashes(ahash: u64, dhash: u64, phash: u64) -> PerceptualHashes {
+        PerceptualHashes {
+            orig_path: format!("{}-{}-{}", ahash, dhash, phash),
+            ahash,
+            dhash,
+            phash,
+        }
+    }
+
+    #[test]
+    fn test_clusters_are_transitive() {
+        let images = vec![
+            hashes(0, 0, 0),
+            hashes(u64::MAX, 0xffff, 0xff00),
+            // Four bits from the first, and four from this one to the next
+            hashes(0b1111, 0, 0),
+            hashes(0b1111_1111, 0, 0),
+            hashes(0xffff_0000, 0xffff_0000, 0xffff_0000),
+            hashes(u64::MAX ^ 1, 0xffff, 0xff00),
+        ];
+        let policy: SimilarityPolicy = Default::default();
+        let clusters = find_duplicate_clusters(&images, &policy);
+        assert_eq!(clusters.len(), 2);
+
+        assert_eq!(clusters[0].members, vec![0, 2, 3]);
+        assert_eq!(clusters[0].distances.len(), 3);
+        assert_eq!(clusters[0].representative, 2);
+        assert!(!clusters[0].distances[1].similarity.similar);
+
+        assert_eq!(clusters[1].members, vec![1, 5]);
+        assert_eq!(clusters[1].distances[0].similarity.distances.ahash, 1);
+    }
+
+    #[test]
+    fn test_no_clusters_without_duplicates() {
+        let images = vec![hashes(0, 0, 0), hashes(u64::MAX, 0, 0)];
+        assert!(find_duplicate_clusters(&images, &Default::default()).is_empty());
+    }
+}
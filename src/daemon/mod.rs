@@ -0,0 +1,417 @@
+// Copyright 2016 Drew Short <drew@sothr.com>.
+//
+// Licensed under the MIT license<LICENSE-MIT or http://opensource.org/licenses/MIT>.
+// This file may not be copied, modified, or distributed except according to those terms.
+
+use std::fs;
+use std::io::{BufReader, BufWriter, Error, ErrorKind};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+
+use batch::{self, BatchConfig, BatchInput};
+use hash::{PerceptualHashes, Precision};
+use index::file::IndexFile;
+use similarity::SimilarityPolicy;
+use PIHash;
+
+pub use self::protocol::{QueryMatch, Request, Response, MAX_FRAME_LENGTH};
+
+mod protocol;
+
+// Structs/Enums //
+
+/**
+ * A long running process answering requests over a Unix domain socket
+ *
+ * Connections stay open for as many requests as the client sends, each
+ * handled on its own thread. They share one library, so with a memory
+ * cache (see PIHash::keep_in_memory) prepared images and matrices stay
+ * decoded between requests, and one index for queries. The requests and
+ * their encoding are described by Request.
+ */
+pub struct Daemon {
+    listener: UnixListener,
+    path: PathBuf,
+    state: Arc<State>,
+}
+
+struct State {
+    lib: PIHash,
+    index: Option<Mutex<IndexFile>>,
+    policy: SimilarityPolicy,
+}
+
+/**
+ * A connection to a daemon
+ */
+pub struct Client {
+    reader: BufReader<UnixStream>,
+    writer: BufWriter<UnixStream>,
+}
+
+impl Daemon {
+    /**
+     * Listen on a socket. A socket left behind by a daemon that's no longer
+     * running is replaced, but one that's still answering, or anything that
+     * isn't a socket, is an error.
+     * Without an index, queries are answered with an error.
+     */
+    pub fn bind(
+        path: &Path,
+        lib: PIHash,
+        index: Option<IndexFile>,
+        policy: SimilarityPolicy,
+    ) -> Result<Daemon, Error> {
+        match fs::symlink_metadata(path) {
+            Ok(metadata) => {
+                // Only ever replace a socket, never a mistyped file
+                if !metadata.file_type().is_socket() {
+                    return Err(Error::new(
+                        ErrorKind::AlreadyExists,
+                        format!("{} exists and isn't a socket", path.display()),
+                    ));
+                }
+                if UnixStream::connect(path).is_ok() {
+                    return Err(Error::new(
+                        ErrorKind::AddrInUse,
+                        format!("A daemon is already listening on {}", path.display()),
+                    ));
+                }
+                fs::remove_file(path)?;
+            }
+            Err(ref e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        Ok(Daemon {
+            listener: UnixListener::bind(path)?,
+            path: path.to_path_buf(),
+            state: Arc::new(State {
+                lib,
+                index: index.map(Mutex::new),
+                policy,
+            }),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /**
+     * Accept connections forever, handling each on a new thread
+     */
+    pub fn run(&self) {
+        for stream in self.listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let state = self.state.clone();
+                    thread::spawn(move || {
+                        if let Err(e) = handle_connection(&state, stream) {
+                            eprintln!("Closed a daemon connection: {}", e);
+                        }
+                    });
+                }
+                Err(e) => eprintln!("Unable to accept a connection: {}", e),
+            }
+        }
+    }
+
+    /**
+     * Answer a request without going through a connection
+     */
+    pub fn handle(&self, request: Request) -> Response {
+        self.state.handle(request)
+    }
+}
+
+impl Drop for Daemon {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+impl State {
+    fn handle(&self, request: Request) -> Response {
+        match request {
+            Request::Hash { precision, input } => {
+                match batch::hash_input(input, &precision, &self.lib.cache) {
+                    Ok(hashes) => Response::Hashes(hashes),
+                    Err(e) => Response::Error(format!("{}", e)),
+                }
+            }
+            Request::Query { limit, input } => match self.query(input, limit) {
+                Ok(matches) => Response::Matches(matches),
+                Err(e) => Response::Error(format!("{}", e)),
+            },
+            Request::HashBatch { precision, inputs } => Response::Batch(
+                self.hash_batch(inputs, precision)
+                    .into_iter()
+                    .map(|hashes| match hashes {
+                        Ok(hashes) => Response::Hashes(hashes),
+                        Err(e) => Response::Error(format!("{}", e)),
+                    })
+                    .collect(),
+            ),
+            Request::QueryBatch { limit, inputs } => {
+                let precision = match self.lock_index() {
+                    Ok(index) => index.precision(),
+                    Err(e) => return Response::Error(format!("{}", e)),
+                };
+                let hashed = self.hash_batch(inputs, precision);
+                Response::Batch(
+                    hashed
+                        .into_iter()
+                        .map(
+                            |hashes| match hashes.and_then(|hashes| self.find(&hashes, limit)) {
+                                Ok(matches) => Response::Matches(matches),
+                                Err(e) => Response::Error(format!("{}", e)),
+                            },
+                        )
+                        .collect(),
+                )
+            }
+        }
+    }
+
+    fn query(&self, input: BatchInput, limit: u32) -> Result<Vec<QueryMatch>, Error> {
+        let precision = self.lock_index()?.precision();
+        // Hash without holding the lock so other connections aren't held up
+        let hashes = batch::hash_input(input, &precision, &self.lib.cache)?;
+        self.find(&hashes, limit)
+    }
+
+    fn find(&self, hashes: &PerceptualHashes, limit: u32) -> Result<Vec<QueryMatch>, Error> {
+        let mut matches = self.lock_index()?.query(hashes, &self.policy);
+        if limit > 0 {
+            matches.truncate(limit as usize);
+        }
+        Ok(matches
+            .into_iter()
+            .map(|found| QueryMatch {
+                id: found.record.id,
+                hashes: found.record.hashes,
+                score: found.similarity.score,
+            })
+            .collect())
+    }
+
+    /**
+     * Hash the inputs across a thread per core, in input order
+     */
+    fn hash_batch(
+        &self,
+        inputs: Vec<BatchInput>,
+        precision: Precision,
+    ) -> Vec<Result<PerceptualHashes, Error>> {
+        let mut results: Vec<_> = self
+            .lib
+            .hash_batch(inputs, &BatchConfig::new(precision))
+            .collect();
+        results.sort_by_key(|result| result.index);
+        results.into_iter().map(|result| result.hashes).collect()
+    }
+
+    fn lock_index(&self) -> Result<MutexGuard<'_, IndexFile>, Error> {
+        match self.index {
+            // Queries only read the index, so a panic can't leave it broken
+            Some(ref index) => Ok(index.lock().unwrap_or_else(|e| e.into_inner())),
+            None => Err(Error::new(
+                ErrorKind::NotFound,
+                "The daemon isn't serving an index",
+            )),
+        }
+    }
+}
+
+impl Client {
+    pub fn connect(path: &Path) -> Result<Client, Error> {
+        let stream = UnixStream::connect(path)?;
+        Ok(Client {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: BufWriter::new(stream),
+        })
+    }
+
+    /**
+     * Send a request and wait for its response
+     */
+    pub fn send(&mut self, request: &Request) -> Result<Response, Error> {
+        protocol::write_frame(&mut self.writer, &request.encode())?;
+        match protocol::read_frame(&mut self.reader)? {
+            Some(payload) => Response::decode(&payload),
+            None => Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                "The daemon closed the connection",
+            )),
+        }
+    }
+
+    pub fn get_perceptual_hashes(
+        &mut self,
+        input: BatchInput,
+        precision: Precision,
+    ) -> Result<PerceptualHashes, Error> {
+        match self.send(&Request::Hash { precision, input })? {
+            Response::Hashes(hashes) => Ok(hashes),
+            response => Err(get_response_error(response)),
+        }
+    }
+
+    /**
+     * Find the indexed images similar to an input, returning every match
+     * when the limit is 0
+     */
+    pub fn query(&mut self, input: BatchInput, limit: u32) -> Result<Vec<QueryMatch>, Error> {
+        match self.send(&Request::Query { limit, input })? {
+            Response::Matches(matches) => Ok(matches),
+            response => Err(get_response_error(response)),
+        }
+    }
+
+    /**
+     * Hash many inputs in one request, with a result for each in order
+     */
+    pub fn get_perceptual_hashes_batch(
+        &mut self,
+        inputs: Vec<BatchInput>,
+        precision: Precision,
+    ) -> Result<Vec<Result<PerceptualHashes, Error>>, Error> {
+        match self.send(&Request::HashBatch { precision, inputs })? {
+            Response::Batch(responses) => Ok(responses
+                .into_iter()
+                .map(|response| match response {
+                    Response::Hashes(hashes) => Ok(hashes),
+                    response => Err(get_response_error(response)),
+                })
+                .collect()),
+            response => Err(get_response_error(response)),
+        }
+    }
+}
+
+// Functions //
+
+fn handle_connection(state: &State, stream: UnixStream) -> Result<(), Error> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+    loop {
+        let payload = match protocol::read_frame(&mut reader)? {
+            Some(payload) => payload,
+            None => return Ok(()),
+        };
+        let response = match Request::decode(&payload) {
+            Ok(request) => state.handle(request),
+            Err(e) => Response::Error(format!("Malformed request: {}", e)),
+        };
+        protocol::write_frame(&mut writer, &response.encode())?;
+    }
+}
+
+fn get_response_error(response: Response) -> Error {
+    match response {
+        Response::Error(message) => Error::other(message),
+        _ => Error::new(
+            ErrorKind::InvalidData,
+            "Unexpected response from the daemon",
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env::temp_dir;
+    use std::fs::{self, remove_file};
+    use std::path::{Path, PathBuf};
+    use std::thread;
+
+    use batch::BatchInput;
+    use cache::CacheMode;
+    use hash::Precision;
+    use index::file::IndexFile;
+    use similarity::SimilarityPolicy;
+    use PIHash;
+
+    use super::{Client, Daemon};
+
+    #[test]
+    fn test_daemon_over_socket() {
+        let socket_path = temp_dir().join("pihash_test_daemon.sock");
+        let index_path = temp_dir().join("pihash_test_daemon.index");
+        let indexed = Path::new("test_images/sample_04_large.jpg");
+        let lib = PIHash::new(None, CacheMode::Disabled);
+        let hashes = lib.get_perceptual_hashes(indexed, &Precision::Medium);
+        let index =
+            IndexFile::create(&index_path, &Precision::Medium, vec![(hashes, Vec::new())]).unwrap();
+        let daemon =
+            Daemon::bind(&socket_path, lib, Some(index), SimilarityPolicy::default()).unwrap();
+        // Only one daemon can answer on a socket
+        assert!(Daemon::bind(
+            &socket_path,
+            PIHash::new(None, CacheMode::Disabled),
+            None,
+            SimilarityPolicy::default()
+        )
+        .is_err());
+        thread::spawn(move || daemon.run());
+
+        let mut client = Client::connect(&socket_path).unwrap();
+        let data = fs::read("test_images/sample_04_medium.jpg").unwrap();
+        let from_bytes = client
+            .get_perceptual_hashes(
+                BatchInput::Bytes {
+                    name: String::from("medium"),
+                    data: data.clone(),
+                },
+                Precision::Medium,
+            )
+            .unwrap();
+        assert_eq!(from_bytes.orig_path, "medium");
+
+        let matches = client
+            .query(
+                BatchInput::Bytes {
+                    name: String::new(),
+                    data,
+                },
+                1,
+            )
+            .unwrap();
+        assert_eq!(matches.len(), 1);
+        assert_eq!(
+            matches[0].hashes.orig_path,
+            "test_images/sample_04_large.jpg"
+        );
+
+        let results = client
+            .get_perceptual_hashes_batch(
+                vec![
+                    BatchInput::Path(PathBuf::from("test_images/sample_04_medium.jpg")),
+                    BatchInput::Path(PathBuf::from("test_images/missing.jpg")),
+                ],
+                Precision::Medium,
+            )
+            .unwrap();
+        assert_eq!(results.len(), 2);
+        assert_eq!(*results[0].as_ref().unwrap(), from_bytes);
+        assert!(results[1].is_err());
+        remove_file(&index_path).unwrap();
+    }
+
+    #[test]
+    fn test_bind_only_replaces_sockets() {
+        let path = temp_dir().join("pihash_test_daemon_notes.txt");
+        fs::write(&path, "not a socket").unwrap();
+        let result = Daemon::bind(
+            &path,
+            PIHash::new(None, CacheMode::Disabled),
+            None,
+            SimilarityPolicy::default(),
+        );
+        assert!(result.is_err());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "not a socket");
+        remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,477 @@
+// Copyright 2016 Drew Short <drew@sothr.com>.
+//
+// Licensed under the MIT license<LICENSE-MIT or http://opensource.org/licenses/MIT>.
+// This file may not be copied, modified, or distributed except according to those terms.
+
+use std::io::{Error, ErrorKind, Read, Write};
+use std::path::PathBuf;
+
+use batch::BatchInput;
+use hash::{PerceptualHashes, Precision};
+
+// Constants //
+
+pub const MAX_FRAME_LENGTH: u32 = 64 * 1024 * 1024;
+
+const REQUEST_HASH: u8 = 0x01;
+const REQUEST_QUERY: u8 = 0x02;
+const REQUEST_HASH_BATCH: u8 = 0x03;
+const REQUEST_QUERY_BATCH: u8 = 0x04;
+const RESPONSE_HASHES: u8 = 0x81;
+const RESPONSE_MATCHES: u8 = 0x82;
+const RESPONSE_BATCH: u8 = 0x83;
+const RESPONSE_ERROR: u8 = 0xff;
+const INPUT_PATH: u8 = 0;
+const INPUT_BYTES: u8 = 1;
+
+// Structs/Enums //
+
+/**
+ * A request to a daemon
+ *
+ * Every message is a frame: the length of the payload (u32) followed by the
+ * payload. A payload starts with its kind (u8). All numbers are little
+ * endian, and strings and byte strings are their length (u32) followed by
+ * their bytes.
+ *
+ * Requests:
+ *
+ * * 0x01 hash: precision (u8, 0 low, 1 medium, 2 high) and an input
+ * * 0x02 query: limit (u32, 0 for every match) and an input
+ * * 0x03 hash batch: precision (u8), a count (u32) and that many inputs
+ * * 0x04 query batch: limit (u32), a count (u32) and that many inputs
+ *
+ * An input is 0 (u8) and a path, or 1 (u8), a name and the encoded image.
+ * Queries use the precision of the index being served.
+ *
+ * Responses:
+ *
+ * * 0x81 hashes: the ahash, dhash and phash (u64) and the path or name
+ * * 0x82 matches: a count (u32) and that many matches, most similar first.
+ *   Each is the record id (u64), the score (f64), the hashes and the path.
+ * * 0x83 batch: a count (u32) and a frame holding a response for each input
+ * * 0xff error: a message
+ */
+#[derive(Debug)]
+pub enum Request {
+    Hash {
+        precision: Precision,
+        input: BatchInput,
+    },
+    /// Find the indexed images similar to the input. A limit of 0 returns
+    /// every match.
+    Query {
+        limit: u32,
+        input: BatchInput,
+    },
+    /// Hash every input across the threads of the daemon, answered with a
+    /// response for each in the same order
+    HashBatch {
+        precision: Precision,
+        inputs: Vec<BatchInput>,
+    },
+    QueryBatch {
+        limit: u32,
+        inputs: Vec<BatchInput>,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Response {
+    Hashes(PerceptualHashes),
+    Matches(Vec<QueryMatch>),
+    Batch(Vec<Response>),
+    Error(String),
+}
+
+/**
+ * An indexed image similar to a query, with its path in hashes.orig_path
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct QueryMatch {
+    pub id: u64,
+    pub hashes: PerceptualHashes,
+    pub score: f64,
+}
+
+/**
+ * Reads the fields of a payload, failing at the first one that's cut short
+ */
+struct Decoder<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl Request {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut payload = Vec::new();
+        match *self {
+            Request::Hash {
+                ref precision,
+                ref input,
+            } => {
+                payload.push(REQUEST_HASH);
+                payload.push(encode_precision(precision));
+                encode_input(&mut payload, input);
+            }
+            Request::Query { limit, ref input } => {
+                payload.push(REQUEST_QUERY);
+                payload.extend_from_slice(&limit.to_le_bytes());
+                encode_input(&mut payload, input);
+            }
+            Request::HashBatch {
+                ref precision,
+                ref inputs,
+            } => {
+                payload.push(REQUEST_HASH_BATCH);
+                payload.push(encode_precision(precision));
+                encode_inputs(&mut payload, inputs);
+            }
+            Request::QueryBatch { limit, ref inputs } => {
+                payload.push(REQUEST_QUERY_BATCH);
+                payload.extend_from_slice(&limit.to_le_bytes());
+                encode_inputs(&mut payload, inputs);
+            }
+        }
+        payload
+    }
+
+    pub fn decode(payload: &[u8]) -> Result<Request, Error> {
+        let mut decoder = Decoder::new(payload);
+        let request = match decoder.u8()? {
+            REQUEST_HASH => Request::Hash {
+                precision: decoder.precision()?,
+                input: decoder.input()?,
+            },
+            REQUEST_QUERY => Request::Query {
+                limit: decoder.u32()?,
+                input: decoder.input()?,
+            },
+            REQUEST_HASH_BATCH => Request::HashBatch {
+                precision: decoder.precision()?,
+                inputs: decoder.inputs()?,
+            },
+            REQUEST_QUERY_BATCH => Request::QueryBatch {
+                limit: decoder.u32()?,
+                inputs: decoder.inputs()?,
+            },
+            kind => return Err(invalid(&format!("Unknown request kind {:#04x}", kind))),
+        };
+        decoder.finish()?;
+        Ok(request)
+    }
+}
+
+impl Response {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut payload = Vec::new();
+        match *self {
+            Response::Hashes(ref hashes) => {
+                payload.push(RESPONSE_HASHES);
+                encode_hashes(&mut payload, hashes);
+            }
+            Response::Matches(ref matches) => {
+                payload.push(RESPONSE_MATCHES);
+                payload.extend_from_slice(&(matches.len() as u32).to_le_bytes());
+                for found in matches {
+                    payload.extend_from_slice(&found.id.to_le_bytes());
+                    payload.extend_from_slice(&found.score.to_bits().to_le_bytes());
+                    encode_hashes(&mut payload, &found.hashes);
+                }
+            }
+            Response::Batch(ref responses) => {
+                payload.push(RESPONSE_BATCH);
+                payload.extend_from_slice(&(responses.len() as u32).to_le_bytes());
+                for response in responses {
+                    encode_bytes(&mut payload, &response.encode());
+                }
+            }
+            Response::Error(ref message) => {
+                payload.push(RESPONSE_ERROR);
+                encode_bytes(&mut payload, message.as_bytes());
+            }
+        }
+        payload
+    }
+
+    pub fn decode(payload: &[u8]) -> Result<Response, Error> {
+        let mut decoder = Decoder::new(payload);
+        let response = match decoder.u8()? {
+            RESPONSE_HASHES => Response::Hashes(decoder.hashes()?),
+            RESPONSE_MATCHES => {
+                let count = decoder.u32()?;
+                let mut matches = Vec::new();
+                for _ in 0..count {
+                    let id = decoder.u64()?;
+                    let score = f64::from_bits(decoder.u64()?);
+                    matches.push(QueryMatch {
+                        id,
+                        score,
+                        hashes: decoder.hashes()?,
+                    });
+                }
+                Response::Matches(matches)
+            }
+            RESPONSE_BATCH => {
+                let count = decoder.u32()?;
+                let mut responses = Vec::new();
+                for _ in 0..count {
+                    responses.push(Response::decode(decoder.bytes()?)?);
+                }
+                Response::Batch(responses)
+            }
+            RESPONSE_ERROR => Response::Error(decoder.string()?),
+            kind => return Err(invalid(&format!("Unknown response kind {:#04x}", kind))),
+        };
+        decoder.finish()?;
+        Ok(response)
+    }
+}
+
+impl<'a> Decoder<'a> {
+    fn new(bytes: &'a [u8]) -> Decoder<'a> {
+        Decoder { bytes, position: 0 }
+    }
+
+    fn take(&mut self, length: usize) -> Result<&'a [u8], Error> {
+        if self.bytes.len() - self.position < length {
+            return Err(invalid("The message is cut short"));
+        }
+        let taken = &self.bytes[self.position..self.position + length];
+        self.position += length;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> Result<u8, Error> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, Error> {
+        let mut bytes = [0u8; 4];
+        bytes.copy_from_slice(self.take(4)?);
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    fn u64(&mut self) -> Result<u64, Error> {
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    fn bytes(&mut self) -> Result<&'a [u8], Error> {
+        let length = self.u32()? as usize;
+        self.take(length)
+    }
+
+    fn string(&mut self) -> Result<String, Error> {
+        String::from_utf8(self.bytes()?.to_vec()).map_err(|_| invalid("A string isn't UTF-8"))
+    }
+
+    fn precision(&mut self) -> Result<Precision, Error> {
+        match self.u8()? {
+            0 => Ok(Precision::Low),
+            1 => Ok(Precision::Medium),
+            2 => Ok(Precision::High),
+            precision => Err(invalid(&format!("Unknown precision {}", precision))),
+        }
+    }
+
+    fn input(&mut self) -> Result<BatchInput, Error> {
+        match self.u8()? {
+            INPUT_PATH => Ok(BatchInput::Path(PathBuf::from(self.string()?))),
+            INPUT_BYTES => Ok(BatchInput::Bytes {
+                name: self.string()?,
+                data: self.bytes()?.to_vec(),
+            }),
+            kind => Err(invalid(&format!("Unknown input kind {}", kind))),
+        }
+    }
+
+    fn inputs(&mut self) -> Result<Vec<BatchInput>, Error> {
+        let count = self.u32()?;
+        let mut inputs = Vec::new();
+        for _ in 0..count {
+            inputs.push(self.input()?);
+        }
+        Ok(inputs)
+    }
+
+    fn hashes(&mut self) -> Result<PerceptualHashes, Error> {
+        Ok(PerceptualHashes {
+            ahash: self.u64()?,
+            dhash: self.u64()?,
+            phash: self.u64()?,
+            orig_path: self.string()?,
+        })
+    }
+
+    fn finish(&self) -> Result<(), Error> {
+        if self.position == self.bytes.len() {
+            Ok(())
+        } else {
+            Err(invalid("Unexpected bytes after the message"))
+        }
+    }
+}
+
+// Functions //
+
+/**
+ * Write a payload with its length in front
+ */
+pub fn write_frame<W: Write>(writer: &mut W, payload: &[u8]) -> Result<(), Error> {
+    if payload.len() > MAX_FRAME_LENGTH as usize {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("Messages are limited to {} bytes", MAX_FRAME_LENGTH),
+        ));
+    }
+    writer.write_all(&(payload.len() as u32).to_le_bytes())?;
+    writer.write_all(payload)?;
+    writer.flush()
+}
+
+/**
+ * Read the next payload
+ *
+ * # Returns
+ *
+ * None if the stream ended between frames
+ */
+pub fn read_frame<R: Read>(reader: &mut R) -> Result<Option<Vec<u8>>, Error> {
+    let mut length = [0u8; 4];
+    let mut read = 0;
+    while read < length.len() {
+        match reader.read(&mut length[read..]) {
+            Ok(0) if read == 0 => return Ok(None),
+            Ok(0) => {
+                return Err(Error::new(
+                    ErrorKind::UnexpectedEof,
+                    "The frame is cut short",
+                ))
+            }
+            Ok(count) => read += count,
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    let length = u32::from_le_bytes(length);
+    if length > MAX_FRAME_LENGTH {
+        return Err(invalid(&format!(
+            "Messages are limited to {} bytes",
+            MAX_FRAME_LENGTH
+        )));
+    }
+    let mut payload = vec![0u8; length as usize];
+    reader.read_exact(&mut payload)?;
+    Ok(Some(payload))
+}
+
+fn encode_precision(precision: &Precision) -> u8 {
+    match *precision {
+        Precision::Low => 0,
+        Precision::Medium => 1,
+        Precision::High => 2,
+    }
+}
+
+fn encode_bytes(payload: &mut Vec<u8>, bytes: &[u8]) {
+    payload.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+    payload.extend_from_slice(bytes);
+}
+
+fn encode_input(payload: &mut Vec<u8>, input: &BatchInput) {
+    match *input {
+        BatchInput::Path(ref path) => {
+            payload.push(INPUT_PATH);
+            encode_bytes(payload, path.to_string_lossy().as_bytes());
+        }
+        BatchInput::Bytes { ref name, ref data } => {
+            payload.push(INPUT_BYTES);
+            encode_bytes(payload, name.as_bytes());
+            encode_bytes(payload, data);
+        }
+    }
+}
+
+fn encode_inputs(payload: &mut Vec<u8>, inputs: &[BatchInput]) {
+    payload.extend_from_slice(&(inputs.len() as u32).to_le_bytes());
+    for input in inputs {
+        encode_input(payload, input);
+    }
+}
+
+fn encode_hashes(payload: &mut Vec<u8>, hashes: &PerceptualHashes) {
+    payload.extend_from_slice(&hashes.ahash.to_le_bytes());
+    payload.extend_from_slice(&hashes.dhash.to_le_bytes());
+    payload.extend_from_slice(&hashes.phash.to_le_bytes());
+    encode_bytes(payload, hashes.orig_path.as_bytes());
+}
+
+fn invalid(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::path::PathBuf;
+
+    use batch::BatchInput;
+    use hash::{PerceptualHashes, Precision};
+
+    use super::{read_frame, write_frame, QueryMatch, Request, Response};
+
+    #[test]
+    fn test_encode_and_decode() {
+        let request = Request::QueryBatch {
+            limit: 5,
+            inputs: vec![
+                BatchInput::Path(PathBuf::from("a.jpg")),
+                BatchInput::Bytes {
+                    name: String::from("b.png"),
+                    data: vec![1, 2, 3],
+                },
+            ],
+        };
+        let encoded = request.encode();
+        assert_eq!(Request::decode(&encoded).unwrap().encode(), encoded);
+        let hash = Request::Hash {
+            precision: Precision::High,
+            input: BatchInput::Path(PathBuf::from("c.jpg")),
+        };
+        assert_eq!(
+            Request::decode(&hash.encode()).unwrap().encode(),
+            hash.encode()
+        );
+
+        let hashes = PerceptualHashes {
+            orig_path: String::from("a.jpg"),
+            ahash: 1,
+            dhash: u64::MAX,
+            phash: 3,
+        };
+        let response = Response::Batch(vec![
+            Response::Hashes(hashes.clone()),
+            Response::Matches(vec![QueryMatch {
+                id: 64,
+                hashes,
+                score: 0.75,
+            }]),
+            Response::Error(String::from("Unable to read b.png")),
+        ]);
+        let mut stream = Vec::new();
+        write_frame(&mut stream, &response.encode()).unwrap();
+        let mut reader = Cursor::new(stream);
+        let payload = read_frame(&mut reader).unwrap().unwrap();
+        assert_eq!(Response::decode(&payload).unwrap(), response);
+        assert!(read_frame(&mut reader).unwrap().is_none());
+
+        // Anything cut short or left over is rejected
+        assert!(Request::decode(&encoded[..encoded.len() - 1]).is_err());
+        let mut extra = encoded.clone();
+        extra.push(0);
+        assert!(Request::decode(&extra).is_err());
+        assert!(Request::decode(&[0x7f]).is_err());
+    }
+}
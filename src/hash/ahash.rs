@@ -2,22 +2,42 @@
 //
 // Licensed under the MIT license<LICENSE-MIT or http://opensource.org/licenses/MIT>.
 // This file may not be copied, modified, or distributed except according to those terms.
+use std::io::Error;
 use std::path::Path;
 
 use cache::Cache;
 
 use super::{HashType, PerceptualHash, Precision, PreparedImage};
-use super::image::GenericImageView;
-use super::prepare_image;
+use super::image::{DynamicImage, GenericImageView};
+use super::{prepare_image_from_memory, try_prepare_image};
 
 pub struct AHash {
     prepared_image: Box<PreparedImage>,
 }
 
 impl AHash {
-    pub fn new(path: &Path, precision: &Precision, cache: &Option<Cache>) -> Self {
+    /**
+     * Prepare an image to be hashed, failing if it can't be read
+     */
+    pub fn try_new(
+        path: &Path,
+        precision: &Precision,
+        cache: &Option<Cache>,
+        digest: &Option<String>,
+    ) -> Result<Self, Error> {
+        let prepared_image = try_prepare_image(path, &HashType::AHash, precision, cache, digest)?;
+        Ok(AHash {
+            prepared_image: Box::new(prepared_image),
+        })
+    }
+
+    pub fn from_image(image: &DynamicImage, precision: &Precision) -> Self {
         AHash {
-            prepared_image: Box::new(prepare_image(&path, &HashType::AHash, &precision, cache)),
+            prepared_image: Box::new(prepare_image_from_memory(
+                image,
+                &HashType::AHash,
+                precision,
+            )),
         }
     }
 }
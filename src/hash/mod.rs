@@ -9,11 +9,14 @@ extern crate image;
 use std::f64;
 use std::fmt;
 use std::fmt::{Error, Formatter};
+use std::io;
 use std::path::Path;
+use std::str::FromStr;
 
 use serde::export::fmt::Debug;
 
-use cache::Cache;
+use cache::{Cache, HashKey};
+use similarity::{Similarity, SimilarityPolicy};
 
 use self::image::FilterType;
 
@@ -39,8 +42,10 @@ const FLOAT_PRECISION_MIN_4: f64 = f64::MIN / 10000_f64;
 // Can round to 5 significant factors of precision
 const FLOAT_PRECISION_MAX_5: f64 = f64::MAX / 100000_f64;
 const FLOAT_PRECISION_MIN_5: f64 = f64::MIN / 100000_f64;
-// Hamming Distance Similarity Limit //
-const HAMMING_DISTANCE_SIMILARITY_LIMIT: u64 = 5u64;
+
+/// Describes how images are prepared before they are hashed. Cached hashes
+/// are keyed by it, so change it whenever the preparation changes.
+pub const PREPROCESSING: &str = "lanczos3-grayscale";
 
 // Structs/Enums //
 
@@ -48,14 +53,13 @@ const HAMMING_DISTANCE_SIMILARITY_LIMIT: u64 = 5u64;
  * Prepared image that can be used to generate hashes
  */
 pub struct PreparedImage {
-    orig_path: String,
     image: Option<image::DynamicImage>,
 }
 
 /**
  * Wraps the various perceptual hashes
  */
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct PerceptualHashes {
     pub orig_path: String,
     pub ahash: u64,
@@ -78,20 +82,36 @@ impl PartialEq for PerceptualHashes {
 }
 
 impl PerceptualHashes {
-    pub fn similar(&self, other: &PerceptualHashes) -> bool {
-        if self.orig_path != other.orig_path
-            && calculate_hamming_distance(self.ahash, other.ahash)
-            <= HAMMING_DISTANCE_SIMILARITY_LIMIT
-            && calculate_hamming_distance(self.dhash, other.dhash)
-            <= HAMMING_DISTANCE_SIMILARITY_LIMIT
-            && calculate_hamming_distance(self.phash, other.phash)
-            <= HAMMING_DISTANCE_SIMILARITY_LIMIT
-        {
-            true
-        } else {
-            false
+    /**
+     * Get the hash of a specific type
+     */
+    pub fn get(&self, hash_type: &HashType) -> u64 {
+        match *hash_type {
+            HashType::AHash => self.ahash,
+            HashType::DHash => self.dhash,
+            HashType::PHash => self.phash,
         }
     }
+
+    /**
+     * Check if two sets of hashes are similar using the default similarity policy
+     */
+    pub fn similar(&self, other: &PerceptualHashes) -> bool {
+        self.orig_path != other.orig_path
+            && self.similarity(other, &SimilarityPolicy::default()).similar
+    }
+
+    /**
+     * Compare two sets of hashes according to the provided policy
+     *
+     * # Returns
+     *
+     * The per-algorithm hamming distances, a normalised score between 0.0 and 1.0
+     * and whether the policy considers the images similar
+     */
+    pub fn similarity(&self, other: &PerceptualHashes, policy: &SimilarityPolicy) -> Similarity {
+        policy.compare(self, other)
+    }
 }
 
 /**
@@ -101,7 +121,7 @@ impl PerceptualHashes {
  * Medium aims for 64 bit precision
  * High aims for 128 bit precision
  */
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub enum Precision {
     Low,
     Medium,
@@ -117,18 +137,82 @@ impl Precision {
             Precision::High => 16,
         }
     }
+
+    /**
+     * Get the number of bits a hash of this precision carries.
+     * Hashes are stored in a u64, so anything larger is truncated to 64 bits.
+     */
+    pub fn get_hash_length(&self) -> u32 {
+        let size = self.get_size();
+        if size * size > 64 {
+            64
+        } else {
+            size * size
+        }
+    }
 }
 
 /**
  * Types of hashes supported
  */
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub enum HashType {
     AHash,
     DHash,
     PHash,
 }
 
+impl HashType {
+    /**
+     * The version of the algorithm. Bump it whenever a change to the
+     * algorithm changes the hashes it makes, so that cached ones aren't
+     * used.
+     */
+    pub fn get_version(&self) -> u32 {
+        match *self {
+            HashType::AHash => 1,
+            HashType::DHash => 1,
+            HashType::PHash => 1,
+        }
+    }
+}
+
+impl FromStr for Precision {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Precision, String> {
+        match s.to_lowercase().as_str() {
+            "low" => Ok(Precision::Low),
+            "medium" => Ok(Precision::Medium),
+            "high" => Ok(Precision::High),
+            _ => Err(format!("Unknown precision '{}'", s)),
+        }
+    }
+}
+
+impl fmt::Display for Precision {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Precision::Low => write!(f, "Low"),
+            Precision::Medium => write!(f, "Medium"),
+            Precision::High => write!(f, "High"),
+        }
+    }
+}
+
+impl FromStr for HashType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<HashType, String> {
+        match s.to_lowercase().as_str() {
+            "ahash" => Ok(HashType::AHash),
+            "dhash" => Ok(HashType::DHash),
+            "phash" => Ok(HashType::PHash),
+            _ => Err(format!("Unknown hash type '{}'", s)),
+        }
+    }
+}
+
 impl fmt::Display for HashType {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
@@ -167,61 +251,96 @@ pub fn prepare_image(
     precision: &Precision,
     cache: &Option<Cache>,
 ) -> PreparedImage {
-    let image_path = path.to_str().unwrap();
-    let size: u32 = match *hash_type {
-        HashType::PHash => precision.get_size() * 4,
-        _ => precision.get_size(),
-    };
-    // Check if we have the already converted image in a cache and use that if possible.
-    match *cache {
-        Some(ref cache) => {
-            match cache.get_image_from_cache(&path, size) {
-                Some(image) => PreparedImage {
-                    orig_path: String::from(&*image_path),
-                    image: Some(image),
-                },
-                None => {
-                    let processed_image = process_image(&image_path, size);
-                    // Oh, and save it in a cache
-                    match processed_image.image {
-                        Some(ref image) => {
-                            match cache.put_image_in_cache(&path, size, &image) {
-                                Ok(_) => {}
-                                Err(e) => println!("Unable to store image in cache. {}", e),
-                            };
-                        }
-                        None => {}
-                    };
-                    processed_image
-                }
-            }
+    let digest = get_digest(path, cache);
+    match try_prepare_image(path, hash_type, precision, cache, &digest) {
+        Ok(prepared_image) => prepared_image,
+        Err(e) => {
+            eprintln!("Error Processing Image [{}]: {} ", path.display(), e);
+            PreparedImage { image: None }
         }
-        None => process_image(&image_path, size),
     }
 }
 
 /**
- * Turn the image into something we can work with
+ * Prepare an image to be hashed, failing if it can't be read or decoded.
+ * The prepared image is cached under the digest of the file, when there is
+ * one, so that the file isn't digested again.
  */
-fn process_image(image_path: &str, size: u32) -> PreparedImage {
-    // Otherwise let's do that work now and store it.
-    // println!("Path: {}", image_path);
-    let image = match image::open(Path::new(image_path)) {
-        Ok(image) => {
-            let small_image = image.resize_exact(size, size, FilterType::Lanczos3);
-            Some(small_image.grayscale())
+pub fn try_prepare_image(
+    path: &Path,
+    hash_type: &HashType,
+    precision: &Precision,
+    cache: &Option<Cache>,
+    digest: &Option<String>,
+) -> Result<PreparedImage, io::Error> {
+    get_path_str(path)?;
+    let size = get_prepared_size(hash_type, precision);
+    // Check if we have the already converted image in a cache and use that if possible.
+    if let (Some(cache), Some(digest)) = (cache.as_ref(), digest.as_ref()) {
+        if let Some(image) = cache.get_image_from_cache_by_digest(digest, size) {
+            return Ok(PreparedImage { image: Some(image) });
         }
-        Err(e) => {
-            println!("Error Processing Image [{}]: {} ", image_path, e);
-            None
+    }
+    let image = process_image(path, size)?;
+    // Oh, and save it in a cache
+    if let (Some(cache), Some(digest)) = (cache.as_ref(), digest.as_ref()) {
+        if let Err(e) = cache.put_image_in_cache_by_digest(digest, size, &image) {
+            eprintln!("Unable to store image in cache. {}", e);
         }
-    };
+    }
+    Ok(PreparedImage { image: Some(image) })
+}
+
+/**
+ * Prepare an image that is already in memory to be hashed. Prepared images
+ * that don't come from a file are never cached.
+ */
+pub fn prepare_image_from_memory(
+    image: &image::DynamicImage,
+    hash_type: &HashType,
+    precision: &Precision,
+) -> PreparedImage {
+    let size = get_prepared_size(hash_type, precision);
     PreparedImage {
-        orig_path: String::from(&*image_path),
-        image,
+        image: Some(shrink_image(image, size)),
+    }
+}
+
+/**
+ * The dimensions of the square image a hash type is calculated from
+ */
+fn get_prepared_size(hash_type: &HashType, precision: &Precision) -> u32 {
+    match *hash_type {
+        HashType::PHash => precision.get_size() * 4,
+        _ => precision.get_size(),
     }
 }
 
+fn shrink_image(image: &image::DynamicImage, size: u32) -> image::DynamicImage {
+    let small_image = image.resize_exact(size, size, FilterType::Lanczos3);
+    small_image.grayscale()
+}
+
+/**
+ * Turn the image into something we can work with
+ */
+fn process_image(path: &Path, size: u32) -> Result<image::DynamicImage, io::Error> {
+    match image::open(path) {
+        Ok(image) => Ok(shrink_image(&image, size)),
+        Err(image::ImageError::IoError(e)) => Err(e),
+        Err(e) => Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+    }
+}
+
+fn get_path_str(path: &Path) -> Result<&str, io::Error> {
+    path.to_str().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Path is not valid UTF-8: {}", path.display()),
+        )
+    })
+}
+
 /**
  * Get a specific HashType hash
  */
@@ -231,10 +350,13 @@ pub fn get_perceptual_hash(
     hash_type: &HashType,
     cache: &Option<Cache>,
 ) -> u64 {
-    match *hash_type {
-        HashType::AHash => ahash::AHash::new(&path, &precision, &cache).get_hash(&cache),
-        HashType::DHash => dhash::DHash::new(&path, &precision, &cache).get_hash(&cache),
-        HashType::PHash => phash::PHash::new(&path, &precision, &cache).get_hash(&cache),
+    let digest = get_digest(path, cache);
+    match try_get_hash(path, precision, hash_type, cache, &digest) {
+        Ok(hash) => hash,
+        Err(e) => {
+            eprintln!("Error Processing Image [{}]: {} ", path.display(), e);
+            0u64
+        }
     }
 }
 
@@ -246,15 +368,113 @@ pub fn get_perceptual_hashes(
     precision: &Precision,
     cache: &Option<Cache>,
 ) -> PerceptualHashes {
-    let image_path = path.to_str().unwrap();
-    let ahash = ahash::AHash::new(&path, &precision, &cache).get_hash(&cache);
-    let dhash = dhash::DHash::new(&path, &precision, &cache).get_hash(&cache);
-    let phash = phash::PHash::new(&path, &precision, &cache).get_hash(&cache);
-    PerceptualHashes {
-        orig_path: String::from(&*image_path),
+    match try_get_perceptual_hashes(path, precision, cache) {
+        Ok(hashes) => hashes,
+        Err(e) => {
+            eprintln!("Error Processing Image [{}]: {} ", path.display(), e);
+            PerceptualHashes {
+                orig_path: path.to_string_lossy().into_owned(),
+                ahash: 0,
+                dhash: 0,
+                phash: 0,
+            }
+        }
+    }
+}
+
+/**
+ * Get all perceptual hashes for an image, failing if it can't be read or
+ * decoded instead of hashing it to 0
+ */
+pub fn try_get_perceptual_hashes(
+    path: &Path,
+    precision: &Precision,
+    cache: &Option<Cache>,
+) -> Result<PerceptualHashes, io::Error> {
+    let image_path = get_path_str(path)?;
+    // The file is only digested once for all the hashes
+    let digest = get_digest(path, cache);
+    let ahash = try_get_hash(path, precision, &HashType::AHash, cache, &digest)?;
+    let dhash = try_get_hash(path, precision, &HashType::DHash, cache, &digest)?;
+    let phash = try_get_hash(path, precision, &HashType::PHash, cache, &digest)?;
+    Ok(PerceptualHashes {
+        orig_path: String::from(image_path),
         ahash,
         dhash,
         phash,
+    })
+}
+
+/**
+ * Get a hash from the cache, or calculate it and cache it for next time.
+ * Hashes of images that can't be read are never cached.
+ */
+fn try_get_hash(
+    path: &Path,
+    precision: &Precision,
+    hash_type: &HashType,
+    cache: &Option<Cache>,
+    digest: &Option<String>,
+) -> Result<u64, io::Error> {
+    let key = digest
+        .as_ref()
+        .map(|digest| HashKey::new(digest, *hash_type, *precision));
+    if let (Some(cache), Some(key)) = (cache.as_ref(), key.as_ref()) {
+        if let Some(hash) = cache.get_hash_from_cache(key) {
+            return Ok(hash);
+        }
+    }
+    let hash = match *hash_type {
+        HashType::AHash => ahash::AHash::try_new(path, precision, cache, digest)?.get_hash(cache),
+        HashType::DHash => dhash::DHash::try_new(path, precision, cache, digest)?.get_hash(cache),
+        HashType::PHash => phash::PHash::try_new(path, precision, cache, digest)?.get_hash(cache),
+    };
+    if let (Some(cache), Some(key)) = (cache.as_ref(), key.as_ref()) {
+        if let Err(e) = cache.put_hash_in_cache(key, hash) {
+            eprintln!("Unable to store hash in cache. {}", e);
+        }
+    }
+    Ok(hash)
+}
+
+/**
+ * The digest hashes are cached under, if there is a cache. Reading the
+ * file will fail again while hashing it, with a better error.
+ */
+fn get_digest(path: &Path, cache: &Option<Cache>) -> Option<String> {
+    cache
+        .as_ref()
+        .and_then(|cache| cache.get_file_hash(path).ok())
+}
+
+/**
+ * Get a specific HashType hash for an image that is already in memory
+ */
+pub fn get_perceptual_hash_from_image(
+    image: &image::DynamicImage,
+    precision: &Precision,
+    hash_type: &HashType,
+) -> u64 {
+    match *hash_type {
+        HashType::AHash => ahash::AHash::from_image(image, precision).get_hash(&None),
+        HashType::DHash => dhash::DHash::from_image(image, precision).get_hash(&None),
+        HashType::PHash => phash::PHash::from_image(image, precision).get_hash(&None),
+    }
+}
+
+/**
+ * Get all perceptual hashes for an image that is already in memory
+ */
+pub fn get_perceptual_hashes_from_image(
+    orig_path: &str,
+    image: &image::DynamicImage,
+    precision: &Precision,
+) -> PerceptualHashes {
+    PerceptualHashes {
+        orig_path: String::from(orig_path),
+        ahash: get_perceptual_hash_from_image(image, precision, &HashType::AHash),
+        dhash: get_perceptual_hash_from_image(image, precision, &HashType::DHash),
+        phash: get_perceptual_hash_from_image(image, precision, &HashType::PHash),
     }
 }
 
@@ -271,7 +491,12 @@ pub fn calculate_hamming_distance(hash1: u64, hash2: u64) -> u64 {
 
 #[cfg(test)]
 mod tests {
-    use hash::calculate_hamming_distance;
+    use std::env::temp_dir;
+    use std::fs::remove_dir_all;
+    use std::path::Path;
+
+    use cache::{Cache, HashKey};
+    use hash::{calculate_hamming_distance, get_perceptual_hashes, HashType, Precision};
 
     #[test]
     fn test_no_hamming_distance() {
@@ -290,4 +515,28 @@ mod tests {
         let hamming_distance = calculate_hamming_distance(0, 3);
         assert_eq!(hamming_distance, 2);
     }
+
+    #[test]
+    fn test_cached_hashes() {
+        let cache_dir = temp_dir().join("pihash_test_hash_cache");
+        let _ = remove_dir_all(&cache_dir);
+        let cache = Cache::from_directory(&cache_dir.to_string_lossy());
+        cache.init().unwrap();
+        let path = Path::new("test_images/sample_03_small.jpg");
+
+        let hashes = get_perceptual_hashes(path, &Precision::Low, &Some(cache.clone()));
+        let digest = cache.get_file_hash(path).unwrap();
+        let key = HashKey::new(&digest, HashType::DHash, Precision::Low);
+        assert_eq!(cache.get_hash_from_cache(&key), Some(hashes.dhash));
+        assert_eq!(cache.stats().unwrap().hashes.entries, 3);
+
+        // Later lookups are answered from the cache
+        cache.put_hash_in_cache(&key, 42).unwrap();
+        let cache = Some(cache);
+        assert_eq!(
+            get_perceptual_hashes(path, &Precision::Low, &cache).dhash,
+            42
+        );
+        remove_dir_all(&cache_dir).unwrap();
+    }
 }
@@ -2,6 +2,7 @@
 //
 // Licensed under the MIT license<LICENSE-MIT or http://opensource.org/licenses/MIT>.
 // This file may not be copied, modified, or distributed except according to those terms.
+use std::io::Error;
 use std::path::Path;
 
 use cache::Cache;
@@ -10,16 +11,39 @@ use super::{HashType, PerceptualHash, Precision, PreparedImage};
 use super::dft;
 use super::dft::Transform;
 use super::image::{DynamicImage, GenericImageView, Pixel};
-use super::prepare_image;
+use super::{prepare_image_from_memory, try_prepare_image};
 
 pub struct PHash {
     prepared_image: Box<PreparedImage>,
+    // The matrix is cached under the digest of the file, if there is one
+    digest: Option<String>,
 }
 
 impl PHash {
-    pub fn new(path: &Path, precision: &Precision, cache: &Option<Cache>) -> Self {
+    /**
+     * Prepare an image to be hashed, failing if it can't be read
+     */
+    pub fn try_new(
+        path: &Path,
+        precision: &Precision,
+        cache: &Option<Cache>,
+        digest: &Option<String>,
+    ) -> Result<Self, Error> {
+        let prepared_image = try_prepare_image(path, &HashType::PHash, precision, cache, digest)?;
+        Ok(PHash {
+            prepared_image: Box::new(prepared_image),
+            digest: digest.clone(),
+        })
+    }
+
+    pub fn from_image(image: &DynamicImage, precision: &Precision) -> Self {
         PHash {
-            prepared_image: Box::new(prepare_image(&path, &HashType::PHash, &precision, cache)),
+            prepared_image: Box::new(prepare_image_from_memory(
+                image,
+                &HashType::PHash,
+                precision,
+            )),
+            digest: None,
         }
     }
 }
@@ -42,28 +66,21 @@ impl PerceptualHash for PHash {
                 // Either from the cache or calculate it
                 // Pretty fast already, so caching doesn't make a huge difference
                 // At least compared to opening and processing the images
-                let data_matrix: Vec<Vec<f64>> = match *cache {
-                    Some(ref c) => {
-                        match c.get_matrix_from_cache(
-                            &Path::new(&self.prepared_image.orig_path),
-                            width as u32,
-                        ) {
+                let data_matrix: Vec<Vec<f64>> = match (cache.as_ref(), self.digest.as_ref()) {
+                    (Some(c), Some(digest)) => {
+                        match c.get_matrix_from_cache_by_digest(digest, width) {
                             Some(matrix) => matrix,
                             None => {
                                 let matrix = create_data_matrix(width, height, &image);
-                                match c.put_matrix_in_cache(
-                                    &Path::new(&self.prepared_image.orig_path),
-                                    width as u32,
-                                    &matrix,
-                                ) {
+                                match c.put_matrix_in_cache_by_digest(digest, width, &matrix) {
                                     Ok(_) => {}
-                                    Err(e) => println!("Unable to store matrix in cache. {}", e),
+                                    Err(e) => eprintln!("Unable to store matrix in cache. {}", e),
                                 };
                                 matrix
                             }
                         }
                     }
-                    None => create_data_matrix(width, height, &image),
+                    _ => create_data_matrix(width, height, image),
                 };
 
                 // Only need the top left quadrant
@@ -0,0 +1,386 @@
+// Copyright 2016 Drew Short <drew@sothr.com>.
+//
+// Licensed under the MIT license<LICENSE-MIT or http://opensource.org/licenses/MIT>.
+// This file may not be copied, modified, or distributed except according to those terms.
+
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+
+use super::{HammingDistance, Match};
+
+// Structs/Enums //
+
+struct Node<K, V> {
+    key: K,
+    // Every value inserted under this exact key. A node whose values have
+    // all been removed stays in the tree to route searches to its children.
+    values: Vec<V>,
+    // (distance from this key, node index) pairs
+    children: Vec<(u64, usize)>,
+}
+
+/**
+ * A Burkhard-Keller tree for searching keys by hamming distance
+ *
+ * Each child of a node is filed under its distance from that node, which lets
+ * searches skip every subtree that the triangle inequality rules out.
+ */
+pub struct BKTree<K, V> {
+    nodes: Vec<Node<K, V>>,
+    len: usize,
+}
+
+// Used to keep the k nearest candidates with the furthest on top of the heap
+struct Candidate {
+    distance: u64,
+    node: usize,
+    value: usize,
+}
+
+impl PartialEq for Candidate {
+    fn eq(&self, other: &Candidate) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Candidate {}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Candidate) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Candidate {
+    fn cmp(&self, other: &Candidate) -> Ordering {
+        (self.distance, self.node, self.value).cmp(&(other.distance, other.node, other.value))
+    }
+}
+
+impl<K: HammingDistance, V> Default for BKTree<K, V> {
+    fn default() -> BKTree<K, V> {
+        BKTree::new()
+    }
+}
+
+impl<K: HammingDistance, V> BKTree<K, V> {
+    pub fn new() -> BKTree<K, V> {
+        BKTree {
+            nodes: Vec::new(),
+            len: 0,
+        }
+    }
+
+    /**
+     * The number of values in the tree
+     */
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /**
+     * Add a value under a key. Several values can share the same key.
+     */
+    pub fn insert(&mut self, key: K, value: V) {
+        self.len += 1;
+        if self.nodes.is_empty() {
+            self.nodes.push(Node {
+                key,
+                values: vec![value],
+                children: Vec::new(),
+            });
+            return;
+        }
+
+        let mut current = 0;
+        loop {
+            let distance = self.nodes[current].key.hamming_distance(&key);
+            if distance == 0 {
+                self.nodes[current].values.push(value);
+                return;
+            }
+            let child = self.nodes[current]
+                .children
+                .iter()
+                .find(|&&(child_distance, _)| child_distance == distance)
+                .map(|&(_, child)| child);
+            match child {
+                Some(child) => current = child,
+                None => {
+                    let new_node = self.nodes.len();
+                    self.nodes.push(Node {
+                        key,
+                        values: vec![value],
+                        children: Vec::new(),
+                    });
+                    self.nodes[current].children.push((distance, new_node));
+                    return;
+                }
+            }
+        }
+    }
+
+    /**
+     * Remove every value stored under a key
+     *
+     * # Returns
+     *
+     * The removed values
+     */
+    pub fn remove_key(&mut self, key: &K) -> Vec<V> {
+        match self.find_node(key) {
+            Some(node) => {
+                let values: Vec<V> = self.nodes[node].values.drain(..).collect();
+                self.len -= values.len();
+                values
+            }
+            None => Vec::new(),
+        }
+    }
+
+    /**
+     * Get the values stored under exactly this key
+     */
+    pub fn get(&self, key: &K) -> &[V] {
+        match self.find_node(key) {
+            Some(node) => &self.nodes[node].values,
+            None => &[],
+        }
+    }
+
+    /**
+     * Find every value whose key is within max_distance of the query,
+     * ordered from nearest to furthest
+     */
+    pub fn find_within(&self, key: &K, max_distance: u64) -> Vec<Match<'_, K, V>> {
+        let mut matches = Vec::new();
+        if self.nodes.is_empty() {
+            return matches;
+        }
+
+        let mut stack = vec![0];
+        while let Some(current) = stack.pop() {
+            let node = &self.nodes[current];
+            let distance = node.key.hamming_distance(key);
+            if distance <= max_distance {
+                for value in &node.values {
+                    matches.push(Match {
+                        distance,
+                        key: &node.key,
+                        value,
+                    });
+                }
+            }
+            // Anything within range of the query has to be filed in this window
+            let lower = distance.saturating_sub(max_distance);
+            let upper = distance.saturating_add(max_distance);
+            for &(child_distance, child) in &node.children {
+                if child_distance >= lower && child_distance <= upper {
+                    stack.push(child);
+                }
+            }
+        }
+        matches.sort_by_key(|found| found.distance);
+        matches
+    }
+
+    /**
+     * Find the k values with the nearest keys, ordered from nearest to furthest
+     */
+    pub fn find_nearest(&self, key: &K, k: usize) -> Vec<Match<'_, K, V>> {
+        let mut nearest: BinaryHeap<Candidate> = BinaryHeap::with_capacity(k + 1);
+        if self.nodes.is_empty() || k == 0 {
+            return Vec::new();
+        }
+
+        let mut stack = vec![0];
+        while let Some(current) = stack.pop() {
+            let node = &self.nodes[current];
+            let distance = node.key.hamming_distance(key);
+            for value in 0..node.values.len() {
+                nearest.push(Candidate {
+                    distance,
+                    node: current,
+                    value,
+                });
+                if nearest.len() > k {
+                    nearest.pop();
+                }
+            }
+
+            // Until k values are found nothing can be ruled out
+            let radius = if nearest.len() < k {
+                u64::MAX
+            } else {
+                nearest.peek().map(|furthest| furthest.distance).unwrap_or(0)
+            };
+            let lower = distance.saturating_sub(radius);
+            let upper = distance.saturating_add(radius);
+            // Visit the children closest to the query first to shrink the radius sooner
+            let mut children: Vec<&(u64, usize)> = node
+                .children
+                .iter()
+                .filter(|&&(child_distance, _)| child_distance >= lower && child_distance <= upper)
+                .collect();
+            children.sort_by_key(|&&(child_distance, _)| child_distance.abs_diff(distance));
+            // The stack is last in first out, so push the closest last
+            for &&(_, child) in children.iter().rev() {
+                stack.push(child);
+            }
+        }
+
+        nearest
+            .into_sorted_vec()
+            .into_iter()
+            .map(|candidate| {
+                let node = &self.nodes[candidate.node];
+                Match {
+                    distance: candidate.distance,
+                    key: &node.key,
+                    value: &node.values[candidate.value],
+                }
+            })
+            .collect()
+    }
+
+    /**
+     * Iterate over every key and value in the tree
+     */
+    pub fn iter(&self) -> Box<dyn Iterator<Item = (&K, &V)> + '_> {
+        Box::new(
+            self.nodes
+                .iter()
+                .flat_map(|node| node.values.iter().map(move |value| (&node.key, value))),
+        )
+    }
+
+    fn find_node(&self, key: &K) -> Option<usize> {
+        if self.nodes.is_empty() {
+            return None;
+        }
+        let mut current = 0;
+        loop {
+            let distance = self.nodes[current].key.hamming_distance(key);
+            if distance == 0 {
+                return Some(current);
+            }
+            match self.nodes[current]
+                .children
+                .iter()
+                .find(|&&(child_distance, _)| child_distance == distance)
+            {
+                Some(&(_, child)) => current = child,
+                None => return None,
+            }
+        }
+    }
+}
+
+impl<K: HammingDistance, V: PartialEq> BKTree<K, V> {
+    /**
+     * Remove a single value stored under a key
+     *
+     * # Returns
+     *
+     * If the value was found and removed
+     */
+    pub fn remove(&mut self, key: &K, value: &V) -> bool {
+        if let Some(node) = self.find_node(key) {
+            let values = &mut self.nodes[node].values;
+            if let Some(position) = values.iter().position(|existing| existing == value) {
+                values.remove(position);
+                self.len -= 1;
+                return true;
+            }
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use index::HammingDistance;
+
+    use super::BKTree;
+
+    // Small deterministic generator so the tests don't need a random crate
+    fn keys(count: usize) -> Vec<u64> {
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        (0..count)
+            .map(|_| {
+                state = state
+                    .wrapping_mul(6_364_136_223_846_793_005)
+                    .wrapping_add(1_442_695_040_888_963_407);
+                state
+            })
+            .collect()
+    }
+
+    fn build(keys: &[u64]) -> BKTree<u64, usize> {
+        let mut tree = BKTree::new();
+        for (index, key) in keys.iter().enumerate() {
+            tree.insert(*key, index);
+        }
+        tree
+    }
+
+    #[test]
+    fn test_find_within_matches_linear_scan() {
+        let keys = keys(500);
+        let tree = build(&keys);
+        let query = keys[42] ^ 0b1011;
+        for max_distance in [0, 3, 10, 24, u64::MAX].iter() {
+            let mut found: Vec<usize> = tree
+                .find_within(&query, *max_distance)
+                .iter()
+                .map(|found| *found.value)
+                .collect();
+            found.sort();
+            let expected: Vec<usize> = (0..keys.len())
+                .filter(|&index| keys[index].hamming_distance(&query) <= *max_distance)
+                .collect();
+            assert_eq!(found, expected);
+        }
+    }
+
+    #[test]
+    fn test_find_nearest_matches_linear_scan() {
+        let keys = keys(500);
+        let tree = build(&keys);
+        let query = keys[7] ^ 0xff;
+        let found: Vec<u64> = tree
+            .find_nearest(&query, 5)
+            .iter()
+            .map(|found| found.distance)
+            .collect();
+        let mut expected: Vec<u64> = keys.iter().map(|key| key.hamming_distance(&query)).collect();
+        expected.sort();
+        expected.truncate(5);
+        assert_eq!(found, expected);
+    }
+
+    #[test]
+    fn test_duplicate_keys_and_removal() {
+        let mut tree = BKTree::new();
+        tree.insert(1u64, "first");
+        tree.insert(1u64, "second");
+        tree.insert(3u64, "third");
+        assert_eq!(tree.len(), 3);
+        assert_eq!(tree.get(&1), &["first", "second"]);
+
+        assert!(tree.remove(&1, &"first"));
+        assert!(!tree.remove(&1, &"first"));
+        assert_eq!(tree.len(), 2);
+
+        // Removed nodes still route searches to their children
+        assert_eq!(tree.remove_key(&1), vec!["second"]);
+        let found = tree.find_within(&3, 0);
+        assert_eq!(found.len(), 1);
+        assert_eq!(*found[0].value, "third");
+        assert_eq!(tree.find_nearest(&1, 2).len(), 1);
+    }
+}
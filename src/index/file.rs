@@ -0,0 +1,802 @@
+// Copyright 2016 Drew Short <drew@sothr.com>.
+//
+// Licensed under the MIT license<LICENSE-MIT or http://opensource.org/licenses/MIT>.
+// This file may not be copied, modified, or distributed except according to those terms.
+
+extern crate memmap;
+
+use std::cell::Cell;
+use std::collections::HashSet;
+use std::fs::{metadata, rename, File, Metadata, OpenOptions};
+use std::io::{BufWriter, Error, ErrorKind, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use cache::FileLock;
+use hash::{calculate_hamming_distance, HashType, PerceptualHashes, Precision};
+use similarity::{Similarity, SimilarityPolicy};
+use PIHash;
+
+use self::memmap::Mmap;
+
+use super::mih::{
+    count_within, for_each_within, searched_hash_types, substring, substring_ranges,
+    PerceptualHashIndex,
+};
+
+// Constants //
+
+const INDEX_MAGIC: &[u8; 8] = b"PIHINDEX";
+const INDEX_FORMAT_VERSION: u32 = 1;
+const HEADER_LENGTH: u64 = 64;
+const INDEX_SUBSTRINGS: u32 = 4;
+// flags + three hashes + path length + metadata length
+const RECORD_HEADER_LENGTH: usize = 1 + 8 * 3 + 4 + 4;
+const RECORD_REMOVED: u8 = 1;
+const HASH_TYPES: [HashType; 3] = [HashType::AHash, HashType::DHash, HashType::PHash];
+
+// Structs/Enums //
+
+/**
+ * A set of hashes stored in an index file along with the path of the image
+ * (kept in hashes.orig_path) and any metadata the caller attached to it.
+ * The id is stable until the index is compacted.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct IndexRecord {
+    pub id: u64,
+    pub hashes: PerceptualHashes,
+    pub metadata: Vec<u8>,
+}
+
+/**
+ * A record found by querying an index file
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct IndexMatch {
+    pub record: IndexRecord,
+    pub similarity: Similarity,
+}
+
+/**
+ * A similarity index stored in a single memory mapped file
+ *
+ * The file starts with a fixed size header followed by the records and a set
+ * of sorted multi-index hash tables over those records. Records added after
+ * the tables were written are appended to the end of the file and are indexed
+ * in memory when the file is opened, so adding entries never requires a
+ * rebuild. Compacting the index folds them into the tables.
+ *
+ * Header layout, all values are little endian:
+ *
+ * * magic (8 bytes) and format version (u32)
+ * * precision of the hashes (u8) followed by 3 reserved bytes
+ * * number of records covered by the tables (u64)
+ * * offset and length of the tables (u64, u64)
+ *
+ * Each record is a flags byte, the ahash, dhash and phash (u64), the length
+ * of the path and of the metadata (u32) and then the path and metadata bytes.
+ *
+ * The tables are the offsets of the covered records (u64 each) followed by a
+ * table per hash type and substring. Every table entry is the substring value
+ * in the upper 32 bits and the record number in the lower 32 bits, sorted.
+ *
+ * Records covered by the tables are only checked when they're read, so
+ * opening a large index doesn't read every record. Any that are corrupt are
+ * skipped.
+ */
+pub struct IndexFile {
+    path: PathBuf,
+    precision: Precision,
+    map: Mmap,
+    sealed_count: u64,
+    table_offset: u64,
+    tail: PerceptualHashIndex<(u64, Vec<u8>)>,
+    // Removed records in the tail and those covered by the tables that were removed since opening
+    removed: HashSet<u64>,
+    // Removed or unreadable records covered by the tables, counted when first needed
+    removed_sealed: Cell<Option<u64>>,
+    end: u64,
+    identity: u64,
+}
+
+impl IndexFile {
+    /**
+     * Create a new index file from a set of hashes and their metadata,
+     * replacing any existing file at the path.
+     *
+     * The hashes are expected to be calculated at the given precision, for
+     * example the results of PIHash::get_pihashes for Precision::Medium.
+     */
+    pub fn create<I>(path: &Path, precision: &Precision, records: I) -> Result<IndexFile, Error>
+    where
+        I: IntoIterator<Item = (PerceptualHashes, Vec<u8>)>,
+    {
+        let temp_path = PathBuf::from(format!("{}.tmp", path.display()));
+        {
+            let mut writer = BufWriter::new(File::create(&temp_path)?);
+            writer.write_all(&[0u8; HEADER_LENGTH as usize])?;
+
+            let mut offsets: Vec<u64> = Vec::new();
+            let mut hashes: Vec<[u64; 3]> = Vec::new();
+            let mut position = HEADER_LENGTH;
+            for (record_hashes, metadata) in records {
+                let encoded = encode_record(&record_hashes, &metadata);
+                writer.write_all(&encoded)?;
+                offsets.push(position);
+                hashes.push([record_hashes.ahash, record_hashes.dhash, record_hashes.phash]);
+                position += encoded.len() as u64;
+            }
+            if offsets.len() as u64 > u64::from(u32::MAX) {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    "Too many records for a single index file",
+                ));
+            }
+
+            let table_offset = position;
+            for offset in &offsets {
+                writer.write_all(&offset.to_le_bytes())?;
+            }
+            let ranges = substring_ranges(precision.get_hash_length(), INDEX_SUBSTRINGS);
+            for hash_index in 0..HASH_TYPES.len() {
+                for range in &ranges {
+                    let mut table: Vec<u64> = hashes
+                        .iter()
+                        .enumerate()
+                        .map(|(record, record_hashes)| {
+                            substring(record_hashes[hash_index], *range) << 32 | record as u64
+                        })
+                        .collect();
+                    table.sort();
+                    for entry in table {
+                        writer.write_all(&entry.to_le_bytes())?;
+                    }
+                }
+            }
+            let table_count = (HASH_TYPES.len() * ranges.len()) as u64;
+            let table_length = offsets.len() as u64 * 8 * (1 + table_count);
+
+            let mut file = writer.into_inner().map_err(|e| e.into_error())?;
+            file.seek(SeekFrom::Start(0))?;
+            file.write_all(&encode_header(
+                precision,
+                offsets.len() as u64,
+                table_offset,
+                table_length,
+            ))?;
+            file.sync_all()?;
+        }
+        rename(&temp_path, path)?;
+        IndexFile::open(path)
+    }
+
+    /**
+     * Open an existing index file. Only the header and the records appended
+     * since the tables were written are read.
+     */
+    pub fn open(path: &Path) -> Result<IndexFile, Error> {
+        let file = File::open(path)?;
+        let map = unsafe { Mmap::map(&file)? };
+        if map.len() < HEADER_LENGTH as usize || &map[0..8] != INDEX_MAGIC {
+            return Err(Error::new(ErrorKind::InvalidData, "Not a pihash index file"));
+        }
+        if read_u32(&map, 8) != INDEX_FORMAT_VERSION {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "Unsupported index file version",
+            ));
+        }
+        let precision = match map[12] {
+            0 => Precision::Low,
+            1 => Precision::Medium,
+            2 => Precision::High,
+            _ => return Err(Error::new(ErrorKind::InvalidData, "Unknown index precision")),
+        };
+        let sealed_count = read_u64(&map, 16);
+        let table_offset = read_u64(&map, 24);
+        let table_length = read_u64(&map, 32);
+        let table_count = (HASH_TYPES.len()
+            * substring_ranges(precision.get_hash_length(), INDEX_SUBSTRINGS).len())
+            as u64;
+        let expected_length = sealed_count
+            .checked_mul(8)
+            .and_then(|length| length.checked_mul(1 + table_count));
+        if sealed_count > u64::from(u32::MAX)
+            || table_offset < HEADER_LENGTH
+            || expected_length != Some(table_length)
+        {
+            return Err(Error::new(ErrorKind::InvalidData, "Corrupt index file header"));
+        }
+        let end = match table_offset.checked_add(table_length) {
+            Some(end) if end <= map.len() as u64 => end,
+            _ => return Err(Error::new(ErrorKind::InvalidData, "Truncated index file")),
+        };
+
+        let mut index = IndexFile {
+            path: PathBuf::from(path),
+            precision,
+            map,
+            sealed_count,
+            table_offset,
+            tail: PerceptualHashIndex::new(precision.get_hash_length(), INDEX_SUBSTRINGS),
+            removed: HashSet::new(),
+            removed_sealed: Cell::new(None),
+            end,
+            identity: file_identity(&file.metadata()?),
+        };
+
+        // Index everything appended since the tables were written. A partially
+        // written record at the very end is ignored.
+        while let Some((record, removed, next)) = read_record(&index.map, index.end as usize) {
+            if removed {
+                index.removed.insert(record.id);
+            }
+            index.tail.insert(record.hashes, (record.id, record.metadata));
+            index.end = next as u64;
+        }
+        Ok(index)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn precision(&self) -> Precision {
+        self.precision
+    }
+
+    /**
+     * The number of records that haven't been removed
+     */
+    pub fn len(&self) -> usize {
+        let removed_tail = self.removed.iter().filter(|&&id| id >= self.table_offset).count();
+        (self.sealed_count - self.count_removed_sealed()) as usize + self.tail.len() - removed_tail
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /**
+     * Append a set of hashes to the index without rebuilding it
+     *
+     * # Returns
+     *
+     * The id of the new record
+     */
+    pub fn append(&mut self, hashes: &PerceptualHashes, metadata: &[u8]) -> Result<u64, Error> {
+        let _lock = self.lock()?;
+        let encoded = encode_record(hashes, metadata);
+        let mut file = OpenOptions::new().write(true).open(&self.path)?;
+        // Drop any partially written record left behind by an earlier failure
+        file.set_len(self.end)?;
+        file.seek(SeekFrom::Start(self.end))?;
+        file.write_all(&encoded)?;
+        file.sync_data()?;
+
+        let id = self.end;
+        self.end += encoded.len() as u64;
+        self.tail.insert(hashes.clone(), (id, metadata.to_vec()));
+        Ok(id)
+    }
+
+    /**
+     * Mark a record as removed. It stays in the file until the index is compacted.
+     *
+     * # Returns
+     *
+     * If a record with the id was found
+     */
+    pub fn remove(&mut self, id: u64) -> Result<bool, Error> {
+        let _lock = self.lock()?;
+        if self.get(id).is_none() {
+            return Ok(false);
+        }
+        let mut file = OpenOptions::new().write(true).open(&self.path)?;
+        file.seek(SeekFrom::Start(id))?;
+        file.write_all(&[RECORD_REMOVED])?;
+        file.sync_data()?;
+        self.removed.insert(id);
+        if let (true, Some(count)) = (id < self.table_offset, self.removed_sealed.get()) {
+            self.removed_sealed.set(Some(count + 1));
+        }
+        Ok(true)
+    }
+
+    /**
+     * Get a record that hasn't been removed by its id
+     */
+    pub fn get(&self, id: u64) -> Option<IndexRecord> {
+        if self.removed.contains(&id) {
+            return None;
+        }
+        if id < self.table_offset {
+            // Only ids listed in the tables are valid record starts
+            let mut low = 0;
+            let mut high = self.sealed_count;
+            while low < high {
+                let middle = (low + high) / 2;
+                let offset = self.record_offset(middle)?;
+                if offset == id {
+                    return self.sealed_record(middle);
+                } else if offset < id {
+                    low = middle + 1;
+                } else {
+                    high = middle;
+                }
+            }
+            None
+        } else {
+            self.tail
+                .iter()
+                .find(|&(_, &(record_id, _))| record_id == id)
+                .map(|(hashes, &(record_id, ref metadata))| IndexRecord {
+                    id: record_id,
+                    hashes: hashes.clone(),
+                    metadata: metadata.clone(),
+                })
+        }
+    }
+
+    /**
+     * Get every record that hasn't been removed
+     */
+    pub fn records(&self) -> Vec<IndexRecord> {
+        let mut records = Vec::new();
+        for sealed in 0..self.sealed_count {
+            if let Some(record) = self.sealed_record(sealed) {
+                records.push(record);
+            }
+        }
+        for (hashes, &(id, ref metadata)) in self.tail.iter() {
+            if !self.removed.contains(&id) {
+                records.push(IndexRecord {
+                    id,
+                    hashes: hashes.clone(),
+                    metadata: metadata.clone(),
+                });
+            }
+        }
+        records
+    }
+
+    /**
+     * Find every record the policy considers similar to the hashes, ordered
+     * from the most to the least similar
+     */
+    pub fn query(&self, hashes: &PerceptualHashes, policy: &SimilarityPolicy) -> Vec<IndexMatch> {
+        let mut candidates: Vec<u64> = Vec::new();
+        for hash_type in &searched_hash_types(policy) {
+            candidates.extend(self.find_sealed(
+                hashes.get(hash_type),
+                hash_type,
+                policy.max_distance(hash_type),
+            ));
+        }
+        candidates.sort();
+        candidates.dedup();
+
+        let mut matches = Vec::new();
+        for sealed in candidates {
+            if let Some(record) = self.sealed_record(sealed) {
+                let similarity = policy.compare(hashes, &record.hashes);
+                if similarity.similar {
+                    matches.push(IndexMatch { record, similarity });
+                }
+            }
+        }
+        for found in self.tail.find_similar(hashes, policy) {
+            let (id, ref metadata) = *found.value;
+            if !self.removed.contains(&id) {
+                matches.push(IndexMatch {
+                    record: IndexRecord {
+                        id,
+                        hashes: found.hashes.clone(),
+                        metadata: metadata.clone(),
+                    },
+                    similarity: found.similarity,
+                });
+            }
+        }
+        matches.sort_by(|first, second| {
+            second
+                .similarity
+                .score
+                .partial_cmp(&first.similarity.score)
+                .unwrap_or(::std::cmp::Ordering::Equal)
+        });
+        matches
+    }
+
+    /**
+     * Hash an image at the precision of the index and find the similar
+     * records, failing if the image can't be hashed
+     */
+    pub fn query_image(
+        &self,
+        lib: &PIHash,
+        path: &Path,
+        policy: &SimilarityPolicy,
+    ) -> Result<Vec<IndexMatch>, Error> {
+        let hashes = lib.try_get_perceptual_hashes(path, &self.precision)?;
+        Ok(self.query(&hashes, policy))
+    }
+
+    /**
+     * Rewrite the index without the removed records and with every appended
+     * record covered by the tables. Record ids change when compacting.
+     */
+    pub fn compact(&mut self) -> Result<(), Error> {
+        let _lock = self.lock()?;
+        let records: Vec<(PerceptualHashes, Vec<u8>)> = self
+            .records()
+            .into_iter()
+            .map(|record| (record.hashes, record.metadata))
+            .collect();
+        let path = self.path.clone();
+        *self = IndexFile::create(&path, &self.precision, records)?;
+        Ok(())
+    }
+
+    /**
+     * Take the lock shared by everything writing to the index file and pick
+     * up any changes other processes made since it was opened
+     */
+    fn lock(&mut self) -> Result<FileLock, Error> {
+        let lock = FileLock::lock(&PathBuf::from(format!("{}.lock", self.path.display())))?;
+        let current = metadata(&self.path)?;
+        if current.len() != self.end || file_identity(&current) != self.identity {
+            *self = IndexFile::open(&self.path)?;
+        }
+        Ok(lock)
+    }
+
+    fn record_offset(&self, sealed: u64) -> Option<u64> {
+        let position = sealed.checked_mul(8)?.checked_add(self.table_offset)?;
+        read_u64_checked(&self.map, position)
+    }
+
+    /**
+     * Get the offset of a record covered by the tables, or None if it doesn't
+     * sit whole between the header and the tables
+     */
+    fn sealed_offset(&self, sealed: u64) -> Option<u64> {
+        let offset = self.record_offset(sealed)?;
+        if offset < HEADER_LENGTH {
+            return None;
+        }
+        match record_end(&self.map, offset as usize) {
+            Some(next) if next as u64 <= self.table_offset => Some(offset),
+            _ => None,
+        }
+    }
+
+    /**
+     * Get a record covered by the tables if it's intact and hasn't been removed
+     */
+    fn sealed_record(&self, sealed: u64) -> Option<IndexRecord> {
+        let offset = self.sealed_offset(sealed)?;
+        match read_record(&self.map, offset as usize) {
+            Some((record, false, _)) if !self.removed.contains(&offset) => Some(record),
+            _ => None,
+        }
+    }
+
+    fn count_removed_sealed(&self) -> u64 {
+        if let Some(count) = self.removed_sealed.get() {
+            return count;
+        }
+        let count = (0..self.sealed_count)
+            .filter(|&sealed| self.sealed_record(sealed).is_none())
+            .count() as u64;
+        self.removed_sealed.set(Some(count));
+        count
+    }
+
+    fn table_entry(&self, table: usize, entry: u64) -> Option<u64> {
+        let table_length = self.sealed_count.checked_mul(8)?;
+        let position = (table as u64)
+            .checked_add(1)?
+            .checked_mul(table_length)?
+            .checked_add(entry.checked_mul(8)?)?
+            .checked_add(self.table_offset)?;
+        read_u64_checked(&self.map, position)
+    }
+
+    /**
+     * Get the numbers of the records covered by the tables whose hash of this
+     * type is within max_distance
+     */
+    fn find_sealed(&self, hash: u64, hash_type: &HashType, max_distance: u64) -> Vec<u64> {
+        let hash_length = self.precision.get_hash_length();
+        let ranges = substring_ranges(hash_length, INDEX_SUBSTRINGS);
+        let hash_index = HASH_TYPES
+            .iter()
+            .position(|candidate| candidate == hash_type)
+            .unwrap_or(0);
+        let substring_distance = (max_distance / ranges.len() as u64) as u32;
+        let probes: u64 = ranges
+            .iter()
+            .map(|&(_, length)| count_within(length, substring_distance))
+            .sum();
+
+        let mut candidates: Vec<u64> = Vec::new();
+        if max_distance >= hash_length as u64 || probes >= self.sealed_count {
+            candidates.extend(0..self.sealed_count);
+        } else {
+            for (substring_index, range) in ranges.iter().enumerate() {
+                let table = hash_index * ranges.len() + substring_index;
+                for_each_within(substring(hash, *range), range.1, substring_distance, &mut |value| {
+                    // Find the first entry for this substring value
+                    let mut low = 0;
+                    let mut high = self.sealed_count;
+                    while low < high {
+                        let middle = (low + high) / 2;
+                        match self.table_entry(table, middle) {
+                            Some(entry) if entry >> 32 < value => low = middle + 1,
+                            _ => high = middle,
+                        }
+                    }
+                    while low < self.sealed_count {
+                        match self.table_entry(table, low) {
+                            Some(entry) if entry >> 32 == value => {
+                                candidates.push(entry & 0xffff_ffff)
+                            }
+                            _ => break,
+                        }
+                        low += 1;
+                    }
+                });
+            }
+        }
+
+        candidates.retain(|&sealed| {
+            match self
+                .sealed_offset(sealed)
+                .and_then(|offset| offset.checked_add(1 + hash_index as u64 * 8))
+                .and_then(|position| read_u64_checked(&self.map, position))
+            {
+                Some(found) => calculate_hamming_distance(hash, found) <= max_distance,
+                None => false,
+            }
+        });
+        candidates
+    }
+}
+
+// Functions //
+
+fn encode_header(
+    precision: &Precision,
+    sealed_count: u64,
+    table_offset: u64,
+    table_length: u64,
+) -> Vec<u8> {
+    let mut header = Vec::with_capacity(HEADER_LENGTH as usize);
+    header.extend_from_slice(INDEX_MAGIC);
+    header.extend_from_slice(&INDEX_FORMAT_VERSION.to_le_bytes());
+    header.push(match *precision {
+        Precision::Low => 0,
+        Precision::Medium => 1,
+        Precision::High => 2,
+    });
+    header.extend_from_slice(&[0u8; 3]);
+    header.extend_from_slice(&sealed_count.to_le_bytes());
+    header.extend_from_slice(&table_offset.to_le_bytes());
+    header.extend_from_slice(&table_length.to_le_bytes());
+    header.resize(HEADER_LENGTH as usize, 0);
+    header
+}
+
+fn encode_record(hashes: &PerceptualHashes, metadata: &[u8]) -> Vec<u8> {
+    let path = hashes.orig_path.as_bytes();
+    let mut record = Vec::with_capacity(RECORD_HEADER_LENGTH + path.len() + metadata.len());
+    record.push(0u8);
+    record.extend_from_slice(&hashes.ahash.to_le_bytes());
+    record.extend_from_slice(&hashes.dhash.to_le_bytes());
+    record.extend_from_slice(&hashes.phash.to_le_bytes());
+    record.extend_from_slice(&(path.len() as u32).to_le_bytes());
+    record.extend_from_slice(&(metadata.len() as u32).to_le_bytes());
+    record.extend_from_slice(path);
+    record.extend_from_slice(metadata);
+    record
+}
+
+/**
+ * Decode the record starting at offset
+ *
+ * # Returns
+ *
+ * The record, if it was removed and the offset of the next record, or None
+ * if there isn't a complete record at the offset
+ */
+fn read_record(bytes: &[u8], offset: usize) -> Option<(IndexRecord, bool, usize)> {
+    let next = record_end(bytes, offset)?;
+    let path_start = offset + RECORD_HEADER_LENGTH;
+    let metadata_start = path_start + read_u32(bytes, offset + 25) as usize;
+    let record = IndexRecord {
+        id: offset as u64,
+        hashes: PerceptualHashes {
+            orig_path: String::from_utf8_lossy(&bytes[path_start..metadata_start]).into_owned(),
+            ahash: read_u64(bytes, offset + 1),
+            dhash: read_u64(bytes, offset + 9),
+            phash: read_u64(bytes, offset + 17),
+        },
+        metadata: bytes[metadata_start..next].to_vec(),
+    };
+    Some((record, bytes[offset] & RECORD_REMOVED != 0, next))
+}
+
+/**
+ * Get the offset just past the record starting at offset, or None if there
+ * isn't a complete record at the offset
+ */
+fn record_end(bytes: &[u8], offset: usize) -> Option<usize> {
+    if offset.checked_add(RECORD_HEADER_LENGTH)? > bytes.len() {
+        return None;
+    }
+    let path_length = read_u32(bytes, offset + 25) as usize;
+    let metadata_length = read_u32(bytes, offset + 29) as usize;
+    let next = (offset + RECORD_HEADER_LENGTH)
+        .checked_add(path_length)?
+        .checked_add(metadata_length)?;
+    if next > bytes.len() {
+        return None;
+    }
+    Some(next)
+}
+
+/**
+ * Something that changes when the index file is replaced, such as by
+ * compacting it in another process
+ */
+#[cfg(unix)]
+fn file_identity(metadata: &Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+
+    metadata.ino()
+}
+
+#[cfg(not(unix))]
+fn file_identity(_metadata: &Metadata) -> u64 {
+    0
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    let mut buffer = [0u8; 4];
+    buffer.copy_from_slice(&bytes[offset..offset + 4]);
+    u32::from_le_bytes(buffer)
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    let mut buffer = [0u8; 8];
+    buffer.copy_from_slice(&bytes[offset..offset + 8]);
+    u64::from_le_bytes(buffer)
+}
+
+fn read_u64_checked(bytes: &[u8], offset: u64) -> Option<u64> {
+    match offset.checked_add(8) {
+        Some(end) if end <= bytes.len() as u64 => Some(read_u64(bytes, offset as usize)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env::temp_dir;
+    use std::fs::{remove_file, OpenOptions};
+    use std::io::{ErrorKind, Seek, SeekFrom, Write};
+    use std::path::{Path, PathBuf};
+
+    use cache::CacheMode;
+    use hash::{PerceptualHashes, Precision};
+    use similarity::SimilarityPolicy;
+    use PIHash;
+
+    use super::IndexFile;
+
+    fn hashes(path: &str, seed: u64) -> PerceptualHashes {
+        PerceptualHashes {
+            orig_path: String::from(path),
+            ahash: seed.wrapping_mul(0x9e37_79b9_7f4a_7c15),
+            dhash: seed.wrapping_mul(0xc2b2_ae3d_27d4_eb4f),
+            phash: seed.wrapping_mul(0x1656_67b1_9e37_79f9),
+        }
+    }
+
+    fn index_path(name: &str) -> PathBuf {
+        temp_dir().join(format!("pihash_index_file_{}.idx", name))
+    }
+
+    #[test]
+    fn test_query_append_and_reopen() {
+        let path = index_path("query");
+        let records =
+            (1..200).map(|seed| (hashes(&format!("image_{}", seed), seed), vec![seed as u8]));
+        let mut index = IndexFile::create(&path, &Precision::Medium, records).unwrap();
+        assert_eq!(index.len(), 199);
+
+        let policy: SimilarityPolicy = Default::default();
+        let mut query = hashes("query", 42);
+        query.ahash ^= 0b101;
+        let matches = index.query(&query, &policy);
+        assert_eq!(matches.len(), 1);
+        assert_eq!(matches[0].record.hashes.orig_path, "image_42");
+        assert_eq!(matches[0].record.metadata, vec![42u8]);
+        assert_eq!(matches[0].similarity.distances.ahash, 2);
+
+        let id = index.append(&hashes("appended", 500), b"new").unwrap();
+        assert_eq!(index.query(&hashes("query", 500), &policy).len(), 1);
+
+        let mut reopened = IndexFile::open(&path).unwrap();
+        assert_eq!(reopened.len(), 200);
+        assert_eq!(reopened.get(id).unwrap().metadata, b"new".to_vec());
+
+        assert!(reopened.remove(matches[0].record.id).unwrap());
+        assert!(reopened.query(&query, &policy).is_empty());
+        assert_eq!(reopened.len(), 199);
+        reopened.compact().unwrap();
+        assert_eq!(reopened.len(), 199);
+        assert_eq!(reopened.query(&hashes("query", 500), &policy).len(), 1);
+        assert!(IndexFile::open(&path).unwrap().query(&query, &policy).is_empty());
+
+        remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_empty_index() {
+        let path = index_path("empty");
+        let mut index = IndexFile::create(&path, &Precision::Low, Vec::new()).unwrap();
+        let policy = SimilarityPolicy::new(Precision::Low);
+        assert!(index.is_empty());
+        assert!(index.query(&hashes("query", 1), &policy).is_empty());
+        index.append(&hashes("first", 1), &[]).unwrap();
+        assert_eq!(IndexFile::open(&path).unwrap().len(), 1);
+
+        // An image that can't be hashed is an error rather than a query
+        let lib = PIHash::new(None, CacheMode::Disabled);
+        let missing = Path::new("test_images/missing.jpg");
+        assert!(index.query_image(&lib, missing, &policy).is_err());
+        remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_rejects_truncated_and_corrupt_files() {
+        let path = index_path("truncated");
+        let records = (1..50).map(|seed| (hashes(&format!("image_{}", seed), seed), Vec::new()));
+        IndexFile::create(&path, &Precision::Medium, records).unwrap();
+        let length = path.metadata().unwrap().len();
+
+        // Cutting into the tables
+        OpenOptions::new().write(true).open(&path).unwrap().set_len(length - 8).unwrap();
+        assert_eq!(IndexFile::open(&path).err().unwrap().kind(), ErrorKind::InvalidData);
+
+        // A table offset that would overflow
+        let mut file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len(length).unwrap();
+        file.seek(SeekFrom::Start(24)).unwrap();
+        file.write_all(&u64::MAX.to_le_bytes()).unwrap();
+        assert_eq!(IndexFile::open(&path).err().unwrap().kind(), ErrorKind::InvalidData);
+
+        // A table length that doesn't match the number of records
+        let table_offset = length - 49 * 8 * 13;
+        file.seek(SeekFrom::Start(24)).unwrap();
+        file.write_all(&table_offset.to_le_bytes()).unwrap();
+        file.write_all(&(49u64 * 8 * 13 - 8).to_le_bytes()).unwrap();
+        assert_eq!(IndexFile::open(&path).err().unwrap().kind(), ErrorKind::InvalidData);
+
+        // A record offset past the end of the records is skipped when read
+        file.seek(SeekFrom::Start(32)).unwrap();
+        file.write_all(&(49u64 * 8 * 13).to_le_bytes()).unwrap();
+        assert_eq!(IndexFile::open(&path).unwrap().len(), 49);
+        file.seek(SeekFrom::Start(table_offset)).unwrap();
+        file.write_all(&(length - 4).to_le_bytes()).unwrap();
+        let index = IndexFile::open(&path).unwrap();
+        assert_eq!(index.len(), 48);
+        assert_eq!(index.records().len(), 48);
+        assert!(index.query(&hashes("query", 1), &SimilarityPolicy::default()).is_empty());
+        assert_eq!(index.query(&hashes("query", 2), &SimilarityPolicy::default()).len(), 1);
+
+        remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,556 @@
+// Copyright 2016 Drew Short <drew@sothr.com>.
+//
+// Licensed under the MIT license<LICENSE-MIT or http://opensource.org/licenses/MIT>.
+// This file may not be copied, modified, or distributed except according to those terms.
+
+use std::collections::{HashMap, HashSet};
+use std::default::Default;
+
+use hash::{calculate_hamming_distance, HashType, PerceptualHashes};
+use similarity::{CombinationMode, Similarity, SimilarityPolicy};
+
+use super::Match;
+
+// Constants //
+
+// A 64 bit hash split into four 16 bit substrings
+const DEFAULT_HASH_LENGTH: u32 = 64;
+const DEFAULT_SUBSTRINGS: u32 = 4;
+
+// Structs/Enums //
+
+/**
+ * Multi-index hashing for exact hamming range searches
+ *
+ * Each hash is split into substrings with a hash table per substring. Two
+ * hashes within a distance r of each other must have at least one substring
+ * within r / substrings of each other, so a search only has to look up the
+ * few substring values close to the query and check the candidates found.
+ */
+pub struct MultiIndexHash<V> {
+    hash_length: u32,
+    // (first bit, number of bits) of every substring
+    substrings: Vec<(u32, u32)>,
+    tables: Vec<HashMap<u64, Vec<usize>>>,
+    entries: Vec<(u64, V)>,
+}
+
+/**
+ * A multi-index hash over all three perceptual hashes of each image
+ */
+pub struct PerceptualHashIndex<V> {
+    ahash: MultiIndexHash<usize>,
+    dhash: MultiIndexHash<usize>,
+    phash: MultiIndexHash<usize>,
+    entries: Vec<(PerceptualHashes, V)>,
+}
+
+/**
+ * A set of hashes found by a similarity search and how it compares to the query
+ */
+#[derive(Debug, PartialEq)]
+pub struct SimilarMatch<'a, V: 'a> {
+    pub similarity: Similarity,
+    pub hashes: &'a PerceptualHashes,
+    pub value: &'a V,
+}
+
+impl<V> Default for MultiIndexHash<V> {
+    fn default() -> MultiIndexHash<V> {
+        MultiIndexHash::new(DEFAULT_HASH_LENGTH, DEFAULT_SUBSTRINGS)
+    }
+}
+
+impl<V> MultiIndexHash<V> {
+    /**
+     * Create an empty index for hashes of hash_length bits split into the
+     * requested number of substrings
+     */
+    pub fn new(hash_length: u32, substrings: u32) -> MultiIndexHash<V> {
+        let hash_length = hash_length.clamp(1, 64);
+        let ranges = substring_ranges(hash_length, substrings);
+        MultiIndexHash {
+            hash_length,
+            tables: ranges.iter().map(|_| HashMap::new()).collect(),
+            substrings: ranges,
+            entries: Vec::new(),
+        }
+    }
+
+    /**
+     * Build an index from many hashes at once
+     */
+    pub fn build<I>(hash_length: u32, substrings: u32, items: I) -> MultiIndexHash<V>
+    where
+        I: IntoIterator<Item = (u64, V)>,
+    {
+        let mut index = MultiIndexHash::new(hash_length, substrings);
+        let items = items.into_iter();
+        index.entries.reserve(items.size_hint().0);
+        for (key, value) in items {
+            index.insert(key, value);
+        }
+        index
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn insert(&mut self, key: u64, value: V) {
+        let position = self.entries.len();
+        for (table, range) in self.tables.iter_mut().zip(self.substrings.iter()) {
+            table
+                .entry(substring(key, *range))
+                .or_insert_with(Vec::new)
+                .push(position);
+        }
+        self.entries.push((key, value));
+    }
+
+    /**
+     * Find every value whose key is within max_distance of the query,
+     * ordered from nearest to furthest
+     */
+    pub fn find_within(&self, key: u64, max_distance: u64) -> Vec<Match<'_, u64, V>> {
+        let mut matches: Vec<Match<'_, u64, V>> = self
+            .find_positions(key, max_distance)
+            .into_iter()
+            .map(|position| {
+                let (found, value) = &self.entries[position];
+                Match {
+                    distance: calculate_hamming_distance(key, *found),
+                    key: found,
+                    value,
+                }
+            })
+            .collect();
+        matches.sort_by_key(|found| found.distance);
+        matches
+    }
+
+    /**
+     * Iterate over every key and value in the index
+     */
+    pub fn iter(&self) -> Box<dyn Iterator<Item = (&u64, &V)> + '_> {
+        Box::new(self.entries.iter().map(|(key, value)| (key, value)))
+    }
+
+    fn find_positions(&self, key: u64, max_distance: u64) -> Vec<usize> {
+        if max_distance >= self.hash_length as u64 {
+            return (0..self.entries.len()).collect();
+        }
+        let substring_distance = (max_distance / self.substrings.len() as u64) as u32;
+
+        // Probing every nearby substring value can cost more than just checking everything
+        let probes: u64 = self
+            .substrings
+            .iter()
+            .map(|&(_, length)| count_within(length, substring_distance))
+            .sum();
+        if probes >= self.entries.len() as u64 {
+            return (0..self.entries.len())
+                .filter(|&position| {
+                    calculate_hamming_distance(key, self.entries[position].0) <= max_distance
+                })
+                .collect();
+        }
+
+        let mut checked: HashSet<usize> = HashSet::new();
+        let mut positions = Vec::new();
+        for (table, range) in self.tables.iter().zip(self.substrings.iter()) {
+            let query = substring(key, *range);
+            for_each_within(query, range.1, substring_distance, &mut |value| {
+                if let Some(candidates) = table.get(&value) {
+                    for &position in candidates {
+                        if checked.insert(position)
+                            && calculate_hamming_distance(key, self.entries[position].0)
+                                <= max_distance
+                        {
+                            positions.push(position);
+                        }
+                    }
+                }
+            });
+        }
+        positions
+    }
+}
+
+impl<V> Default for PerceptualHashIndex<V> {
+    fn default() -> PerceptualHashIndex<V> {
+        PerceptualHashIndex::new(DEFAULT_HASH_LENGTH, DEFAULT_SUBSTRINGS)
+    }
+}
+
+impl<V> PerceptualHashIndex<V> {
+    pub fn new(hash_length: u32, substrings: u32) -> PerceptualHashIndex<V> {
+        PerceptualHashIndex {
+            ahash: MultiIndexHash::new(hash_length, substrings),
+            dhash: MultiIndexHash::new(hash_length, substrings),
+            phash: MultiIndexHash::new(hash_length, substrings),
+            entries: Vec::new(),
+        }
+    }
+
+    /**
+     * Build an index from many sets of hashes at once
+     */
+    pub fn build<I>(hash_length: u32, substrings: u32, items: I) -> PerceptualHashIndex<V>
+    where
+        I: IntoIterator<Item = (PerceptualHashes, V)>,
+    {
+        let mut index = PerceptualHashIndex::new(hash_length, substrings);
+        let items = items.into_iter();
+        index.entries.reserve(items.size_hint().0);
+        for (hashes, value) in items {
+            index.insert(hashes, value);
+        }
+        index
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn insert(&mut self, hashes: PerceptualHashes, value: V) {
+        let position = self.entries.len();
+        self.ahash.insert(hashes.ahash, position);
+        self.dhash.insert(hashes.dhash, position);
+        self.phash.insert(hashes.phash, position);
+        self.entries.push((hashes, value));
+    }
+
+    /**
+     * Get the hashes and value stored at a position, positions are assigned
+     * in insertion order
+     */
+    pub fn get(&self, position: usize) -> Option<(&PerceptualHashes, &V)> {
+        self.entries
+            .get(position)
+            .map(|(hashes, value)| (hashes, value))
+    }
+
+    /**
+     * Iterate over every set of hashes and value in the index
+     */
+    pub fn iter(&self) -> Box<dyn Iterator<Item = (&PerceptualHashes, &V)> + '_> {
+        Box::new(
+            self.entries
+                .iter()
+                .map(|(hashes, value)| (hashes, value)),
+        )
+    }
+
+    /**
+     * Find every entry where a single hash type is within max_distance of the query
+     */
+    pub fn find_within(
+        &self,
+        hashes: &PerceptualHashes,
+        hash_type: &HashType,
+        max_distance: u64,
+    ) -> Vec<Match<'_, PerceptualHashes, V>> {
+        let mut matches: Vec<Match<'_, PerceptualHashes, V>> = self
+            .table(hash_type)
+            .find_positions(hashes.get(hash_type), max_distance)
+            .into_iter()
+            .map(|position| {
+                let (found, value) = &self.entries[position];
+                Match {
+                    distance: calculate_hamming_distance(
+                        hashes.get(hash_type),
+                        found.get(hash_type),
+                    ),
+                    key: found,
+                    value,
+                }
+            })
+            .collect();
+        matches.sort_by_key(|found| found.distance);
+        matches
+    }
+
+    /**
+     * Find every entry the policy considers similar to the query, ordered
+     * from the most to the least similar
+     */
+    pub fn find_similar(
+        &self,
+        hashes: &PerceptualHashes,
+        policy: &SimilarityPolicy,
+    ) -> Vec<SimilarMatch<'_, V>> {
+        let mut similar: Vec<SimilarMatch<'_, V>> = self
+            .find_similar_positions(hashes, policy)
+            .into_iter()
+            .filter_map(|position| {
+                let (found, value) = &self.entries[position];
+                let similarity = policy.compare(hashes, found);
+                if similarity.similar {
+                    Some(SimilarMatch {
+                        similarity,
+                        hashes: found,
+                        value,
+                    })
+                } else {
+                    None
+                }
+            })
+            .collect();
+        similar.sort_by(|first, second| {
+            second
+                .similarity
+                .score
+                .partial_cmp(&first.similarity.score)
+                .unwrap_or(::std::cmp::Ordering::Equal)
+        });
+        similar
+    }
+
+    /**
+     * Get the positions of every entry that could satisfy the policy
+     */
+    pub fn find_similar_positions(
+        &self,
+        hashes: &PerceptualHashes,
+        policy: &SimilarityPolicy,
+    ) -> Vec<usize> {
+        let mut positions: Vec<usize> = Vec::new();
+        for hash_type in &searched_hash_types(policy) {
+            positions.extend(
+                self.table(hash_type)
+                    .find_positions(hashes.get(hash_type), policy.max_distance(hash_type)),
+            );
+        }
+        positions.sort();
+        positions.dedup();
+        positions
+    }
+
+    fn table(&self, hash_type: &HashType) -> &MultiIndexHash<usize> {
+        match *hash_type {
+            HashType::AHash => &self.ahash,
+            HashType::DHash => &self.dhash,
+            HashType::PHash => &self.phash,
+        }
+    }
+}
+
+// Functions //
+
+/**
+ * The hash types that have to be searched to find every entry that could
+ * satisfy a policy
+ */
+pub(crate) fn searched_hash_types(policy: &SimilarityPolicy) -> Vec<HashType> {
+    let hash_types = [HashType::AHash, HashType::DHash, HashType::PHash];
+    match policy.mode {
+        // Every hash has to pass, so the narrowest search finds every candidate
+        CombinationMode::All => hash_types
+            .iter()
+            .min_by_key(|hash_type| policy.max_distance(hash_type))
+            .cloned()
+            .into_iter()
+            .collect(),
+        CombinationMode::Any => hash_types.to_vec(),
+        // Only hashes with a weight can help a vote pass
+        CombinationMode::WeightedVote(quorum) => {
+            let weighted: Vec<HashType> = hash_types
+                .iter()
+                .filter(|hash_type| policy.threshold(hash_type).weight > 0_f64)
+                .cloned()
+                .collect();
+            if quorum > 0_f64 && !weighted.is_empty() {
+                weighted
+            } else {
+                hash_types.to_vec()
+            }
+        }
+    }
+}
+
+/**
+ * Split a hash into the requested number of (first bit, number of bits)
+ * substrings. The bits are spread as evenly as possible with the earlier
+ * substrings taking the remainder.
+ */
+pub(crate) fn substring_ranges(hash_length: u32, substrings: u32) -> Vec<(u32, u32)> {
+    let count = substrings.max(1).min(hash_length);
+    let mut ranges = Vec::with_capacity(count as usize);
+    let mut start = 0;
+    for index in 0..count {
+        let length = hash_length / count + if index < hash_length % count { 1 } else { 0 };
+        ranges.push((start, length));
+        start += length;
+    }
+    ranges
+}
+
+/**
+ * Extract the bits of a substring as a number
+ */
+pub(crate) fn substring(key: u64, (start, length): (u32, u32)) -> u64 {
+    let shifted = key >> start;
+    if length >= 64 {
+        shifted
+    } else {
+        shifted & ((1u64 << length) - 1)
+    }
+}
+
+/**
+ * The number of values of a length in bits within a distance of any value
+ */
+pub(crate) fn count_within(length: u32, distance: u32) -> u64 {
+    // Binomial coefficients for 64 bits overflow u64 part way through the product
+    let mut total = 0u128;
+    let mut combinations = 1u128;
+    for flipped in 0..(distance.min(length) + 1) {
+        if flipped > 0 {
+            combinations = combinations * u128::from(length - flipped + 1) / u128::from(flipped);
+        }
+        total += combinations;
+    }
+    total.min(u128::from(u64::MAX)) as u64
+}
+
+/**
+ * Call f with every value of a length in bits within a distance of value
+ */
+pub(crate) fn for_each_within<F: FnMut(u64)>(value: u64, length: u32, distance: u32, f: &mut F) {
+    f(value);
+    if distance > 0 {
+        flip_bits(value, 0, length, distance, f);
+    }
+}
+
+fn flip_bits<F: FnMut(u64)>(value: u64, first_bit: u32, length: u32, remaining: u32, f: &mut F) {
+    for bit in first_bit..length {
+        let flipped = value ^ (1u64 << bit);
+        f(flipped);
+        if remaining > 1 {
+            flip_bits(flipped, bit + 1, length, remaining - 1, f);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hash::PerceptualHashes;
+    use index::HammingDistance;
+    use similarity::{CombinationMode, SimilarityPolicy};
+
+    use super::{count_within, for_each_within, MultiIndexHash, PerceptualHashIndex};
+
+    fn keys(count: usize) -> Vec<u64> {
+        let mut state = 0x9e37_79b9_7f4a_7c15u64;
+        (0..count)
+            .map(|_| {
+                state = state
+                    .wrapping_mul(6_364_136_223_846_793_005)
+                    .wrapping_add(1_442_695_040_888_963_407);
+                state
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_enumerates_every_nearby_value() {
+        let mut values = Vec::new();
+        for_each_within(0b1010, 8, 2, &mut |value| values.push(value));
+        assert_eq!(values.len() as u64, count_within(8, 2));
+        values.sort();
+        values.dedup();
+        assert_eq!(values.len() as u64, count_within(8, 2));
+        assert!(values.iter().all(|value| (value ^ 0b1010u64).count_ones() <= 2));
+    }
+
+    #[test]
+    fn test_find_within_matches_linear_scan() {
+        let mut keys = keys(2000);
+        // Plant some near duplicates so small radii have results
+        for index in 0..50 {
+            let near = keys[index] ^ (0b1001 << index);
+            keys.push(near);
+        }
+        let index = MultiIndexHash::build(64, 4, keys.iter().cloned().zip(0..keys.len()));
+        assert_eq!(index.len(), keys.len());
+        for max_distance in [0u64, 2, 5, 10, 20].iter() {
+            for query in keys.iter().take(60) {
+                let mut found: Vec<usize> = index
+                    .find_within(*query, *max_distance)
+                    .iter()
+                    .map(|found| *found.value)
+                    .collect();
+                found.sort();
+                let expected: Vec<usize> = (0..keys.len())
+                    .filter(|&position| keys[position].hamming_distance(query) <= *max_distance)
+                    .collect();
+                assert_eq!(found, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn test_single_table_matches_linear_scan() {
+        assert_eq!(count_within(64, 32), 10_139_684_107_326_071_075);
+        assert_eq!(count_within(64, 64), u64::MAX);
+
+        let keys = keys(3000);
+        let index = MultiIndexHash::build(64, 1, keys.iter().cloned().zip(0..keys.len()));
+        for max_distance in [0u64, 2, 30, 40, 63].iter() {
+            for query in keys.iter().take(5) {
+                let mut found: Vec<usize> = index
+                    .find_within(*query, *max_distance)
+                    .iter()
+                    .map(|found| *found.value)
+                    .collect();
+                found.sort();
+                let expected: Vec<usize> = (0..keys.len())
+                    .filter(|&position| keys[position].hamming_distance(query) <= *max_distance)
+                    .collect();
+                assert_eq!(found, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn test_find_similar_uses_policy() {
+        let keys = keys(300);
+        let mut index = PerceptualHashIndex::default();
+        for (position, chunk) in keys.chunks(3).enumerate() {
+            index.insert(
+                PerceptualHashes {
+                    orig_path: format!("{}", position),
+                    ahash: chunk[0],
+                    dhash: chunk[1],
+                    phash: chunk[2],
+                },
+                position,
+            );
+        }
+        let query = PerceptualHashes {
+            orig_path: String::from("query"),
+            ahash: keys[30] ^ 0b11,
+            dhash: keys[31] ^ 0b1,
+            phash: keys[32] ^ 0xff_ffff,
+        };
+
+        let mut policy: SimilarityPolicy = Default::default();
+        assert!(index.find_similar(&query, &policy).is_empty());
+
+        policy.mode = CombinationMode::Any;
+        let similar = index.find_similar(&query, &policy);
+        assert_eq!(similar.len(), 1);
+        assert_eq!(*similar[0].value, 10);
+        assert_eq!(similar[0].similarity.distances.dhash, 1);
+    }
+}
@@ -0,0 +1,90 @@
+// Copyright 2016 Drew Short <drew@sothr.com>.
+//
+// Licensed under the MIT license<LICENSE-MIT or http://opensource.org/licenses/MIT>.
+// This file may not be copied, modified, or distributed except according to those terms.
+
+use hash::{calculate_hamming_distance, PerceptualHashes};
+
+pub mod bktree;
+pub mod file;
+pub mod mih;
+
+// Structs/Enums //
+
+/**
+ * A value found by searching an index and how far its key is from the query
+ */
+#[derive(Debug, PartialEq)]
+pub struct Match<'a, K: 'a, V: 'a> {
+    pub distance: u64,
+    pub key: &'a K,
+    pub value: &'a V,
+}
+
+// Traits //
+
+/**
+ * Anything that can be compared by the number of bits that differ
+ *
+ * Indexes rely on the distance being a metric, it must be zero only for
+ * identical keys, symmetric and satisfy the triangle inequality.
+ */
+pub trait HammingDistance {
+    fn hamming_distance(&self, other: &Self) -> u64;
+}
+
+impl HammingDistance for u64 {
+    fn hamming_distance(&self, other: &u64) -> u64 {
+        calculate_hamming_distance(*self, *other)
+    }
+}
+
+impl HammingDistance for u32 {
+    fn hamming_distance(&self, other: &u32) -> u64 {
+        (self ^ other).count_ones() as u64
+    }
+}
+
+impl HammingDistance for u128 {
+    fn hamming_distance(&self, other: &u128) -> u64 {
+        (self ^ other).count_ones() as u64
+    }
+}
+
+/**
+ * Byte strings of different lengths count every missing bit as different
+ */
+impl HammingDistance for Vec<u8> {
+    fn hamming_distance(&self, other: &Vec<u8>) -> u64 {
+        let common: u64 = self
+            .iter()
+            .zip(other.iter())
+            .map(|(byte1, byte2)| (byte1 ^ byte2).count_ones() as u64)
+            .sum();
+        let longest = if self.len() > other.len() { self } else { other };
+        let extra = longest.len() - self.len().min(other.len());
+        common + extra as u64 * 8
+    }
+}
+
+/**
+ * The combined distance of all three hashes
+ */
+impl HammingDistance for PerceptualHashes {
+    fn hamming_distance(&self, other: &PerceptualHashes) -> u64 {
+        calculate_hamming_distance(self.ahash, other.ahash)
+            + calculate_hamming_distance(self.dhash, other.dhash)
+            + calculate_hamming_distance(self.phash, other.phash)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::HammingDistance;
+
+    #[test]
+    fn test_byte_string_distance() {
+        assert_eq!(vec![0u8, 0xff].hamming_distance(&vec![0u8, 0x0f]), 4);
+        assert_eq!(vec![0u8].hamming_distance(&vec![0u8, 0u8]), 8);
+    }
+}
@@ -13,12 +13,30 @@ extern crate serde;
 extern crate test;
 
 use std::ffi::CStr;
-use std::path::Path;
+use std::io::{Error, Write};
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::slice;
 
-use cache::Cache;
+use cache::{Cache, CacheMode};
 
+pub mod batch;
 pub mod cache;
+pub mod calibration;
+pub mod cluster;
+#[cfg(unix)]
+pub mod daemon;
 pub mod hash;
+pub mod index;
+pub mod output;
+pub mod report;
+pub mod resolution;
+pub mod robustness;
+pub mod scan;
+pub mod server;
+pub mod similarity;
+#[cfg(target_os = "linux")]
+pub mod watch;
 
 #[repr(C)]
 pub struct PIHash {
@@ -28,23 +46,34 @@ pub struct PIHash {
 impl PIHash {
     /**
      * Create a new pihash library, and initialize a cache of a path is passed.
-     * If none is passed then no cache is initialized or used with the library
+     * If none is passed, or the cache is disabled, then no cache is
+     * initialized or used with the library
      */
-    pub fn new(cache_path: Option<&str>) -> PIHash {
-        match cache_path {
-            Some(path) => {
-                let cache = Cache {
-                    cache_dir: String::from(path),
-                    use_cache: true,
-                };
-                match cache.init() {
-                    Ok(_) => PIHash { cache: Some(cache) },
-                    Err(e) => {
-                        println!("Error creating library with cache: {}", e);
-                        PIHash { cache: None }
-                    }
+    pub fn new(cache_path: Option<&str>, mode: CacheMode) -> PIHash {
+        let cache = cache_path
+            .filter(|_| mode != CacheMode::Disabled)
+            .map(|cache_path| {
+                let mut cache = Cache::from_directory(cache_path);
+                cache.set_mode(mode);
+                cache
+            });
+        PIHash::with_cache(cache)
+    }
+
+    /**
+     * Create a new pihash library using a cache that has been set up, such
+     * as one with another backend. The cache is initialized before use, and
+     * dropped if that fails.
+     */
+    pub fn with_cache(cache: Option<Cache>) -> PIHash {
+        match cache {
+            Some(cache) => match cache.init() {
+                Ok(_) => PIHash { cache: Some(cache) },
+                Err(e) => {
+                    eprintln!("Error creating library with cache: {}", e);
+                    PIHash { cache: None }
                 }
-            }
+            },
             None => PIHash { cache: None },
         }
     }
@@ -58,6 +87,84 @@ impl PIHash {
         hash::get_perceptual_hash(&path, &precision, &hash_type, &self.cache)
     }
 
+    pub fn get_perceptual_hashes(
+        &self,
+        path: &Path,
+        precision: &hash::Precision,
+    ) -> hash::PerceptualHashes {
+        hash::get_perceptual_hashes(path, precision, &self.cache)
+    }
+
+    /**
+     * Get all the hashes of an image, failing if it can't be read or decoded
+     */
+    pub fn try_get_perceptual_hashes(
+        &self,
+        path: &Path,
+        precision: &hash::Precision,
+    ) -> Result<hash::PerceptualHashes, Error> {
+        hash::try_get_perceptual_hashes(path, precision, &self.cache)
+    }
+
+    /**
+     * Keep up to `capacity` cached values in memory in front of the cache
+     * for processes that hash the same images again and again. Has no
+     * effect without a cache.
+     */
+    pub fn keep_in_memory(&mut self, capacity: usize) {
+        if let Some(ref mut cache) = self.cache {
+            cache.keep_in_memory(capacity);
+        }
+    }
+
+    /**
+     * If results are being cached
+     */
+    pub fn has_cache(&self) -> bool {
+        self.cache.is_some()
+    }
+
+    /**
+     * Hash many images across a pool of threads. Results are returned in the
+     * order they complete, each with its own error if it couldn't be hashed.
+     */
+    pub fn hash_batch<I: IntoIterator<Item = batch::BatchInput>>(
+        &self,
+        inputs: I,
+        config: &batch::BatchConfig,
+    ) -> batch::BatchResults<I::IntoIter> {
+        batch::hash_batch(&self.cache, inputs, config)
+    }
+
+    /**
+     * Write an HTML page showing duplicate clusters, with thumbnails that are
+     * kept in the cache between reports
+     */
+    pub fn write_duplicate_report<W: Write>(
+        &self,
+        writer: &mut W,
+        hashes: &[hash::PerceptualHashes],
+        clusters: &[report::ReportCluster],
+        policy: &similarity::SimilarityPolicy,
+        hash_types: &[hash::HashType],
+    ) -> Result<(), Error> {
+        report::write_html_report(writer, hashes, clusters, policy, hash_types, &self.cache)
+    }
+
+    /**
+     * Keep an index file up to date with the images in a directory, hashing
+     * through the cache of the library
+     */
+    #[cfg(target_os = "linux")]
+    pub fn watch(
+        &self,
+        root: &Path,
+        index: index::file::IndexFile,
+        config: watch::WatchConfig,
+    ) -> Result<watch::IndexWatcher, Error> {
+        watch::IndexWatcher::new(root, index, self.cache.clone(), config)
+    }
+
     pub fn get_pihashes(&self, path: &Path) -> hash::PerceptualHashes {
         hash::get_perceptual_hashes(&path, &hash::Precision::Medium, &self.cache)
     }
@@ -109,12 +216,41 @@ pub extern "C" fn ext_init(cache_path_char: *const libc::c_char) -> *const libc:
             Err(_) => None,
         };
         // println!("Created new lib, with cache at {}", path_str.unwrap());
-        let lib = Box::new(PIHash::new(path_str));
+        let lib = Box::new(PIHash::new(path_str, CacheMode::ReadWrite));
         let ptr = Box::into_raw(lib) as *mut libc::c_void;
         ptr
     }
 }
 
+/**
+ * Like ext_init, with the cache used in a mode: 0 disabled, 1 read-only,
+ * 2 write-only or 3 read-write. Returns NULL for any other mode or a null
+ * path.
+ *
+ * # Safety
+ *
+ * cache_path_char has to be null or a nul terminated string
+ */
+#[no_mangle]
+pub unsafe extern "C" fn ext_init_with_cache_mode(
+    cache_path_char: *const libc::c_char,
+    mode: u32,
+) -> *const libc::c_void {
+    if cache_path_char.is_null() {
+        return ptr::null();
+    }
+    let mode = match mode {
+        0 => CacheMode::Disabled,
+        1 => CacheMode::ReadOnly,
+        2 => CacheMode::WriteOnly,
+        3 => CacheMode::ReadWrite,
+        _ => return ptr::null(),
+    };
+    let path_cstr = CStr::from_ptr(cache_path_char);
+    let lib = Box::new(PIHash::new(path_cstr.to_str().ok(), mode));
+    Box::into_raw(lib) as *const libc::c_void
+}
+
 #[no_mangle]
 pub extern "C" fn ext_free(raw_lib: *const libc::c_void) {
     unsafe {
@@ -166,11 +302,7 @@ pub extern "C" fn ext_get_pihashes(lib: &PIHash, path_char: *const libc::c_char)
         let image_path = get_str_from_cstr(path_str);
         let path = Path::new(&image_path);
         let pihashes = lib.get_pihashes(path);
-        Box::into_raw(Box::new(PIHashes {
-            ahash: pihashes.ahash,
-            dhash: pihashes.dhash,
-            phash: pihashes.phash,
-        }))
+        Box::into_raw(Box::new(get_pihashes(&pihashes)))
     }
 }
 
@@ -181,11 +313,237 @@ pub extern "C" fn ext_free_pihashes(raw_pihashes: *const libc::c_void) {
     }
 }
 
+#[repr(C)]
+pub struct PIHashMatch {
+    id: u64,
+    score: f64,
+}
+
+/**
+ * Connect to a daemon, returning null if it can't be reached
+ *
+ * # Safety
+ *
+ * socket_path_char has to be null or a nul terminated string
+ */
+#[cfg(unix)]
+#[no_mangle]
+pub unsafe extern "C" fn ext_daemon_connect(
+    socket_path_char: *const libc::c_char,
+) -> *const libc::c_void {
+    if socket_path_char.is_null() {
+        return ptr::null();
+    }
+    let path_cstr = CStr::from_ptr(socket_path_char);
+    let client = path_cstr
+        .to_str()
+        .ok()
+        .and_then(|path| daemon::Client::connect(Path::new(path)).ok());
+    match client {
+        Some(client) => Box::into_raw(Box::new(client)) as *const libc::c_void,
+        None => ptr::null(),
+    }
+}
+
+/**
+ * Close a connection to a daemon
+ *
+ * # Safety
+ *
+ * raw_client has to be null or a client from ext_daemon_connect that
+ * hasn't already been disconnected
+ */
+#[cfg(unix)]
+#[no_mangle]
+pub unsafe extern "C" fn ext_daemon_disconnect(raw_client: *const libc::c_void) {
+    if !raw_client.is_null() {
+        drop(Box::from_raw(raw_client as *mut daemon::Client));
+    }
+}
+
+/**
+ * Hash an image through a daemon, returning null if it can't be hashed.
+ * The result is freed with ext_free_pihashes.
+ *
+ * # Safety
+ *
+ * client has to be null or a connected client, and path_char null or a nul
+ * terminated string
+ */
+#[cfg(unix)]
+#[no_mangle]
+pub unsafe extern "C" fn ext_daemon_get_pihashes(
+    client: *mut daemon::Client,
+    path_char: *const libc::c_char,
+) -> *mut PIHashes {
+    let client = match client.as_mut() {
+        Some(client) if !path_char.is_null() => client,
+        _ => return ptr::null_mut(),
+    };
+    match CStr::from_ptr(path_char).to_str() {
+        Ok(path) => get_daemon_pihashes(client, batch::BatchInput::Path(PathBuf::from(path))),
+        Err(_) => ptr::null_mut(),
+    }
+}
+
+/**
+ * Hash an encoded image that is already in memory through a daemon
+ *
+ * # Safety
+ *
+ * client has to be null or a connected client, and data null or readable
+ * for length bytes
+ */
+#[cfg(unix)]
+#[no_mangle]
+pub unsafe extern "C" fn ext_daemon_get_pihashes_from_memory(
+    client: *mut daemon::Client,
+    data: *const u8,
+    length: libc::size_t,
+) -> *mut PIHashes {
+    let client = match client.as_mut() {
+        Some(client) if !data.is_null() => client,
+        _ => return ptr::null_mut(),
+    };
+    let data = slice::from_raw_parts(data, length).to_vec();
+    get_daemon_pihashes(
+        client,
+        batch::BatchInput::Bytes {
+            name: String::new(),
+            data,
+        },
+    )
+}
+
+/**
+ * Hash many images through a daemon in one request, filling in a result
+ * for each path. Images that can't be hashed are left as zeros.
+ *
+ * # Returns
+ *
+ * The number of images hashed, or -1 if the daemon couldn't be used
+ *
+ * # Safety
+ *
+ * client has to be null or a connected client. paths_char has to be null
+ * or count nul terminated strings, and results null or room for count
+ * results.
+ */
+#[cfg(unix)]
+#[no_mangle]
+pub unsafe extern "C" fn ext_daemon_get_pihashes_batch(
+    client: *mut daemon::Client,
+    paths_char: *const *const libc::c_char,
+    count: libc::size_t,
+    results: *mut PIHashes,
+) -> i64 {
+    let client = match client.as_mut() {
+        Some(client) if !paths_char.is_null() && !results.is_null() => client,
+        _ => return -1,
+    };
+    let paths = slice::from_raw_parts(paths_char, count);
+    let results = slice::from_raw_parts_mut(results, count);
+    let mut inputs = Vec::with_capacity(count);
+    for path_char in paths {
+        if path_char.is_null() {
+            return -1;
+        }
+        match CStr::from_ptr(*path_char).to_str() {
+            Ok(path) => inputs.push(batch::BatchInput::Path(PathBuf::from(path))),
+            Err(_) => return -1,
+        }
+    }
+    match client.get_perceptual_hashes_batch(inputs, hash::Precision::Medium) {
+        Ok(hashed) => {
+            let mut completed = 0;
+            for (result, hashes) in results.iter_mut().zip(hashed) {
+                *result = match hashes {
+                    Ok(hashes) => {
+                        completed += 1;
+                        get_pihashes(&hashes)
+                    }
+                    Err(_) => PIHashes {
+                        ahash: 0,
+                        dhash: 0,
+                        phash: 0,
+                    },
+                };
+            }
+            completed
+        }
+        Err(_) => -1,
+    }
+}
+
+/**
+ * Find the images indexed by a daemon that are similar to an image, most
+ * similar first
+ *
+ * # Returns
+ *
+ * The number of matches written, at most capacity, or -1 on failure
+ *
+ * # Safety
+ *
+ * client has to be null or a connected client, path_char null or a nul
+ * terminated string and matches null or room for capacity matches
+ */
+#[cfg(unix)]
+#[no_mangle]
+pub unsafe extern "C" fn ext_daemon_query(
+    client: *mut daemon::Client,
+    path_char: *const libc::c_char,
+    matches: *mut PIHashMatch,
+    capacity: libc::size_t,
+) -> i64 {
+    let client = match client.as_mut() {
+        Some(client) if !path_char.is_null() && !matches.is_null() => client,
+        _ => return -1,
+    };
+    if capacity == 0 {
+        return 0;
+    }
+    let path = match CStr::from_ptr(path_char).to_str() {
+        Ok(path) => PathBuf::from(path),
+        Err(_) => return -1,
+    };
+    let limit = capacity.min(u32::MAX as usize) as u32;
+    match client.query(batch::BatchInput::Path(path), limit) {
+        Ok(found) => {
+            let matches = slice::from_raw_parts_mut(matches, capacity);
+            for (target, found) in matches.iter_mut().zip(&found) {
+                *target = PIHashMatch {
+                    id: found.id,
+                    score: found.score,
+                };
+            }
+            found.len() as i64
+        }
+        Err(_) => -1,
+    }
+}
+
+#[cfg(unix)]
+fn get_daemon_pihashes(client: &mut daemon::Client, input: batch::BatchInput) -> *mut PIHashes {
+    match client.get_perceptual_hashes(input, hash::Precision::Medium) {
+        Ok(hashes) => Box::into_raw(Box::new(get_pihashes(&hashes))),
+        Err(_) => ptr::null_mut(),
+    }
+}
+
+fn get_pihashes(hashes: &hash::PerceptualHashes) -> PIHashes {
+    PIHashes {
+        ahash: hashes.ahash,
+        dhash: hashes.dhash,
+        phash: hashes.phash,
+    }
+}
+
 fn get_str_from_cstr(path_str: &CStr) -> &str {
     match path_str.to_str() {
         Ok(result) => result,
         Err(e) => {
-            println!(
+            eprintln!(
                 "Error: {}. Unable to parse '{}'",
                 e,
                 to_hex_string(path_str.to_bytes())
@@ -196,7 +554,7 @@ fn get_str_from_cstr(path_str: &CStr) -> &str {
 }
 
 fn to_hex_string(bytes: &[u8]) -> String {
-    println!("length: {}", bytes.len());
+    eprintln!("length: {}", bytes.len());
     let mut strs: Vec<String> = Vec::new();
     for byte in bytes {
         // println!("{:02x}", byte);
@@ -212,7 +570,7 @@ mod tests {
     use std::fs;
     use std::path::Path;
 
-    use cache;
+    use cache::{self, CacheMode};
     use hash;
     use hash::{PerceptualHash, PerceptualHashes};
 
@@ -220,8 +578,15 @@ mod tests {
     #[cfg(feature = "bench")]
     use super::test::Bencher;
 
-    thread_local!(static LIB: PIHash = PIHash::new(Some(cache::DEFAULT_CACHE_DIR)));
-    thread_local!(static NO_CACHE_LIB: PIHash = PIHash::new(None));
+    thread_local!(static LIB: PIHash =
+        PIHash::new(Some(cache::DEFAULT_CACHE_DIR), CacheMode::ReadWrite));
+    thread_local!(static NO_CACHE_LIB: PIHash = PIHash::new(None, CacheMode::Disabled));
+
+    #[test]
+    fn test_library_can_be_shared_between_threads() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<PIHash>();
+    }
 
     #[test]
     fn test_can_get_test_images() {
@@ -668,11 +1033,54 @@ mod tests {
         test_images(&sample_hashes);
     }
 
+    #[test]
+    fn test_init_with_cache_mode() {
+        use std::ffi::CString;
+        use std::ptr;
+
+        use super::{ext_free, ext_init_with_cache_mode};
+
+        let path = CString::new(cache::DEFAULT_CACHE_DIR).unwrap();
+        unsafe {
+            assert!(ext_init_with_cache_mode(ptr::null(), 3).is_null());
+            assert!(ext_init_with_cache_mode(path.as_ptr(), 4).is_null());
+            let lib = ext_init_with_cache_mode(path.as_ptr(), 0);
+            assert!(!lib.is_null());
+            ext_free(lib);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_daemon_functions_reject_null_pointers() {
+        use std::ptr;
+
+        use super::{
+            ext_daemon_connect, ext_daemon_disconnect, ext_daemon_get_pihashes,
+            ext_daemon_get_pihashes_batch, ext_daemon_get_pihashes_from_memory, ext_daemon_query,
+        };
+
+        unsafe {
+            assert!(ext_daemon_connect(ptr::null()).is_null());
+            ext_daemon_disconnect(ptr::null());
+            assert!(ext_daemon_get_pihashes(ptr::null_mut(), ptr::null()).is_null());
+            assert!(ext_daemon_get_pihashes_from_memory(ptr::null_mut(), ptr::null(), 0).is_null());
+            assert_eq!(
+                ext_daemon_get_pihashes_batch(ptr::null_mut(), ptr::null(), 0, ptr::null_mut()),
+                -1
+            );
+            assert_eq!(
+                ext_daemon_query(ptr::null_mut(), ptr::null(), ptr::null_mut(), 1),
+                -1
+            );
+        }
+    }
+
     #[cfg(feature = "bench")]
     #[bench]
     fn bench_with_cache(bench: &mut Bencher) -> () {
         // Prep_library
-        let lib = PIHash::new(Some(cache::DEFAULT_CACHE_DIR));
+        let lib = PIHash::new(Some(cache::DEFAULT_CACHE_DIR), CacheMode::ReadWrite);
 
         // Setup the caches to make sure we're good to properly bench
         // All pihashes so that the matrices are pulled from cache as well
@@ -695,7 +1103,7 @@ mod tests {
     #[bench]
     fn bench_without_cache(bench: &mut Bencher) -> () {
         // Prep_library
-        let lib = PIHash::new(None);
+        let lib = PIHash::new(None, CacheMode::Disabled);
 
         bench.iter(|| {
             lib.get_perceptual_hash(
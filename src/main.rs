@@ -9,13 +9,40 @@ extern crate rustc_serialize;
 #[macro_use]
 extern crate serde_derive;
 
-use std::path::Path;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
 use docopt::Docopt;
 
+use pihash::batch::{BatchConfig, BatchInput};
+use pihash::cache::{
+    Cache, CacheCounts, CacheEntryStats, CacheLimits, CacheMode, CacheStatus, MemoryBackend,
+    StoreBackend,
+};
+#[cfg(unix)]
+use pihash::daemon::Daemon;
+use pihash::hash::{HashType, PerceptualHashes, Precision};
+use pihash::index::file::IndexFile;
+use pihash::output::{OutputFormat, Record, RecordWriter, Value};
+use pihash::report::ReportCluster;
+use pihash::cluster::DuplicateCluster;
+use pihash::resolution::{
+    plan_resolution, ClusterResolution, DuplicateAction, KeepPolicy, ResolutionStep,
+};
+use pihash::scan::{GlobPattern, ScanConfig};
+use pihash::server::{Server, ServerConfig};
+use pihash::similarity::{Similarity, SimilarityPolicy};
+#[cfg(target_os = "linux")]
+use pihash::watch::{WatchConfig, WatchEvent};
+
 // Getting the version information from cargo during compile time
 const VERSION: &'static str = env!("CARGO_PKG_VERSION");
 
+// The file in the cache directory used by the store backend
+const CACHE_STORE_FILE: &str = "cache.store";
+
 // The usage description
 const USAGE: &'static str = "
 Perceptual Image Hashing (pihash)
@@ -23,7 +50,116 @@ Calculate the perceptual hash values for an input or compare the
 input file to a set of other images and return a list of the similar
 images.
 
+Hash prints the hashes of every file, and compare prints the distances
+between every pair of images.
+
+Dedup scans directories and prints the groups of near duplicate images,
+marking the image that is kept with a *. The kept image is the one most
+like the rest of its group unless --keep picks another (representative,
+largest-resolution, largest-file, oldest, shortest-path or preferred,
+which keeps an image under the --prefer directory). The --action applied
+to the other images is report, quarantine (move under the --quarantine
+directory, keeping their path below the scanned directory), delete,
+hardlink or symlink (replace with a link to the kept image). Anything
+but report is always previewed first. That is all the --dry-run option
+does, while with --apply the changes are made once the preview is
+confirmed, or without asking if --yes is also given. An HTML page
+showing each group with thumbnails and what will happen to every image
+can be written with --report.
+
+Index build scans directories into a new index file, index add appends
+images to an existing one and index query finds the indexed images that
+are similar to each image. Queries and additions use the precision the
+index was built with.
+
+Cache stats counts the entries of each kind, the hits and misses of
+every run that used the cache and the space it takes on disk. Cache
+verify checks that every entry can be read and matches its checksum, and
+cache clean deletes the cache. Cache evict removes values until the
+cache is within its --cache-max-bytes and --cache-max-entries limits,
+least recently used (lru) or least frequently used (lfu) first as picked
+by --eviction. Limits are also applied as other commands run. Cache
+prune removes the values of anything but the images under the given
+directories. Cache export writes every value to a single archive file,
+which cache import adds to another cache, so that new machines can start
+with a warm cache.
+
+The cache keeps a file per value under the --cache directory, or a
+single cache.store file in it with --cache-backend=store, which suits
+filesystems where many small files are slow but can only be open in one
+process at a time. Any number of processes, even on different machines
+sharing it over NFS, can use a directory of files. A directory is only
+used as a cache if it is empty or already marked as one by its
+cache.meta file, and clean only removes what the cache made. Values kept
+by older versions are moved into the current version when the cache is
+opened, dropping those that can no longer be used. A memory cache lasts
+only as long as the process, holding up to --memory-entries values.
+Files are identified in the cache by a digest of their contents, sha1 or
+the much faster xxh64. With --trust-metadata the digest of a file is
+remembered by its device, inode, size and modification time, so
+unchanged files aren't read again. With --cache-mode=read-only values
+are looked up in the cache but it is never changed, so a cache built
+elsewhere can be shared from a read-only mount, though it must already
+be a cache. Write-only makes every value again and replaces the cached
+one, refreshing the cache, and disabled is the same as --nocache. The
+cache commands follow the mode too, so a cache that can't be written can
+only be inspected with stats, verify and export.
+
+Calibrate reads a file of 'first,second,label' lines, where the label
+is duplicate or not-duplicate, and reports how well each hash separates
+the duplicates along with a recommended similarity policy.
+
+Robustness applies a set of transformations to every image in a
+directory of originals (the test_images corpus by default) and reports
+how far each hash moves at every precision.
+
+Scan recursively finds the images under a directory, skipping anything
+excluded by the filters or a .pihashignore file, and prints the hashes
+of each image as it is completed. Lists are comma separated.
+
+Watch keeps an index of the images under a directory up to date as files
+are added, changed and deleted, reporting every new image that is a near
+duplicate of one already indexed. The index is created at the --precision
+if it doesn't exist. Use ndjson to read the events as they happen.
+
+Serve answers HTTP requests on the --listen address, hashing images sent
+in a request body (or named by {\"path\": ...} in a JSON body) and
+querying, adding to and removing from the --index, which is created at
+the --precision if it doesn't exist. Requests are GET /health, POST
+/hash, POST /query, POST /index, GET /index/<id> and DELETE /index/<id>.
+Each of the --threads handles one connection at a time, and the rest
+wait their turn.
+
+Daemon answers the binary protocol of the library (and the ext_daemon_*
+functions of ffi/pihash.h) on a Unix domain socket, hashing images and
+querying the --index over connections that stay open. The daemon keeps
+up to --memory-entries prepared images decoded in memory.
+
+Every command can print json (an array of objects), ndjson (an object
+per line), csv or tsv instead of text. Field names are the same across
+formats, and comparisons include the distance and score of each hash as
+<hash>_distance and <hash>_score. Errors are always printed to stderr.
+
 Usage:
+    pihash [options] hash <files>...
+    pihash [options] compare <images>...
+    pihash [options] dedup <dirs>...
+    pihash [options] index build <index> <dirs>...
+    pihash [options] index query <index> <images>...
+    pihash [options] index add <index> <images>...
+    pihash [options] cache stats
+    pihash [options] cache clean
+    pihash [options] cache verify
+    pihash [options] cache evict
+    pihash [options] cache prune <dirs>...
+    pihash [options] cache export <archive>
+    pihash [options] cache import <archive>
+    pihash [options] calibrate <pairs>
+    pihash [options] robustness [<originals>]
+    pihash [options] scan <root>
+    pihash [options] watch <dir>
+    pihash [options] serve
+    pihash [options] daemon <socket>
     pihash [options] <path> [<comparison>...]
     pihash (--help | --version)
 
@@ -34,6 +170,38 @@ Options:
     -d, --dhash     Include an dhash calculation.
     -p, --phash     Include an phash calculation.
     -n, --nocache  Disable caching behavior.
+    --cache=<dir>            Directory of the cache [default: ./.hash_cache].
+    --cache-backend=<kind>   How the cache keeps values: files, memory or store [default: files].
+    --cache-mode=<mode>      Use of the cache: read-write, read-only, write-only or disabled [default: read-write].
+    --digest=<algorithm>     Digest identifying files in the cache: sha1 or xxh64 [default: sha1].
+    --trust-metadata         Reuse the digests of files whose metadata hasn't changed.
+    --cache-max-bytes=<n>    Bytes the cache may grow to.
+    --cache-max-entries=<n>  Most values the cache may keep.
+    --eviction=<policy>      Values evicted first: lru or lfu [default: lru].
+    --algorithm=<list>       Hashes to use: ahash, dhash and/or phash. All of them by default.
+    --precision=<precision>  Precision of the hashes: low, medium or high [default: medium].
+    --extensions=<list>      Extensions of the images to scan [default: jpg,jpeg,png,gif,bmp,tiff].
+    --include=<globs>        Only scan files matching one of these patterns.
+    --exclude=<globs>        Skip files and directories matching these patterns.
+    --min-size=<bytes>       Skip files smaller than this.
+    --max-size=<bytes>       Skip files larger than this.
+    --min-dimensions=<WxH>   Skip images smaller than this.
+    --max-dimensions=<WxH>   Skip images larger than this.
+    --follow-symlinks        Follow symbolic links while scanning.
+    --no-ignore              Don't read .pihashignore files.
+    --threads=<threads>      Number of threads to hash or serve with, one per core by default.
+    --format=<format>        Output format: text, json, ndjson, csv or tsv [default: text].
+    --action=<action>        What dedup does with duplicates [default: report].
+    --keep=<policy>          Which duplicate dedup keeps [default: representative].
+    --prefer=<dir>           Directory to keep duplicates from with --keep=preferred.
+    --quarantine=<dir>       Directory to move duplicates into with --action=quarantine.
+    --dry-run                Show what dedup would do without changing anything.
+    --report=<file>          Write an HTML report of the duplicates found by dedup.
+    --index=<file>           Index file used by watch and serve [default: ./pihash.index].
+    --listen=<address>       Address serve listens on [default: 127.0.0.1:8080].
+    --memory-entries=<n>     Values the daemon or a memory cache keeps [default: 1024].
+    --apply                  Let dedup change files once the preview is confirmed.
+    --yes                    Apply dedup changes without asking for confirmation.
 ";
 
 #[derive(Debug, Deserialize)]
@@ -45,6 +213,131 @@ struct Args {
     arg_path: String,
     arg_comparison: Vec<String>,
     flag_nocache: bool,
+    flag_cache: String,
+    flag_cache_backend: String,
+    flag_cache_mode: String,
+    flag_digest: String,
+    flag_trust_metadata: bool,
+    flag_cache_max_bytes: Option<u64>,
+    flag_cache_max_entries: Option<u64>,
+    flag_eviction: String,
+    flag_algorithm: Option<String>,
+    flag_precision: String,
+    cmd_hash: bool,
+    arg_files: Vec<String>,
+    cmd_compare: bool,
+    arg_images: Vec<String>,
+    cmd_dedup: bool,
+    arg_dirs: Vec<String>,
+    cmd_index: bool,
+    cmd_build: bool,
+    cmd_query: bool,
+    cmd_add: bool,
+    arg_index: String,
+    cmd_cache: bool,
+    cmd_stats: bool,
+    cmd_clean: bool,
+    cmd_verify: bool,
+    cmd_evict: bool,
+    cmd_prune: bool,
+    cmd_export: bool,
+    cmd_import: bool,
+    arg_archive: String,
+    cmd_calibrate: bool,
+    arg_pairs: String,
+    cmd_robustness: bool,
+    arg_originals: Option<String>,
+    cmd_scan: bool,
+    arg_root: String,
+    cmd_watch: bool,
+    arg_dir: String,
+    cmd_serve: bool,
+    cmd_daemon: bool,
+    arg_socket: String,
+    flag_index: String,
+    flag_listen: String,
+    flag_memory_entries: usize,
+    flag_extensions: String,
+    flag_include: Option<String>,
+    flag_exclude: Option<String>,
+    flag_min_size: Option<u64>,
+    flag_max_size: Option<u64>,
+    flag_min_dimensions: Option<String>,
+    flag_max_dimensions: Option<String>,
+    flag_follow_symlinks: bool,
+    flag_no_ignore: bool,
+    flag_threads: Option<usize>,
+    flag_format: String,
+    flag_action: String,
+    flag_keep: String,
+    flag_prefer: Option<String>,
+    flag_quarantine: Option<String>,
+    flag_dry_run: bool,
+    flag_report: Option<String>,
+    flag_apply: bool,
+    flag_yes: bool,
+}
+
+/**
+ * Sends results to stdout as either human readable text or records in one
+ * of the machine readable formats
+ */
+struct Output {
+    writer: Option<RecordWriter<io::Stdout>>,
+}
+
+impl Output {
+    fn new(args: &Args) -> Output {
+        if args.flag_format.to_lowercase() == "text" {
+            return Output { writer: None };
+        }
+        match args.flag_format.parse::<OutputFormat>() {
+            Ok(format) => Output {
+                writer: Some(RecordWriter::new(io::stdout(), format)),
+            },
+            Err(e) => exit_with_error(format!("Error: {}", e)),
+        }
+    }
+
+    fn is_text(&self) -> bool {
+        self.writer.is_none()
+    }
+
+    /**
+     * Print text that has no equivalent record
+     */
+    fn text<F: FnOnce() -> String>(&mut self, text: F) {
+        if self.is_text() {
+            println!("{}", text());
+        }
+    }
+
+    /**
+     * Write a record that has no equivalent text
+     */
+    fn record(&mut self, record: Record) {
+        if let Some(ref mut writer) = self.writer {
+            if let Err(e) = writer.write(&record) {
+                exit_with_error(format!("Unable to write output: {}", e));
+            }
+        }
+    }
+
+    fn emit<F: FnOnce() -> String>(&mut self, record: Record, text: F) {
+        if self.is_text() {
+            self.text(text);
+        } else {
+            self.record(record);
+        }
+    }
+
+    fn finish(&mut self) {
+        if let Some(ref mut writer) = self.writer {
+            if let Err(e) = writer.finish() {
+                exit_with_error(format!("Unable to write output: {}", e));
+            }
+        }
+    }
 }
 
 fn main() {
@@ -58,90 +351,1137 @@ fn main() {
         std::process::exit(0);
     }
 
-    let cache = if args.flag_nocache {
+    let mut output = Output::new(&args);
+
+    // Inspecting the cache shouldn't create it
+    if args.cmd_cache {
+        cache(&args, &mut output);
+        output.finish();
+        return;
+    }
+
+    let mode = parse_cache_mode(&args);
+    let cache = if mode == CacheMode::Disabled {
         None
     } else {
-        Some(pihash::cache::DEFAULT_CACHE_DIR)
+        let mut cache = open_cache(&args);
+        cache.set_mode(mode);
+        init_cache(&cache);
+        if cache.limits().is_some() && mode.can_write() {
+            if let Err(e) = cache.enforce_limits() {
+                eprintln!("Unable to keep the cache within its limits: {}", e);
+            }
+        }
+        Some(cache)
     };
 
-    // Init the hashing library
-    let lib = pihash::PIHash::new(cache);
+    // Init the hashing library, keeping the cache to save its counters
+    let lib = pihash::PIHash::with_cache(cache.clone());
+
+    // Servers take the library, to share it between connections
+    if args.cmd_serve {
+        serve(lib, &args);
+        return;
+    } else if args.cmd_daemon {
+        daemon(lib, &args);
+        return;
+    }
 
     // println!("{:?}", args);
-    if args.arg_comparison.len() > 0 {
+    if args.cmd_hash {
+        hash(&lib, &args, &mut output);
+    } else if args.cmd_compare {
+        compare(&lib, &args, &mut output);
+    } else if args.cmd_dedup {
+        dedup(&lib, &args, &mut output);
+    } else if args.cmd_index {
+        index(&lib, &args, &mut output);
+    } else if args.cmd_calibrate {
+        calibrate(&lib, &args, &mut output);
+    } else if args.cmd_robustness {
+        robustness(&args, &mut output);
+    } else if args.cmd_scan {
+        scan(&lib, &args, &mut output);
+    } else if args.cmd_watch {
+        watch(&lib, &args, &mut output);
+    } else if !args.arg_comparison.is_empty() {
+        let precision = parse_precision(&args);
+        let policy = get_policy(&args, precision);
+        let hash_types = get_requested_hash_types(&args);
         let base_image_path = Path::new(&args.arg_path);
-        let base_hash = get_requested_perceptual_hashes(&lib, &base_image_path, &args);
+        let base_hash = get_requested_perceptual_hashes(&lib, base_image_path, &precision, &args);
 
-        let mut comparison_hashes: Vec<pihash::hash::PerceptualHashes> = Vec::new();
+        let mut comparison_hashes: Vec<PerceptualHashes> = Vec::new();
         for index in 0..args.arg_comparison.len() {
             comparison_hashes.push(get_requested_perceptual_hashes(
                 &lib,
-                &Path::new(&args.arg_comparison[index]),
+                Path::new(&args.arg_comparison[index]),
+                &precision,
                 &args,
             ));
         }
 
         let mut similar_images: Vec<String> = Vec::new();
         for comparison_hash in comparison_hashes {
-            if base_hash.similar(&comparison_hash) {
+            if base_hash.orig_path == comparison_hash.orig_path {
+                continue;
+            }
+            let similarity = policy.compare(&base_hash, &comparison_hash);
+            output.record(
+                Record::new()
+                    .with("first", base_hash.orig_path.as_str())
+                    .with("second", comparison_hash.orig_path.as_str())
+                    .with_similarity(&similarity, &policy, &hash_types),
+            );
+            if similarity.similar {
                 similar_images.push(String::from(&comparison_hash.orig_path));
             }
         }
 
-        println!("Base Image:");
-        println!("{}", base_image_path.to_str().unwrap());
-        println!("Similar Images:");
-        for similar_image in similar_images {
-            println!("{}", similar_image);
-        }
+        output.text(|| {
+            let mut lines = vec![
+                String::from("Base Image:"),
+                String::from(base_image_path.to_str().unwrap()),
+                String::from("Similar Images:"),
+            ];
+            lines.extend(similar_images);
+            lines.join("\n")
+        });
     } else {
+        let precision = parse_precision(&args);
         let image_path = Path::new(&args.arg_path);
-        let hashes = get_requested_perceptual_hashes(&lib, &image_path, &args);
-        let hash_result = format!(
-            r#"
+        let hashes = get_requested_perceptual_hashes(&lib, image_path, &precision, &args);
+        output.emit(
+            Record::from_hashes(&hashes, &get_requested_hash_types(&args)),
+            || {
+                format!(
+                    r#"
 file: {}
 ahash: {}
 dhash: {}
 phash: {}
 "#,
-            hashes.orig_path, hashes.ahash, hashes.dhash, hashes.phash
+                    hashes.orig_path, hashes.ahash, hashes.dhash, hashes.phash
+                )
+            },
         );
-        println!("{}", hash_result);
     }
+    if let Some(ref cache) = cache {
+        if let Err(e) = cache.save_counters() {
+            eprintln!("Unable to save the cache counters: {}", e);
+        }
+    }
+    output.finish();
 }
 
-fn flags_get_all_perceptual_hashes(args: &Args) -> bool {
-    (args.flag_ahash && args.flag_dhash && args.flag_phash)
-        || (!args.flag_ahash && !args.flag_dhash && !args.flag_phash)
+fn hash(lib: &pihash::PIHash, args: &Args, output: &mut Output) {
+    let inputs = args.arg_files.iter().map(|file| BatchInput::from(PathBuf::from(file)));
+    let hash_types = get_requested_hash_types(args);
+    for hashes in hash_images(lib, inputs, parse_precision(args), args) {
+        output.emit(Record::from_hashes(&hashes, &hash_types), || {
+            let mut lines = vec![format!("file: {}", hashes.orig_path)];
+            for hash_type in &hash_types {
+                lines.push(format!("{}: {}", get_hash_name(hash_type), hashes.get(hash_type)));
+            }
+            lines.push(String::new());
+            lines.join("\n")
+        });
+    }
 }
 
-fn get_requested_perceptual_hashes(
-    lib: &pihash::PIHash,
-    image_path: &Path,
-    args: &Args,
-) -> pihash::hash::PerceptualHashes {
-    let ahash = if args.flag_ahash || flags_get_all_perceptual_hashes(&args) {
-        lib.get_ahash(&image_path)
-    } else {
-        0u64
+fn compare(lib: &pihash::PIHash, args: &Args, output: &mut Output) {
+    let precision = parse_precision(args);
+    let policy = get_policy(args, precision);
+    let inputs = args.arg_images.iter().map(|image| BatchInput::from(PathBuf::from(image)));
+    let hashes = hash_images(lib, inputs, precision, args);
+    let hash_types = get_requested_hash_types(args);
+    for first in 0..hashes.len() {
+        for second in (first + 1)..hashes.len() {
+            let first = &hashes[first];
+            let second = &hashes[second];
+            let similarity = policy.compare(first, second);
+            let record = Record::new()
+                .with("first", first.orig_path.as_str())
+                .with("second", second.orig_path.as_str());
+            output.emit(
+                record.with_similarity(&similarity, &policy, &hash_types),
+                || {
+                    format!(
+                        "{} <-> {}: {}",
+                        first.orig_path,
+                        second.orig_path,
+                        describe_similarity(&similarity, &hash_types)
+                    )
+                },
+            );
+        }
+    }
+}
+
+fn dedup(lib: &pihash::PIHash, args: &Args, output: &mut Output) {
+    let precision = parse_precision(args);
+    let policy = get_policy(args, precision);
+    let hash_types = get_requested_hash_types(args);
+    let action = parse_duplicate_action(args);
+    let keep_policy = parse_keep_policy(args);
+    // Nothing is changed without either previewing it or asking for it
+    if action.is_destructive() && !args.flag_dry_run && !args.flag_apply {
+        exit_with_error(format!(
+            "Refusing to {} duplicates without --apply, preview the changes with --dry-run",
+            action
+        ));
+    }
+    let roots: Vec<PathBuf> = args.arg_dirs.iter().map(PathBuf::from).collect();
+
+    let inputs = scan_inputs(&args.arg_dirs, get_scan_config(args));
+    let hashes = hash_images(lib, inputs, precision, args);
+    let paths: Vec<PathBuf> = hashes.iter().map(|hashes| PathBuf::from(&hashes.orig_path)).collect();
+    let clusters = pihash::cluster::find_duplicate_clusters(&hashes, &policy);
+    if clusters.is_empty() {
+        output.text(|| format!("No duplicates found in {} images", hashes.len()));
+    }
+    let mut failed = false;
+    let mut resolutions = Vec::new();
+    for (number, cluster) in clusters.iter().enumerate() {
+        match plan_resolution(cluster, &paths, &keep_policy, &action, &roots) {
+            Ok(resolution) => resolutions.push((number, cluster, resolution)),
+            Err(e) => {
+                eprintln!("Unable to resolve cluster {}: {}", number + 1, e);
+                failed = true;
+            }
+        }
+    }
+
+    // The report shows the plan, so it's written before anything is moved
+    if let Some(ref report_path) = args.flag_report {
+        let report_clusters: Vec<ReportCluster> = resolutions
+            .iter()
+            .map(|&(_, cluster, ref resolution)| ReportCluster {
+                cluster,
+                resolution: Some(resolution),
+            })
+            .collect();
+        let written = File::create(report_path).and_then(|mut file| {
+            lib.write_duplicate_report(&mut file, &hashes, &report_clusters, &policy, &hash_types)
+        });
+        if let Err(e) = written {
+            exit_with_error(format!("Unable to write {}: {}", report_path, e));
+        }
+    }
+
+    // Destructive actions are always previewed, and only applied once confirmed
+    let apply = action.is_destructive() && args.flag_apply && !args.flag_dry_run;
+    if !apply || !args.flag_yes {
+        failed |= emit_resolutions(
+            &resolutions,
+            &hashes,
+            &paths,
+            &policy,
+            &hash_types,
+            false,
+            output,
+        );
+    }
+    if apply && resolutions.iter().any(|(_, _, resolution)| !resolution.steps.is_empty()) {
+        if !args.flag_yes && !confirm(&format!("Really {} the duplicates listed above?", action)) {
+            exit_with_error(String::from("Nothing was changed"));
+        }
+        failed |= emit_resolutions(
+            &resolutions,
+            &hashes,
+            &paths,
+            &policy,
+            &hash_types,
+            true,
+            output,
+        );
+    }
+    if failed {
+        output.finish();
+        std::process::exit(1);
+    }
+}
+
+/**
+ * Print what happens to every member of the clusters, applying the steps
+ * when asked to
+ *
+ * # Returns
+ *
+ * If any step failed
+ */
+fn emit_resolutions(
+    resolutions: &[(usize, &DuplicateCluster, ClusterResolution)],
+    hashes: &[PerceptualHashes],
+    paths: &[PathBuf],
+    policy: &SimilarityPolicy,
+    hash_types: &[HashType],
+    apply: bool,
+    output: &mut Output,
+) -> bool {
+    let mut failed = false;
+    for &(number, cluster, ref resolution) in resolutions {
+        output.text(|| format!("Cluster {} ({} images):", number + 1, cluster.members.len()));
+        let representative = &hashes[cluster.representative];
+        for member in &cluster.members {
+            let step = resolution
+                .steps
+                .iter()
+                .find(|&&(duplicate, _)| duplicate == *member)
+                .map(|(_, step)| step);
+            let (status, detail) = match step {
+                None => (String::from("kept"), String::new()),
+                Some(step) if step.action == DuplicateAction::Report => {
+                    (String::from("reported"), String::new())
+                }
+                Some(step) if !apply => {
+                    (String::from("planned"), format!(" (would {})", describe_step(step)))
+                }
+                Some(step) => match step.apply(&paths[resolution.keep]) {
+                    Ok(_) => (String::from("done"), format!(" ({})", describe_step(step))),
+                    Err(e) => {
+                        eprintln!("Unable to {} {}: {}", step.action, step.path.display(), e);
+                        failed = true;
+                        (String::from("failed"), String::from(" (failed)"))
+                    }
+                },
+            };
+
+            // Each member is described by how far it is from the representative
+            let similarity = policy.compare(&hashes[*member], representative);
+            let record = Record::new()
+                .with("cluster", number + 1)
+                .with("file", hashes[*member].orig_path.as_str())
+                .with("representative", *member == cluster.representative)
+                .with("keep", step.is_none())
+                .with(
+                    "action",
+                    step.map_or(String::from("keep"), |step| format!("{}", step.action)),
+                )
+                .with(
+                    "destination",
+                    step.and_then(|step| step.destination.as_ref())
+                        .map(|destination| destination.to_string_lossy().into_owned()),
+                )
+                .with("status", status);
+            output.emit(
+                record.with_similarity(&similarity, policy, hash_types),
+                || {
+                    let marker = if step.is_none() { "*" } else { " " };
+                    format!("{} {}{}", marker, hashes[*member].orig_path, detail)
+                },
+            );
+        }
+        output.text(String::new);
+    }
+    failed
+}
+
+fn index(lib: &pihash::PIHash, args: &Args, output: &mut Output) {
+    let index_path = Path::new(&args.arg_index);
+    if args.cmd_build {
+        let precision = parse_precision(args);
+        let inputs = scan_inputs(&args.arg_dirs, get_scan_config(args));
+        let hashes = hash_images(lib, inputs, precision, args);
+        let count = hashes.len();
+        let records = hashes.into_iter().map(|hashes| (hashes, Vec::new()));
+        match IndexFile::create(index_path, &precision, records) {
+            Ok(_) => output.emit(
+                Record::new()
+                    .with("index", args.arg_index.as_str())
+                    .with("images", count),
+                || format!("Indexed {} images into {}", count, args.arg_index),
+            ),
+            Err(e) => exit_with_error(format!("Unable to build {}: {}", args.arg_index, e)),
+        }
+        return;
+    }
+
+    let mut index = match IndexFile::open(index_path) {
+        Ok(index) => index,
+        Err(e) => exit_with_error(format!("Unable to open {}: {}", args.arg_index, e)),
+    };
+    let precision = index.precision();
+    let inputs = args.arg_images.iter().map(|image| BatchInput::from(PathBuf::from(image)));
+    let hashes = hash_images(lib, inputs, precision, args);
+    if args.cmd_add {
+        for image_hashes in hashes {
+            match index.append(&image_hashes, &[]) {
+                Ok(id) => output.emit(
+                    Record::new()
+                        .with("file", image_hashes.orig_path.as_str())
+                        .with("id", id),
+                    || format!("Added {} as {}", image_hashes.orig_path, id),
+                ),
+                Err(e) => exit_with_error(format!(
+                    "Unable to add {} to {}: {}",
+                    image_hashes.orig_path, args.arg_index, e
+                )),
+            }
+        }
+    } else if args.cmd_query {
+        let policy = get_policy(args, precision);
+        let hash_types = get_requested_hash_types(args);
+        for image_hashes in hashes {
+            output.text(|| format!("{}:", image_hashes.orig_path));
+            for found in index.query(&image_hashes, &policy) {
+                let record = Record::new()
+                    .with("query", image_hashes.orig_path.as_str())
+                    .with("match", found.record.hashes.orig_path.as_str())
+                    .with("id", found.record.id);
+                output.emit(
+                    record.with_similarity(&found.similarity, &policy, &hash_types),
+                    || {
+                        format!(
+                            "  {} ({})",
+                            found.record.hashes.orig_path,
+                            describe_similarity(&found.similarity, &hash_types)
+                        )
+                    },
+                );
+            }
+        }
+    }
+}
+
+/**
+ * The cache chosen by --cache, --cache-backend, --digest,
+ * --trust-metadata and the limits
+ */
+fn open_cache(args: &Args) -> Cache {
+    let mut cache = match args.flag_cache_backend.as_str() {
+        "files" => Cache::from_directory(&args.flag_cache),
+        "memory" => Cache::new(Arc::new(MemoryBackend::new(args.flag_memory_entries))),
+        "store" => {
+            let path = Path::new(&args.flag_cache).join(CACHE_STORE_FILE);
+            match StoreBackend::open(&path) {
+                Ok(backend) => Cache::new(Arc::new(backend)),
+                Err(e) => exit_with_error(format!("Unable to open {}: {}", path.display(), e)),
+            }
+        }
+        other => exit_with_error(format!("Unknown cache backend: {}", other)),
+    };
+    match args.flag_digest.parse() {
+        Ok(algorithm) => cache.use_digest(algorithm),
+        Err(e) => exit_with_error(e),
+    }
+    cache.trust_file_metadata(args.flag_trust_metadata);
+    if args.flag_cache_max_bytes.is_some() || args.flag_cache_max_entries.is_some() {
+        let policy = match args.flag_eviction.parse() {
+            Ok(policy) => policy,
+            Err(e) => exit_with_error(e),
+        };
+        cache.set_limits(Some(CacheLimits {
+            max_bytes: args.flag_cache_max_bytes,
+            max_entries: args.flag_cache_max_entries,
+            policy,
+        }));
+    }
+    cache
+}
+
+fn parse_cache_mode(args: &Args) -> CacheMode {
+    if args.flag_nocache {
+        return CacheMode::Disabled;
+    }
+    match args.flag_cache_mode.parse() {
+        Ok(mode) => mode,
+        Err(e) => exit_with_error(e),
+    }
+}
+
+/**
+ * Set up the cache, reporting any migration, and refuse to go on with a
+ * directory that isn't one
+ */
+fn init_cache(cache: &Cache) {
+    match cache.init() {
+        Ok(status @ CacheStatus::Migrated { .. }) => {
+            eprintln!("Cache {}: {}", cache.location(), status)
+        }
+        Ok(_) => (),
+        Err(e) => exit_with_error(format!(
+            "Unable to use the cache at {}: {}",
+            cache.location(),
+            e
+        )),
+    }
+}
+
+fn cache(args: &Args, output: &mut Output) {
+    // Inspecting a store that doesn't exist shouldn't create it, though
+    // importing into one does
+    let store_path = Path::new(&args.flag_cache).join(CACHE_STORE_FILE);
+    if args.flag_cache_backend == "store" && !store_path.is_file() && !args.cmd_import {
+        exit_with_error(format!("{} is not a pihash cache", store_path.display()));
+    }
+    let mut cache = open_cache(args);
+    cache.set_mode(parse_cache_mode(args));
+    let location = cache.location();
+    // Values of older versions are moved over before they're inspected
+    if cache.is_initialized() || args.cmd_import {
+        init_cache(&cache);
+    }
+    if args.cmd_stats {
+        match cache.stats() {
+            Ok(stats) => {
+                output.text(|| format!("Cache: {}", location));
+                let mut total = CacheEntryStats::default();
+                let mut total_counts = CacheCounts::default();
+                for &(kind, entry_stats, counts) in [
+                    ("images", &stats.images, &stats.counters.images),
+                    ("matrices", &stats.matrices, &stats.counters.matrices),
+                    ("thumbnails", &stats.thumbnails, &stats.counters.thumbnails),
+                    ("hashes", &stats.hashes, &stats.counters.hashes),
+                    ("digests", &stats.digests, &stats.counters.digests),
+                ]
+                .iter()
+                {
+                    total.entries += entry_stats.entries;
+                    total.bytes += entry_stats.bytes;
+                    total_counts.hits += counts.hits;
+                    total_counts.misses += counts.misses;
+                    output.emit(
+                        Record::new()
+                            .with("cache", location.as_str())
+                            .with("kind", kind)
+                            .with("entries", entry_stats.entries)
+                            .with("bytes", entry_stats.bytes)
+                            .with("hits", counts.hits)
+                            .with("misses", counts.misses)
+                            .with("disk_bytes", Value::Null),
+                        || {
+                            format!(
+                                "{}{}: {} entries, {} bytes, {} hits, {} misses",
+                                kind[..1].to_uppercase(),
+                                &kind[1..],
+                                entry_stats.entries,
+                                entry_stats.bytes,
+                                counts.hits,
+                                counts.misses
+                            )
+                        },
+                    );
+                }
+                // The disk usage includes everything kept beside the values
+                output.emit(
+                    Record::new()
+                        .with("cache", location.as_str())
+                        .with("kind", "total")
+                        .with("entries", total.entries)
+                        .with("bytes", total.bytes)
+                        .with("hits", total_counts.hits)
+                        .with("misses", total_counts.misses)
+                        .with("disk_bytes", stats.disk_bytes),
+                    || format!("Disk usage: {} bytes", stats.disk_bytes),
+                );
+            }
+            Err(e) => exit_with_error(format!("Unable to read {}: {}", location, e)),
+        }
+    } else if args.cmd_clean {
+        // Only delete directories that are recognisably a cache
+        if !cache.is_initialized() {
+            exit_with_error(format!("{} is not a pihash cache", location));
+        }
+        match cache.clean() {
+            Ok(_) => output.emit(
+                Record::new()
+                    .with("cache", location.as_str())
+                    .with("removed", true),
+                || format!("Removed {}", location),
+            ),
+            Err(e) => exit_with_error(format!("Unable to remove {}: {}", location, e)),
+        }
+    } else if args.cmd_verify {
+        match cache.verify() {
+            Ok(verification) => {
+                output.text(|| format!("Checked {} entries", verification.checked));
+                for corrupted in &verification.corrupted {
+                    output.emit(
+                        Record::new()
+                            .with("entry", format!("{}", corrupted))
+                            .with("status", "corrupted"),
+                        || format!("Corrupted: {}", corrupted),
+                    );
+                }
+                if !verification.corrupted.is_empty() {
+                    output.finish();
+                    std::process::exit(1);
+                }
+            }
+            Err(e) => exit_with_error(format!("Unable to read {}: {}", location, e)),
+        }
+    } else if args.cmd_evict {
+        if cache.limits().is_none() {
+            exit_with_error(String::from(
+                "Cache evict needs --cache-max-bytes or --cache-max-entries",
+            ));
+        }
+        match cache.enforce_limits() {
+            Ok(evicted) => output.emit(
+                Record::new()
+                    .with("cache", location.as_str())
+                    .with("entries", evicted.entries)
+                    .with("bytes", evicted.bytes),
+                || format!("Evicted {} entries, {} bytes", evicted.entries, evicted.bytes),
+            ),
+            Err(e) => exit_with_error(format!("Unable to evict from {}: {}", location, e)),
+        }
+    } else if args.cmd_prune {
+        let files: Vec<PathBuf> = scan_inputs(&args.arg_dirs, get_scan_config(args))
+            .filter_map(|input| match input {
+                BatchInput::Path(path) => Some(path),
+                _ => None,
+            })
+            .collect();
+        match cache.prune(&files) {
+            Ok(pruned) => output.emit(
+                Record::new()
+                    .with("cache", location.as_str())
+                    .with("entries", pruned.entries)
+                    .with("bytes", pruned.bytes),
+                || format!("Pruned {} entries, {} bytes", pruned.entries, pruned.bytes),
+            ),
+            Err(e) => exit_with_error(format!("Unable to prune {}: {}", location, e)),
+        }
+    } else if args.cmd_export {
+        let exported = File::create(&args.arg_archive)
+            .and_then(|archive| cache.export(archive));
+        match exported {
+            Ok(exported) => output.emit(
+                Record::new()
+                    .with("cache", location.as_str())
+                    .with("archive", args.arg_archive.as_str())
+                    .with("entries", exported.entries)
+                    .with("bytes", exported.bytes),
+                || {
+                    format!(
+                        "Exported {} entries, {} bytes to {}",
+                        exported.entries, exported.bytes, args.arg_archive
+                    )
+                },
+            ),
+            Err(e) => exit_with_error(format!(
+                "Unable to export {} to {}: {}",
+                location, args.arg_archive, e
+            )),
+        }
+    } else if args.cmd_import {
+        let imported = File::open(&args.arg_archive).and_then(|archive| cache.import(archive));
+        match imported {
+            Ok(imported) => output.emit(
+                Record::new()
+                    .with("cache", location.as_str())
+                    .with("archive", args.arg_archive.as_str())
+                    .with("entries", imported.entries)
+                    .with("bytes", imported.bytes),
+                || {
+                    format!(
+                        "Imported {} entries, {} bytes from {}",
+                        imported.entries, imported.bytes, args.arg_archive
+                    )
+                },
+            ),
+            Err(e) => exit_with_error(format!(
+                "Unable to import {} into {}: {}",
+                args.arg_archive, location, e
+            )),
+        }
+    }
+}
+
+fn calibrate(lib: &pihash::PIHash, args: &Args, output: &mut Output) {
+    let precision = parse_precision(args);
+    let pairs = match pihash::calibration::read_labelled_pairs(Path::new(&args.arg_pairs)) {
+        Ok(pairs) => pairs,
+        Err(e) => exit_with_error(format!(
+            "Unable to read labelled pairs from {}: {}",
+            args.arg_pairs, e
+        )),
+    };
+
+    let report =
+        pihash::calibration::calibrate(lib, &pairs, &precision, &get_requested_hash_types(args));
+    for skipped in &report.skipped {
+        eprintln!("Unable to hash {}", skipped);
+    }
+    output.text(|| format!("{}", report));
+    // The best threshold of each hash, followed by the recommended policy
+    let mut rows: Vec<(String, Option<u64>, &pihash::calibration::ConfusionMatrix)> = report
+        .algorithms
+        .iter()
+        .map(|algorithm| {
+            (
+                get_hash_name(&algorithm.hash_type),
+                Some(algorithm.best_threshold.threshold),
+                &algorithm.best_threshold.results,
+            )
+        })
+        .collect();
+    rows.push((
+        String::from("policy"),
+        None,
+        &report.recommended_policy_results,
+    ));
+    for (algorithm, threshold, results) in rows {
+        output.record(
+            Record::new()
+                .with("algorithm", algorithm)
+                .with("threshold", threshold)
+                .with("true_positives", results.true_positives)
+                .with("false_positives", results.false_positives)
+                .with("true_negatives", results.true_negatives)
+                .with("false_negatives", results.false_negatives)
+                .with("precision", results.precision())
+                .with("recall", results.recall())
+                .with("false_positive_rate", results.false_positive_rate())
+                .with("f1_score", results.f1_score()),
+        );
+    }
+}
+
+fn robustness(args: &Args, output: &mut Output) {
+    let originals = match args.arg_originals {
+        Some(ref originals) => originals.as_str(),
+        None => pihash::robustness::DEFAULT_INPUT_DIR,
+    };
+    let config = pihash::robustness::RobustnessConfig {
+        hash_types: get_requested_hash_types(args),
+        ..Default::default()
+    };
+    let report = match pihash::robustness::evaluate_directory(Path::new(originals), &config) {
+        Ok(report) => report,
+        Err(e) => exit_with_error(format!("Unable to evaluate {}: {}", originals, e)),
+    };
+    output.text(|| format!("{}", report));
+    if output.is_text() {
+        return;
+    }
+    for result in &report.results {
+        let distribution = &result.distribution;
+        output.record(
+            Record::new()
+                .with("transformation", format!("{}", result.transformation))
+                .with("precision", format!("{}", result.precision).to_lowercase())
+                .with("algorithm", get_hash_name(&result.hash_type))
+                .with("images", distribution.len())
+                .with("min", distribution.min())
+                .with("median", distribution.median())
+                .with("mean", distribution.mean())
+                .with("p95", distribution.percentile(95_f64))
+                .with("max", distribution.max()),
+        );
+    }
+    for (name, error) in &report.failures {
+        eprintln!("Failed {}: {}", name, error);
+    }
+}
+
+fn scan(lib: &pihash::PIHash, args: &Args, output: &mut Output) {
+    let inputs = scan_inputs(std::slice::from_ref(&args.arg_root), get_scan_config(args));
+    let hash_types = get_requested_hash_types(args);
+    for result in lib.hash_batch(inputs, &get_batch_config(args, parse_precision(args))) {
+        match result.hashes {
+            Ok(mut hashes) => {
+                hashes.orig_path = result.source;
+                output.emit(Record::from_hashes(&hashes, &hash_types), || {
+                    let columns: Vec<String> = hash_types
+                        .iter()
+                        .map(|hash_type| format!("{}", hashes.get(hash_type)))
+                        .collect();
+                    format!("{}\t{}", hashes.orig_path, columns.join("\t"))
+                });
+            }
+            Err(e) => eprintln!("Unable to hash {}: {}", result.source, e),
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn watch(lib: &pihash::PIHash, args: &Args, output: &mut Output) {
+    let index = open_or_create_index(args);
+    let policy = get_policy(args, index.precision());
+    let hash_types = get_requested_hash_types(args);
+    let config = WatchConfig {
+        policy,
+        scan: get_scan_config(args),
+    };
+    let mut watcher = match lib.watch(Path::new(&args.arg_dir), index, config) {
+        Ok(watcher) => watcher,
+        Err(e) => exit_with_error(format!("Unable to watch {}: {}", args.arg_dir, e)),
     };
 
-    let dhash = if args.flag_dhash || flags_get_all_perceptual_hashes(&args) {
-        lib.get_dhash(&image_path)
+    loop {
+        let event = match watcher.next_event() {
+            Ok(event) => event,
+            Err(e) => exit_with_error(format!("Stopped watching {}: {}", args.arg_dir, e)),
+        };
+        let (name, path, id) = match event {
+            WatchEvent::Added { ref path, id } => ("added", path, id),
+            WatchEvent::Updated { ref path, id } => ("updated", path, id),
+            WatchEvent::Removed { ref path, id } => ("removed", path, id),
+            WatchEvent::NearDuplicate {
+                ref path,
+                id,
+                ref matches,
+            } => {
+                for found in matches {
+                    let record = Record::new()
+                        .with("event", "near-duplicate")
+                        .with("file", path.as_str())
+                        .with("id", id)
+                        .with("match", found.record.hashes.orig_path.as_str())
+                        .with("match_id", found.record.id);
+                    output.emit(
+                        record.with_similarity(&found.similarity, &policy, &hash_types),
+                        || {
+                            format!(
+                                "Near duplicate {} <-> {}: {}",
+                                path,
+                                found.record.hashes.orig_path,
+                                describe_similarity(&found.similarity, &hash_types)
+                            )
+                        },
+                    );
+                }
+                continue;
+            }
+            WatchEvent::Failed {
+                ref path,
+                ref error,
+            } => {
+                eprintln!("Unable to index {}: {}", path, error);
+                continue;
+            }
+        };
+        let mut record = Record::new()
+            .with("event", name)
+            .with("file", path.as_str())
+            .with("id", id)
+            .with("match", None::<String>)
+            .with("match_id", None::<u64>);
+        // Keep the columns the same as near duplicate events
+        for hash_type in &hash_types {
+            let hash_name = get_hash_name(hash_type);
+            record.push(&format!("{}_distance", hash_name), None::<u64>);
+            record.push(&format!("{}_score", hash_name), None::<f64>);
+        }
+        let record = record
+            .with("score", None::<f64>)
+            .with("similar", None::<bool>);
+        output.emit(record, || match name {
+            "added" => format!("Added {} as {}", path, id),
+            "updated" => format!("Updated {} as {}", path, id),
+            _ => format!("Removed {}", path),
+        });
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn watch(_lib: &pihash::PIHash, _args: &Args, _output: &mut Output) {
+    exit_with_error(String::from("Watching is only supported on Linux"));
+}
+
+fn serve(lib: pihash::PIHash, args: &Args) {
+    let index = open_or_create_index(args);
+    let mut config = ServerConfig {
+        precision: parse_precision(args),
+        hash_types: get_requested_hash_types(args),
+        policy: get_policy(args, index.precision()),
+        ..Default::default()
+    };
+    if let Some(threads) = args.flag_threads {
+        config.threads = threads;
+    }
+    let server = match Server::bind(args.flag_listen.as_str(), lib, Some(index), config) {
+        Ok(server) => server,
+        Err(e) => exit_with_error(format!("Unable to listen on {}: {}", args.flag_listen, e)),
+    };
+    match server.local_addr() {
+        Ok(address) => eprintln!("Listening on http://{}", address),
+        Err(_) => eprintln!("Listening on http://{}", args.flag_listen),
+    }
+    server.run();
+}
+
+#[cfg(unix)]
+fn daemon(mut lib: pihash::PIHash, args: &Args) {
+    let index = open_or_create_index(args);
+    let policy = get_policy(args, index.precision());
+    lib.keep_in_memory(args.flag_memory_entries);
+    let daemon = match Daemon::bind(Path::new(&args.arg_socket), lib, Some(index), policy) {
+        Ok(daemon) => daemon,
+        Err(e) => exit_with_error(format!("Unable to listen on {}: {}", args.arg_socket, e)),
+    };
+    eprintln!("Listening on {}", daemon.path().display());
+    daemon.run();
+}
+
+#[cfg(not(unix))]
+fn daemon(_lib: pihash::PIHash, _args: &Args) {
+    exit_with_error(String::from("The daemon needs Unix domain sockets"));
+}
+
+/**
+ * Open the --index file, creating it at the --precision if it doesn't exist
+ */
+fn open_or_create_index(args: &Args) -> IndexFile {
+    let index_path = Path::new(&args.flag_index);
+    let index = if index_path.exists() {
+        IndexFile::open(index_path)
     } else {
-        0u64
+        IndexFile::create(index_path, &parse_precision(args), Vec::new())
+    };
+    match index {
+        Ok(index) => index,
+        Err(e) => exit_with_error(format!("Unable to open {}: {}", args.flag_index, e)),
+    }
+}
+
+/**
+ * Hash every input across the batch pipeline, reporting the ones that fail
+ * on stderr and returning the rest in input order
+ */
+fn hash_images<I: IntoIterator<Item = BatchInput>>(
+    lib: &pihash::PIHash,
+    inputs: I,
+    precision: Precision,
+    args: &Args,
+) -> Vec<PerceptualHashes> {
+    let mut results: Vec<(usize, PerceptualHashes)> = Vec::new();
+    for result in lib.hash_batch(inputs, &get_batch_config(args, precision)) {
+        match result.hashes {
+            Ok(hashes) => results.push((result.index, hashes)),
+            Err(e) => eprintln!("Unable to hash {}: {}", result.source, e),
+        }
+    }
+    results.sort_by_key(|&(index, _)| index);
+    results.into_iter().map(|(_, hashes)| hashes).collect()
+}
+
+/**
+ * Lazily scan each directory in turn. Problems are reported on stderr to
+ * keep the output parseable.
+ */
+fn scan_inputs<'a>(dirs: &'a [String], config: ScanConfig) -> impl Iterator<Item = BatchInput> + 'a {
+    dirs.iter()
+        .flat_map(move |dir| pihash::scan::scan_directory(Path::new(dir), config.clone()))
+        .filter_map(|scanned| match scanned {
+            Ok(image) => Some(BatchInput::from(image)),
+            Err(e) => {
+                eprintln!("Unable to scan {}", e);
+                None
+            }
+        })
+}
+
+fn get_batch_config(args: &Args, precision: Precision) -> BatchConfig {
+    let mut batch_config = BatchConfig::new(precision);
+    if let Some(threads) = args.flag_threads {
+        batch_config.threads = threads;
+        batch_config.max_in_flight = threads * 2;
+    }
+    batch_config
+}
+
+fn get_scan_config(args: &Args) -> ScanConfig {
+    let mut config = ScanConfig {
+        extensions: split_list(&args.flag_extensions),
+        ..Default::default()
     };
+    if let Some(ref include) = args.flag_include {
+        config.include = parse_globs(include);
+    }
+    if let Some(ref exclude) = args.flag_exclude {
+        config.exclude = parse_globs(exclude);
+    }
+    config.min_file_size = args.flag_min_size;
+    config.max_file_size = args.flag_max_size;
+    if let Some(ref dimensions) = args.flag_min_dimensions {
+        config.min_dimensions = Some(parse_dimensions(dimensions));
+    }
+    if let Some(ref dimensions) = args.flag_max_dimensions {
+        config.max_dimensions = Some(parse_dimensions(dimensions));
+    }
+    config.follow_symlinks = args.flag_follow_symlinks;
+    config.use_ignore_files = !args.flag_no_ignore;
+    config
+}
+
+fn split_list(list: &str) -> Vec<String> {
+    list.split(',')
+        .map(|item| item.trim())
+        .filter(|item| !item.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+fn parse_globs(list: &str) -> Vec<GlobPattern> {
+    match split_list(list).iter().map(|glob| glob.parse()).collect() {
+        Ok(globs) => globs,
+        Err(e) => exit_with_error(format!("Error: {}", e)),
+    }
+}
+
+fn parse_dimensions(dimensions: &str) -> (u32, u32) {
+    match pihash::scan::parse_dimensions(dimensions) {
+        Ok(dimensions) => dimensions,
+        Err(e) => exit_with_error(format!("Error: {}", e)),
+    }
+}
+
+fn parse_duplicate_action(args: &Args) -> DuplicateAction {
+    match args.flag_action.to_lowercase().as_str() {
+        "report" => DuplicateAction::Report,
+        "quarantine" => match args.flag_quarantine {
+            Some(ref quarantine) => DuplicateAction::Quarantine(PathBuf::from(quarantine)),
+            None => exit_with_error(String::from("Error: --action=quarantine needs --quarantine")),
+        },
+        "delete" => DuplicateAction::Delete,
+        "hardlink" => DuplicateAction::Hardlink,
+        "symlink" => DuplicateAction::Symlink,
+        _ => exit_with_error(format!("Error: Unknown action '{}'", args.flag_action)),
+    }
+}
+
+fn parse_keep_policy(args: &Args) -> KeepPolicy {
+    match args.flag_keep.to_lowercase().as_str() {
+        "representative" => KeepPolicy::Representative,
+        "largest-resolution" => KeepPolicy::LargestResolution,
+        "largest-file" => KeepPolicy::LargestFile,
+        "oldest" => KeepPolicy::OldestModified,
+        "shortest-path" => KeepPolicy::ShortestPath,
+        "preferred" => match args.flag_prefer {
+            Some(ref prefer) => KeepPolicy::PreferredDirectory(PathBuf::from(prefer)),
+            None => exit_with_error(String::from("Error: --keep=preferred needs --prefer")),
+        },
+        _ => exit_with_error(format!("Error: Unknown keep policy '{}'", args.flag_keep)),
+    }
+}
+
+fn parse_precision(args: &Args) -> Precision {
+    match args.flag_precision.parse() {
+        Ok(precision) => precision,
+        Err(e) => exit_with_error(format!("Error: {}", e)),
+    }
+}
+
+/**
+ * Ask a yes or no question on stderr, anything but yes is taken as no
+ */
+fn confirm(question: &str) -> bool {
+    eprint!("{} [y/N] ", question);
+    let _ = io::stderr().flush();
+    let mut answer = String::new();
+    match io::stdin().read_line(&mut answer) {
+        Ok(_) => matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"),
+        Err(_) => false,
+    }
+}
+
+fn exit_with_error(message: String) -> ! {
+    eprintln!("{}", message);
+    std::process::exit(1);
+}
+
+/**
+ * A policy that only considers the requested hashes
+ */
+fn get_policy(args: &Args, precision: Precision) -> SimilarityPolicy {
+    SimilarityPolicy::for_hash_types(precision, &get_requested_hash_types(args))
+}
 
-    let phash = if args.flag_phash || flags_get_all_perceptual_hashes(&args) {
-        lib.get_phash(&image_path)
+fn describe_step(step: &ResolutionStep) -> String {
+    let destination = step
+        .destination
+        .as_ref()
+        .map_or(String::new(), |destination| format!(" {}", destination.display()));
+    match step.action {
+        DuplicateAction::Report => String::from("report"),
+        DuplicateAction::Quarantine(_) => format!("quarantine to{}", destination),
+        DuplicateAction::Delete => String::from("delete"),
+        DuplicateAction::Hardlink => format!("hardlink to{}", destination),
+        DuplicateAction::Symlink => format!("symlink to{}", destination),
+    }
+}
+
+fn describe_similarity(similarity: &Similarity, hash_types: &[HashType]) -> String {
+    let mut parts: Vec<String> = hash_types
+        .iter()
+        .map(|hash_type| {
+            format!(
+                "{} {}",
+                get_hash_name(hash_type),
+                similarity.distances.get(hash_type)
+            )
+        })
+        .collect();
+    parts.push(format!("score {:.3}", similarity.score));
+    parts.push(String::from(if similarity.similar {
+        "similar"
     } else {
-        0u64
+        "different"
+    }));
+    parts.join(", ")
+}
+
+fn get_hash_name(hash_type: &HashType) -> String {
+    format!("{}", hash_type).to_lowercase()
+}
+
+fn get_requested_hash_types(args: &Args) -> Vec<HashType> {
+    let mut hash_types = Vec::new();
+    if let Some(ref algorithms) = args.flag_algorithm {
+        for algorithm in split_list(algorithms) {
+            match algorithm.parse() {
+                Ok(hash_type) => {
+                    if !hash_types.contains(&hash_type) {
+                        hash_types.push(hash_type);
+                    }
+                }
+                Err(e) => exit_with_error(format!("Error: {}", e)),
+            }
+        }
+    }
+    if args.flag_ahash && !hash_types.contains(&HashType::AHash) {
+        hash_types.push(HashType::AHash);
+    }
+    if args.flag_dhash && !hash_types.contains(&HashType::DHash) {
+        hash_types.push(HashType::DHash);
+    }
+    if args.flag_phash && !hash_types.contains(&HashType::PHash) {
+        hash_types.push(HashType::PHash);
+    }
+    if hash_types.is_empty() {
+        hash_types = vec![HashType::AHash, HashType::DHash, HashType::PHash];
+    }
+    hash_types.sort_by_key(|hash_type| match *hash_type {
+        HashType::AHash => 0,
+        HashType::DHash => 1,
+        HashType::PHash => 2,
+    });
+    hash_types
+}
+
+fn get_requested_perceptual_hashes(
+    lib: &pihash::PIHash,
+    image_path: &Path,
+    precision: &Precision,
+    args: &Args,
+) -> PerceptualHashes {
+    let hash_types = get_requested_hash_types(args);
+    let get_hash = |hash_type: HashType| {
+        if hash_types.contains(&hash_type) {
+            lib.get_perceptual_hash(image_path, precision, &hash_type)
+        } else {
+            0u64
+        }
     };
 
-    pihash::hash::PerceptualHashes {
+    PerceptualHashes {
         orig_path: String::from(image_path.to_str().unwrap()),
-        ahash,
-        dhash,
-        phash,
+        ahash: get_hash(HashType::AHash),
+        dhash: get_hash(HashType::DHash),
+        phash: get_hash(HashType::PHash),
     }
 }
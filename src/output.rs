@@ -0,0 +1,451 @@
+// Copyright 2016 Drew Short <drew@sothr.com>.
+//
+// Licensed under the MIT license<LICENSE-MIT or http://opensource.org/licenses/MIT>.
+// This file may not be copied, modified, or distributed except according to those terms.
+
+use std::fmt;
+use std::io::{Error, ErrorKind, Write};
+use std::str::FromStr;
+
+use super::rustc_serialize::json::Json;
+use hash::{HashType, PerceptualHashes};
+use similarity::{Similarity, SimilarityPolicy};
+
+// Structs/Enums //
+
+/**
+ * The machine readable formats records can be written in
+ */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum OutputFormat {
+    /// A single JSON array of objects
+    Json,
+    /// One JSON object per line
+    Ndjson,
+    /// Comma separated values with a header row
+    Csv,
+    /// Tab separated values with a header row
+    Tsv,
+}
+
+/**
+ * A single field value
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Integer(u64),
+    Float(f64),
+    Text(String),
+    /// Written as a JSON array, or as its JSON text in a table cell
+    List(Vec<Value>),
+    /// Written as a nested JSON object, or as its JSON text in a table cell
+    Object(Record),
+}
+
+/**
+ * An ordered set of named fields, written as a JSON object or a table row
+ */
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Record {
+    fields: Vec<(String, Value)>,
+}
+
+/**
+ * Writes records as they are produced
+ *
+ * Tables take their columns from the first record, and every following
+ * record must have the same fields in the same order.
+ */
+pub struct RecordWriter<W: Write> {
+    writer: W,
+    format: OutputFormat,
+    columns: Option<Vec<String>>,
+    finished: bool,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<OutputFormat, String> {
+        match s.to_lowercase().as_str() {
+            "json" => Ok(OutputFormat::Json),
+            "ndjson" => Ok(OutputFormat::Ndjson),
+            "csv" => Ok(OutputFormat::Csv),
+            "tsv" => Ok(OutputFormat::Tsv),
+            _ => Err(format!("Unknown output format '{}'", s)),
+        }
+    }
+}
+
+impl fmt::Display for OutputFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            OutputFormat::Json => write!(f, "json"),
+            OutputFormat::Ndjson => write!(f, "ndjson"),
+            OutputFormat::Csv => write!(f, "csv"),
+            OutputFormat::Tsv => write!(f, "tsv"),
+        }
+    }
+}
+
+impl From<bool> for Value {
+    fn from(value: bool) -> Value {
+        Value::Bool(value)
+    }
+}
+
+impl From<u64> for Value {
+    fn from(value: u64) -> Value {
+        Value::Integer(value)
+    }
+}
+
+impl From<usize> for Value {
+    fn from(value: usize) -> Value {
+        Value::Integer(value as u64)
+    }
+}
+
+impl From<f64> for Value {
+    fn from(value: f64) -> Value {
+        Value::Float(value)
+    }
+}
+
+impl<'a> From<&'a str> for Value {
+    fn from(value: &'a str) -> Value {
+        Value::Text(String::from(value))
+    }
+}
+
+impl From<String> for Value {
+    fn from(value: String) -> Value {
+        Value::Text(value)
+    }
+}
+
+impl From<Record> for Value {
+    fn from(value: Record) -> Value {
+        Value::Object(value)
+    }
+}
+
+impl<T: Into<Value>> From<Vec<T>> for Value {
+    fn from(values: Vec<T>) -> Value {
+        Value::List(values.into_iter().map(Into::into).collect())
+    }
+}
+
+impl<T: Into<Value>> From<Option<T>> for Value {
+    fn from(value: Option<T>) -> Value {
+        match value {
+            Some(value) => value.into(),
+            None => Value::Null,
+        }
+    }
+}
+
+impl Value {
+    /**
+     * The value as compact JSON. Nested records keep their field order.
+     */
+    pub fn to_json_string(&self) -> String {
+        match *self {
+            Value::Null => Json::Null.to_string(),
+            Value::Bool(value) => Json::Boolean(value).to_string(),
+            Value::Integer(value) => Json::U64(value).to_string(),
+            Value::Float(value) => Json::F64(value).to_string(),
+            Value::Text(ref value) => Json::String(value.clone()).to_string(),
+            Value::List(ref values) => {
+                let values: Vec<String> = values.iter().map(Value::to_json_string).collect();
+                format!("[{}]", values.join(","))
+            }
+            Value::Object(ref record) => record.to_json_string(),
+        }
+    }
+
+    /**
+     * The value as it appears in a table cell, before any quoting
+     */
+    fn to_cell(&self) -> String {
+        match *self {
+            Value::Null => String::new(),
+            Value::Bool(value) => format!("{}", value),
+            Value::Integer(value) => format!("{}", value),
+            Value::Float(value) => format!("{}", value),
+            Value::Text(ref value) => value.clone(),
+            Value::List(_) | Value::Object(_) => self.to_json_string(),
+        }
+    }
+}
+
+impl Record {
+    pub fn new() -> Record {
+        Default::default()
+    }
+
+    /**
+     * The hashes of an image with a field for each requested hash
+     */
+    pub fn from_hashes(hashes: &PerceptualHashes, hash_types: &[HashType]) -> Record {
+        let mut record = Record::new().with("file", hashes.orig_path.as_str());
+        for hash_type in hash_types {
+            record.push(&get_hash_name(hash_type), hashes.get(hash_type));
+        }
+        record
+    }
+
+    /**
+     * Append a field, returning the record so calls can be chained
+     */
+    pub fn with<V: Into<Value>>(mut self, name: &str, value: V) -> Record {
+        self.push(name, value);
+        self
+    }
+
+    pub fn push<V: Into<Value>>(&mut self, name: &str, value: V) {
+        self.fields.push((String::from(name), value.into()));
+    }
+
+    /**
+     * Append the distance and score of each requested hash and the overall
+     * result of a comparison
+     */
+    pub fn with_similarity(
+        mut self,
+        similarity: &Similarity,
+        policy: &SimilarityPolicy,
+        hash_types: &[HashType],
+    ) -> Record {
+        for hash_type in hash_types {
+            let distance = similarity.distances.get(hash_type);
+            let name = get_hash_name(hash_type);
+            self.push(&format!("{}_distance", name), distance);
+            self.push(&format!("{}_score", name), policy.hash_score(distance));
+        }
+        self.with("score", similarity.score)
+            .with("similar", similarity.similar)
+    }
+
+    pub fn fields(&self) -> &[(String, Value)] {
+        &self.fields
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Value> {
+        self.fields
+            .iter()
+            .find(|(field, _)| field == name)
+            .map(|(_, value)| value)
+    }
+
+    /**
+     * The record as a compact JSON object with the fields in order
+     */
+    pub fn to_json_string(&self) -> String {
+        let fields: Vec<String> = self
+            .fields
+            .iter()
+            .map(|(name, value)| {
+                format!("{}:{}", Json::String(name.clone()), value.to_json_string())
+            })
+            .collect();
+        format!("{{{}}}", fields.join(","))
+    }
+}
+
+impl<W: Write> RecordWriter<W> {
+    pub fn new(writer: W, format: OutputFormat) -> RecordWriter<W> {
+        RecordWriter {
+            writer,
+            format,
+            columns: None,
+            finished: false,
+        }
+    }
+
+    pub fn format(&self) -> OutputFormat {
+        self.format
+    }
+
+    pub fn write(&mut self, record: &Record) -> Result<(), Error> {
+        let first = self.columns.is_none();
+        let names: Vec<String> = record
+            .fields()
+            .iter()
+            .map(|(name, _)| name.clone())
+            .collect();
+        match self.columns {
+            Some(ref columns) => {
+                if *columns != names {
+                    return Err(Error::new(
+                        ErrorKind::InvalidInput,
+                        format!(
+                            "Record fields [{}] don't match the columns [{}]",
+                            names.join(", "),
+                            columns.join(", ")
+                        ),
+                    ));
+                }
+            }
+            None => self.columns = Some(names.clone()),
+        }
+
+        match self.format {
+            OutputFormat::Json => {
+                let separator = if first { "[\n" } else { ",\n" };
+                write!(self.writer, "{}{}", separator, record.to_json_string())
+            }
+            OutputFormat::Ndjson => writeln!(self.writer, "{}", record.to_json_string()),
+            OutputFormat::Csv | OutputFormat::Tsv => {
+                if first {
+                    let header: Vec<String> = names.iter().map(|name| self.quote(name)).collect();
+                    self.write_row(&header)?;
+                }
+                let cells: Vec<String> = record
+                    .fields()
+                    .iter()
+                    .map(|(_, value)| self.quote(&value.to_cell()))
+                    .collect();
+                self.write_row(&cells)
+            }
+        }
+    }
+
+    /**
+     * Close the JSON array and flush. Called automatically on drop, but
+     * errors can only be seen by calling it directly.
+     */
+    pub fn finish(&mut self) -> Result<(), Error> {
+        if self.finished {
+            return Ok(());
+        }
+        self.finished = true;
+        if self.format == OutputFormat::Json {
+            if self.columns.is_none() {
+                writeln!(self.writer, "[]")?;
+            } else {
+                writeln!(self.writer, "\n]")?;
+            }
+        }
+        self.writer.flush()
+    }
+
+    fn write_row(&mut self, cells: &[String]) -> Result<(), Error> {
+        let separator = match self.format {
+            OutputFormat::Tsv => "\t",
+            _ => ",",
+        };
+        writeln!(self.writer, "{}", cells.join(separator))
+    }
+
+    fn quote(&self, cell: &str) -> String {
+        match self.format {
+            OutputFormat::Csv => quote_csv(cell),
+            _ => escape_tsv(cell),
+        }
+    }
+}
+
+impl<W: Write> Drop for RecordWriter<W> {
+    fn drop(&mut self) {
+        let _ = self.finish();
+    }
+}
+
+// Functions //
+
+fn get_hash_name(hash_type: &HashType) -> String {
+    format!("{}", hash_type).to_lowercase()
+}
+
+/**
+ * Quote a cell as described in RFC 4180 when it needs to be
+ */
+fn quote_csv(cell: &str) -> String {
+    if cell.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", cell.replace('"', "\"\""))
+    } else {
+        String::from(cell)
+    }
+}
+
+/**
+ * TSV has no quoting, so tabs and line breaks are backslash escaped
+ */
+fn escape_tsv(cell: &str) -> String {
+    cell.replace('\\', "\\\\")
+        .replace('\t', "\\t")
+        .replace('\n', "\\n")
+        .replace('\r', "\\r")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{OutputFormat, Record, RecordWriter, Value};
+
+    fn write_records(format: OutputFormat, records: &[Record]) -> String {
+        let mut output = Vec::new();
+        {
+            let mut writer = RecordWriter::new(&mut output, format);
+            for record in records {
+                writer.write(record).unwrap();
+            }
+            writer.finish().unwrap();
+        }
+        String::from_utf8(output).unwrap()
+    }
+
+    fn records() -> Vec<Record> {
+        vec![
+            Record::new()
+                .with("file", "a, \"b\".jpg")
+                .with("distance", 3_u64)
+                .with("similar", true),
+            Record::new()
+                .with("file", "c\td.jpg")
+                .with("distance", None::<u64>)
+                .with("similar", false),
+        ]
+    }
+
+    #[test]
+    fn test_json_formats() {
+        assert_eq!(
+            write_records(OutputFormat::Ndjson, &records()),
+            "{\"file\":\"a, \\\"b\\\".jpg\",\"distance\":3,\"similar\":true}\n\
+             {\"file\":\"c\\td.jpg\",\"distance\":null,\"similar\":false}\n"
+        );
+        assert_eq!(
+            write_records(OutputFormat::Json, &records()[..1]),
+            "[\n{\"file\":\"a, \\\"b\\\".jpg\",\"distance\":3,\"similar\":true}\n]\n"
+        );
+        assert_eq!(write_records(OutputFormat::Json, &[]), "[]\n");
+
+        let nested = Record::new()
+            .with("matches", vec![Record::new().with("id", 7_u64)])
+            .with("empty", Vec::<Value>::new());
+        assert_eq!(
+            nested.to_json_string(),
+            "{\"matches\":[{\"id\":7}],\"empty\":[]}"
+        );
+    }
+
+    #[test]
+    fn test_table_formats() {
+        assert_eq!(
+            write_records(OutputFormat::Csv, &records()),
+            "file,distance,similar\n\"a, \"\"b\"\".jpg\",3,true\nc\td.jpg,,false\n"
+        );
+        assert_eq!(
+            write_records(OutputFormat::Tsv, &records()),
+            "file\tdistance\tsimilar\na, \"b\".jpg\t3\ttrue\nc\\td.jpg\t\tfalse\n"
+        );
+
+        let mut output = Vec::new();
+        let mut writer = RecordWriter::new(&mut output, OutputFormat::Csv);
+        writer.write(&records()[0]).unwrap();
+        assert!(writer.write(&Record::new().with("other", 1_u64)).is_err());
+    }
+}
@@ -0,0 +1,317 @@
+// Copyright 2016 Drew Short <drew@sothr.com>.
+//
+// Licensed under the MIT license<LICENSE-MIT or http://opensource.org/licenses/MIT>.
+// This file may not be copied, modified, or distributed except according to those terms.
+
+extern crate image;
+
+use std::fs;
+use std::io::{Error, Write};
+use std::path::Path;
+
+use self::image::{DynamicImage, ImageOutputFormat};
+use super::rustc_serialize::base64::{ToBase64, STANDARD};
+use cache::Cache;
+use cluster::DuplicateCluster;
+use hash::{HashType, PerceptualHashes};
+use resolution::{ClusterResolution, DuplicateAction};
+use similarity::SimilarityPolicy;
+
+// Constants //
+
+/// The longest side of a thumbnail. Thumbnails keep their aspect ratio and
+/// are cached alongside the prepared images under this size.
+pub const THUMBNAIL_SIZE: u32 = 128;
+
+const REPORT_STYLE: &str = "
+body { font-family: sans-serif; margin: 2em; color: #222; background: #fafafa; }
+section { margin-bottom: 2em; }
+.members { display: flex; flex-wrap: wrap; gap: 1em; }
+.member { width: 220px; padding: 0.75em; border: 1px solid #ccc; border-radius: 4px; background: #fff; }
+.member.keep { border: 2px solid #2a7d2a; }
+.thumbnail { height: 128px; display: flex; align-items: center; justify-content: center; background: #eee; }
+.thumbnail img { max-width: 128px; max-height: 128px; }
+.path { word-break: break-all; font-size: 0.85em; margin: 0.5em 0; }
+.badge { display: inline-block; padding: 0.1em 0.5em; border-radius: 3px; color: #fff; background: #a33; }
+.keep .badge { background: #2a7d2a; }
+table { border-collapse: collapse; font-size: 0.85em; }
+td { padding: 0.1em 0.75em 0.1em 0; }
+";
+
+// Structs/Enums //
+
+/**
+ * A cluster to include in a report, along with what is planned for it. The
+ * representative is shown as the keeper when there is no plan.
+ */
+#[derive(Copy, Clone, Debug)]
+pub struct ReportCluster<'a> {
+    pub cluster: &'a DuplicateCluster,
+    pub resolution: Option<&'a ClusterResolution>,
+}
+
+// Functions //
+
+/**
+ * Write a self-contained HTML page showing every cluster with thumbnails,
+ * file sizes, dimensions and the distance of each member from the keeper
+ *
+ * `hashes` are the images the clusters were found in. Thumbnails are
+ * embedded in the page, so it can be moved around or mailed on its own.
+ */
+pub fn write_html_report<W: Write>(
+    writer: &mut W,
+    hashes: &[PerceptualHashes],
+    clusters: &[ReportCluster],
+    policy: &SimilarityPolicy,
+    hash_types: &[HashType],
+    cache: &Option<Cache>,
+) -> Result<(), Error> {
+    let images: usize = clusters
+        .iter()
+        .map(|report| report.cluster.members.len())
+        .sum();
+    writeln!(writer, "<!DOCTYPE html>")?;
+    writeln!(writer, "<html>\n<head>\n<meta charset=\"utf-8\">")?;
+    writeln!(writer, "<title>Duplicate images</title>")?;
+    writeln!(writer, "<style>{}</style>\n</head>\n<body>", REPORT_STYLE)?;
+    writeln!(writer, "<h1>Duplicate images</h1>")?;
+    writeln!(
+        writer,
+        "<p>{} clusters containing {} images</p>",
+        clusters.len(),
+        images
+    )?;
+
+    for (number, report) in clusters.iter().enumerate() {
+        let cluster = report.cluster;
+        let keep = report
+            .resolution
+            .map_or(cluster.representative, |resolution| resolution.keep);
+        writeln!(writer, "<section>")?;
+        writeln!(
+            writer,
+            "<h2>Cluster {} ({} images)</h2>",
+            number + 1,
+            cluster.members.len()
+        )?;
+        writeln!(writer, "<div class=\"members\">")?;
+        for &member in &cluster.members {
+            let path = Path::new(&hashes[member].orig_path);
+            let class = if member == keep {
+                "member keep"
+            } else {
+                "member"
+            };
+            writeln!(writer, "<div class=\"{}\">", class)?;
+
+            write!(writer, "<div class=\"thumbnail\">")?;
+            match get_thumbnail(path, cache).and_then(|thumbnail| encode_png(&thumbnail)) {
+                Some(png) => write!(
+                    writer,
+                    "<img src=\"data:image/png;base64,{}\" alt=\"\">",
+                    png.to_base64(STANDARD)
+                )?,
+                None => write!(writer, "No preview")?,
+            }
+            writeln!(writer, "</div>")?;
+
+            let badge = if member == keep {
+                String::from("Keep")
+            } else {
+                get_planned_action(report.resolution, member)
+            };
+            writeln!(
+                writer,
+                "<span class=\"badge\">{}</span>",
+                escape_html(&badge)
+            )?;
+            writeln!(
+                writer,
+                "<div class=\"path\">{}</div>",
+                escape_html(&hashes[member].orig_path)
+            )?;
+
+            writeln!(writer, "<table>")?;
+            let file_size = fs::metadata(path)
+                .map(|metadata| format_file_size(metadata.len()))
+                .unwrap_or_else(|_| String::from("unknown"));
+            writeln!(writer, "<tr><td>Size</td><td>{}</td></tr>", file_size)?;
+            let dimensions = image::image_dimensions(path)
+                .map(|(width, height)| format!("{} &times; {}", width, height))
+                .unwrap_or_else(|_| String::from("unknown"));
+            writeln!(
+                writer,
+                "<tr><td>Dimensions</td><td>{}</td></tr>",
+                dimensions
+            )?;
+            if member != keep {
+                let similarity = policy.compare(&hashes[member], &hashes[keep]);
+                for hash_type in hash_types {
+                    writeln!(
+                        writer,
+                        "<tr><td>{}</td><td>{}</td></tr>",
+                        hash_type,
+                        similarity.distances.get(hash_type)
+                    )?;
+                }
+                writeln!(
+                    writer,
+                    "<tr><td>Score</td><td>{:.3}</td></tr>",
+                    similarity.score
+                )?;
+            }
+            writeln!(writer, "</table>")?;
+            writeln!(writer, "</div>")?;
+        }
+        writeln!(writer, "</div>\n</section>")?;
+    }
+    writeln!(writer, "</body>\n</html>")
+}
+
+/**
+ * Get a thumbnail of an image, from the cache if it's been made before
+ */
+pub fn get_thumbnail(path: &Path, cache: &Option<Cache>) -> Option<DynamicImage> {
+    if let Some(ref cache) = *cache {
+        if let Some(thumbnail) = cache.get_thumbnail_from_cache(path, THUMBNAIL_SIZE) {
+            return Some(thumbnail);
+        }
+    }
+    let thumbnail = image::open(path)
+        .ok()?
+        .thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE);
+    if let Some(ref cache) = *cache {
+        if let Err(e) = cache.put_thumbnail_in_cache(path, THUMBNAIL_SIZE, &thumbnail) {
+            eprintln!("Unable to store thumbnail in cache. {}", e);
+        }
+    }
+    Some(thumbnail)
+}
+
+fn encode_png(image: &DynamicImage) -> Option<Vec<u8>> {
+    let mut png = Vec::new();
+    image.write_to(&mut png, ImageOutputFormat::PNG).ok()?;
+    Some(png)
+}
+
+fn get_planned_action(resolution: Option<&ClusterResolution>, member: usize) -> String {
+    let step = resolution.and_then(|resolution| {
+        resolution
+            .steps
+            .iter()
+            .find(|&&(duplicate, _)| duplicate == member)
+            .map(|(_, step)| step)
+    });
+    match step {
+        Some(step) => match step.action {
+            DuplicateAction::Report => String::from("Duplicate"),
+            DuplicateAction::Quarantine(_) => match step.destination {
+                Some(ref destination) => format!("Quarantine to {}", destination.display()),
+                None => String::from("Quarantine"),
+            },
+            DuplicateAction::Delete => String::from("Delete"),
+            DuplicateAction::Hardlink => String::from("Replace with a hard link"),
+            DuplicateAction::Symlink => String::from("Replace with a symbolic link"),
+        },
+        None => String::from("Duplicate"),
+    }
+}
+
+fn format_file_size(bytes: u64) -> String {
+    let units = ["bytes", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024_f64 && unit < units.len() - 1 {
+        size /= 1024_f64;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", bytes, units[unit])
+    } else {
+        format!("{:.1} {}", size, units[unit])
+    }
+}
+
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env::temp_dir;
+    use std::fs::remove_dir_all;
+    use std::path::Path;
+
+    use cache::Cache;
+    use cluster::DuplicateCluster;
+    use hash::{get_perceptual_hashes, HashType, Precision};
+    use similarity::SimilarityPolicy;
+
+    use super::image::GenericImageView;
+    use super::{get_thumbnail, write_html_report, ReportCluster, THUMBNAIL_SIZE};
+
+    #[test]
+    fn test_html_report() {
+        let cache_dir = temp_dir().join("pihash_test_report_cache");
+        let _ = remove_dir_all(&cache_dir);
+        let cache = Some(Cache::from_directory(&cache_dir.to_string_lossy()));
+        let paths = [
+            "test_images/sample_02_large.jpg",
+            "test_images/sample_02_<small>.jpg",
+        ];
+        let mut hashes: Vec<_> = paths
+            .iter()
+            .map(|path| get_perceptual_hashes(Path::new(path), &Precision::Medium, &None))
+            .collect();
+        // A file that can't be read still gets a card, without a preview
+        hashes[1].orig_path = String::from(paths[1]);
+        let cluster = DuplicateCluster {
+            members: vec![0, 1],
+            distances: Vec::new(),
+            representative: 0,
+        };
+
+        let mut html = Vec::new();
+        write_html_report(
+            &mut html,
+            &hashes,
+            &[ReportCluster {
+                cluster: &cluster,
+                resolution: None,
+            }],
+            &SimilarityPolicy::default(),
+            &[HashType::DHash],
+            &cache,
+        )
+        .unwrap();
+        let html = String::from_utf8(html).unwrap();
+        assert!(html.contains("<h2>Cluster 1 (2 images)</h2>"));
+        assert_eq!(html.matches("data:image/png;base64,").count(), 1);
+        assert!(html.contains("No preview"));
+        assert!(html.contains("sample_02_&lt;small&gt;.jpg"));
+        assert!(html.contains("<tr><td>DHash</td>"));
+
+        // The thumbnail is cached for the next report, apart from prepared images
+        let cache_ref = cache.as_ref().unwrap();
+        let cached = cache_ref.get_thumbnail_from_cache(Path::new(paths[0]), THUMBNAIL_SIZE);
+        let (width, height) = cached.unwrap().dimensions();
+        assert_eq!(width.max(height), THUMBNAIL_SIZE);
+        assert!(cache_ref.get_image_from_cache(Path::new(paths[0]), THUMBNAIL_SIZE).is_none());
+        let stats = cache_ref.stats().unwrap();
+        assert_eq!((stats.thumbnails.entries, stats.images.entries), (1, 0));
+        assert!(get_thumbnail(Path::new(paths[0]), &cache).is_some());
+        remove_dir_all(&cache_dir).unwrap();
+    }
+}
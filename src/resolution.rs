@@ -0,0 +1,508 @@
+// Copyright 2016 Drew Short <drew@sothr.com>.
+//
+// Licensed under the MIT license<LICENSE-MIT or http://opensource.org/licenses/MIT>.
+// This file may not be copied, modified, or distributed except according to those terms.
+
+extern crate image;
+
+use std::fmt;
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use super::libc;
+use cluster::DuplicateCluster;
+
+// Structs/Enums //
+
+/**
+ * Which member of a duplicate cluster is kept
+ *
+ * Ties go to the cluster representative, then to the earliest member.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub enum KeepPolicy {
+    /// The member that is most like the rest of the cluster
+    Representative,
+    /// The most pixels
+    LargestResolution,
+    /// The most bytes
+    LargestFile,
+    /// The earliest modification time
+    OldestModified,
+    /// The fewest characters in the path
+    ShortestPath,
+    /// Anything under the directory, falling back to the representative
+    PreferredDirectory(PathBuf),
+}
+
+/**
+ * What happens to the members of a cluster that aren't kept
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub enum DuplicateAction {
+    /// Leave the files alone
+    Report,
+    /// Move into this directory, keeping their path relative to the scanned
+    /// root they were found under
+    Quarantine(PathBuf),
+    Delete,
+    /// Replace with a hard link to the kept file
+    Hardlink,
+    /// Replace with a symbolic link to the kept file
+    Symlink,
+}
+
+/**
+ * What will happen to a single duplicate
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct ResolutionStep {
+    pub path: PathBuf,
+    pub action: DuplicateAction,
+    /// Where a quarantined file is moved to, or what a link points at
+    pub destination: Option<PathBuf>,
+}
+
+/**
+ * The kept member of a cluster and what happens to the rest
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct ClusterResolution {
+    pub keep: usize,
+    pub steps: Vec<(usize, ResolutionStep)>,
+}
+
+impl fmt::Display for DuplicateAction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DuplicateAction::Report => write!(f, "report"),
+            DuplicateAction::Quarantine(_) => write!(f, "quarantine"),
+            DuplicateAction::Delete => write!(f, "delete"),
+            DuplicateAction::Hardlink => write!(f, "hardlink"),
+            DuplicateAction::Symlink => write!(f, "symlink"),
+        }
+    }
+}
+
+impl DuplicateAction {
+    /**
+     * Whether the action changes anything on disk
+     */
+    pub fn is_destructive(&self) -> bool {
+        *self != DuplicateAction::Report
+    }
+}
+
+impl ResolutionStep {
+    /**
+     * Carry out the step. The kept file has to still exist, and nothing is
+     * ever overwritten except the duplicate itself.
+     */
+    pub fn apply(&self, keep: &Path) -> Result<(), Error> {
+        if self.action.is_destructive() && !keep.is_file() {
+            return Err(Error::new(
+                ErrorKind::NotFound,
+                format!("The kept file {} no longer exists", keep.display()),
+            ));
+        }
+        match self.action {
+            DuplicateAction::Report => Ok(()),
+            DuplicateAction::Quarantine(_) => {
+                let destination = self.destination.as_ref().ok_or_else(|| {
+                    Error::new(ErrorKind::InvalidInput, "Quarantine needs a destination")
+                })?;
+                if fs::symlink_metadata(destination).is_ok() {
+                    return Err(Error::new(
+                        ErrorKind::AlreadyExists,
+                        format!("{} already exists", destination.display()),
+                    ));
+                }
+                if let Some(parent) = destination.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                move_file(&self.path, destination)
+            }
+            DuplicateAction::Delete => fs::remove_file(&self.path),
+            DuplicateAction::Hardlink => replace_with(&self.path, |temp| fs::hard_link(keep, temp)),
+            DuplicateAction::Symlink => {
+                // Relative paths would be resolved against the link's directory
+                let target = keep.canonicalize()?;
+                replace_with(&self.path, |temp| symlink(&target, temp))
+            }
+        }
+    }
+}
+
+// Functions //
+
+/**
+ * Pick the member of a cluster to keep and plan what happens to the others
+ *
+ * `paths` are the files that were clustered, and `roots` the directories
+ * they were scanned from, which quarantine uses to rebuild the structure.
+ * Members that are really the kept file, through a link, are only reported,
+ * as are members that are only linked to the kept file through others.
+ */
+pub fn plan_resolution(
+    cluster: &DuplicateCluster,
+    paths: &[PathBuf],
+    keep_policy: &KeepPolicy,
+    action: &DuplicateAction,
+    roots: &[PathBuf],
+) -> Result<ClusterResolution, Error> {
+    let keep = choose_keeper(cluster, paths, keep_policy)?;
+    let mut steps = Vec::new();
+    for &member in &cluster.members {
+        if member == keep {
+            continue;
+        }
+        let path = &paths[member];
+        let action = if is_same_file(path, &paths[keep]) || !cluster.is_similar(keep, member) {
+            DuplicateAction::Report
+        } else {
+            action.clone()
+        };
+        let destination = match action {
+            DuplicateAction::Quarantine(ref quarantine) => {
+                Some(quarantine.join(get_relative_path(path, roots)))
+            }
+            DuplicateAction::Hardlink | DuplicateAction::Symlink => Some(paths[keep].clone()),
+            _ => None,
+        };
+        steps.push((
+            member,
+            ResolutionStep {
+                path: path.clone(),
+                action,
+                destination,
+            },
+        ));
+    }
+    Ok(ClusterResolution { keep, steps })
+}
+
+/**
+ * Get the member of a cluster that the policy keeps
+ */
+pub fn choose_keeper(
+    cluster: &DuplicateCluster,
+    paths: &[PathBuf],
+    keep_policy: &KeepPolicy,
+) -> Result<usize, Error> {
+    // The representative goes first so that it wins any ties
+    let mut candidates = vec![cluster.representative];
+    candidates.extend(
+        cluster
+            .members
+            .iter()
+            .filter(|&&member| member != cluster.representative),
+    );
+
+    let mut best: Option<(u128, usize)> = None;
+    for member in candidates {
+        let key = get_keep_key(
+            &paths[member],
+            member == cluster.representative,
+            keep_policy,
+        )?;
+        if best.is_none_or(|(best_key, _)| key < best_key) {
+            best = Some((key, member));
+        }
+    }
+    best.map(|(_, member)| member)
+        .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "The cluster has no members"))
+}
+
+/**
+ * A key for how a file ranks under the policy, where lower is better
+ */
+fn get_keep_key(
+    path: &Path,
+    representative: bool,
+    keep_policy: &KeepPolicy,
+) -> Result<u128, Error> {
+    Ok(match *keep_policy {
+        KeepPolicy::Representative => !representative as u128,
+        KeepPolicy::LargestResolution => {
+            let (width, height) = image::image_dimensions(path).map_err(|e| {
+                Error::new(ErrorKind::InvalidData, format!("{}: {}", path.display(), e))
+            })?;
+            u128::MAX - u128::from(width) * u128::from(height)
+        }
+        KeepPolicy::LargestFile => u128::MAX - u128::from(fs::metadata(path)?.len()),
+        KeepPolicy::OldestModified => {
+            let modified = fs::metadata(path)?.modified()?;
+            // Anything from before the epoch counts as the oldest possible
+            modified
+                .duration_since(UNIX_EPOCH)
+                .map(|age| age.as_nanos())
+                .unwrap_or(0)
+        }
+        KeepPolicy::ShortestPath => path.to_string_lossy().chars().count() as u128,
+        KeepPolicy::PreferredDirectory(ref directory) => {
+            let preferred = match (path.canonicalize(), directory.canonicalize()) {
+                (Ok(path), Ok(directory)) => path.starts_with(directory),
+                _ => path.starts_with(directory),
+            };
+            // Prefer the representative among the files in the directory
+            (!preferred as u128) * 2 + !representative as u128
+        }
+    })
+}
+
+/**
+ * Get the path of a file below the deepest root it is in, or just its name
+ * when it isn't under any of them
+ */
+fn get_relative_path(path: &Path, roots: &[PathBuf]) -> PathBuf {
+    roots
+        .iter()
+        .filter_map(|root| path.strip_prefix(root).ok())
+        .min_by_key(|relative| relative.components().count())
+        .map(Path::to_path_buf)
+        .or_else(|| path.file_name().map(PathBuf::from))
+        .unwrap_or_else(|| path.to_path_buf())
+}
+
+/**
+ * Whether two paths lead to the same file, through links or otherwise
+ */
+fn is_same_file(first: &Path, second: &Path) -> bool {
+    match (first.canonicalize(), second.canonicalize()) {
+        (Ok(first), Ok(second)) if first == second => return true,
+        _ => {}
+    }
+    is_same_inode(first, second)
+}
+
+#[cfg(unix)]
+fn is_same_inode(first: &Path, second: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    match (fs::metadata(first), fs::metadata(second)) {
+        (Ok(first), Ok(second)) => first.dev() == second.dev() && first.ino() == second.ino(),
+        _ => false,
+    }
+}
+
+#[cfg(not(unix))]
+fn is_same_inode(_first: &Path, _second: &Path) -> bool {
+    false
+}
+
+#[cfg(unix)]
+fn symlink(target: &Path, link: &Path) -> Result<(), Error> {
+    ::std::os::unix::fs::symlink(target, link)
+}
+
+#[cfg(not(unix))]
+fn symlink(_target: &Path, _link: &Path) -> Result<(), Error> {
+    Err(Error::new(
+        ErrorKind::Other,
+        "Symbolic links are only supported on unix",
+    ))
+}
+
+/**
+ * Rename a file, copying it when the destination is on another filesystem
+ */
+fn move_file(from: &Path, to: &Path) -> Result<(), Error> {
+    match fs::rename(from, to) {
+        Err(ref e) if e.raw_os_error() == Some(libc::EXDEV) => {
+            fs::copy(from, to)?;
+            fs::remove_file(from)
+        }
+        result => result,
+    }
+}
+
+/**
+ * Swap a file for whatever `create` makes at a temporary path next to it, so
+ * the file is never missing if something fails part way
+ */
+fn replace_with<F: FnOnce(&Path) -> Result<(), Error>>(
+    path: &Path,
+    create: F,
+) -> Result<(), Error> {
+    let name = path
+        .file_name()
+        .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "The path has no file name"))?;
+    let temp_path = path.with_file_name(format!(".{}.pihash.tmp", name.to_string_lossy()));
+    let _ = fs::remove_file(&temp_path);
+    create(&temp_path)?;
+    let result = fs::rename(&temp_path, path);
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env::temp_dir;
+    use std::fs::{self, create_dir_all, remove_dir_all, File};
+    use std::io::Write;
+    use std::path::PathBuf;
+
+    use cluster::{DuplicateCluster, PairDistance};
+    use similarity::{HashDistances, Similarity};
+
+    use super::{choose_keeper, is_same_file, plan_resolution, DuplicateAction, KeepPolicy};
+
+    fn create_files(name: &str, files: &[(&str, usize)]) -> (PathBuf, Vec<PathBuf>) {
+        let root = temp_dir().join(name);
+        let _ = remove_dir_all(&root);
+        let mut paths = Vec::new();
+        for &(file, size) in files {
+            let path = root.join(file);
+            create_dir_all(path.parent().unwrap()).unwrap();
+            File::create(&path)
+                .unwrap()
+                .write_all(&vec![0; size])
+                .unwrap();
+            paths.push(path);
+        }
+        (root, paths)
+    }
+
+    fn pair(first: usize, second: usize, similar: bool) -> PairDistance {
+        PairDistance {
+            first,
+            second,
+            similarity: Similarity {
+                distances: HashDistances {
+                    ahash: 0,
+                    dhash: 0,
+                    phash: 0,
+                },
+                score: if similar { 1.0 } else { 0.0 },
+                similar,
+            },
+        }
+    }
+
+    fn cluster(size: usize) -> DuplicateCluster {
+        let mut distances = Vec::new();
+        for first in 0..size {
+            for second in (first + 1)..size {
+                distances.push(pair(first, second, true));
+            }
+        }
+        DuplicateCluster {
+            members: (0..size).collect(),
+            distances,
+            representative: 1,
+        }
+    }
+
+    #[test]
+    fn test_keep_policies() {
+        let (root, paths) = create_files(
+            "pihash_test_keep_policies",
+            &[("a/long_name.jpg", 10), ("b/c.jpg", 5), ("c.jpg", 20)],
+        );
+        let cluster = cluster(3);
+        let keeper = |policy| choose_keeper(&cluster, &paths, &policy).unwrap();
+        assert_eq!(keeper(KeepPolicy::Representative), 1);
+        assert_eq!(keeper(KeepPolicy::LargestFile), 2);
+        assert_eq!(keeper(KeepPolicy::ShortestPath), 2);
+        assert_eq!(keeper(KeepPolicy::PreferredDirectory(root.join("a"))), 0);
+        // Nothing is preferred, so the representative is kept
+        assert_eq!(keeper(KeepPolicy::PreferredDirectory(root.join("d"))), 1);
+        remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_apply_actions() {
+        let (root, paths) = create_files(
+            "pihash_test_apply_actions",
+            &[
+                ("keep.jpg", 20),
+                ("nested/quarantined.jpg", 10),
+                ("linked.jpg", 5),
+            ],
+        );
+        let roots = vec![root.clone()];
+        let quarantine = root.join("quarantine");
+        let mut resolution = plan_resolution(
+            &cluster(2),
+            &paths,
+            &KeepPolicy::LargestFile,
+            &DuplicateAction::Quarantine(quarantine.clone()),
+            &roots,
+        )
+        .unwrap();
+        assert_eq!(resolution.keep, 0);
+        let (_, step) = resolution.steps.remove(0);
+        assert_eq!(
+            step.destination,
+            Some(quarantine.join("nested/quarantined.jpg"))
+        );
+        step.apply(&paths[0]).unwrap();
+        assert!(!paths[1].exists());
+        assert!(quarantine.join("nested/quarantined.jpg").is_file());
+
+        let linked = DuplicateCluster {
+            members: vec![0, 2],
+            distances: vec![pair(0, 2, true)],
+            representative: 0,
+        };
+        let resolution = plan_resolution(
+            &linked,
+            &paths,
+            &KeepPolicy::Representative,
+            &DuplicateAction::Hardlink,
+            &roots,
+        )
+        .unwrap();
+        resolution.steps[0].1.apply(&paths[0]).unwrap();
+        assert!(is_same_file(&paths[0], &paths[2]));
+        assert_eq!(fs::metadata(&paths[2]).unwrap().len(), 20);
+
+        // Now that they are the same file there is nothing left to do
+        let resolution = plan_resolution(
+            &linked,
+            &paths,
+            &KeepPolicy::Representative,
+            &DuplicateAction::Delete,
+            &roots,
+        )
+        .unwrap();
+        assert_eq!(resolution.steps[0].1.action, DuplicateAction::Report);
+        remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_only_act_on_members_similar_to_the_keeper() {
+        let (root, paths) = create_files(
+            "pihash_test_chained_cluster",
+            &[("a.jpg", 20), ("b.jpg", 10), ("c.jpg", 5)],
+        );
+        // A~B and B~C, but A and C aren't similar
+        let chained = DuplicateCluster {
+            members: vec![0, 1, 2],
+            distances: vec![pair(0, 1, true), pair(0, 2, false), pair(1, 2, true)],
+            representative: 1,
+        };
+        let resolution = plan_resolution(
+            &chained,
+            &paths,
+            &KeepPolicy::LargestFile,
+            &DuplicateAction::Delete,
+            ::std::slice::from_ref(&root),
+        )
+        .unwrap();
+        assert_eq!(resolution.keep, 0);
+        let actions: Vec<_> = resolution
+            .steps
+            .iter()
+            .map(|&(member, ref step)| (member, step.action.clone()))
+            .collect();
+        assert_eq!(
+            actions,
+            vec![(1, DuplicateAction::Delete), (2, DuplicateAction::Report)]
+        );
+        remove_dir_all(&root).unwrap();
+    }
+}
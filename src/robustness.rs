@@ -0,0 +1,491 @@
+// Copyright 2016 Drew Short <drew@sothr.com>.
+//
+// Licensed under the MIT license<LICENSE-MIT or http://opensource.org/licenses/MIT>.
+// This file may not be copied, modified, or distributed except according to those terms.
+
+extern crate image;
+
+use std::default::Default;
+use std::fmt;
+use std::fs::read_dir;
+use std::io::Error;
+use std::path::Path;
+
+use hash::{calculate_hamming_distance, get_perceptual_hash_from_image, HashType, Precision};
+use scan::DEFAULT_IMAGE_EXTENSIONS;
+
+use self::image::{DynamicImage, FilterType, GenericImageView, ImageOutputFormat, Rgba, RgbaImage};
+
+// Constants //
+
+// The corpus used when no directory of originals is provided
+pub const DEFAULT_INPUT_DIR: &str = "./test_images";
+// How much of the image shows through a watermark
+const WATERMARK_OPACITY: f64 = 0.5;
+// Height in pixels of the stripes drawn by the watermark
+const WATERMARK_STRIPE_HEIGHT: u32 = 4;
+
+// Structs/Enums //
+
+/**
+ * The modifications that can be applied to an original image
+ */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Transformation {
+    /// Re-encode as a JPEG of the given quality (1 - 100)
+    JpegRecompression(u8),
+    /// Resize both dimensions by the given factor
+    Rescale(f64),
+    /// Remove the given fraction of the width and height from each edge
+    Crop(f64),
+    /// Rotate clockwise by the given number of degrees around the center
+    Rotation(f64),
+    /// Add the given value to every channel
+    Brightness(i32),
+    /// Raise every normalised channel to the given power
+    Gamma(f64),
+    /// Gaussian blur with the given sigma
+    Blur(f32),
+    /// Overlay striped text-like watermark covering the given fraction of the
+    /// width in the bottom right corner
+    Watermark(f64),
+    FlipHorizontal,
+    FlipVertical,
+}
+
+/**
+ * What to evaluate in a robustness run
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct RobustnessConfig {
+    pub transformations: Vec<Transformation>,
+    pub hash_types: Vec<HashType>,
+    pub precisions: Vec<Precision>,
+}
+
+/**
+ * The hamming distances between the originals and their transformed variants
+ */
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DistanceDistribution {
+    distances: Vec<u64>,
+}
+
+/**
+ * The distances for a single transformation, hash type and precision
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct TransformationResult {
+    pub transformation: Transformation,
+    pub hash_type: HashType,
+    pub precision: Precision,
+    pub distribution: DistanceDistribution,
+}
+
+/**
+ * The results of a robustness run
+ */
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RobustnessReport {
+    pub originals: usize,
+    pub results: Vec<TransformationResult>,
+    pub failures: Vec<(String, String)>,
+}
+
+impl Default for RobustnessConfig {
+    fn default() -> RobustnessConfig {
+        RobustnessConfig {
+            transformations: default_transformations(),
+            hash_types: vec![HashType::AHash, HashType::DHash, HashType::PHash],
+            precisions: vec![Precision::Low, Precision::Medium, Precision::High],
+        }
+    }
+}
+
+impl fmt::Display for Transformation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Transformation::JpegRecompression(quality) => write!(f, "jpeg(quality={})", quality),
+            Transformation::Rescale(factor) => write!(f, "rescale(factor={})", factor),
+            Transformation::Crop(fraction) => write!(f, "crop(fraction={})", fraction),
+            Transformation::Rotation(degrees) => write!(f, "rotation(degrees={})", degrees),
+            Transformation::Brightness(value) => write!(f, "brightness(value={})", value),
+            Transformation::Gamma(gamma) => write!(f, "gamma(value={})", gamma),
+            Transformation::Blur(sigma) => write!(f, "blur(sigma={})", sigma),
+            Transformation::Watermark(fraction) => write!(f, "watermark(fraction={})", fraction),
+            Transformation::FlipHorizontal => write!(f, "flip(horizontal)"),
+            Transformation::FlipVertical => write!(f, "flip(vertical)"),
+        }
+    }
+}
+
+impl Transformation {
+    /**
+     * Create a transformed copy of the provided image
+     */
+    pub fn apply(&self, image: &DynamicImage) -> Result<DynamicImage, Error> {
+        let (width, height) = image.dimensions();
+        let transformed = match *self {
+            Transformation::JpegRecompression(quality) => {
+                let mut buffer: Vec<u8> = Vec::new();
+                if let Err(e) = image.write_to(&mut buffer, ImageOutputFormat::JPEG(quality)) {
+                    return Err(Error::other(e));
+                }
+                match image::load_from_memory(&buffer) {
+                    Ok(image) => image,
+                    Err(e) => return Err(Error::other(e)),
+                }
+            }
+            Transformation::Rescale(factor) => image.resize_exact(
+                scale_dimension(width, factor),
+                scale_dimension(height, factor),
+                FilterType::Lanczos3,
+            ),
+            Transformation::Crop(fraction) => {
+                let x = (width as f64 * fraction) as u32;
+                let y = (height as f64 * fraction) as u32;
+                let mut cropped = image.clone();
+                cropped.crop(
+                    x,
+                    y,
+                    width.saturating_sub(x * 2).max(1),
+                    height.saturating_sub(y * 2).max(1),
+                )
+            }
+            Transformation::Rotation(degrees) => rotate(image, degrees),
+            Transformation::Brightness(value) => image.brighten(value),
+            Transformation::Gamma(gamma) => adjust_gamma(image, gamma),
+            Transformation::Blur(sigma) => image.blur(sigma),
+            Transformation::Watermark(fraction) => watermark(image, fraction),
+            Transformation::FlipHorizontal => image.fliph(),
+            Transformation::FlipVertical => image.flipv(),
+        };
+        Ok(transformed)
+    }
+}
+
+impl DistanceDistribution {
+    pub fn new(mut distances: Vec<u64>) -> DistanceDistribution {
+        distances.sort();
+        DistanceDistribution { distances }
+    }
+
+    pub fn len(&self) -> usize {
+        self.distances.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.distances.is_empty()
+    }
+
+    pub fn distances(&self) -> &[u64] {
+        &self.distances
+    }
+
+    pub fn min(&self) -> u64 {
+        self.distances.first().cloned().unwrap_or(0)
+    }
+
+    pub fn max(&self) -> u64 {
+        self.distances.last().cloned().unwrap_or(0)
+    }
+
+    pub fn mean(&self) -> f64 {
+        if self.distances.is_empty() {
+            0_f64
+        } else {
+            self.distances.iter().sum::<u64>() as f64 / self.distances.len() as f64
+        }
+    }
+
+    pub fn median(&self) -> u64 {
+        self.percentile(50_f64)
+    }
+
+    /**
+     * Get the distance at the given percentile (0 - 100) using the nearest rank
+     */
+    pub fn percentile(&self, percentile: f64) -> u64 {
+        if self.distances.is_empty() {
+            return 0;
+        }
+        let rank = (percentile / 100_f64 * self.distances.len() as f64).ceil() as usize;
+        self.distances[rank.max(1).min(self.distances.len()) - 1]
+    }
+}
+
+// Functions //
+
+/**
+ * The transformations evaluated when none are configured
+ */
+pub fn default_transformations() -> Vec<Transformation> {
+    vec![
+        Transformation::JpegRecompression(90),
+        Transformation::JpegRecompression(70),
+        Transformation::JpegRecompression(50),
+        Transformation::JpegRecompression(30),
+        Transformation::JpegRecompression(10),
+        Transformation::Rescale(0.5),
+        Transformation::Rescale(0.25),
+        Transformation::Rescale(2.0),
+        Transformation::Crop(0.05),
+        Transformation::Crop(0.1),
+        Transformation::Rotation(2.0),
+        Transformation::Rotation(5.0),
+        Transformation::Rotation(90.0),
+        Transformation::Brightness(20),
+        Transformation::Brightness(-20),
+        Transformation::Gamma(0.8),
+        Transformation::Gamma(1.2),
+        Transformation::Blur(1.0),
+        Transformation::Blur(3.0),
+        Transformation::Watermark(0.25),
+        Transformation::FlipHorizontal,
+    ]
+}
+
+/**
+ * Evaluate every image in a directory
+ *
+ * Files that can't be decoded are recorded as failures rather than aborting
+ * the run.
+ */
+pub fn evaluate_directory(dir: &Path, config: &RobustnessConfig) -> Result<RobustnessReport, Error> {
+    let mut paths = Vec::new();
+    for entry in read_dir(dir)? {
+        let path = entry?.path();
+        let is_image = match path.extension().and_then(|ext| ext.to_str()) {
+            Some(ext) => DEFAULT_IMAGE_EXTENSIONS.contains(&ext.to_lowercase().as_str()),
+            None => false,
+        };
+        if path.is_file() && is_image {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+
+    let mut originals = Vec::new();
+    let mut failures = Vec::new();
+    for path in paths {
+        let name = path.to_string_lossy().into_owned();
+        match image::open(&path) {
+            Ok(image) => originals.push((name, image)),
+            Err(e) => failures.push((name, format!("{}", e))),
+        }
+    }
+
+    let mut report = evaluate_images(&originals, config);
+    failures.append(&mut report.failures);
+    report.failures = failures;
+    Ok(report)
+}
+
+/**
+ * Evaluate a set of named images that are already in memory
+ */
+pub fn evaluate_images(
+    originals: &[(String, DynamicImage)],
+    config: &RobustnessConfig,
+) -> RobustnessReport {
+    // Hash the originals once per hash type and precision
+    let mut original_hashes: Vec<Vec<u64>> = Vec::with_capacity(originals.len());
+    for (_, image) in originals {
+        let mut hashes = Vec::new();
+        for precision in &config.precisions {
+            for hash_type in &config.hash_types {
+                hashes.push(get_perceptual_hash_from_image(image, precision, hash_type));
+            }
+        }
+        original_hashes.push(hashes);
+    }
+
+    let mut report = RobustnessReport {
+        originals: originals.len(),
+        results: Vec::new(),
+        failures: Vec::new(),
+    };
+    for transformation in &config.transformations {
+        let combinations = config.precisions.len() * config.hash_types.len();
+        let mut distances: Vec<Vec<u64>> = vec![Vec::new(); combinations];
+        for (index, (name, image)) in originals.iter().enumerate() {
+            let variant = match transformation.apply(image) {
+                Ok(variant) => variant,
+                Err(e) => {
+                    report
+                        .failures
+                        .push((name.clone(), format!("{}: {}", transformation, e)));
+                    continue;
+                }
+            };
+            let mut combination = 0;
+            for precision in &config.precisions {
+                for hash_type in &config.hash_types {
+                    let hash = get_perceptual_hash_from_image(&variant, precision, hash_type);
+                    distances[combination].push(calculate_hamming_distance(
+                        original_hashes[index][combination],
+                        hash,
+                    ));
+                    combination += 1;
+                }
+            }
+        }
+
+        let mut combination_distances = distances.into_iter();
+        for precision in &config.precisions {
+            for hash_type in &config.hash_types {
+                report.results.push(TransformationResult {
+                    transformation: *transformation,
+                    hash_type: *hash_type,
+                    precision: *precision,
+                    distribution: DistanceDistribution::new(
+                        combination_distances.next().unwrap_or_default(),
+                    ),
+                });
+            }
+        }
+    }
+    report
+}
+
+fn scale_dimension(dimension: u32, factor: f64) -> u32 {
+    ((dimension as f64 * factor).round() as u32).max(1)
+}
+
+/**
+ * Rotate around the center keeping the original dimensions. Corners that have
+ * no source pixel are filled with black.
+ */
+fn rotate(image: &DynamicImage, degrees: f64) -> DynamicImage {
+    let source = image.to_rgba();
+    let (width, height) = source.dimensions();
+    let (sin, cos) = degrees.to_radians().sin_cos();
+    let center_x = width as f64 / 2_f64;
+    let center_y = height as f64 / 2_f64;
+    let rotated = RgbaImage::from_fn(width, height, |x, y| {
+        // Find the source pixel that lands on this one after rotating
+        let dx = x as f64 + 0.5 - center_x;
+        let dy = y as f64 + 0.5 - center_y;
+        let source_x = cos * dx + sin * dy + center_x;
+        let source_y = cos * dy - sin * dx + center_y;
+        if source_x >= 0_f64
+            && source_y >= 0_f64
+            && source_x < width as f64
+            && source_y < height as f64
+        {
+            *source.get_pixel(source_x as u32, source_y as u32)
+        } else {
+            Rgba([0, 0, 0, 255])
+        }
+    });
+    DynamicImage::ImageRgba8(rotated)
+}
+
+fn adjust_gamma(image: &DynamicImage, gamma: f64) -> DynamicImage {
+    let mut adjusted = image.to_rgba();
+    for pixel in adjusted.pixels_mut() {
+        for channel in 0..3 {
+            let value = pixel.0[channel] as f64 / 255_f64;
+            pixel.0[channel] = (value.powf(gamma) * 255_f64).round() as u8;
+        }
+    }
+    DynamicImage::ImageRgba8(adjusted)
+}
+
+fn watermark(image: &DynamicImage, fraction: f64) -> DynamicImage {
+    let mut marked = image.to_rgba();
+    let (width, height) = marked.dimensions();
+    let mark_width = scale_dimension(width, fraction).min(width);
+    let mark_height = (mark_width / 3).max(1).min(height);
+    for y in (height - mark_height)..height {
+        // Alternate light and dark stripes so it resembles text rather than a flat box
+        let stripe_value = if (y / WATERMARK_STRIPE_HEIGHT).is_multiple_of(2) {
+            255_f64
+        } else {
+            0_f64
+        };
+        for x in (width - mark_width)..width {
+            let pixel = marked.get_pixel_mut(x, y);
+            for channel in 0..3 {
+                let value = pixel.0[channel] as f64;
+                pixel.0[channel] =
+                    (value * WATERMARK_OPACITY + stripe_value * (1_f64 - WATERMARK_OPACITY)) as u8;
+            }
+        }
+    }
+    DynamicImage::ImageRgba8(marked)
+}
+
+impl fmt::Display for RobustnessReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Evaluated {} originals", self.originals)?;
+        writeln!(f, "transformation\tprecision\thash\tmin\tmedian\tmean\tp95\tmax")?;
+        for result in &self.results {
+            let distribution = &result.distribution;
+            writeln!(
+                f,
+                "{}\t{}\t{}\t{}\t{}\t{:.2}\t{}\t{}",
+                result.transformation,
+                result.precision,
+                result.hash_type,
+                distribution.min(),
+                distribution.median(),
+                distribution.mean(),
+                distribution.percentile(95_f64),
+                distribution.max()
+            )?;
+        }
+        for (name, error) in &self.failures {
+            writeln!(f, "Failed {}: {}", name, error)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hash::{HashType, Precision};
+
+    use super::image::{DynamicImage, GenericImageView, ImageBuffer, Luma};
+    use super::{evaluate_images, DistanceDistribution, RobustnessConfig, Transformation};
+
+    fn gradient() -> DynamicImage {
+        DynamicImage::ImageLuma8(ImageBuffer::from_fn(64, 64, |x, y| {
+            Luma([(x * 2 + y * 2) as u8])
+        }))
+    }
+
+    #[test]
+    fn test_distance_distribution() {
+        let distribution = DistanceDistribution::new(vec![4, 1, 3, 2]);
+        assert_eq!(distribution.min(), 1);
+        assert_eq!(distribution.max(), 4);
+        assert_eq!(distribution.median(), 2);
+        assert_eq!(distribution.percentile(95_f64), 4);
+        assert_eq!(distribution.mean(), 2.5);
+    }
+
+    #[test]
+    fn test_transformations_keep_usable_images() {
+        let image = gradient();
+        for transformation in super::default_transformations() {
+            let variant = transformation.apply(&image).unwrap();
+            let (width, height) = variant.dimensions();
+            assert!(width > 0 && height > 0);
+        }
+    }
+
+    #[test]
+    fn test_identity_transformation_has_no_distance() {
+        let config = RobustnessConfig {
+            transformations: vec![Transformation::Rescale(1.0), Transformation::FlipVertical],
+            hash_types: vec![HashType::AHash, HashType::PHash],
+            precisions: vec![Precision::Medium],
+        };
+        let report = evaluate_images(&[(String::from("gradient"), gradient())], &config);
+        assert_eq!(report.originals, 1);
+        assert_eq!(report.results.len(), 4);
+        assert_eq!(report.results[0].distribution.max(), 0);
+        assert!(report.results[2].distribution.max() > 0);
+    }
+}
@@ -0,0 +1,248 @@
+// Copyright 2016 Drew Short <drew@sothr.com>.
+//
+// Licensed under the MIT license<LICENSE-MIT or http://opensource.org/licenses/MIT>.
+// This file may not be copied, modified, or distributed except according to those terms.
+
+use std::fmt;
+use std::io::{Error, ErrorKind};
+use std::path::{Component, Path};
+use std::str::FromStr;
+
+// Structs/Enums //
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Literal(char),
+    // ?
+    AnyChar,
+    // *, anything within a single path component
+    AnyChars,
+    // ** not followed by a slash, anything at all
+    AnyPath,
+    // **/, zero or more whole directories
+    AnyDirectories,
+    // [...] and [!...]
+    Class { negated: bool, ranges: Vec<(char, char)> },
+}
+
+/**
+ * A shell style glob for matching relative paths
+ *
+ * Supports ?, *, ** and [...] classes, with \ to escape a character. A
+ * pattern without a slash matches the file name at any depth, otherwise it
+ * matches the whole path. A leading slash only marks a pattern as anchored.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct GlobPattern {
+    pattern: String,
+    tokens: Vec<Token>,
+    anchored: bool,
+}
+
+impl FromStr for GlobPattern {
+    type Err = Error;
+
+    fn from_str(pattern: &str) -> Result<GlobPattern, Error> {
+        GlobPattern::new(pattern)
+    }
+}
+
+impl fmt::Display for GlobPattern {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.pattern)
+    }
+}
+
+impl GlobPattern {
+    pub fn new(pattern: &str) -> Result<GlobPattern, Error> {
+        let anchored = pattern.contains('/');
+        let body = pattern.trim_start_matches('/');
+        let chars: Vec<char> = body.chars().collect();
+        let mut tokens = Vec::new();
+        let mut position = 0;
+        while position < chars.len() {
+            match chars[position] {
+                '\\' => {
+                    position += 1;
+                    match chars.get(position) {
+                        Some(&escaped) => tokens.push(Token::Literal(escaped)),
+                        None => return Err(invalid(pattern, "it ends with an escape")),
+                    }
+                }
+                '?' => tokens.push(Token::AnyChar),
+                '*' if chars.get(position + 1) == Some(&'*') => {
+                    position += 1;
+                    if chars.get(position + 1) == Some(&'/') {
+                        position += 1;
+                        tokens.push(Token::AnyDirectories);
+                    } else {
+                        tokens.push(Token::AnyPath);
+                    }
+                }
+                '*' => tokens.push(Token::AnyChars),
+                '[' => {
+                    let (token, end) = parse_class(&chars, position)
+                        .ok_or_else(|| invalid(pattern, "a character class isn't closed"))?;
+                    tokens.push(token);
+                    position = end;
+                }
+                literal => tokens.push(Token::Literal(literal)),
+            }
+            position += 1;
+        }
+        Ok(GlobPattern {
+            pattern: String::from(pattern),
+            tokens,
+            anchored,
+        })
+    }
+
+    /**
+     * Match a relative path, using the file name for unanchored patterns
+     */
+    pub fn matches_path(&self, path: &Path) -> bool {
+        if self.anchored {
+            let components: Vec<String> = path
+                .components()
+                .filter_map(|component| match component {
+                    Component::Normal(name) => Some(name.to_string_lossy().into_owned()),
+                    _ => None,
+                })
+                .collect();
+            self.matches(&components.join("/"))
+        } else {
+            match path.file_name() {
+                Some(name) => self.matches(&name.to_string_lossy()),
+                None => false,
+            }
+        }
+    }
+
+    /**
+     * Match a string with / separated components
+     */
+    pub fn matches(&self, text: &str) -> bool {
+        let text: Vec<char> = text.chars().collect();
+        let width = text.len() + 1;
+        // matched[token * width + position] is whether tokens[token..] match
+        // text[position..], filled in from the end of both
+        let mut matched = vec![false; (self.tokens.len() + 1) * width];
+        matched[self.tokens.len() * width + text.len()] = true;
+        for token in (0..self.tokens.len()).rev() {
+            let row = token * width;
+            let next = (token + 1) * width;
+            // Whether a later slash lets **/ skip ahead to the rest of the pattern
+            let mut after_slash = false;
+            for position in (0..width).rev() {
+                let current = text.get(position).cloned();
+                matched[row + position] = match self.tokens[token] {
+                    Token::Literal(literal) => {
+                        current == Some(literal) && matched[next + position + 1]
+                    }
+                    Token::AnyChar => {
+                        current.is_some_and(|c| c != '/') && matched[next + position + 1]
+                    }
+                    Token::Class {
+                        negated,
+                        ref ranges,
+                    } => current.is_some_and(|c| {
+                        let in_class = ranges.iter().any(|&(low, high)| c >= low && c <= high);
+                        c != '/' && in_class != negated && matched[next + position + 1]
+                    }),
+                    Token::AnyChars => {
+                        matched[next + position]
+                            || current.is_some_and(|c| c != '/' && matched[row + position + 1])
+                    }
+                    Token::AnyPath => {
+                        matched[next + position]
+                            || (current.is_some() && matched[row + position + 1])
+                    }
+                    Token::AnyDirectories => {
+                        if current == Some('/') && matched[next + position + 1] {
+                            after_slash = true;
+                        }
+                        matched[next + position] || after_slash
+                    }
+                };
+            }
+        }
+        matched[0]
+    }
+}
+
+// Functions //
+
+fn parse_class(chars: &[char], start: usize) -> Option<(Token, usize)> {
+    let mut position = start + 1;
+    let negated = match chars.get(position) {
+        Some(&'!') | Some(&'^') => {
+            position += 1;
+            true
+        }
+        _ => false,
+    };
+    let mut ranges = Vec::new();
+    let mut first = true;
+    loop {
+        let low = *chars.get(position)?;
+        // A ] straight after the opening bracket is part of the class
+        if low == ']' && !first {
+            return Some((Token::Class { negated, ranges }, position));
+        }
+        first = false;
+        if chars.get(position + 1) == Some(&'-') {
+            if let Some(&high) = chars.get(position + 2) {
+                if high != ']' {
+                    ranges.push((low, high));
+                    position += 3;
+                    continue;
+                }
+            }
+        }
+        ranges.push((low, low));
+        position += 1;
+    }
+}
+
+fn invalid(pattern: &str, reason: &str) -> Error {
+    Error::new(
+        ErrorKind::InvalidInput,
+        format!("Invalid glob pattern '{}': {}", pattern, reason),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::GlobPattern;
+
+    fn glob(pattern: &str) -> GlobPattern {
+        GlobPattern::new(pattern).unwrap()
+    }
+
+    #[test]
+    fn test_wildcards() {
+        assert!(glob("*.jpg").matches("sample.jpg"));
+        assert!(!glob("*.jpg").matches("dir/sample.jpg"));
+        assert!(glob("sample_0?.png").matches("sample_01.png"));
+        assert!(glob("[a-c]*").matches("beta"));
+        assert!(!glob("[!a-c]*").matches("beta"));
+        assert!(glob("photos/**/*.jpg").matches("photos/sample.jpg"));
+        assert!(glob("photos/**/*.jpg").matches("photos/2016/05/sample.jpg"));
+        assert!(!glob("photos/**/*.jpg").matches("other/photos/sample.jpg"));
+        assert!(glob("photos/**").matches("photos/2016/sample.jpg"));
+        assert!(glob("\\*").matches("*"));
+        assert!(!glob("\\*").matches("a"));
+        assert!(GlobPattern::new("[abc").is_err());
+    }
+
+    #[test]
+    fn test_anchoring() {
+        let path = Path::new("photos/thumbs/sample.jpg");
+        assert!(glob("*.jpg").matches_path(path));
+        assert!(glob("/photos/*/*.jpg").matches_path(path));
+        assert!(!glob("/thumbs/*.jpg").matches_path(path));
+        assert!(glob("**/thumbs/*").matches_path(path));
+    }
+}
@@ -0,0 +1,616 @@
+// Copyright 2016 Drew Short <drew@sothr.com>.
+//
+// Licensed under the MIT license<LICENSE-MIT or http://opensource.org/licenses/MIT>.
+// This file may not be copied, modified, or distributed except according to those terms.
+
+extern crate image;
+
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File, Metadata};
+use std::io::{BufRead, BufReader, Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use batch::BatchInput;
+
+pub use self::glob::GlobPattern;
+
+mod glob;
+
+// Constants //
+
+pub const DEFAULT_IMAGE_EXTENSIONS: [&str; 6] =
+    ["jpg", "jpeg", "png", "gif", "bmp", "tiff"];
+// Read from every scanned directory, with gitignore like rules
+pub const IGNORE_FILE_NAME: &str = ".pihashignore";
+
+// Structs/Enums //
+
+/**
+ * Which files a scan reports
+ *
+ * Include and exclude patterns are matched against paths relative to the
+ * root of the scan. Excluded and ignored directories aren't descended into.
+ * Extensions are compared without case, and an empty list accepts any file
+ * that decodes as an image.
+ */
+#[derive(Clone, Debug)]
+pub struct ScanConfig {
+    pub extensions: Vec<String>,
+    pub include: Vec<GlobPattern>,
+    pub exclude: Vec<GlobPattern>,
+    pub min_file_size: Option<u64>,
+    pub max_file_size: Option<u64>,
+    pub min_dimensions: Option<(u32, u32)>,
+    pub max_dimensions: Option<(u32, u32)>,
+    pub follow_symlinks: bool,
+    pub use_ignore_files: bool,
+}
+
+/**
+ * An image found by a scan
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct ScannedImage {
+    pub path: PathBuf,
+    pub file_size: u64,
+    pub dimensions: (u32, u32),
+}
+
+#[derive(Clone, Debug)]
+struct IgnoreRule {
+    pattern: GlobPattern,
+    negated: bool,
+    directory_only: bool,
+}
+
+// The rules of an ignore file, and the directory they're relative to
+#[derive(Clone, Debug)]
+struct IgnoreFile {
+    base: PathBuf,
+    rules: Vec<IgnoreRule>,
+}
+
+/**
+ * The ignore files a scan read, by the directory they're in, for checking
+ * files that are found some other way than walking the tree
+ */
+#[derive(Clone, Debug, Default)]
+pub struct IgnoreRules {
+    ignore_files: HashMap<PathBuf, IgnoreFile>,
+}
+
+struct PendingDirectory {
+    path: PathBuf,
+    ignore_files: Arc<Vec<IgnoreFile>>,
+}
+
+/**
+ * Walks a directory tree depth first in name order, yielding the images that
+ * pass the filters
+ *
+ * Files that aren't images are skipped quietly. Errors are only yielded for
+ * files and directories that can't be read.
+ */
+pub struct Scanner {
+    root: PathBuf,
+    config: ScanConfig,
+    directories: Vec<PendingDirectory>,
+    files: Vec<(PathBuf, Metadata)>,
+    // Canonical directories already walked, so symlink loops end
+    visited: HashSet<PathBuf>,
+    ignore_rules: IgnoreRules,
+    errors: Vec<Error>,
+}
+
+impl Default for ScanConfig {
+    fn default() -> ScanConfig {
+        ScanConfig {
+            extensions: DEFAULT_IMAGE_EXTENSIONS
+                .iter()
+                .map(|extension| String::from(*extension))
+                .collect(),
+            include: Vec::new(),
+            exclude: Vec::new(),
+            min_file_size: None,
+            max_file_size: None,
+            min_dimensions: None,
+            max_dimensions: None,
+            follow_symlinks: false,
+            use_ignore_files: true,
+        }
+    }
+}
+
+impl ScanConfig {
+    fn accepts_extension(&self, path: &Path) -> bool {
+        if self.extensions.is_empty() {
+            return true;
+        }
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some(extension) => self
+                .extensions
+                .iter()
+                .any(|accepted| accepted.eq_ignore_ascii_case(extension)),
+            None => false,
+        }
+    }
+
+    fn accepts_file_size(&self, size: u64) -> bool {
+        self.min_file_size.is_none_or(|min| size >= min)
+            && self.max_file_size.is_none_or(|max| size <= max)
+    }
+
+    fn accepts_dimensions(&self, (width, height): (u32, u32)) -> bool {
+        self.min_dimensions
+            .is_none_or(|(min_width, min_height)| width >= min_width && height >= min_height)
+            && self
+                .max_dimensions
+                .is_none_or(|(max_width, max_height)| width <= max_width && height <= max_height)
+    }
+}
+
+impl From<ScannedImage> for BatchInput {
+    fn from(image: ScannedImage) -> BatchInput {
+        BatchInput::Path(image.path)
+    }
+}
+
+impl Iterator for Scanner {
+    type Item = Result<ScannedImage, Error>;
+
+    fn next(&mut self) -> Option<Result<ScannedImage, Error>> {
+        loop {
+            if let Some(error) = self.errors.pop() {
+                return Some(Err(error));
+            }
+            if let Some((path, metadata)) = self.files.pop() {
+                if let Some(image) = self.check_file(path, &metadata) {
+                    return Some(Ok(image));
+                }
+                continue;
+            }
+            match self.directories.pop() {
+                Some(directory) => self.read_directory(directory),
+                None => return None,
+            }
+        }
+    }
+}
+
+impl Scanner {
+    /**
+     * Start a scan. A root that is a file is checked against the filters on
+     * its own.
+     */
+    pub fn new(root: &Path, config: ScanConfig) -> Scanner {
+        let mut scanner = Scanner {
+            root: root.to_path_buf(),
+            config,
+            directories: Vec::new(),
+            files: Vec::new(),
+            visited: HashSet::new(),
+            ignore_rules: Default::default(),
+            errors: Vec::new(),
+        };
+        match fs::metadata(root) {
+            Ok(ref metadata) if metadata.is_dir() => scanner.directories.push(PendingDirectory {
+                path: root.to_path_buf(),
+                ignore_files: Arc::new(Vec::new()),
+            }),
+            Ok(metadata) => scanner.files.push((root.to_path_buf(), metadata)),
+            Err(e) => scanner.errors.push(with_path(e, root)),
+        }
+        scanner
+    }
+
+    fn read_directory(&mut self, directory: PendingDirectory) {
+        if self.config.follow_symlinks {
+            match fs::canonicalize(&directory.path) {
+                Ok(canonical) => {
+                    if !self.visited.insert(canonical) {
+                        return;
+                    }
+                }
+                Err(e) => {
+                    self.errors.push(with_path(e, &directory.path));
+                    return;
+                }
+            }
+        }
+
+        let mut ignore_files = directory.ignore_files;
+        if self.config.use_ignore_files {
+            let ignore_path = directory.path.join(IGNORE_FILE_NAME);
+            if ignore_path.is_file() {
+                match read_ignore_file(&ignore_path) {
+                    Ok(rules) => {
+                        let ignore_file = IgnoreFile {
+                            base: directory.path.clone(),
+                            rules,
+                        };
+                        self.ignore_rules
+                            .ignore_files
+                            .insert(directory.path.clone(), ignore_file.clone());
+                        let mut extended = ignore_files.to_vec();
+                        extended.push(ignore_file);
+                        ignore_files = Arc::new(extended);
+                    }
+                    Err(e) => self.errors.push(with_path(e, &ignore_path)),
+                }
+            }
+        }
+
+        let entries = match fs::read_dir(&directory.path) {
+            Ok(entries) => entries,
+            Err(e) => {
+                self.errors.push(with_path(e, &directory.path));
+                return;
+            }
+        };
+        let mut paths = Vec::new();
+        for entry in entries {
+            match entry {
+                Ok(entry) => paths.push(entry.path()),
+                Err(e) => self.errors.push(with_path(e, &directory.path)),
+            }
+        }
+        paths.sort();
+
+        let mut files = Vec::new();
+        let mut directories = Vec::new();
+        for path in paths {
+            let metadata = if self.config.follow_symlinks {
+                fs::metadata(&path)
+            } else {
+                fs::symlink_metadata(&path)
+            };
+            let metadata = match metadata {
+                Ok(metadata) => metadata,
+                Err(e) => {
+                    self.errors.push(with_path(e, &path));
+                    continue;
+                }
+            };
+            let is_directory = metadata.is_dir();
+            if !metadata.is_file() && !is_directory {
+                continue;
+            }
+            if self.is_excluded(&path, is_directory, &ignore_files) {
+                continue;
+            }
+            if is_directory {
+                directories.push(PendingDirectory {
+                    path,
+                    ignore_files: ignore_files.clone(),
+                });
+            } else {
+                files.push((path, metadata));
+            }
+        }
+
+        // Both are stacks, so reverse them to come out in name order
+        files.reverse();
+        self.files = files;
+        directories.reverse();
+        self.directories.append(&mut directories);
+    }
+
+    fn is_excluded(&self, path: &Path, is_directory: bool, ignore_files: &[IgnoreFile]) -> bool {
+        let relative = path.strip_prefix(&self.root).unwrap_or(path);
+        if self
+            .config
+            .exclude
+            .iter()
+            .any(|pattern| pattern.matches_path(relative))
+        {
+            return true;
+        }
+
+        is_ignored_by(path, is_directory, ignore_files)
+    }
+
+    fn check_file(&self, path: PathBuf, metadata: &Metadata) -> Option<ScannedImage> {
+        accept_file(&self.root, path, metadata, &self.config)
+    }
+
+    /**
+     * The ignore files read so far, which is all of those that apply once
+     * the scan is finished
+     */
+    pub fn ignore_rules(&self) -> &IgnoreRules {
+        &self.ignore_rules
+    }
+}
+
+impl IgnoreRules {
+    /**
+     * If a path below the root is ignored, or is in an ignored directory.
+     * Each directory on the way is checked against the ignore files above
+     * it, as a scan would.
+     */
+    pub fn is_ignored(&self, root: &Path, path: &Path, is_directory: bool) -> bool {
+        let relative = match path.strip_prefix(root) {
+            Ok(relative) => relative,
+            Err(_) => return false,
+        };
+        let components: Vec<_> = relative.components().collect();
+        let mut ignore_files = Vec::new();
+        let mut current = root.to_path_buf();
+        for (position, component) in components.iter().enumerate() {
+            if let Some(ignore_file) = self.ignore_files.get(&current) {
+                ignore_files.push(ignore_file.clone());
+            }
+            current.push(component);
+            let is_last = position + 1 == components.len();
+            if is_ignored_by(&current, !is_last || is_directory, &ignore_files) {
+                return true;
+            }
+        }
+        false
+    }
+}
+
+// Functions //
+
+/**
+ * Scan a directory tree for images
+ */
+pub fn scan_directory(root: &Path, config: ScanConfig) -> Scanner {
+    Scanner::new(root, config)
+}
+
+/**
+ * Check a single file below a scan root against the filters, for when files
+ * are found some other way than walking the tree. Ignore files aren't read,
+ * the rules of those an earlier scan read are used instead.
+ */
+pub fn check_image(
+    root: &Path,
+    path: &Path,
+    config: &ScanConfig,
+    ignore_rules: &IgnoreRules,
+) -> Result<Option<ScannedImage>, Error> {
+    let link_metadata = fs::symlink_metadata(path).map_err(|e| with_path(e, path))?;
+    if link_metadata.file_type().is_symlink() && !config.follow_symlinks {
+        return Ok(None);
+    }
+    let metadata = fs::metadata(path).map_err(|e| with_path(e, path))?;
+    if !metadata.is_file() {
+        return Ok(None);
+    }
+    // Excluding a directory excludes everything below it
+    let relative = path.strip_prefix(root).unwrap_or(path);
+    let excluded = relative
+        .ancestors()
+        .filter(|ancestor| !ancestor.as_os_str().is_empty())
+        .any(|ancestor| {
+            config
+                .exclude
+                .iter()
+                .any(|pattern| pattern.matches_path(ancestor))
+        });
+    if excluded || (config.use_ignore_files && ignore_rules.is_ignored(root, path, false)) {
+        return Ok(None);
+    }
+    Ok(accept_file(root, path.to_path_buf(), &metadata, config))
+}
+
+/**
+ * Parse a dimension limit in the form WIDTHxHEIGHT
+ */
+pub fn parse_dimensions(dimensions: &str) -> Result<(u32, u32), Error> {
+    let invalid = || {
+        Error::new(
+            ErrorKind::InvalidInput,
+            format!("Expected dimensions as WIDTHxHEIGHT, got '{}'", dimensions),
+        )
+    };
+    let mut parts = dimensions.splitn(2, ['x', 'X']);
+    let width = parts.next().and_then(|width| width.trim().parse().ok());
+    let height = parts.next().and_then(|height| height.trim().parse().ok());
+    match (width, height) {
+        (Some(width), Some(height)) => Ok((width, height)),
+        _ => Err(invalid()),
+    }
+}
+
+fn accept_file(
+    root: &Path,
+    path: PathBuf,
+    metadata: &Metadata,
+    config: &ScanConfig,
+) -> Option<ScannedImage> {
+    let relative = path.strip_prefix(root).unwrap_or(&path).to_path_buf();
+    let included = config.include.is_empty()
+        || config
+            .include
+            .iter()
+            .any(|pattern| pattern.matches_path(&relative));
+    if !included || !config.accepts_extension(&path) || !config.accepts_file_size(metadata.len())
+    {
+        return None;
+    }
+    // Only the header is read, and anything that doesn't decode isn't an image
+    let dimensions = match image::image_dimensions(&path) {
+        Ok(dimensions) => dimensions,
+        Err(_) => return None,
+    };
+    if !config.accepts_dimensions(dimensions) {
+        return None;
+    }
+    Some(ScannedImage {
+        path,
+        file_size: metadata.len(),
+        dimensions,
+    })
+}
+
+/**
+ * If the ignore files, outermost first, ignore a path. The last rule to
+ * match wins, so deeper ignore files override.
+ */
+fn is_ignored_by(path: &Path, is_directory: bool, ignore_files: &[IgnoreFile]) -> bool {
+    let mut ignored = false;
+    for ignore_file in ignore_files {
+        let relative = path.strip_prefix(&ignore_file.base).unwrap_or(path);
+        for rule in &ignore_file.rules {
+            if (is_directory || !rule.directory_only) && rule.pattern.matches_path(relative) {
+                ignored = !rule.negated;
+            }
+        }
+    }
+    ignored
+}
+
+fn read_ignore_file(path: &Path) -> Result<Vec<IgnoreRule>, Error> {
+    let mut rules = Vec::new();
+    for line in BufReader::new(File::open(path)?).lines() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (negated, line) = match line.strip_prefix('!') {
+            Some(line) => (true, line),
+            None => (false, line),
+        };
+        let directory_only = line.ends_with('/');
+        rules.push(IgnoreRule {
+            pattern: GlobPattern::new(line.trim_end_matches('/'))?,
+            negated,
+            directory_only,
+        });
+    }
+    Ok(rules)
+}
+
+fn with_path(error: Error, path: &Path) -> Error {
+    Error::new(error.kind(), format!("{}: {}", path.display(), error))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env::temp_dir;
+    use std::fs::{copy, create_dir_all, remove_dir_all, File};
+    use std::io::Write;
+    use std::path::{Path, PathBuf};
+
+    use super::{
+        check_image, parse_dimensions, scan_directory, GlobPattern, ScanConfig, IGNORE_FILE_NAME,
+    };
+
+    fn create_tree(name: &str) -> PathBuf {
+        let root = temp_dir().join(name);
+        let _ = remove_dir_all(&root);
+        create_dir_all(root.join("nested/deeper")).unwrap();
+        create_dir_all(root.join("skipped")).unwrap();
+        copy("test_images/sample_02_large.jpg", root.join("large.jpg")).unwrap();
+        copy("test_images/sample_02_small.jpg", root.join("nested/small.jpg")).unwrap();
+        copy("test_images/sample_03_medium.jpg", root.join("nested/deeper/medium.JPG")).unwrap();
+        copy("test_images/sample_04_small.jpg", root.join("skipped/small.jpg")).unwrap();
+        // Not an image, whatever the extension says
+        File::create(root.join("nested/broken.jpg"))
+            .unwrap()
+            .write_all(b"not an image")
+            .unwrap();
+        File::create(root.join("notes.txt"))
+            .unwrap()
+            .write_all(b"notes")
+            .unwrap();
+        root
+    }
+
+    fn scan(root: &Path, config: ScanConfig) -> Vec<String> {
+        scan_directory(root, config)
+            .map(|image| {
+                let path = image.unwrap().path;
+                let relative = path.strip_prefix(root).unwrap().to_path_buf();
+                relative.to_string_lossy().into_owned()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_scan_filters() {
+        let root = create_tree("pihash_test_scan_filters");
+        assert_eq!(
+            scan(&root, Default::default()),
+            vec![
+                "large.jpg",
+                "nested/small.jpg",
+                "nested/deeper/medium.JPG",
+                "skipped/small.jpg",
+            ]
+        );
+
+        let config = ScanConfig {
+            exclude: vec![GlobPattern::new("skipped").unwrap()],
+            include: vec![GlobPattern::new("nested/**").unwrap()],
+            max_dimensions: Some((1000, 1000)),
+            ..Default::default()
+        };
+        assert_eq!(scan(&root, config), vec!["nested/small.jpg", "nested/deeper/medium.JPG"]);
+
+        let config = ScanConfig {
+            extensions: vec![String::from("jpg")],
+            min_file_size: Some(100_000),
+            min_dimensions: Some(parse_dimensions("1000x1000").unwrap()),
+            ..Default::default()
+        };
+        assert_eq!(scan(&root, config.clone()), vec!["large.jpg"]);
+        let rules = Default::default();
+        assert!(check_image(&root, &root.join("large.jpg"), &config, &rules).unwrap().is_some());
+        assert!(check_image(&root, &root.join("nested/small.jpg"), &config, &rules)
+            .unwrap()
+            .is_none());
+
+        let config = ScanConfig {
+            exclude: vec![GlobPattern::new("/nested").unwrap()],
+            ..Default::default()
+        };
+        let excluded = root.join("nested/deeper/medium.JPG");
+        assert!(check_image(&root, &excluded, &config, &rules).unwrap().is_none());
+
+        remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_scan_ignore_files() {
+        let root = create_tree("pihash_test_scan_ignore_files");
+        File::create(root.join(IGNORE_FILE_NAME))
+            .unwrap()
+            .write_all(b"# Comments are skipped\nskipped/\n*.JPG\n")
+            .unwrap();
+        File::create(root.join("nested").join(IGNORE_FILE_NAME))
+            .unwrap()
+            .write_all(b"!medium.JPG\n/small.jpg\n")
+            .unwrap();
+        assert_eq!(
+            scan(&root, Default::default()),
+            vec!["large.jpg", "nested/deeper/medium.JPG"]
+        );
+
+        // Files found some other way follow the rules the scan read
+        let mut scanner = scan_directory(&root, Default::default());
+        assert_eq!(scanner.by_ref().count(), 2);
+        let rules = scanner.ignore_rules();
+        let check = |path: &str| {
+            check_image(&root, &root.join(path), &Default::default(), rules)
+                .unwrap()
+                .is_some()
+        };
+        assert!(check("large.jpg"));
+        assert!(check("nested/deeper/medium.JPG"));
+        assert!(!check("nested/small.jpg"));
+        assert!(!check("skipped/small.jpg"));
+
+        let config = ScanConfig {
+            use_ignore_files: false,
+            ..Default::default()
+        };
+        assert_eq!(scan(&root, config).len(), 4);
+
+        remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,309 @@
+// Copyright 2016 Drew Short <drew@sothr.com>.
+//
+// Licensed under the MIT license<LICENSE-MIT or http://opensource.org/licenses/MIT>.
+// This file may not be copied, modified, or distributed except according to those terms.
+
+use std::io::{BufRead, Error, Read, Write};
+
+use output::Record;
+
+// Constants //
+
+// The request line and every header together
+const MAX_HEAD_LENGTH: u64 = 64 * 1024;
+
+// Structs/Enums //
+
+/**
+ * An HTTP/1.1 request with its whole body read
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct Request {
+    pub method: String,
+    /// The path without the query string
+    pub path: String,
+    /// The decoded query parameters in the order they were given
+    pub query: Vec<(String, String)>,
+    /// Header names are lower case
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+/**
+ * A response with a JSON body
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct Response {
+    pub status: u16,
+    pub body: String,
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn query_param(&self, name: &str) -> Option<&str> {
+        self.query
+            .iter()
+            .find(|(param, _)| param == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /**
+     * If the body is JSON rather than raw image data
+     */
+    pub fn is_json(&self) -> bool {
+        self.header("content-type").is_some_and(|content_type| {
+            content_type
+                .trim()
+                .to_lowercase()
+                .starts_with("application/json")
+        })
+    }
+}
+
+impl Response {
+    pub fn json(status: u16, record: &Record) -> Response {
+        Response {
+            status,
+            body: record.to_json_string(),
+        }
+    }
+
+    /**
+     * An error response, with the message in an "error" field
+     */
+    pub fn error(status: u16, message: &str) -> Response {
+        Response::json(status, &Record::new().with("error", message))
+    }
+
+    /**
+     * Write the response. The connection is closed after every response, so
+     * clients never need to track the length of a body themselves.
+     */
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> Result<(), Error> {
+        write!(
+            writer,
+            "HTTP/1.1 {} {}\r\n\
+             Content-Type: application/json\r\n\
+             Content-Length: {}\r\n\
+             Connection: close\r\n\r\n",
+            self.status,
+            get_reason(self.status),
+            self.body.len() + 1
+        )?;
+        writeln!(writer, "{}", self.body)?;
+        writer.flush()
+    }
+}
+
+// Functions //
+
+/**
+ * Read a request from a connection
+ *
+ * # Returns
+ *
+ * None if the connection was closed before a request was sent, or the
+ * response to send back if the request can't be understood
+ */
+pub fn read_request<R: BufRead>(
+    reader: &mut R,
+    max_body_length: u64,
+) -> Result<Option<Request>, Response> {
+    let mut head = reader.by_ref().take(MAX_HEAD_LENGTH);
+    let request_line = match read_line(&mut head)? {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+    let mut parts = request_line.split_whitespace();
+    let (method, target, version) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(target), Some(version)) => (method, target, version),
+        _ => return Err(Response::error(400, "Malformed request line")),
+    };
+    if !version.starts_with("HTTP/1.") {
+        return Err(Response::error(505, "Only HTTP/1.x is supported"));
+    }
+
+    let mut headers = Vec::new();
+    loop {
+        let line = match read_line(&mut head)? {
+            Some(line) => line,
+            None => return Err(Response::error(400, "Incomplete request headers")),
+        };
+        if line.is_empty() {
+            break;
+        }
+        match line.find(':') {
+            Some(colon) => headers.push((
+                line[..colon].trim().to_lowercase(),
+                String::from(line[colon + 1..].trim()),
+            )),
+            None => return Err(Response::error(400, "Malformed header")),
+        }
+    }
+
+    let (path, query) = match target.find('?') {
+        Some(mark) => (&target[..mark], parse_query(&target[mark + 1..])),
+        None => (target, Vec::new()),
+    };
+    let mut request = Request {
+        method: method.to_uppercase(),
+        path: String::from(path),
+        query,
+        headers,
+        body: Vec::new(),
+    };
+
+    if request.header("transfer-encoding").is_some() {
+        return Err(Response::error(
+            411,
+            "Chunked bodies aren't supported, send a Content-Length",
+        ));
+    }
+    let length = match request.header("content-length") {
+        Some(length) => match length.parse::<u64>() {
+            Ok(length) => length,
+            Err(_) => return Err(Response::error(400, "Malformed Content-Length")),
+        },
+        None => 0,
+    };
+    if length > max_body_length {
+        return Err(Response::error(
+            413,
+            &format!("Bodies are limited to {} bytes", max_body_length),
+        ));
+    }
+    reader
+        .take(length)
+        .read_to_end(&mut request.body)
+        .map_err(|e| Response::error(400, &format!("Unable to read the body: {}", e)))?;
+    if (request.body.len() as u64) < length {
+        return Err(Response::error(
+            400,
+            "The body is shorter than its Content-Length",
+        ));
+    }
+    Ok(Some(request))
+}
+
+/**
+ * Read a line without its line ending
+ */
+fn read_line<R: BufRead>(reader: &mut R) -> Result<Option<String>, Response> {
+    let mut line = Vec::new();
+    match reader.read_until(b'\n', &mut line) {
+        Ok(0) => return Ok(None),
+        Ok(_) => (),
+        Err(e) => {
+            return Err(Response::error(
+                400,
+                &format!("Unable to read the request: {}", e),
+            ))
+        }
+    }
+    if line.last() != Some(&b'\n') {
+        return Err(Response::error(431, "Request headers are too large"));
+    }
+    while line.last() == Some(&b'\n') || line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    String::from_utf8(line)
+        .map(Some)
+        .map_err(|_| Response::error(400, "Request headers must be UTF-8"))
+}
+
+fn parse_query(query: &str) -> Vec<(String, String)> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| match pair.find('=') {
+            Some(equals) => (decode(&pair[..equals]), decode(&pair[equals + 1..])),
+            None => (decode(pair), String::new()),
+        })
+        .collect()
+}
+
+/**
+ * Decode a percent encoded query component, where + is a space
+ */
+fn decode(component: &str) -> String {
+    let bytes = component.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut position = 0;
+    while position < bytes.len() {
+        match bytes[position] {
+            b'+' => decoded.push(b' '),
+            b'%' if is_hex_pair(&bytes[position + 1..]) => {
+                let hex = &component[position + 1..position + 3];
+                decoded.push(u8::from_str_radix(hex, 16).unwrap_or(b'?'));
+                position += 2;
+            }
+            byte => decoded.push(byte),
+        }
+        position += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+fn is_hex_pair(bytes: &[u8]) -> bool {
+    bytes.len() >= 2
+        && (bytes[0] as char).is_ascii_hexdigit()
+        && (bytes[1] as char).is_ascii_hexdigit()
+}
+
+fn get_reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        201 => "Created",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        411 => "Length Required",
+        413 => "Payload Too Large",
+        422 => "Unprocessable Entity",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        505 => "HTTP Version Not Supported",
+        _ => "Unknown",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::read_request;
+
+    #[test]
+    fn test_read_request() {
+        let mut input = Cursor::new(
+            &b"POST /hash?precision=high&name=a+b%2Fc.jpg HTTP/1.1\r\n\
+               Host: localhost\r\n\
+               Content-Type: image/jpeg\r\n\
+               Content-Length: 4\r\n\r\n\
+               abcdextra"[..],
+        );
+        let request = read_request(&mut input, 1024).unwrap().unwrap();
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "/hash");
+        assert_eq!(request.query_param("precision"), Some("high"));
+        assert_eq!(request.query_param("name"), Some("a b/c.jpg"));
+        assert_eq!(request.header("content-type"), Some("image/jpeg"));
+        assert!(!request.is_json());
+        assert_eq!(request.body, b"abcd");
+
+        let mut empty = Cursor::new(&b""[..]);
+        assert_eq!(read_request(&mut empty, 1024), Ok(None));
+
+        let mut large = Cursor::new(&b"POST / HTTP/1.1\r\nContent-Length: 2048\r\n\r\n"[..]);
+        assert_eq!(read_request(&mut large, 1024).unwrap_err().status, 413);
+
+        let mut short = Cursor::new(&b"POST / HTTP/1.1\r\nContent-Length: 8\r\n\r\nab"[..]);
+        assert_eq!(read_request(&mut short, 1024).unwrap_err().status, 400);
+    }
+}
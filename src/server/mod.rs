@@ -0,0 +1,587 @@
+// Copyright 2016 Drew Short <drew@sothr.com>.
+//
+// Licensed under the MIT license<LICENSE-MIT or http://opensource.org/licenses/MIT>.
+// This file may not be copied, modified, or distributed except according to those terms.
+
+extern crate image;
+
+use std::io::{self, BufReader, Error, ErrorKind, Read};
+use std::panic::{self, AssertUnwindSafe};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::path::Path;
+use std::str;
+use std::sync::mpsc::sync_channel;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::rustc_serialize::json::Json;
+use hash::{self, HashType, PerceptualHashes, Precision};
+use index::file::IndexFile;
+use output::{Record, Value};
+use similarity::SimilarityPolicy;
+use PIHash;
+
+pub use self::http::{Request, Response};
+
+mod http;
+
+// Constants //
+
+pub const DEFAULT_MAX_BODY_LENGTH: u64 = 32 * 1024 * 1024;
+pub const DEFAULT_REQUEST_TIMEOUT_SECONDS: u64 = 120;
+// Drop connections that stop sending in the middle of a request
+const READ_TIMEOUT_SECONDS: u64 = 30;
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+// Structs/Enums //
+
+/**
+ * Reads from a connection until a deadline, however often the client sends
+ */
+struct DeadlineReader<'a> {
+    stream: &'a TcpStream,
+    deadline: Instant,
+}
+
+/**
+ * How a server hashes and matches images
+ */
+#[derive(Clone, Debug)]
+pub struct ServerConfig {
+    /// Precision used by /hash when a request doesn't give one
+    pub precision: Precision,
+    /// The hashes included in responses
+    pub hash_types: Vec<HashType>,
+    /// Decides which index records a query matches. It should be for the
+    /// precision of the index.
+    pub policy: SimilarityPolicy,
+    pub max_body_length: u64,
+    /// How long a client has to send a whole request, so that one sending
+    /// slowly can't hold a thread
+    pub request_timeout: Duration,
+    /// Connections handled at once, others wait until a thread is free
+    pub threads: usize,
+}
+
+/**
+ * A small HTTP/JSON API over the library, so that hashing doesn't need the
+ * library to be linked
+ *
+ * Connections are handled by a fixed pool of threads, sharing one library
+ * (and so one cache) and one index. Images are sent either as the raw body of a
+ * request or as a JSON object naming a file the server can read, such as
+ * `{"path": "/srv/images/a.jpg"}`. Only files read by path go through the
+ * cache. Since the server can read any image it has access to, it should
+ * only listen on addresses trusted clients can reach.
+ *
+ * * `GET /health` the version and the state of the index
+ * * `POST /hash?precision=<precision>` the hashes of an image
+ * * `POST /query?limit=<n>` the index records similar to an image
+ * * `POST /index?name=<name>` add an image to the index, named by its path
+ *   or the name parameter
+ * * `GET /index/<id>` and `DELETE /index/<id>` get or remove a record
+ *
+ * Responses are JSON objects using the same field names as the command
+ * line records, and errors are an object with an "error" message.
+ */
+pub struct Server {
+    listener: TcpListener,
+    state: Arc<State>,
+}
+
+struct State {
+    lib: PIHash,
+    index: Option<Mutex<IndexFile>>,
+    config: ServerConfig,
+}
+
+impl Default for ServerConfig {
+    fn default() -> ServerConfig {
+        ServerConfig {
+            precision: Precision::Medium,
+            hash_types: vec![HashType::AHash, HashType::DHash, HashType::PHash],
+            policy: SimilarityPolicy::default(),
+            max_body_length: DEFAULT_MAX_BODY_LENGTH,
+            request_timeout: Duration::from_secs(DEFAULT_REQUEST_TIMEOUT_SECONDS),
+            threads: thread::available_parallelism()
+                .map(|threads| threads.get())
+                .unwrap_or(1),
+        }
+    }
+}
+
+impl Server {
+    /**
+     * Listen on an address. Nothing is accepted until the server is run.
+     * Without an index only /health and /hash are available.
+     */
+    pub fn bind<A: ToSocketAddrs>(
+        address: A,
+        lib: PIHash,
+        index: Option<IndexFile>,
+        config: ServerConfig,
+    ) -> Result<Server, Error> {
+        Ok(Server {
+            listener: TcpListener::bind(address)?,
+            state: Arc::new(State {
+                lib,
+                index: index.map(Mutex::new),
+                config,
+            }),
+        })
+    }
+
+    /**
+     * The address being listened on, useful after binding to port 0
+     */
+    pub fn local_addr(&self) -> Result<SocketAddr, Error> {
+        self.listener.local_addr()
+    }
+
+    /**
+     * Accept connections forever, handling them on a pool of threads.
+     * Accepting stops while every thread is busy, leaving further
+     * connections waiting in the listen backlog. A request that isn't
+     * received within the request timeout is answered with an error.
+     */
+    pub fn run(&self) {
+        let threads = self.state.config.threads.max(1);
+        let (sender, receiver) = sync_channel::<TcpStream>(threads);
+        let receiver = Arc::new(Mutex::new(receiver));
+        for _ in 0..threads {
+            let connections = receiver.clone();
+            let state = self.state.clone();
+            thread::spawn(move || loop {
+                // Only hold the lock while waiting for the next connection
+                let stream = match connections.lock() {
+                    Ok(connections) => connections.recv(),
+                    Err(_) => return,
+                };
+                match stream {
+                    // A panicking request shouldn't take a thread out of the pool
+                    Ok(stream) => {
+                        let _ = panic::catch_unwind(AssertUnwindSafe(|| {
+                            handle_connection(&state, stream)
+                        }));
+                    }
+                    Err(_) => return,
+                }
+            });
+        }
+
+        for stream in self.listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    if sender.send(stream).is_err() {
+                        return;
+                    }
+                }
+                Err(e) => eprintln!("Unable to accept a connection: {}", e),
+            }
+        }
+    }
+
+    /**
+     * Respond to a request without going through a connection
+     */
+    pub fn handle(&self, request: &Request) -> Response {
+        self.state.handle(request)
+    }
+}
+
+impl State {
+    fn handle(&self, request: &Request) -> Response {
+        let segments: Vec<&str> = request
+            .path
+            .split('/')
+            .filter(|segment| !segment.is_empty())
+            .collect();
+        let result = match (request.method.as_str(), segments.as_slice()) {
+            ("GET", ["health"]) => Ok(self.health()),
+            ("POST", ["hash"]) => self.hash(request),
+            ("POST", ["query"]) => self.query(request),
+            ("POST", ["index"]) => self.add(request),
+            ("GET", ["index", id]) => self.get(id),
+            ("DELETE", ["index", id]) => self.remove(id),
+            (_, ["health"])
+            | (_, ["hash"])
+            | (_, ["query"])
+            | (_, ["index"])
+            | (_, ["index", _]) => Err(Response::error(405, "Method not allowed")),
+            _ => Err(Response::error(404, "Not found")),
+        };
+        match result {
+            Ok(response) => response,
+            Err(response) => response,
+        }
+    }
+
+    fn health(&self) -> Response {
+        let mut record = Record::new()
+            .with("status", "ok")
+            .with("version", VERSION)
+            .with("cache", self.lib.has_cache());
+        match self.lock_index() {
+            Ok(index) => {
+                record.push("index", index.path().to_string_lossy().into_owned());
+                record.push("precision", get_precision_name(index.precision()));
+                record.push("records", index.len());
+            }
+            Err(_) => {
+                record.push("index", Value::Null);
+                record.push("precision", Value::Null);
+                record.push("records", Value::Null);
+            }
+        }
+        Response::json(200, &record)
+    }
+
+    fn hash(&self, request: &Request) -> Result<Response, Response> {
+        let precision = match request.query_param("precision") {
+            Some(precision) => precision
+                .parse()
+                .map_err(|e: String| Response::error(400, &e))?,
+            None => self.config.precision,
+        };
+        let hashes = self.get_hashes(request, precision)?;
+        Ok(Response::json(
+            200,
+            &Record::from_hashes(&hashes, &self.config.hash_types)
+                .with("precision", get_precision_name(precision)),
+        ))
+    }
+
+    fn query(&self, request: &Request) -> Result<Response, Response> {
+        let limit = match request.query_param("limit") {
+            Some(limit) => Some(
+                limit
+                    .parse::<usize>()
+                    .map_err(|_| Response::error(400, "The limit must be a number"))?,
+            ),
+            None => None,
+        };
+        let precision = self.lock_index()?.precision();
+        // Hash without holding the lock so other requests aren't held up
+        let hashes = self.get_hashes(request, precision)?;
+        let matches = self.lock_index()?.query(&hashes, &self.config.policy);
+        let matches: Vec<Record> = matches
+            .iter()
+            .take(limit.unwrap_or(matches.len()))
+            .map(|found| {
+                Record::new()
+                    .with("id", found.record.id)
+                    .with("file", found.record.hashes.orig_path.as_str())
+                    .with_similarity(
+                        &found.similarity,
+                        &self.config.policy,
+                        &self.config.hash_types,
+                    )
+            })
+            .collect();
+        Ok(Response::json(
+            200,
+            &Record::new()
+                .with(
+                    "query",
+                    Record::from_hashes(&hashes, &self.config.hash_types),
+                )
+                .with("matches", matches),
+        ))
+    }
+
+    fn add(&self, request: &Request) -> Result<Response, Response> {
+        let precision = self.lock_index()?.precision();
+        let hashes = self.get_hashes(request, precision)?;
+        if hashes.orig_path.is_empty() {
+            return Err(Response::error(
+                400,
+                "Images sent in the body need a name parameter",
+            ));
+        }
+        let id = self
+            .lock_index()?
+            .append(&hashes, &[])
+            .map_err(|e| Response::error(500, &format!("Unable to add to the index: {}", e)))?;
+        Ok(Response::json(
+            201,
+            &Record::new()
+                .with("id", id)
+                .with("file", hashes.orig_path.as_str()),
+        ))
+    }
+
+    fn get(&self, id: &str) -> Result<Response, Response> {
+        let id = parse_id(id)?;
+        match self.lock_index()?.get(id) {
+            Some(record) => Ok(Response::json(
+                200,
+                &Record::new().with("id", record.id).with(
+                    "hashes",
+                    Record::from_hashes(&record.hashes, &self.config.hash_types),
+                ),
+            )),
+            None => Err(Response::error(
+                404,
+                &format!("No record with the id {}", id),
+            )),
+        }
+    }
+
+    fn remove(&self, id: &str) -> Result<Response, Response> {
+        let id = parse_id(id)?;
+        let removed = self.lock_index()?.remove(id).map_err(|e| {
+            Response::error(500, &format!("Unable to remove from the index: {}", e))
+        })?;
+        if removed {
+            Ok(Response::json(
+                200,
+                &Record::new().with("id", id).with("removed", true),
+            ))
+        } else {
+            Err(Response::error(
+                404,
+                &format!("No record with the id {}", id),
+            ))
+        }
+    }
+
+    /**
+     * Hash the image in the body of a request, or the file it names
+     */
+    fn get_hashes(
+        &self,
+        request: &Request,
+        precision: Precision,
+    ) -> Result<PerceptualHashes, Response> {
+        if request.is_json() {
+            let json = str::from_utf8(&request.body)
+                .ok()
+                .and_then(|body| Json::from_str(body).ok())
+                .ok_or_else(|| Response::error(400, "The body isn't valid JSON"))?;
+            let path = json
+                .find("path")
+                .and_then(Json::as_string)
+                .ok_or_else(|| Response::error(400, "Expected an object with a \"path\""))?;
+            self.lib
+                .try_get_perceptual_hashes(Path::new(path), &precision)
+                .map_err(|e| Response::error(422, &format!("Unable to hash {}: {}", path, e)))
+        } else {
+            if request.body.is_empty() {
+                return Err(Response::error(400, "Expected an image in the body"));
+            }
+            let image = image::load_from_memory(&request.body)
+                .map_err(|e| Response::error(422, &format!("Unable to decode the image: {}", e)))?;
+            let name = request.query_param("name").unwrap_or("");
+            Ok(hash::get_perceptual_hashes_from_image(
+                name, &image, &precision,
+            ))
+        }
+    }
+
+    fn lock_index(&self) -> Result<MutexGuard<'_, IndexFile>, Response> {
+        match self.index {
+            // A request that panicked can't have left the index half written,
+            // since every change to it is a single write
+            Some(ref index) => Ok(index.lock().unwrap_or_else(|e| e.into_inner())),
+            None => Err(Response::error(404, "No index is being served")),
+        }
+    }
+}
+
+impl<'a> Read for DeadlineReader<'a> {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        let timed_out = || Error::new(ErrorKind::TimedOut, "The request took too long to send");
+        let remaining = self.deadline.saturating_duration_since(Instant::now());
+        if remaining == Duration::from_secs(0) {
+            return Err(timed_out());
+        }
+        let timeout = remaining.min(Duration::from_secs(READ_TIMEOUT_SECONDS));
+        self.stream.set_read_timeout(Some(timeout))?;
+        match self.stream.read(buffer) {
+            // How a timed out read fails depends on the platform
+            Err(ref e)
+                if (e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut)
+                    && Instant::now() >= self.deadline =>
+            {
+                Err(timed_out())
+            }
+            result => result,
+        }
+    }
+}
+
+// Functions //
+
+fn handle_connection(state: &State, mut stream: TcpStream) {
+    let _ = stream.set_write_timeout(Some(Duration::from_secs(READ_TIMEOUT_SECONDS)));
+    let response = {
+        let mut reader = BufReader::new(DeadlineReader {
+            stream: &stream,
+            deadline: Instant::now() + state.config.request_timeout,
+        });
+        match http::read_request(&mut reader, state.config.max_body_length) {
+            Ok(Some(request)) => state.handle(&request),
+            Ok(None) => return,
+            Err(response) => response,
+        }
+    };
+    // The client may already be gone, and there's no one else to tell
+    let _ = response.write_to(&mut stream);
+}
+
+fn parse_id(id: &str) -> Result<u64, Response> {
+    id.parse()
+        .map_err(|_| Response::error(400, &format!("'{}' isn't a record id", id)))
+}
+
+fn get_precision_name(precision: Precision) -> String {
+    format!("{}", precision).to_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env::temp_dir;
+    use std::fs::{self, remove_file};
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpStream};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use cache::CacheMode;
+    use hash::Precision;
+    use index::file::IndexFile;
+    use PIHash;
+
+    use super::{Server, ServerConfig, READ_TIMEOUT_SECONDS};
+
+    fn send(address: &SocketAddr, head: &str, body: &[u8]) -> (u16, String) {
+        let mut stream = TcpStream::connect(address).unwrap();
+        write!(
+            stream,
+            "{}\r\nHost: localhost\r\nContent-Length: {}\r\n\r\n",
+            head,
+            body.len()
+        )
+        .unwrap();
+        stream.write_all(body).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        let status = response[9..12].parse().unwrap();
+        let body = response.split("\r\n\r\n").nth(1).unwrap();
+        (status, String::from(body.trim()))
+    }
+
+    #[test]
+    fn test_serve_over_localhost() {
+        let index_path = temp_dir().join("pihash_test_server.index");
+        let index = IndexFile::create(&index_path, &Precision::Medium, Vec::new()).unwrap();
+        let server = Server::bind(
+            "127.0.0.1:0",
+            PIHash::new(None, CacheMode::Disabled),
+            Some(index),
+            ServerConfig::default(),
+        )
+        .unwrap();
+        let address = server.local_addr().unwrap();
+        thread::spawn(move || server.run());
+
+        let (status, body) = send(&address, "GET /health HTTP/1.1", b"");
+        assert_eq!(status, 200);
+        assert!(body.contains("\"status\":\"ok\""));
+        assert!(body.contains("\"records\":0"));
+
+        let image = fs::read("test_images/sample_02_large.jpg").unwrap();
+        let (status, body) = send(&address, "POST /hash?precision=low HTTP/1.1", &image);
+        assert_eq!(status, 200);
+        assert!(body.contains("\"precision\":\"low\""));
+
+        let (status, body) = send(
+            &address,
+            "POST /index HTTP/1.1\r\nContent-Type: application/json",
+            b"{\"path\": \"test_images/sample_02_medium.jpg\"}",
+        );
+        assert_eq!(status, 201);
+        assert!(body.ends_with(",\"file\":\"test_images/sample_02_medium.jpg\"}"));
+        let id: u64 = body[6..body.find(',').unwrap()].parse().unwrap();
+
+        let (status, body) = send(&address, "POST /query?limit=1 HTTP/1.1", &image);
+        assert_eq!(status, 200);
+        assert!(body.contains("\"matches\":[{\"id\":"));
+        assert!(body.contains("\"similar\":true}]"));
+
+        let (status, _) = send(&address, "POST /index HTTP/1.1", &image);
+        assert_eq!(status, 400);
+        let (status, _) = send(&address, "POST /hash HTTP/1.1", b"not an image");
+        assert_eq!(status, 422);
+        let (status, body) = send(&address, &format!("GET /index/{} HTTP/1.1", id), b"");
+        assert_eq!(status, 200);
+        assert!(body.contains("sample_02_medium.jpg"));
+        let (status, _) = send(&address, &format!("DELETE /index/{} HTTP/1.1", id), b"");
+        assert_eq!(status, 200);
+        let (status, _) = send(&address, &format!("DELETE /index/{} HTTP/1.1", id), b"");
+        assert_eq!(status, 404);
+        let (status, _) = send(&address, "PUT /hash HTTP/1.1", b"");
+        assert_eq!(status, 405);
+        remove_file(&index_path).unwrap();
+    }
+
+    #[test]
+    fn test_connections_wait_for_a_free_thread() {
+        let config = ServerConfig {
+            threads: 1,
+            ..Default::default()
+        };
+        let server =
+            Server::bind("127.0.0.1:0", PIHash::new(None, CacheMode::Disabled), None, config)
+                .unwrap();
+        let address = server.local_addr().unwrap();
+        thread::spawn(move || server.run());
+
+        // The only thread is stuck reading from a client that sends nothing
+        let idle = TcpStream::connect(address).unwrap();
+        let mut waiting = TcpStream::connect(address).unwrap();
+        waiting
+            .set_read_timeout(Some(Duration::from_millis(500)))
+            .unwrap();
+        waiting
+            .write_all(b"GET /health HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .unwrap();
+        let mut response = String::new();
+        assert!(waiting.read_to_string(&mut response).is_err());
+        assert!(response.is_empty());
+
+        drop(idle);
+        waiting.set_read_timeout(None).unwrap();
+        waiting.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"));
+    }
+
+    #[test]
+    fn test_slow_requests_time_out() {
+        let config = ServerConfig {
+            threads: 1,
+            request_timeout: Duration::from_millis(300),
+            ..Default::default()
+        };
+        let server =
+            Server::bind("127.0.0.1:0", PIHash::new(None, CacheMode::Disabled), None, config)
+                .unwrap();
+        let address = server.local_addr().unwrap();
+        thread::spawn(move || server.run());
+
+        // Every header arrives well within the read timeout, but the request never ends
+        let started = Instant::now();
+        let mut slow = TcpStream::connect(address).unwrap();
+        slow.write_all(b"GET /health HTTP/1.1\r\n").unwrap();
+        for _ in 0..3 {
+            thread::sleep(Duration::from_millis(50));
+            slow.write_all(b"X-Slow: 1\r\n").unwrap();
+        }
+        let mut response = String::new();
+        slow.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 400"));
+        assert!(response.contains("took too long"));
+        assert!(started.elapsed() < Duration::from_secs(READ_TIMEOUT_SECONDS));
+    }
+}
@@ -0,0 +1,340 @@
+// Copyright 2016 Drew Short <drew@sothr.com>.
+//
+// Licensed under the MIT license<LICENSE-MIT or http://opensource.org/licenses/MIT>.
+// This file may not be copied, modified, or distributed except according to those terms.
+
+use std::default::Default;
+
+use hash::{calculate_hamming_distance, HashType, PerceptualHashes, Precision};
+
+// Constants //
+
+// The historical similarity limit for 64 bit (Medium precision) hashes
+const DEFAULT_MAX_DISTANCE: f64 = 5_f64;
+const DEFAULT_HASH_LENGTH: f64 = 64_f64;
+
+// Structs/Enums //
+
+/**
+ * How the results of the individual hash comparisons are combined into a
+ * single similar/not similar decision
+ */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum CombinationMode {
+    /// Every hash must be within its threshold
+    All,
+    /// At least one hash must be within its threshold
+    Any,
+    /// The weights of the hashes within their threshold must add up to at
+    /// least the given fraction (0.0 - 1.0) of the total weight
+    WeightedVote(f64),
+}
+
+/**
+ * The limit and voting weight for a single hash type
+ *
+ * The limit is stored as a ratio of the hash length so that the same policy
+ * can be applied to hashes of any precision. A hash without any weight is
+ * ignored, unless none of the hashes have a weight.
+ */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct HashThreshold {
+    pub max_distance_ratio: f64,
+    pub weight: f64,
+}
+
+impl Default for HashThreshold {
+    fn default() -> HashThreshold {
+        HashThreshold {
+            max_distance_ratio: DEFAULT_MAX_DISTANCE / DEFAULT_HASH_LENGTH,
+            weight: 1_f64,
+        }
+    }
+}
+
+/**
+ * Rules for deciding if two sets of perceptual hashes are similar
+ */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct SimilarityPolicy {
+    pub precision: Precision,
+    pub mode: CombinationMode,
+    pub ahash: HashThreshold,
+    pub dhash: HashThreshold,
+    pub phash: HashThreshold,
+}
+
+/**
+ * The hamming distances between each of the hashes of two images
+ */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct HashDistances {
+    pub ahash: u64,
+    pub dhash: u64,
+    pub phash: u64,
+}
+
+/**
+ * The result of comparing two sets of perceptual hashes
+ */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Similarity {
+    pub distances: HashDistances,
+    pub score: f64,
+    pub similar: bool,
+}
+
+impl Default for SimilarityPolicy {
+    fn default() -> SimilarityPolicy {
+        SimilarityPolicy::new(Precision::Medium)
+    }
+}
+
+impl HashDistances {
+    pub fn between(hashes1: &PerceptualHashes, hashes2: &PerceptualHashes) -> HashDistances {
+        HashDistances {
+            ahash: calculate_hamming_distance(hashes1.ahash, hashes2.ahash),
+            dhash: calculate_hamming_distance(hashes1.dhash, hashes2.dhash),
+            phash: calculate_hamming_distance(hashes1.phash, hashes2.phash),
+        }
+    }
+
+    pub fn get(&self, hash_type: &HashType) -> u64 {
+        match *hash_type {
+            HashType::AHash => self.ahash,
+            HashType::DHash => self.dhash,
+            HashType::PHash => self.phash,
+        }
+    }
+}
+
+impl SimilarityPolicy {
+    /**
+     * Create a policy with the default thresholds scaled to the hash length
+     * of the provided precision.
+     */
+    pub fn new(precision: Precision) -> SimilarityPolicy {
+        SimilarityPolicy {
+            precision,
+            mode: CombinationMode::All,
+            ahash: Default::default(),
+            dhash: Default::default(),
+            phash: Default::default(),
+        }
+    }
+
+    /**
+     * Create a policy that only looks at some of the hashes. The others
+     * neither vote nor veto.
+     */
+    pub fn for_hash_types(precision: Precision, hash_types: &[HashType]) -> SimilarityPolicy {
+        let mut policy = SimilarityPolicy::new(precision);
+        for hash_type in [HashType::AHash, HashType::DHash, HashType::PHash].iter() {
+            if !hash_types.contains(hash_type) {
+                let threshold = policy.threshold_mut(hash_type);
+                threshold.max_distance_ratio = 1_f64;
+                threshold.weight = 0_f64;
+            }
+        }
+        policy
+    }
+
+    pub fn threshold(&self, hash_type: &HashType) -> &HashThreshold {
+        match *hash_type {
+            HashType::AHash => &self.ahash,
+            HashType::DHash => &self.dhash,
+            HashType::PHash => &self.phash,
+        }
+    }
+
+    pub fn threshold_mut(&mut self, hash_type: &HashType) -> &mut HashThreshold {
+        match *hash_type {
+            HashType::AHash => &mut self.ahash,
+            HashType::DHash => &mut self.dhash,
+            HashType::PHash => &mut self.phash,
+        }
+    }
+
+    /**
+     * Get the maximum hamming distance allowed for a hash type at the
+     * precision of this policy.
+     */
+    pub fn max_distance(&self, hash_type: &HashType) -> u64 {
+        let hash_length = self.precision.get_hash_length() as f64;
+        (self.threshold(hash_type).max_distance_ratio * hash_length).round() as u64
+    }
+
+    /**
+     * Set the maximum hamming distance allowed for a hash type at the
+     * precision of this policy.
+     */
+    pub fn set_max_distance(&mut self, hash_type: &HashType, distance: u64) {
+        let hash_length = self.precision.get_hash_length() as f64;
+        self.threshold_mut(hash_type).max_distance_ratio = distance as f64 / hash_length;
+    }
+
+    /**
+     * How alike two hashes with this hamming distance are, where 1.0 is
+     * identical and 0.0 means every bit differs.
+     */
+    pub fn hash_score(&self, distance: u64) -> f64 {
+        let hash_length = self.precision.get_hash_length() as f64;
+        1_f64 - (distance as f64 / hash_length).min(1_f64)
+    }
+
+    /**
+     * Compare two sets of hashes
+     */
+    pub fn compare(&self, hashes1: &PerceptualHashes, hashes2: &PerceptualHashes) -> Similarity {
+        self.evaluate(HashDistances::between(hashes1, hashes2))
+    }
+
+    /**
+     * Score a set of already calculated distances
+     *
+     * The score is the weighted mean of the score of each hash.
+     */
+    pub fn evaluate(&self, distances: HashDistances) -> Similarity {
+        let hash_types = [HashType::AHash, HashType::DHash, HashType::PHash];
+        let total_weight: f64 = hash_types
+            .iter()
+            .map(|hash_type| self.threshold(hash_type).weight)
+            .sum();
+
+        let mut score = 0_f64;
+        let mut counted = 0;
+        let mut passed = 0;
+        let mut passed_weight = 0_f64;
+        for hash_type in hash_types.iter() {
+            let distance = distances.get(hash_type);
+            let likeness = self.hash_score(distance);
+            // Without any weights every hash counts equally
+            let weight = if total_weight > 0_f64 {
+                self.threshold(hash_type).weight
+            } else {
+                1_f64
+            };
+            // Hashes without any weight take no part in the decision
+            if weight <= 0_f64 {
+                continue;
+            }
+            score += likeness * weight;
+            counted += 1;
+            if distance <= self.max_distance(hash_type) {
+                passed += 1;
+                passed_weight += weight;
+            }
+        }
+        let total_weight = if total_weight > 0_f64 {
+            total_weight
+        } else {
+            hash_types.len() as f64
+        };
+
+        let similar = match self.mode {
+            CombinationMode::All => passed == counted,
+            CombinationMode::Any => passed > 0,
+            CombinationMode::WeightedVote(quorum) => passed_weight / total_weight >= quorum,
+        };
+
+        Similarity {
+            distances,
+            score: score / total_weight,
+            similar,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hash::{HashType, PerceptualHashes, Precision};
+
+    use super::{CombinationMode, HashDistances, SimilarityPolicy};
+
+    fn distances(ahash: u64, dhash: u64, phash: u64) -> HashDistances {
+        HashDistances {
+            ahash,
+            dhash,
+            phash,
+        }
+    }
+
+    #[test]
+    fn test_default_policy_matches_legacy_limit() {
+        let policy: SimilarityPolicy = Default::default();
+        assert_eq!(policy.max_distance(&HashType::AHash), 5);
+        assert!(policy.evaluate(distances(5, 5, 5)).similar);
+        assert!(!policy.evaluate(distances(5, 6, 5)).similar);
+    }
+
+    #[test]
+    fn test_thresholds_scale_with_precision() {
+        let policy = SimilarityPolicy::new(Precision::Low);
+        assert_eq!(policy.max_distance(&HashType::PHash), 1);
+    }
+
+    #[test]
+    fn test_policy_for_hash_types() {
+        let policy = SimilarityPolicy::for_hash_types(Precision::Medium, &[HashType::DHash]);
+        let distances = HashDistances {
+            ahash: 64,
+            dhash: 5,
+            phash: 64,
+        };
+        let similarity = policy.evaluate(distances);
+        assert!(similarity.similar);
+        assert_eq!(similarity.score, 59_f64 / 64_f64);
+        assert_eq!(policy.hash_score(16), 0.75);
+        assert!(!policy.evaluate(HashDistances { dhash: 6, ..distances }).similar);
+
+        // The excluded hashes don't count as matches either
+        let policy = SimilarityPolicy {
+            mode: CombinationMode::Any,
+            ..SimilarityPolicy::for_hash_types(Precision::Medium, &[HashType::DHash])
+        };
+        assert!(policy.evaluate(distances).similar);
+        let unmatched = HashDistances {
+            ahash: 0,
+            dhash: 6,
+            phash: 0,
+        };
+        assert!(!policy.evaluate(unmatched).similar);
+    }
+
+    #[test]
+    fn test_combination_modes() {
+        let mut policy = SimilarityPolicy {
+            mode: CombinationMode::Any,
+            ..Default::default()
+        };
+        assert!(policy.evaluate(distances(0, 30, 30)).similar);
+        assert!(!policy.evaluate(distances(30, 30, 30)).similar);
+
+        policy.mode = CombinationMode::WeightedVote(0.5);
+        policy.phash.weight = 2_f64;
+        assert!(policy.evaluate(distances(30, 30, 0)).similar);
+        assert!(!policy.evaluate(distances(0, 30, 30)).similar);
+    }
+
+    #[test]
+    fn test_similarity_score() {
+        let hashes = PerceptualHashes {
+            orig_path: String::from("a"),
+            ahash: 0,
+            dhash: 0,
+            phash: 0,
+        };
+        let inverted = PerceptualHashes {
+            orig_path: String::from("b"),
+            ahash: u64::MAX,
+            dhash: u64::MAX,
+            phash: u64::MAX,
+        };
+        let policy: SimilarityPolicy = Default::default();
+        assert_eq!(hashes.similarity(&hashes, &policy).score, 1_f64);
+        let similarity = hashes.similarity(&inverted, &policy);
+        assert_eq!(similarity.score, 0_f64);
+        assert_eq!(similarity.distances, distances(64, 64, 64));
+        assert!(!similarity.similar);
+    }
+}
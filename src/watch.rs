@@ -0,0 +1,629 @@
+// Copyright 2016 Drew Short <drew@sothr.com>.
+//
+// Licensed under the MIT license<LICENSE-MIT or http://opensource.org/licenses/MIT>.
+// This file may not be copied, modified, or distributed except according to those terms.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::ffi::{CString, OsStr};
+use std::fs::{self, Metadata};
+use std::io::{Error, ErrorKind};
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::time::UNIX_EPOCH;
+
+use super::libc;
+use cache::Cache;
+use hash;
+use index::file::{IndexFile, IndexMatch};
+use scan::{self, IgnoreRules, ScanConfig, IGNORE_FILE_NAME};
+use similarity::SimilarityPolicy;
+
+// Constants //
+
+const WATCH_MASK: u32 = libc::IN_CLOSE_WRITE
+    | libc::IN_MOVED_TO
+    | libc::IN_MOVED_FROM
+    | libc::IN_CREATE
+    | libc::IN_DELETE
+    | libc::IN_DELETE_SELF
+    | libc::IN_MOVE_SELF;
+// Room for a batch of events with the longest possible names
+const EVENT_BUFFER_LENGTH: usize = 64 * (16 + 256);
+
+// Structs/Enums //
+
+/**
+ * A change below a watched directory
+ */
+#[derive(Debug)]
+pub enum FileEvent {
+    /// A file was written and closed, or moved into the tree
+    Changed(PathBuf),
+    /// A file was deleted or moved out of the tree
+    Removed(PathBuf),
+    /// A directory was deleted or moved out of the tree, along with
+    /// everything in it
+    DirectoryRemoved(PathBuf),
+    /// The kernel dropped events, so anything could have changed
+    Overflow,
+    /// A new directory couldn't be watched
+    Failed { path: PathBuf, error: Error },
+}
+
+/**
+ * Watches a directory tree with inotify
+ *
+ * New directories are watched as they appear, and every file already in
+ * them is reported as changed so that nothing created before the watch was
+ * added is missed. Symbolic links to directories aren't followed.
+ */
+pub struct DirectoryWatcher {
+    fd: libc::c_int,
+    root: PathBuf,
+    directories: HashMap<libc::c_int, PathBuf>,
+    events: VecDeque<FileEvent>,
+}
+
+/**
+ * What an index watcher did in response to a change
+ */
+#[derive(Debug)]
+pub enum WatchEvent {
+    Added {
+        path: String,
+        id: u64,
+    },
+    /// The file changed and was hashed again, which gives it a new id
+    Updated {
+        path: String,
+        id: u64,
+    },
+    Removed {
+        path: String,
+        id: u64,
+    },
+    /// A file that was just added or updated is similar to indexed images
+    NearDuplicate {
+        path: String,
+        id: u64,
+        matches: Vec<IndexMatch>,
+    },
+    Failed {
+        path: String,
+        error: Error,
+    },
+}
+
+/**
+ * Which files an index watcher keeps and when it reports near duplicates.
+ * The policy needs the precision of the index.
+ */
+#[derive(Clone, Debug)]
+pub struct WatchConfig {
+    pub policy: SimilarityPolicy,
+    pub scan: ScanConfig,
+}
+
+/**
+ * Keeps an index file up to date with the images in a directory tree
+ *
+ * Each record's metadata is the size and modification time of the file it
+ * was hashed from, so on start up only files that changed while nothing was
+ * watching get hashed again. The ignore files read by a scan of the whole
+ * directory apply to the changes after it, and changing one scans it again.
+ */
+pub struct IndexWatcher {
+    watcher: DirectoryWatcher,
+    index: IndexFile,
+    cache: Option<Cache>,
+    config: WatchConfig,
+    ignore_rules: IgnoreRules,
+    // The id and metadata of every record, by path
+    records: HashMap<String, (u64, Vec<u8>)>,
+    events: VecDeque<WatchEvent>,
+}
+
+impl DirectoryWatcher {
+    pub fn new(root: &Path) -> Result<DirectoryWatcher, Error> {
+        let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC) };
+        if fd < 0 {
+            return Err(Error::last_os_error());
+        }
+        let mut watcher = DirectoryWatcher {
+            fd,
+            root: root.to_path_buf(),
+            directories: HashMap::new(),
+            events: VecDeque::new(),
+        };
+        // Files that already exist aren't news
+        watcher.watch_tree(root)?;
+        Ok(watcher)
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /**
+     * Wait for the next change
+     */
+    pub fn next_event(&mut self) -> Result<FileEvent, Error> {
+        loop {
+            if let Some(event) = self.events.pop_front() {
+                return Ok(event);
+            }
+            self.read_events()?;
+        }
+    }
+
+    /**
+     * Watch a directory and everything below it
+     *
+     * # Returns
+     *
+     * The files that were found
+     */
+    fn watch_tree(&mut self, directory: &Path) -> Result<Vec<PathBuf>, Error> {
+        self.add_watch(directory)?;
+        let mut files = Vec::new();
+        for entry in fs::read_dir(directory)? {
+            let entry = entry?;
+            let path = entry.path();
+            if entry.file_type()?.is_dir() {
+                match self.watch_tree(&path) {
+                    Ok(mut found) => files.append(&mut found),
+                    Err(error) => self.events.push_back(FileEvent::Failed { path, error }),
+                }
+            } else {
+                files.push(path);
+            }
+        }
+        Ok(files)
+    }
+
+    fn add_watch(&mut self, directory: &Path) -> Result<(), Error> {
+        let c_path = CString::new(directory.as_os_str().as_bytes())?;
+        let wd = unsafe { libc::inotify_add_watch(self.fd, c_path.as_ptr(), WATCH_MASK) };
+        if wd < 0 {
+            let error = Error::last_os_error();
+            return Err(Error::new(
+                error.kind(),
+                format!("Unable to watch {}: {}", directory.display(), error),
+            ));
+        }
+        self.directories.insert(wd, directory.to_path_buf());
+        Ok(())
+    }
+
+    /**
+     * Stop watching a directory that left the tree, and everything below it
+     */
+    fn unwatch_tree(&mut self, directory: &Path) {
+        let watched: Vec<libc::c_int> = self
+            .directories
+            .iter()
+            .filter(|&(_, path)| path.starts_with(directory))
+            .map(|(&wd, _)| wd)
+            .collect();
+        for wd in watched {
+            unsafe {
+                libc::inotify_rm_watch(self.fd, wd);
+            }
+            self.directories.remove(&wd);
+        }
+    }
+
+    fn read_events(&mut self) -> Result<(), Error> {
+        let mut buffer = vec![0u8; EVENT_BUFFER_LENGTH];
+        let length = unsafe {
+            libc::read(
+                self.fd,
+                buffer.as_mut_ptr() as *mut libc::c_void,
+                buffer.len(),
+            )
+        };
+        if length < 0 {
+            let error = Error::last_os_error();
+            return if error.kind() == ErrorKind::Interrupted {
+                Ok(())
+            } else {
+                Err(error)
+            };
+        }
+
+        // Each event is a header followed by a nul padded name
+        let header_length = mem::size_of::<libc::inotify_event>();
+        let length = length as usize;
+        let mut offset = 0;
+        while offset + header_length <= length {
+            let event: libc::inotify_event =
+                unsafe { ptr::read_unaligned(buffer[offset..].as_ptr() as *const _) };
+            let name_start = offset + header_length;
+            let name_end = (name_start + event.len as usize).min(length);
+            let name = &buffer[name_start..name_end];
+            let name = &name[..name
+                .iter()
+                .position(|&byte| byte == 0)
+                .unwrap_or(name.len())];
+            self.handle_event(&event, OsStr::from_bytes(name))?;
+            offset = name_end;
+        }
+        Ok(())
+    }
+
+    fn handle_event(&mut self, event: &libc::inotify_event, name: &OsStr) -> Result<(), Error> {
+        if event.mask & libc::IN_Q_OVERFLOW != 0 {
+            self.events.push_back(FileEvent::Overflow);
+            return Ok(());
+        }
+        if event.mask & libc::IN_IGNORED != 0 {
+            self.directories.remove(&event.wd);
+            return Ok(());
+        }
+        let directory = match self.directories.get(&event.wd) {
+            Some(directory) => directory.clone(),
+            None => return Ok(()),
+        };
+        if event.mask & (libc::IN_DELETE_SELF | libc::IN_MOVE_SELF) != 0 {
+            // Directories below the root are handled through their parent
+            if directory == self.root {
+                return Err(Error::new(
+                    ErrorKind::NotFound,
+                    format!("The watched directory {} was removed", self.root.display()),
+                ));
+            }
+            return Ok(());
+        }
+
+        let path = directory.join(name);
+        let arrived = event.mask & (libc::IN_CREATE | libc::IN_MOVED_TO) != 0;
+        let departed = event.mask & (libc::IN_DELETE | libc::IN_MOVED_FROM) != 0;
+        if event.mask & libc::IN_ISDIR != 0 {
+            if arrived {
+                match self.watch_tree(&path) {
+                    Ok(files) => self
+                        .events
+                        .extend(files.into_iter().map(FileEvent::Changed)),
+                    Err(error) => self.events.push_back(FileEvent::Failed { path, error }),
+                }
+            } else if departed {
+                self.unwatch_tree(&path);
+                self.events.push_back(FileEvent::DirectoryRemoved(path));
+            }
+        } else if event.mask & (libc::IN_CLOSE_WRITE | libc::IN_MOVED_TO) != 0 {
+            // Created files are reported once they are closed
+            self.events.push_back(FileEvent::Changed(path));
+        } else if departed {
+            self.events.push_back(FileEvent::Removed(path));
+        }
+        Ok(())
+    }
+}
+
+impl Drop for DirectoryWatcher {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
+
+impl IndexWatcher {
+    /**
+     * Start watching, then bring the index up to date with the directory.
+     * Whatever that changed is returned by the first calls to next_event.
+     */
+    pub fn new(
+        root: &Path,
+        index: IndexFile,
+        cache: Option<Cache>,
+        config: WatchConfig,
+    ) -> Result<IndexWatcher, Error> {
+        // The watch comes first so nothing slips in during the scan
+        let watcher = DirectoryWatcher::new(root)?;
+        let records = index
+            .records()
+            .into_iter()
+            .map(|record| (record.hashes.orig_path, (record.id, record.metadata)))
+            .collect();
+        let mut index_watcher = IndexWatcher {
+            watcher,
+            index,
+            cache,
+            config,
+            ignore_rules: Default::default(),
+            records,
+            events: VecDeque::new(),
+        };
+        index_watcher.synchronize();
+        Ok(index_watcher)
+    }
+
+    pub fn index(&self) -> &IndexFile {
+        &self.index
+    }
+
+    /**
+     * Wait for the next change to the index
+     */
+    pub fn next_event(&mut self) -> Result<WatchEvent, Error> {
+        loop {
+            if let Some(event) = self.events.pop_front() {
+                return Ok(event);
+            }
+            match self.watcher.next_event()? {
+                FileEvent::Changed(ref path) | FileEvent::Removed(ref path)
+                    if path.file_name() == Some(OsStr::new(IGNORE_FILE_NAME)) =>
+                {
+                    self.synchronize()
+                }
+                FileEvent::Changed(path) => {
+                    let root = self.watcher.root();
+                    match scan::check_image(root, &path, &self.config.scan, &self.ignore_rules) {
+                        Ok(Some(image)) => self.update(&image.path),
+                        // Replaced by something that isn't a watched image
+                        Ok(None) => self.remove(&path.to_string_lossy()),
+                        Err(ref e) if e.kind() == ErrorKind::NotFound => {
+                            self.remove(&path.to_string_lossy())
+                        }
+                        Err(error) => self.fail(&path, error),
+                    }
+                }
+                FileEvent::Removed(path) => self.remove(&path.to_string_lossy()),
+                FileEvent::DirectoryRemoved(directory) => {
+                    let removed: Vec<String> = self
+                        .records
+                        .keys()
+                        .filter(|path| Path::new(path).starts_with(&directory))
+                        .cloned()
+                        .collect();
+                    for path in removed {
+                        self.remove(&path);
+                    }
+                }
+                FileEvent::Overflow => self.synchronize(),
+                FileEvent::Failed { path, error } => self.fail(&path, error),
+            }
+        }
+    }
+
+    /**
+     * Scan the whole directory, hashing anything that is new or changed and
+     * dropping the records of files that have gone
+     */
+    pub fn synchronize(&mut self) {
+        let root = self.watcher.root().to_path_buf();
+        let mut found = HashSet::new();
+        let mut scanner = scan::scan_directory(&root, self.config.scan.clone());
+        for scanned in scanner.by_ref() {
+            match scanned {
+                Ok(image) => {
+                    found.insert(image.path.to_string_lossy().into_owned());
+                    self.update(&image.path);
+                }
+                Err(error) => self.fail(&root, error),
+            }
+        }
+        self.ignore_rules = scanner.ignore_rules().clone();
+        let gone: Vec<String> = self
+            .records
+            .keys()
+            .filter(|path| Path::new(path).starts_with(&root) && !found.contains(*path))
+            .cloned()
+            .collect();
+        for path in gone {
+            self.remove(&path);
+        }
+    }
+
+    fn update(&mut self, path: &Path) {
+        let key = path.to_string_lossy().into_owned();
+        let stamp = match fs::metadata(path) {
+            Ok(metadata) => get_file_stamp(&metadata),
+            Err(error) => return self.fail(path, error),
+        };
+        let previous = self.records.get(&key).cloned();
+        if let Some((_, ref previous_stamp)) = previous {
+            if *previous_stamp == stamp {
+                return;
+            }
+        }
+
+        let precision = self.index.precision();
+        let hashes = match hash::try_get_perceptual_hashes(path, &precision, &self.cache) {
+            Ok(hashes) => hashes,
+            Err(error) => return self.fail(path, error),
+        };
+        if let Some((id, _)) = previous {
+            if let Err(error) = self.index.remove(id) {
+                return self.fail(path, error);
+            }
+            self.records.remove(&key);
+        }
+        let matches: Vec<IndexMatch> = self
+            .index
+            .query(&hashes, &self.config.policy)
+            .into_iter()
+            .filter(|found| found.record.hashes.orig_path != key)
+            .collect();
+        let id = match self.index.append(&hashes, &stamp) {
+            Ok(id) => id,
+            Err(error) => return self.fail(path, error),
+        };
+        self.records.insert(key.clone(), (id, stamp));
+        self.events.push_back(if previous.is_some() {
+            WatchEvent::Updated {
+                path: key.clone(),
+                id,
+            }
+        } else {
+            WatchEvent::Added {
+                path: key.clone(),
+                id,
+            }
+        });
+        if !matches.is_empty() {
+            self.events.push_back(WatchEvent::NearDuplicate {
+                path: key,
+                id,
+                matches,
+            });
+        }
+    }
+
+    fn remove(&mut self, path: &str) {
+        if let Some((id, stamp)) = self.records.remove(path) {
+            match self.index.remove(id) {
+                Ok(_) => self.events.push_back(WatchEvent::Removed {
+                    path: String::from(path),
+                    id,
+                }),
+                Err(error) => {
+                    // Still indexed, so it can be retried
+                    self.records.insert(String::from(path), (id, stamp));
+                    self.fail(Path::new(path), error);
+                }
+            }
+        }
+    }
+
+    fn fail(&mut self, path: &Path, error: Error) {
+        self.events.push_back(WatchEvent::Failed {
+            path: path.to_string_lossy().into_owned(),
+            error,
+        });
+    }
+}
+
+// Functions //
+
+/**
+ * The size and modification time of a file, which change when it's written
+ */
+fn get_file_stamp(metadata: &Metadata) -> Vec<u8> {
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |age| age.as_nanos() as u64);
+    let mut stamp = Vec::with_capacity(16);
+    stamp.extend_from_slice(&metadata.len().to_le_bytes());
+    stamp.extend_from_slice(&modified.to_le_bytes());
+    stamp
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env::temp_dir;
+    use std::fs::{copy, create_dir_all, remove_dir_all, remove_file, File};
+    use std::io::Write;
+
+    use hash::Precision;
+    use index::file::IndexFile;
+    use scan::IGNORE_FILE_NAME;
+    use similarity::SimilarityPolicy;
+
+    use super::{IndexWatcher, WatchConfig, WatchEvent};
+
+    #[test]
+    fn test_index_watcher() {
+        let root = temp_dir().join("pihash_test_watch");
+        let _ = remove_dir_all(&root);
+        let watched = root.join("watched");
+        create_dir_all(&watched).unwrap();
+        copy(
+            "test_images/sample_03_small.jpg",
+            watched.join("existing.jpg"),
+        )
+        .unwrap();
+        let index_path = root.join("watched.index");
+        let index = IndexFile::create(&index_path, &Precision::Medium, Vec::new()).unwrap();
+        let config = WatchConfig {
+            policy: SimilarityPolicy::new(Precision::Medium),
+            scan: Default::default(),
+        };
+
+        let mut watcher = IndexWatcher::new(&watched, index, None, config.clone()).unwrap();
+        match watcher.next_event().unwrap() {
+            WatchEvent::Added { ref path, .. } => assert!(path.ends_with("existing.jpg")),
+            event => panic!("Unexpected event {:?}", event),
+        }
+
+        copy("test_images/sample_02_large.jpg", watched.join("large.jpg")).unwrap();
+        let large_id = match watcher.next_event().unwrap() {
+            WatchEvent::Added { id, .. } => id,
+            event => panic!("Unexpected event {:?}", event),
+        };
+        create_dir_all(watched.join("nested")).unwrap();
+        copy(
+            "test_images/sample_02_small.jpg",
+            watched.join("nested/small.jpg"),
+        )
+        .unwrap();
+        match watcher.next_event().unwrap() {
+            WatchEvent::Added { ref path, .. } => assert!(path.ends_with("nested/small.jpg")),
+            event => panic!("Unexpected event {:?}", event),
+        }
+        match watcher.next_event().unwrap() {
+            WatchEvent::NearDuplicate { ref matches, .. } => {
+                assert_eq!(matches.len(), 1);
+                assert_eq!(matches[0].record.id, large_id);
+            }
+            event => panic!("Unexpected event {:?}", event),
+        }
+        remove_file(watched.join("large.jpg")).unwrap();
+        match watcher.next_event().unwrap() {
+            WatchEvent::Removed { id, .. } => assert_eq!(id, large_id),
+            event => panic!("Unexpected event {:?}", event),
+        }
+        drop(watcher);
+
+        // Changes made while nothing was watching are found on start up
+        remove_file(watched.join("nested/small.jpg")).unwrap();
+        let index = IndexFile::open(&index_path).unwrap();
+        assert_eq!(index.len(), 2);
+        let mut watcher = IndexWatcher::new(&watched, index, None, config).unwrap();
+        match watcher.next_event().unwrap() {
+            WatchEvent::Removed { ref path, .. } => assert!(path.ends_with("nested/small.jpg")),
+            event => panic!("Unexpected event {:?}", event),
+        }
+        assert_eq!(watcher.index().len(), 1);
+        drop(watcher);
+        remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_ignored_changes() {
+        let root = temp_dir().join("pihash_test_watch_ignored");
+        let _ = remove_dir_all(&root);
+        let watched = root.join("watched");
+        create_dir_all(watched.join("ignored")).unwrap();
+        File::create(watched.join(IGNORE_FILE_NAME))
+            .unwrap()
+            .write_all(b"ignored/\n")
+            .unwrap();
+        let index_path = root.join("watched.index");
+        let index = IndexFile::create(&index_path, &Precision::Medium, Vec::new()).unwrap();
+        let config = WatchConfig {
+            policy: SimilarityPolicy::new(Precision::Medium),
+            scan: Default::default(),
+        };
+        let mut watcher = IndexWatcher::new(&watched, index, None, config).unwrap();
+
+        copy(
+            "test_images/sample_03_small.jpg",
+            watched.join("ignored/small.jpg"),
+        )
+        .unwrap();
+        copy("test_images/sample_02_large.jpg", watched.join("kept.jpg")).unwrap();
+        match watcher.next_event().unwrap() {
+            WatchEvent::Added { ref path, .. } => assert!(path.ends_with("kept.jpg")),
+            event => panic!("Unexpected event {:?}", event),
+        }
+        assert_eq!(watcher.index().len(), 1);
+        drop(watcher);
+        remove_dir_all(&root).unwrap();
+    }
+}